@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+
+const WARMUP_RUNS: usize = 2;
+const MEASURED_RUNS: usize = 10;
+
+// Runs the routine a few times to warm up, then reports 
+// the mean and the fastest of the measured runs
+pub fn bench<F: FnMut()>(name: &str, mut routine: F) -> Duration {
+    bench_with_setup(name, || (), |_| routine())
+}
+
+// Like bench, but each run gets its input from setup, which is not timed
+pub fn bench_with_setup<T, S, F>(name: &str, mut setup: S, mut routine: F) -> Duration
+    where S: FnMut() -> T, F: FnMut(T) {
+
+    for _ in 0..WARMUP_RUNS {
+        routine(setup());
+    }
+
+    let mut timings = Vec::with_capacity(MEASURED_RUNS);
+    for _ in 0..MEASURED_RUNS {
+        let input = setup();
+        let start = Instant::now();
+        routine(input);
+        timings.push(start.elapsed());
+    }
+
+    let total: Duration = timings.iter().sum();
+    let mean = total / MEASURED_RUNS as u32;
+    let fastest = *timings.iter().min().unwrap();
+
+    println!("{:<24} mean: {:>10.3?}   min: {:>10.3?}   ({} runs)", 
+        name, mean, fastest, MEASURED_RUNS);
+
+    mean
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use rlox::{frontend::interpreter, backend::InterpretResult};
+
+const GLOBAL_LOOP: &str = "
+    var count = 0;
+    var total = 0;
+    while (count < 100000) {
+        total = total + count;
+        count = count + 1;
+    }
+";
+
+const LOCAL_LOOP: &str = "
+    {
+        var count = 0;
+        var total = 0;
+        while (count < 100000) {
+            total = total + count;
+            count = count + 1;
+        }
+    }
+";
+
+fn main() {
+
+    common::bench("global variable loop", || {
+        assert_eq!(interpreter::interpret(GLOBAL_LOOP), InterpretResult::Ok);
+    });
+
+    common::bench("local variable loop", || {
+        assert_eq!(interpreter::interpret(LOCAL_LOOP), InterpretResult::Ok);
+    });
+
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use rlox::{frontend::{compiler::Compiler, interpreter}, backend::{InterpretResult, vm::VM, objects::FunData, globals::GlobalTable}};
+
+const FIB: &str = "
+    fun fib(n) {
+        if (n < 2) return n;
+        return fib(n - 1) + fib(n - 2);
+    }
+
+    var result = fib(22);
+";
+
+const NESTED_LOOPS: &str = "
+    {
+        var total = 0;
+        for (var i = 0; i < 300; i = i + 1) {
+            for (var j = 0; j < 300; j = j + 1) {
+                if (j == i) continue;
+                total = total + i * j;
+            }
+        }
+    }
+";
+
+const STRING_BUILDING: &str = "
+    {
+        var text = \"\";
+        for (var i = 0; i < 2000; i = i + 1) {
+            text = text + \"x\";
+        }
+    }
+";
+
+// Only the run is timed, so that the cost of the dispatch loop is not hidden by the compiler
+fn bench_run(name: &str, source: &str) {
+    common::bench_with_setup(name, || compile(source), |(mut vm, fun)| {
+        assert_eq!(vm.run_fun(fun), InterpretResult::Ok);
+    });
+}
+
+fn compile(source: &str) -> (VM, FunData) {
+    let globals = GlobalTable::new_rc_refcell();
+    let mut vm = VM::new(&globals);
+    let fun = Compiler::new(source, vm.heap_mut(), &globals).compile().unwrap();
+    (vm, fun)
+}
+
+fn main() {
+    bench_run("recursive fib(22)", FIB);
+    bench_run("nested loops", NESTED_LOOPS);
+    bench_run("string building", STRING_BUILDING);
+
+    common::bench("compile and run fib(22)", || {
+        assert_eq!(interpreter::interpret(FIB), InterpretResult::Ok);
+    });
+}
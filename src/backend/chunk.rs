@@ -1,13 +1,31 @@
 use std::collections::HashMap;
 
-use super::{value::Value, instruction::{Instruction, OpCode}};
+use super::{value::Value, instruction::{Instruction, OpCode}, heap::HeapRef};
+
+// Debug information about a local variable: it lives in the given 
+// stack slot of its frame while the ip is in [start, end)
+#[derive(Debug, Clone)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     code: Vec<u8>,
     values: Vec<Value>,
-    string_idxs: HashMap<String, usize>,
+    string_idxs: HashMap<HeapRef<String>, usize>,
+    global_names: HashMap<u32, String>, // names of referenced global slots
     lines: Vec<(i32, usize)>, // source code line mapping
+    locals: Vec<LocalInfo>, // debug table of local variables
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -21,7 +39,9 @@ impl Chunk {
             code: Vec::with_capacity(capacity),
             values: Vec::new(),
             string_idxs: HashMap::new(),
+            global_names: HashMap::new(),
             lines: Vec::new(),
+            locals: Vec::new(),
         }
     }
     
@@ -137,6 +157,10 @@ impl Chunk {
                 self.write(OpCode::Closure as u8, line);
                 self.write_u16(value_idx, line);
             },
+            Instruction::AssertFailed { value_idx } => {
+                self.write(OpCode::AssertFailed as u8, line);
+                self.write_u16(value_idx, line);
+            },
         }
 
         let next_offset = self.code.len();
@@ -144,6 +168,22 @@ impl Chunk {
         (start_offset, next_offset)
     }
 
+    pub fn truncate(&mut self, offset: usize) {
+        self.code.truncate(offset);
+
+        let mut total_offset = 0;
+        let mut num_lines = 0;
+        for (_, cnt) in self.lines.iter_mut() {
+            if total_offset >= offset {
+                break;
+            }
+            *cnt = (*cnt).min(offset - total_offset);
+            total_offset += *cnt;
+            num_lines += 1;
+        }
+        self.lines.truncate(num_lines);
+    }
+
     pub fn update_jump_offset(&mut self, offset: usize, jump_delta: u16) {
         let bytes = jump_delta.to_be_bytes();
         self.code[offset + 1] = bytes[0];
@@ -154,38 +194,35 @@ impl Chunk {
         self.code.get(offset)
     }
 
+    #[inline]
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.code[offset]
+    }
+
     pub fn read_n_bytes(&self, offset: usize, n: usize) -> &[u8] {
         &self.code[offset..(offset + n)]
     }
 
+    #[inline]
     pub fn read_u16(&self, offset: usize) -> u16 {
-        let bytes_slice = self.read_n_bytes(offset, 2);
-        let mut bytes: [u8; 2] = [0; 2];
-        for (i, byte) in bytes_slice.iter().enumerate() {
-            bytes[i] = *byte;
-        }
-        u16::from_be_bytes(bytes) 
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
     }
 
+    #[inline]
     pub fn read_u32(&self, offset: usize) -> u32 {
-        let bytes_slice = self.read_n_bytes(offset, 4);
-        let mut bytes: [u8; 4] = [0; 4];
-        for (i, byte) in bytes_slice.iter().enumerate() {
-            bytes[i] = *byte;
-        }
-        u32::from_be_bytes(bytes) 
+        u32::from_be_bytes([
+            self.code[offset], 
+            self.code[offset + 1],
+            self.code[offset + 2],
+            self.code[offset + 3],
+        ])
     }
 
     pub fn read_instruction(&self, offset: usize) -> Option<(Instruction, usize)> {
 
-        let op_code_opt = self.read(offset);
+        let op_code = *self.read(offset)?;
         let mut next_offset = offset + 1;
 
-        if op_code_opt.is_none() {
-            return None;
-        }
-
-        let op_code = op_code_opt.unwrap().clone();
         let op_code_res: Result<OpCode, String> = op_code.try_into();
 
         if op_code_res.is_err() {
@@ -196,16 +233,13 @@ impl Chunk {
             OpCode::Constant => {
                 let value_idx_opt = self.read(next_offset);
                 next_offset += 1;
-                match value_idx_opt {
-                    Some(value_idx) => 
-                        Some((Instruction::Constant { value_idx: value_idx.clone() }, next_offset)),
-                    None => None
-                }
+                value_idx_opt.map(|value_idx| 
+                    (Instruction::Constant { value_idx: *value_idx }, next_offset))
             },
             OpCode::ConstantLong => {
                 let value_idx = self.read_u32(next_offset);
                 next_offset += 4;
-                Some((Instruction::ConstantLong { value_idx: value_idx.clone() }, 
+                Some((Instruction::ConstantLong { value_idx }, 
                     next_offset))
             },
             OpCode::Nil => 
@@ -281,31 +315,30 @@ impl Chunk {
             OpCode::Call => {
                 let num_args_opt = self.read(next_offset);
                 next_offset += 1;
-                match num_args_opt {
-                    Some(num_args) => {
-                        let num_args = num_args.clone();
-                        Some((Instruction::Call { num_args }, next_offset))
-                    },
-                    None => None,
-                }
+                num_args_opt.map(|num_args|
+                    (Instruction::Call { num_args: *num_args }, next_offset))
             },
             OpCode::Closure => {
                 let value_idx = self.read_u16(next_offset);
                 next_offset += 2;
                 Some((Instruction::Closure { value_idx }, next_offset))
+            },
+            OpCode::AssertFailed => {
+                let value_idx = self.read_u16(next_offset);
+                next_offset += 2;
+                Some((Instruction::AssertFailed { value_idx }, next_offset))
             }
         }
     }
 
     pub fn add_value(&mut self, value: Value) -> usize {
-        if let Value::Str(sref) = &value {
-            let s = sref.get_string();
-            if self.string_idxs.contains_key(&s) {
-                self.string_idxs[&s]
+        if let Value::Str(sref) = value {
+            if let Some(value_idx) = self.string_idxs.get(&sref) {
+                *value_idx
             } else {
                 self.values.push(value);
                 let value_idx = self.values.len() - 1;
-                self.string_idxs.insert(s, value_idx);
+                self.string_idxs.insert(sref, value_idx);
                 value_idx
             }
         } else {
@@ -314,6 +347,48 @@ impl Chunk {
         }
     }
 
+    pub fn num_values(&self) -> usize {
+        self.values.len()
+    }
+
+    // Drops the values from len on, which no instruction may refer to anymore
+    pub fn truncate_values(&mut self, len: usize) {
+        self.values.truncate(len);
+        self.string_idxs.retain(|_, value_idx| *value_idx < len);
+    }
+
+    pub fn add_global_name(&mut self, global_idx: u32, name: &str) {
+        self.global_names
+            .entry(global_idx)
+            .or_insert_with(|| name.to_string());
+    }
+
+    pub fn get_global_name(&self, global_idx: u32) -> Option<&str> {
+        self.global_names.get(&global_idx).map(|name| name.as_str())
+    }
+
+    // Registers a local variable that is live from the current end of the chunk on
+    pub fn begin_local(&mut self, name: &str, slot: usize) -> usize {
+        self.locals.push(LocalInfo {
+            name: name.to_string(),
+            slot,
+            start: self.code.len(),
+            end: usize::MAX,
+        });
+        self.locals.len() - 1
+    }
+
+    pub fn end_local(&mut self, local_idx: usize) {
+        self.locals[local_idx].end = self.code.len();
+    }
+
+    // Local variables that are live at the offset
+    pub fn locals_at(&self, offset: usize) -> impl Iterator<Item = &LocalInfo> {
+        self.locals
+            .iter()
+            .filter(move |local| local.start <= offset && offset < local.end)
+    }
+
     pub fn read_value(&self, offset: usize) -> Option<&Value> {
         self.values.get(offset)
     }
@@ -324,14 +399,19 @@ impl Chunk {
         for (line, cnt) in self.lines.iter() {
             total_offset += cnt;
             if offset < total_offset {
-                return Some(line.clone());
+                return Some(*line);
             }
         }
 
         None
     }
 
-    pub fn instruction_iter(self: &Chunk) -> InstructionIter {
+    // Lines that have bytecode, in the order of the code
+    pub fn code_lines(&self) -> impl Iterator<Item = i32> + '_ {
+        self.lines.iter().map(|(line, _)| *line)
+    }
+
+    pub fn instruction_iter(self: &Chunk) -> InstructionIter<'_> {
         InstructionIter { 
             chunk: self,
             offset: 0 }
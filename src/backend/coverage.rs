@@ -0,0 +1,136 @@
+use std::{io::{self, Write}, collections::BTreeMap};
+
+use super::{observer::{VmObserver, VmState}, objects::FunData, heap::HeapManager, value::Value};
+
+// Records how often the bytecode of each source line is executed. Executable
+// lines are taken from the line tables of the script and all functions in it,
+// when the script starts.
+pub struct Coverage {
+    source_path: String,
+    lines: BTreeMap<i32, u64>, // hits of all executable lines
+    functions: BTreeMap<(i32, String), u64>, // calls by first line and name
+    loaded: bool,
+}
+
+impl Coverage {
+
+    pub fn new(source_path: &str) -> Coverage {
+        Coverage {
+            source_path: source_path.to_string(),
+            lines: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            loaded: false,
+        }
+    }
+
+    pub fn lines(&self) -> &BTreeMap<i32, u64> {
+        &self.lines
+    }
+
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|hits| **hits > 0).count()
+    }
+
+    // Ranges of executable lines that were never executed
+    pub fn missed_ranges(&self) -> Vec<(i32, i32)> {
+        let mut ranges: Vec<(i32, i32)> = vec![];
+        let mut previous_hit = true;
+        for (line, hits) in self.lines.iter() {
+            if *hits > 0 {
+                previous_hit = true;
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, last)) if !previous_hit => *last = *line,
+                _ => ranges.push((*line, *line)),
+            }
+            previous_hit = false;
+        }
+        ranges
+    }
+
+    // Tracefile in the LCOV format, see the man page of geninfo
+    pub fn write_lcov(&self, out: &mut dyn Write) -> io::Result<()> {
+
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", self.source_path)?;
+
+        for ((line, name), _) in self.functions.iter() {
+            writeln!(out, "FN:{},{}", line, name)?;
+        }
+        for ((_, name), calls) in self.functions.iter() {
+            writeln!(out, "FNDA:{},{}", calls, name)?;
+        }
+        writeln!(out, "FNF:{}", self.functions.len())?;
+        writeln!(out, "FNH:{}", self.functions.values().filter(|calls| **calls > 0).count())?;
+
+        for (line, hits) in self.lines.iter() {
+            writeln!(out, "DA:{},{}", line, hits)?;
+        }
+        writeln!(out, "LF:{}", self.lines.len())?;
+        writeln!(out, "LH:{}", self.lines_hit())?;
+        writeln!(out, "end_of_record")
+    }
+
+    pub fn write_summary(&self, out: &mut dyn Write) -> io::Result<()> {
+
+        let found = self.lines.len();
+        let hit = self.lines_hit();
+        let percent = if found == 0 { 100.0 } else { 100.0 * hit as f64 / found as f64 };
+        writeln!(out, "{}: {} of {} lines covered ({:.1}%)", self.source_path, hit, found, percent)?;
+
+        let missed: Vec<String> = self.missed_ranges()
+            .iter()
+            .map(|(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+            .collect();
+        if !missed.is_empty() {
+            writeln!(out, "Not covered: {}", missed.join(", "))?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, fun: &FunData, heap: &HeapManager) {
+
+        let chunk = fun.chunk();
+        for line in chunk.code_lines() {
+            self.lines.entry(line).or_insert(0);
+        }
+        if !fun.name.is_empty() {
+            self.functions.entry(function_key(fun)).or_insert(0);
+        }
+
+        let mut value_idx = 0;
+        while let Some(value) = chunk.read_value(value_idx) {
+            match value {
+                Value::Closure(closure) => self.load(heap.get_content(&heap.get_content(closure).fun), heap),
+                Value::Fun(fun) => self.load(heap.get_content(fun), heap),
+                _ => (),
+            }
+            value_idx += 1;
+        }
+    }
+
+}
+
+// Functions are told apart by the line of their first instruction
+fn function_key(fun: &FunData) -> (i32, String) {
+    (fun.chunk().get_line(0).unwrap_or(1), fun.name.clone())
+}
+
+impl VmObserver for Coverage {
+
+    fn on_instruction(&mut self, state: &VmState) {
+        *self.lines.entry(state.line()).or_insert(0) += 1;
+    }
+
+    fn on_call(&mut self, state: &VmState) {
+        if !self.loaded {
+            self.loaded = true;
+            self.load(state.function(), state.heap());
+        }
+        if let Some(calls) = self.functions.get_mut(&function_key(state.function())) {
+            *calls += 1;
+        }
+    }
+
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+// Assigns a slot to every global variable name. The compiler resolves names
+// to slots, so the VM can access globals by index. A slot may be handed out
+// before its variable is defined (e.g. when a function body refers to a 
+// global that is declared later), the VM then reports it as undefined 
+// until the definition has been executed.
+pub struct GlobalTable {
+    names: Vec<String>,
+    slots: HashMap<String, usize>,
+}
+
+impl Default for GlobalTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GlobalTable {
+
+    pub fn new() -> GlobalTable {
+        GlobalTable { 
+            names: vec![], 
+            slots: HashMap::new(), 
+        }
+    }
+
+    pub fn new_rc_refcell() -> Rc<RefCell<GlobalTable>> {
+        Rc::new(RefCell::new(GlobalTable::new()))
+    }
+
+    pub fn resolve(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        let slot = self.names.len();
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), slot);
+        slot
+    }
+
+    pub fn get_slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn get_name(&self, slot: usize) -> Option<&str> {
+        self.names.get(slot).map(|name| name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::GlobalTable;
+
+    #[test]
+    fn resolve_names() {
+
+        let mut globals = GlobalTable::new();
+
+        assert_eq!(globals.resolve("answer"), 0);
+        assert_eq!(globals.resolve("question"), 1);
+        assert_eq!(globals.resolve("answer"), 0);
+
+        assert_eq!(globals.get_slot("question"), Some(1));
+        assert_eq!(globals.get_slot("unknown"), None);
+        assert_eq!(globals.get_name(1), Some("question"));
+        assert_eq!(globals.len(), 2);
+    }
+
+}
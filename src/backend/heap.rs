@@ -1,131 +1,233 @@
-use std::{marker::PhantomData, any::Any, rc::Rc, cell::RefCell, fmt::Display};
+use std::{marker::PhantomData, any::Any, fmt::{self, Debug, Display}, hash::Hash, collections::HashMap};
 
 pub trait HeapObject {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    // Approximate number of bytes owned by the object, counted against the heap limit
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+}
+
+// An allocation that would exceed a limit of the heap
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeapError {
+    TooManyObjects,
+    TooManyBytes,
+}
+
+impl Display for HeapError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapError::TooManyObjects => write!(f, "Heap object limit exceeded."),
+            HeapError::TooManyBytes => write!(f, "Heap size limit exceeded."),
+        }
+    }
 }
 
 struct HeapEntry {
-    object: Box<dyn HeapObject>,
+    generation: u32, // incremented whenever the slot is freed
+    object: Option<Box<dyn HeapObject>>,
+    size: usize, // of the object when it was allocated
 }
 
-#[derive(Clone)]
+// Compact handle to an object owned by a HeapManager. The generation
+// allows to detect handles that outlived the object they referred to.
 pub struct HeapRef<T: HeapObject> {
-    index: usize,
-    heap_manager: Rc<RefCell<HeapManager>>,
-    _marker: PhantomData<T>,
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
 }
 
-impl <T: HeapObject + Clone + 'static> HeapRef<T> {
+impl <T: HeapObject> Clone for HeapRef<T> {
 
-    pub fn get_manager(&self) -> Rc<RefCell<HeapManager>> {
-        self.heap_manager.clone()
+    fn clone(&self) -> Self {
+        *self
     }
+}
+
+impl <T: HeapObject> Copy for HeapRef<T> {}
+
+impl <T: HeapObject> PartialEq for HeapRef<T> {
 
-    pub fn get_content(&self) -> T {
-        let hm = self.heap_manager.borrow();
-        let content = hm.get_content(self);
-        content.clone()
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
     }
- 
 }
 
-impl <T: HeapObject + PartialEq + 'static> PartialEq for HeapRef<T> {
-    
-    fn eq(&self, other: &Self) -> bool {
-        let hm = self.heap_manager.borrow();
-        let data = hm.get_content(self);
-        let other_hm = other.heap_manager.borrow();
-        let other_data = other_hm.get_content(other);
-        data == other_data
+impl <T: HeapObject> Eq for HeapRef<T> {}
+
+impl <T: HeapObject> Hash for HeapRef<T> {
+
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
     }
 }
 
-impl <T: HeapObject + Display + 'static> Display for HeapRef<T> {
-    
+impl <T: HeapObject> Debug for HeapRef<T> {
+
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.heap_manager.borrow().get_content(self))
+        write!(f, "#{}/{}", self.index, self.generation)
     }
 }
 
 pub struct HeapManager {
-    objects: Vec<Option<HeapEntry>>,
-    free_slots: Vec<usize>, 
+    entries: Vec<HeapEntry>,
+    free_slots: Vec<u32>,
+    // Interned strings. The table does not keep its strings alive:
+    // freeing an interned string removes its entry.
+    strings: HashMap<String, HeapRef<String>>,
+    num_bytes: usize, // sizes of all live objects
+    max_objects: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl Default for HeapManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HeapManager {
 
     pub fn new() -> HeapManager {
         HeapManager {
-            objects: vec![], 
-            free_slots: vec![], 
+            entries: vec![],
+            free_slots: vec![],
+            strings: HashMap::new(),
+            num_bytes: 0,
+            max_objects: None,
+            max_bytes: None,
         }
     }
 
-    pub fn new_rc_refcell() -> Rc<RefCell<HeapManager>> {
-        Rc::new(RefCell::new(HeapManager::new()))
+    // Allocations fail once they would exceed a limit, None means unlimited
+    pub fn set_limits(&mut self, max_objects: Option<usize>, max_bytes: Option<usize>) {
+        self.max_objects = max_objects;
+        self.max_bytes = max_bytes;
     }
 
-    pub fn malloc<T: HeapObject + 'static>(heap_manager: &Rc<RefCell<Self>>, object: T) -> HeapRef<T> {
+    // Returns the unique string object with the given content, so that
+    // strings can be compared by handle
+    pub fn intern(&mut self, s: &str) -> Result<HeapRef<String>, HeapError> {
+        if let Some(sref) = self.strings.get(s) {
+            return Ok(*sref);
+        }
+        let sref = self.malloc(s.to_string())?;
+        self.strings.insert(s.to_string(), sref);
+        Ok(sref)
+    }
 
-        let mut hm = heap_manager.as_ref().borrow_mut();
+    pub fn intern_string(&mut self, s: String) -> Result<HeapRef<String>, HeapError> {
+        if let Some(sref) = self.strings.get(&s) {
+            return Ok(*sref);
+        }
+        let sref = self.malloc(s.clone())?;
+        self.strings.insert(s, sref);
+        Ok(sref)
+    }
 
-        let index = if let Some(index) = hm.free_slots.pop() {
-            hm.objects[index] = Some(HeapEntry {
-                object: Box::new(object),
-            });
+    pub fn num_interned(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn malloc<T: HeapObject + 'static>(&mut self, object: T) -> Result<HeapRef<T>, HeapError> {
+
+        let size = object.size();
+        if self.max_objects.is_some_and(|max_objects| self.num_objects() >= max_objects) {
+            return Err(HeapError::TooManyObjects);
+        }
+        if self.max_bytes.is_some_and(|max_bytes| self.num_bytes + size > max_bytes) {
+            return Err(HeapError::TooManyBytes);
+        }
+        self.num_bytes += size;
+
+        let index = if let Some(index) = self.free_slots.pop() {
+            let entry = &mut self.entries[index as usize];
+            entry.object = Some(Box::new(object));
+            entry.size = size;
             index
         } else {
-            hm.objects.push(Some(HeapEntry {
-                object: Box::new(object),
-            }));
-            hm.objects.len() - 1
+            self.entries.push(HeapEntry {
+                generation: 0,
+                object: Some(Box::new(object)),
+                size,
+            });
+            (self.entries.len() - 1) as u32
         };
 
-        HeapRef { 
-            index, 
-            heap_manager: heap_manager.clone(),
-            _marker: PhantomData 
-        }
+        Ok(HeapRef {
+            index,
+            generation: self.entries[index as usize].generation,
+            _marker: PhantomData
+        })
     }
 
     pub fn free<T: HeapObject>(&mut self, obj_ref: HeapRef<T>) {
-        self.free_at_index(obj_ref.index);
-    } 
+        if self.is_valid(&obj_ref) {
+            self.free_at_index(obj_ref.index);
+        }
+    }
 
-    fn free_at_index(&mut self, index: usize) {
-        self.objects[index] = None;
+    fn free_at_index(&mut self, index: u32) {
+        let entry = &mut self.entries[index as usize];
+        let object = entry.object.take();
+        self.num_bytes -= entry.size;
+        if let Some(s) = object.as_ref().and_then(|obj| obj.as_any().downcast_ref::<String>()) {
+            if self.strings.get(s).is_some_and(|sref| sref.index == index) {
+                self.strings.remove(s);
+            }
+        }
+        let entry = &mut self.entries[index as usize];
+        entry.generation = entry.generation.wrapping_add(1);
         self.free_slots.push(index);
     }
 
     pub fn free_all(&mut self) {
-        let idxs_to_free: Vec<usize> = self.objects
+        let idxs_to_free: Vec<u32> = self.entries
             .iter()
             .enumerate()
-            .filter(|(_, entry_opt)|entry_opt.is_some())
-            .map(|(idx, _)| idx)
+            .filter(|(_, entry)| entry.object.is_some())
+            .map(|(idx, _)| idx as u32)
             .collect();
         idxs_to_free.iter().for_each(|i| self.free_at_index(*i));
     }
 
+    pub fn is_valid<T: HeapObject>(&self, obj_ref: &HeapRef<T>) -> bool {
+        match self.entries.get(obj_ref.index as usize) {
+            Some(entry) => entry.generation == obj_ref.generation && entry.object.is_some(),
+            None => false,
+        }
+    }
+
+    pub fn num_objects(&self) -> usize {
+        self.entries.len() - self.free_slots.len()
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
     pub fn get_content<T: HeapObject + 'static>(&self, obj_ref: &HeapRef<T>) -> &T {
-        self.objects.get(obj_ref.index)
-            .unwrap()
+        let entry = &self.entries[obj_ref.index as usize];
+        assert_eq!(entry.generation, obj_ref.generation, "Access to freed heap object.");
+        entry.object
             .as_ref()
             .unwrap()
-            .object
-            .as_ref()
             .as_any()
             .downcast_ref()
             .unwrap()
     }
 
     pub fn get_content_mut<T: HeapObject + 'static>(&mut self, obj_ref: &HeapRef<T>) -> &mut T {
-        self.objects[obj_ref.index]
+        let entry = &mut self.entries[obj_ref.index as usize];
+        assert_eq!(entry.generation, obj_ref.generation, "Access to freed heap object.");
+        entry.object
             .as_mut()
             .unwrap()
-            .object
-            .as_mut()
             .as_any_mut()
             .downcast_mut()
             .unwrap()
@@ -136,18 +238,74 @@ impl HeapManager {
 #[cfg(test)]
 mod tests {
 
-    use super::HeapManager;
+    use super::{HeapManager, HeapError};
 
     #[test]
     fn allocate_then_free() {
 
-        let hm = HeapManager::new_rc_refcell();
-        let obj_ref = HeapManager::malloc(&hm, "My String".to_string());
+        let mut hm = HeapManager::new();
+        let obj_ref = hm.malloc("My String".to_string()).unwrap();
 
         assert_eq!(obj_ref.index, 0);
-        println!("{}", obj_ref.get_manager().borrow_mut().get_content_mut(&obj_ref));
+        println!("{}", hm.get_content_mut(&obj_ref));
+
+        hm.free(obj_ref);
+        assert_eq!(hm.num_objects(), 0);
+    }
+
+    #[test]
+    fn reuse_slot_with_new_generation() {
+
+        let mut hm = HeapManager::new();
+        let old_ref = hm.malloc("old".to_string()).unwrap();
+        hm.free(old_ref);
+        let new_ref = hm.malloc("new".to_string()).unwrap();
+
+        assert_eq!(new_ref.index, old_ref.index);
+        assert_ne!(new_ref, old_ref);
+        assert!(!hm.is_valid(&old_ref));
+        assert_eq!(hm.get_content(&new_ref), "new");
+    }
+
+    #[test]
+    fn intern_strings() {
+
+        let mut hm = HeapManager::new();
+        let s1 = hm.intern("Hallo").unwrap();
+        let s2 = hm.intern_string("Hallo".to_string()).unwrap();
+        let s3 = hm.intern("Welt").unwrap();
+
+        assert_eq!(s1, s2);
+        assert_ne!(s1, s3);
+        assert_eq!(hm.num_objects(), 2);
+
+        hm.free(s1);
+        assert_eq!(hm.num_interned(), 1);
+        let s4 = hm.intern("Hallo").unwrap();
+        assert_ne!(s4, s1);
+        assert_eq!(hm.get_content(&s4), "Hallo");
+    }
+
+    #[test]
+    fn enforce_limits() {
+
+        let mut hm = HeapManager::new();
+        hm.set_limits(Some(2), None);
+        let s1 = hm.malloc("1".to_string()).unwrap();
+        hm.malloc("2".to_string()).unwrap();
+        assert_eq!(hm.malloc("3".to_string()), Err(HeapError::TooManyObjects));
+        hm.free(s1);
+        assert!(hm.malloc("3".to_string()).is_ok());
 
-        hm.borrow_mut().free(obj_ref);
+        let mut hm = HeapManager::new();
+        let big = hm.malloc("x".repeat(100)).unwrap();
+        assert!(hm.num_bytes() > 100);
+        hm.set_limits(None, Some(hm.num_bytes() + 50));
+        assert_eq!(hm.intern(&"y".repeat(100)), Err(HeapError::TooManyBytes));
+        assert_eq!(hm.num_interned(), 0);
+        hm.free(big);
+        assert_eq!(hm.num_bytes(), 0);
+        assert!(hm.intern(&"y".repeat(100)).is_ok());
     }
 
 }
@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     Constant,
     ConstantLong,
@@ -29,43 +29,51 @@ pub enum OpCode {
     Loop,
     Call,
     Closure,
+    AssertFailed,
 }
 
+// Opcodes in the order of their byte values, so that decoding
+// a byte is a single table lookup
+const OP_CODES: [OpCode; 28] = [
+    OpCode::Constant,
+    OpCode::ConstantLong,
+    OpCode::Nil,
+    OpCode::True,
+    OpCode::False,
+    OpCode::Negate,
+    OpCode::Not,
+    OpCode::Equal,
+    OpCode::Greater,
+    OpCode::Less,
+    OpCode::Add,
+    OpCode::Subtract,
+    OpCode::Multiply,
+    OpCode::Divide,
+    OpCode::Return,
+    OpCode::Print,
+    OpCode::Pop,
+    OpCode::DefineGlobal,
+    OpCode::GetGlobal,
+    OpCode::SetGlobal,
+    OpCode::GetLocal,
+    OpCode::SetLocal,
+    OpCode::Jump,
+    OpCode::JumpIfFalse,
+    OpCode::Loop,
+    OpCode::Call,
+    OpCode::Closure,
+    OpCode::AssertFailed,
+];
+
 impl TryFrom<u8> for OpCode {
 
     type Error = String;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            v if v == OpCode::Constant as u8 => Ok(OpCode::Constant),
-            v if v == OpCode::ConstantLong as u8 => Ok(OpCode::ConstantLong),
-            v if v == OpCode::Nil as u8 => Ok(OpCode::Nil),
-            v if v == OpCode::True as u8 => Ok(OpCode::True),
-            v if v == OpCode::False as u8 => Ok(OpCode::False),
-            v if v == OpCode::Negate as u8 => Ok(OpCode::Negate),
-            v if v == OpCode::Not as u8 => Ok(OpCode::Not),
-            v if v == OpCode::Equal as u8 => Ok(OpCode::Equal),
-            v if v == OpCode::Greater as u8 => Ok(OpCode::Greater),
-            v if v == OpCode::Less as u8 => Ok(OpCode::Less),
-            v if v == OpCode::Add as u8 => Ok(OpCode::Add),
-            v if v == OpCode::Subtract as u8 => Ok(OpCode::Subtract),
-            v if v == OpCode::Multiply as u8 => Ok(OpCode::Multiply),
-            v if v == OpCode::Divide as u8 => Ok(OpCode::Divide),
-            v if v == OpCode::Return as u8 => Ok(OpCode::Return),
-            v if v == OpCode::Print as u8 => Ok(OpCode::Print),
-            v if v == OpCode::Pop as u8 => Ok(OpCode::Pop),
-            v if v == OpCode::DefineGlobal as u8 => Ok(OpCode::DefineGlobal),
-            v if v == OpCode::GetGlobal as u8 => Ok(OpCode::GetGlobal),
-            v if v == OpCode::SetGlobal as u8 => Ok(OpCode::SetGlobal),
-            v if v == OpCode::GetLocal as u8 => Ok(OpCode::GetLocal),
-            v if v == OpCode::SetLocal as u8 => Ok(OpCode::SetLocal),
-            v if v == OpCode::Jump as u8 => Ok(OpCode::Jump),
-            v if v == OpCode::JumpIfFalse as u8 => Ok(OpCode::JumpIfFalse),
-            v if v == OpCode::Loop as u8 => Ok(OpCode::Loop),
-            v if v == OpCode::Call as u8 => Ok(OpCode::Call),
-            v if v == OpCode::Closure as u8 => Ok(OpCode::Closure),
-            _ => Err(format!("Unknown opcode {}", value))
-        }
+        OP_CODES
+            .get(value as usize)
+            .copied()
+            .ok_or_else(|| format!("Unknown opcode {}", value))
     }
 }
 
@@ -97,6 +105,7 @@ pub enum Instruction {
     Loop{jump_distance: u16},
     Call{num_args:u8},
     Closure{value_idx: u16},
+    AssertFailed{value_idx: u16}, // of the asserted source text
 }
 
 impl Display for Instruction {
@@ -142,6 +151,23 @@ impl Display for Instruction {
                 write!(f, "Call({num_args})"),
             Self::Closure { value_idx } =>
                 write!(f, "Closure({value_idx})"),
+            Self::AssertFailed { value_idx } =>
+                write!(f, "AssertFailed({value_idx})"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{OpCode, OP_CODES};
+
+    #[test]
+    fn decode_op_codes() {
+
+        for op_code in OP_CODES {
+            assert_eq!(OpCode::try_from(op_code as u8), Ok(op_code));
+        }
+        assert!(OpCode::try_from(OP_CODES.len() as u8).is_err());
+    }
+
+}
@@ -0,0 +1,54 @@
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+// Asks a running VM to stop. Clones share the same flag, so a handle can be
+// given to another thread or a signal handler while the VM runs.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+
+    pub fn new() -> InterruptHandle {
+        InterruptHandle::default()
+    }
+
+    // The VM polls the handle and stops with InterpretResult::Interrupted
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.requested.store(false, Ordering::Relaxed);
+    }
+
+    // Returns whether an interrupt was requested and clears the request
+    pub(crate) fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::Relaxed)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::InterruptHandle;
+
+    #[test]
+    fn clones_share_the_request() {
+
+        let handle = InterruptHandle::new();
+        let clone = handle.clone();
+        assert!(!handle.is_interrupted());
+
+        std::thread::spawn(move || clone.interrupt()).join().unwrap();
+        assert!(handle.is_interrupted());
+        assert!(handle.take());
+        assert!(!handle.take());
+    }
+
+}
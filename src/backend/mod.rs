@@ -6,5 +6,11 @@ pub mod vm;
 pub mod heap;
 pub mod objects;
 pub mod native;
+pub mod globals;
+pub mod trace;
+pub mod observer;
+pub mod interrupt;
+pub mod profiler;
+pub mod coverage;
 
 pub use vm::InterpretResult;
\ No newline at end of file
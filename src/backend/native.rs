@@ -1,30 +1,117 @@
 // Native functions
 
-use super::value::Value;
+use super::{value::Value, objects::{NativeContext, NativeError}, heap::HeapManager};
 
-pub fn sqrt(args: Vec<Value>) -> Result<Value, String> {
+pub fn sqrt(_ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
     if args.len() != 1 {
-        return Err("'sqrt' expects one argument.".to_string());
+        return Err("'sqrt' expects one argument.".into());
     }
     let arg = &args[0];
     match arg {
         Value::Number(x) => Ok(Value::Number(x.sqrt())),
-        _ => Err("Expect number as argument for 'sqrt'".to_string()),
+        _ => Err("Expect number as argument for 'sqrt'".into()),
     }
 }
 
-pub fn concat(args: Vec<Value>) -> Result<Value, String> {
+pub fn concat(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
     if args.len() != 2 {
-        return Err("'concat' expects two arguments.".to_string());
+        return Err("'concat' expects two arguments.".into());
     }
     let s1 = match &args[0] {
         Value::Str(s) => s,
-        _ => return Err("'concat' expects string arguments.".to_string()),
+        _ => return Err("'concat' expects string arguments.".into()),
     };
     let s2 = match &args[1] {
         Value::Str(s) => s,
-        _ => return Err("'concat' expects string arguments.".to_string()),
+        _ => return Err("'concat' expects string arguments.".into()),
     };
 
-    Ok(Value::Str(s1.concat(&s2)))
-}
\ No newline at end of file
+    Ok(Value::Str(s1.concat(s2, ctx.heap)?))
+}
+
+// Number of arguments passed to the script
+pub fn argc(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
+    if !args.is_empty() {
+        return Err("'argc' expects no arguments.".into());
+    }
+    Ok(Value::Number(ctx.script_args.len() as f64))
+}
+
+// All script arguments separated by spaces, Lox has no lists to return them in
+pub fn args(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
+    if !args.is_empty() {
+        return Err("'args' expects no arguments.".into());
+    }
+    Ok(Value::Str(ctx.heap.intern(&ctx.script_args.join(" "))?))
+}
+
+// Script argument with the given index or nil if there is none
+pub fn arg(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
+    if args.len() != 1 {
+        return Err("'arg' expects one argument.".into());
+    }
+    let idx = match args[0] {
+        Value::Number(idx) if idx >= 0.0 && idx.fract() == 0.0 => idx as usize,
+        _ => return Err("Expect non-negative integer as argument for 'arg'".into()),
+    };
+    match ctx.script_args.get(idx) {
+        Some(arg) => Ok(Value::Str(ctx.heap.intern(arg)?)),
+        None => Ok(Value::Nil),
+    }
+}
+
+pub fn assert_eq(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
+    if args.len() != 2 {
+        return Err("'assert_eq' expects two arguments.".into());
+    }
+    if args[0] == args[1] {
+        return Ok(Value::Nil);
+    }
+    Err(NativeError::Assertion(format!("Values are not equal.\n{}", compare(&args[0], &args[1], ctx.heap))))
+}
+
+pub fn assert_ne(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError> {
+    if args.len() != 2 {
+        return Err("'assert_ne' expects two arguments.".into());
+    }
+    if args[0] != args[1] {
+        return Ok(Value::Nil);
+    }
+    Err(NativeError::Assertion(format!("Values are equal.\n  both:  {}", describe(&args[0], false, ctx.heap))))
+}
+
+// Both values below each other, for strings with a mark where they start to differ
+fn compare(left: &Value, right: &Value, heap: &HeapManager) -> String {
+    let show_types = std::mem::discriminant(left) != std::mem::discriminant(right);
+    let (left_text, right_text) = (describe(left, show_types, heap), describe(right, show_types, heap));
+    let mut lines = format!("  left:  {}\n  right: {}", left_text, right_text);
+    if let (Value::Str(_), Value::Str(_)) = (left, right) {
+        let column = left_text
+            .chars()
+            .zip(right_text.chars())
+            .take_while(|(l, r)| l == r)
+            .count();
+        lines.push_str(&format!("\n         {}^", " ".repeat(column)));
+    }
+    lines
+}
+
+// Strings are quoted, so that "1" and 1 can be told apart
+fn describe(value: &Value, show_type: bool, heap: &HeapManager) -> String {
+    let text = match value {
+        Value::Str(s) => format!("\"{}\"", s.get_str(heap)),
+        _ => value.display(heap).to_string(),
+    };
+    let type_name = match value {
+        Value::Str(_) => "string",
+        Value::Number(_) => "number",
+        Value::Bool(_) => "bool",
+        Value::Nil => return text,
+        _ => "function",
+    };
+    if show_type {
+        format!("{} ({})", text, type_name)
+    } else {
+        text
+    }
+}
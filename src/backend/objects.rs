@@ -1,9 +1,9 @@
-use std::{fmt::Display, rc::Rc, cell::{RefCell, RefMut, Ref}};
+use std::fmt::Display;
 
-use super::{heap::{HeapObject, HeapRef, HeapManager}, chunk::Chunk, value::Value};
+use super::{heap::{HeapObject, HeapRef, HeapManager, HeapError}, chunk::Chunk, value::Value};
 
 impl HeapObject for String {
-    
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -11,39 +11,38 @@ impl HeapObject for String {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<String>() + self.len()
+    }
 }
 
 impl HeapRef<String> {
 
-    pub fn concat(&self, other: &HeapRef<String>) -> HeapRef<String> {
-        let s = self.get_string();
-        let other_s = other.get_string();
-        let new_string = s + &other_s;
-        HeapManager::malloc(&self.get_manager(), new_string)
+    pub fn concat(&self, other: &HeapRef<String>, heap: &mut HeapManager) -> Result<HeapRef<String>, HeapError> {
+        let new_string = self.get_str(heap).to_owned() + other.get_str(heap);
+        heap.intern_string(new_string)
     }
 
-    pub fn get_string(&self) -> String {
-        let hm = self.get_manager();
-        let hm_ref = hm.borrow();
-        hm_ref.get_content(self).to_owned()
+    pub fn get_str<'h>(&self, heap: &'h HeapManager) -> &'h str {
+        heap.get_content(self).as_str()
     }
 
 }
 
-#[derive(Clone)]
 pub struct FunData {
     pub arity: u8,
-    chunk: Rc<RefCell<Chunk>>,
+    chunk: Chunk,
     pub name: String,
 }
 
 impl FunData {
 
     pub fn new(name: &str, arity: u8, chunk: Chunk) -> FunData {
-        FunData { 
-            arity, 
-            chunk: Rc::new(RefCell::new(chunk)), 
-            name: name.to_string(), 
+        FunData {
+            arity,
+            chunk,
+            name: name.to_string(),
         }
     }
 
@@ -51,18 +50,18 @@ impl FunData {
         Self::new("", 0, Chunk::new())
     }
 
-    pub fn borrow_chunk(&self) -> Ref<Chunk> {
-        self.chunk.borrow()
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
     }
 
-    pub fn borrow_chunk_mut(&mut self) -> RefMut<Chunk> {
-        self.chunk.borrow_mut()
+    pub fn chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.chunk
     }
 
 }
 
 impl HeapObject for FunData {
-    
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -70,6 +69,10 @@ impl HeapObject for FunData {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<FunData>() + self.chunk.size()
+    }
 }
 
 impl Display for FunData {
@@ -79,18 +82,40 @@ impl Display for FunData {
     }
 }
 
-impl PartialEq for FunData {
+// What native functions may access besides their arguments
+pub struct NativeContext<'a> {
+    pub heap: &'a mut HeapManager,
+    pub script_args: &'a [String],
+}
 
-    fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+// Why a native function failed
+#[derive(Debug, PartialEq)]
+pub enum NativeError {
+    Message(String),
+    Assertion(String), // failed check of a test native
+    Heap(HeapError), // reported like the heap errors of the VM
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
     }
+}
 
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        NativeError::Message(message.to_string())
+    }
 }
 
+impl From<HeapError> for NativeError {
+    fn from(error: HeapError) -> Self {
+        NativeError::Heap(error)
+    }
+}
 
-pub type NativeFn = fn(params: Vec<Value>) -> Result<Value, String>;
+pub type NativeFn = fn(ctx: &mut NativeContext, args: &[Value]) -> Result<Value, NativeError>;
 
-#[derive(Clone)]
 pub struct NativeFunData {
     pub name: String,
     pub arity: u8,
@@ -100,17 +125,17 @@ pub struct NativeFunData {
 impl NativeFunData {
 
     pub fn new(name: &str, arity: u8, native_fn: NativeFn) -> NativeFunData {
-        NativeFunData { 
+        NativeFunData {
             name: name.to_string(),
-            arity, 
-            fun: native_fn, 
+            arity,
+            fun: native_fn,
         }
     }
 
 }
 
 impl HeapObject for NativeFunData {
-    
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -128,36 +153,20 @@ impl Display for NativeFunData {
 
 }
 
-impl PartialEq for NativeFunData {
-
-    fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
-    }
-
-}
-
-#[derive(Clone)]
 pub struct ClosureData {
-    pub fun_data: Rc<RefCell<FunData>>,
+    pub fun: HeapRef<FunData>,
 }
 
 impl ClosureData {
-    
-    pub fn new(fun_data: FunData) -> ClosureData {
-        ClosureData { fun_data: Rc::new(RefCell::new(fun_data)) }
-    }
 
-    pub fn borrow_fun(&self) -> Ref<FunData> {
-        self.fun_data.borrow()
+    pub fn new(fun: HeapRef<FunData>) -> ClosureData {
+        ClosureData { fun }
     }
 
-    pub fn borrow_fun_mut(&mut self) -> RefMut<FunData> {
-        self.fun_data.borrow_mut()
-    }
 }
 
 impl HeapObject for ClosureData {
-    
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -166,20 +175,3 @@ impl HeapObject for ClosureData {
         self
     }
 }
-
-impl Display for ClosureData {
-
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let fun_data = &self.fun_data.as_ref().borrow();
-        write!(f, "<fn {}/{}>", fun_data.name, fun_data.arity)
-    }
-
-}
-
-impl PartialEq for ClosureData {
-
-    fn eq(&self, other: &Self) -> bool {
-        self.fun_data == other.fun_data
-    }
-
-}
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::{rc::Rc, cell::{RefCell, Cell}};
+
+use super::{vm::VM, chunk::Chunk, value::Value, heap::HeapManager, objects::{FunData, NativeFunData}};
+
+// Callbacks of the VM during execution. All methods default to doing
+// nothing, so observers only implement what they are interested in.
+pub trait VmObserver {
+
+    // Before the instruction at state.ip() is executed
+    fn on_instruction(&mut self, _state: &VmState) {}
+
+    // After a frame for the function has been entered (also the top-level script)
+    fn on_call(&mut self, _state: &VmState) {}
+
+    // Before the frame of the function is left, the result is on top of the stack
+    fn on_return(&mut self, _state: &VmState) {}
+
+    // Before the native function is called, its arguments are on top of the stack
+    fn on_native_call(&mut self, _state: &VmState, _native: &NativeFunData) {}
+
+    // Before the error is reported, the frames are still intact
+    fn on_error(&mut self, _state: &VmState, _message: &str) {}
+
+}
+
+// Allows the owner to keep access to an observer that is installed in the VM
+impl <T: VmObserver> VmObserver for Rc<RefCell<T>> {
+
+    fn on_instruction(&mut self, state: &VmState) {
+        self.borrow_mut().on_instruction(state);
+    }
+
+    fn on_call(&mut self, state: &VmState) {
+        self.borrow_mut().on_call(state);
+    }
+
+    fn on_return(&mut self, state: &VmState) {
+        self.borrow_mut().on_return(state);
+    }
+
+    fn on_native_call(&mut self, state: &VmState, native: &NativeFunData) {
+        self.borrow_mut().on_native_call(state, native);
+    }
+
+    fn on_error(&mut self, state: &VmState, message: &str) {
+        self.borrow_mut().on_error(state, message);
+    }
+
+}
+
+// Read-only view of the VM for observers
+pub struct VmState<'a> {
+    vm: &'a VM,
+    ip: usize,
+    stop: Cell<bool>,
+}
+
+impl <'a> VmState<'a> {
+
+    pub(crate) fn new(vm: &'a VM, ip: usize) -> VmState<'a> {
+        VmState { vm, ip, stop: Cell::new(false) }
+    }
+
+    // Asks the VM to stop the execution with InterpretResult::Interrupted. 
+    // Only honored in on_instruction.
+    pub fn stop(&self) {
+        self.stop.set(true);
+    }
+
+    pub(crate) fn stop_requested(&self) -> bool {
+        self.stop.get()
+    }
+
+    // Offset of the current instruction in the chunk of the current function
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn line(&self) -> i32 {
+        self.chunk().get_line(self.ip).unwrap_or(1)
+    }
+
+    pub fn function(&self) -> &'a FunData {
+        self.vm.current_fun()
+    }
+
+    // Name of the current function, "script" for the top level
+    pub fn function_name(&self) -> &'a str {
+        match self.function().name.as_str() {
+            "" => "script",
+            name => name,
+        }
+    }
+
+    pub fn chunk(&self) -> &'a Chunk {
+        self.function().chunk()
+    }
+
+    // Number of active call frames
+    pub fn depth(&self) -> usize {
+        self.vm.frame_count()
+    }
+
+    pub fn stack(&self) -> &'a [Value] {
+        self.vm.stack()
+    }
+
+    // Stack slots of the current frame, starting with the called function
+    pub fn frame_slots(&self) -> &'a [Value] {
+        &self.vm.stack()[self.vm.frame_base()..]
+    }
+
+    pub fn heap(&self) -> &'a HeapManager {
+        self.vm.heap()
+    }
+
+    // Local variables of the current frame that are live at the ip
+    pub fn locals(&self) -> Vec<(&'a str, Value)> {
+        self.frame_locals(0)
+    }
+
+    // Local variables of the frame at the given depth, 0 is the current frame
+    pub fn frame_locals(&self, depth: usize) -> Vec<(&'a str, Value)> {
+        let (fun, saved_ip, base) = match self.vm.frame(depth) {
+            Some(frame) => frame,
+            None => return vec![],
+        };
+        let ip = if depth == 0 { self.ip } else { saved_ip };
+        let slots = &self.stack()[base..];
+        fun.chunk()
+            .locals_at(ip)
+            .filter_map(|local| Some((local.name.as_str(), *slots.get(local.slot)?)))
+            .collect()
+    }
+
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.vm.defined_globals()
+    }
+
+    // Function names (empty for the top level) and lines of all frames, innermost first
+    pub fn backtrace(&self) -> Vec<(String, i32)> {
+        self.vm.backtrace(self.ip)
+    }
+
+}
@@ -0,0 +1,256 @@
+use std::{io::{self, Write}, collections::{HashMap, BTreeMap}, time::{Duration, Instant}, cmp::Reverse};
+
+use super::{observer::{VmObserver, VmState}, objects::NativeFunData};
+
+// Functions are identified by name, so functions with the same name
+// share their statistics like they share a line in a flame graph
+#[derive(Debug, Default)]
+pub struct FunctionProfile {
+    pub name: String, // "script" for the top level
+    pub calls: u64,
+    pub instructions: u64,
+    pub self_time: Duration,
+    pub total_time: Duration, // including callees, recursive calls are counted once
+    pub lines: BTreeMap<i32, LineProfile>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LineProfile {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct NativeProfile {
+    pub name: String,
+    pub calls: u64,
+    pub time: Duration,
+}
+
+struct Frame {
+    function_idx: usize,
+    stack_idx: usize, // into the folded stacks
+    entered: Instant,
+}
+
+// What the time until the next event is attributed to
+#[derive(Clone, Copy)]
+enum Target {
+    Nothing,
+    Line { function_idx: usize, line: i32, stack_idx: usize },
+    Native { native_idx: usize, stack_idx: usize },
+}
+
+// Measures where a program spends its instructions and time. The time between
+// two events of the VM is attributed to the instruction or native function
+// that was started by the first one.
+pub struct Profiler {
+    functions: Vec<FunctionProfile>,
+    function_idxs: HashMap<String, usize>,
+    natives: Vec<NativeProfile>,
+    native_idxs: HashMap<String, usize>,
+    stacks: Vec<(String, Duration)>, // folded call stacks like "script;outer;inner"
+    stack_idxs: HashMap<String, usize>,
+    frames: Vec<Frame>,
+    target: Target,
+    last_event: Instant,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+
+    pub fn new() -> Profiler {
+        Profiler {
+            functions: vec![],
+            function_idxs: HashMap::new(),
+            natives: vec![],
+            native_idxs: HashMap::new(),
+            stacks: vec![],
+            stack_idxs: HashMap::new(),
+            frames: vec![],
+            target: Target::Nothing,
+            last_event: Instant::now(),
+        }
+    }
+
+    pub fn functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    pub fn natives(&self) -> &[NativeProfile] {
+        &self.natives
+    }
+
+    // Attributes the time since the last event and closes the frames that are
+    // still open, e.g. after a runtime error. Call it after the run.
+    pub fn finish(&mut self) {
+        let now = self.attribute_elapsed();
+        self.target = Target::Nothing;
+        while !self.frames.is_empty() {
+            self.leave_frame(now);
+        }
+    }
+
+    // Statistics per function, source line and native function, the most expensive first
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+
+        let mut functions: Vec<&FunctionProfile> = self.functions.iter().collect();
+        functions.sort_by_key(|function| Reverse(function.self_time));
+
+        writeln!(out, "{:>8} {:>12} {:>10} {:>10}  function", "calls", "instructions", "self ms", "total ms")?;
+        for function in functions.iter() {
+            writeln!(out, "{:>8} {:>12} {:>10.3} {:>10.3}  {}",
+                function.calls, function.instructions, millis(function.self_time), millis(function.total_time), function.name)?;
+        }
+
+        let mut lines: Vec<(&str, i32, &LineProfile)> = functions
+            .iter()
+            .flat_map(|function| function.lines
+                .iter()
+                .map(|(line, profile)| (function.name.as_str(), *line, profile)))
+            .collect();
+        lines.sort_by_key(|(_, _, profile)| Reverse(profile.time));
+
+        writeln!(out)?;
+        writeln!(out, "{:>8} {:>12} {:>10}  function", "line", "instructions", "self ms")?;
+        for (name, line, profile) in lines {
+            writeln!(out, "{:>8} {:>12} {:>10.3}  {}", line, profile.instructions, millis(profile.time), name)?;
+        }
+
+        if !self.natives.is_empty() {
+            let mut natives: Vec<&NativeProfile> = self.natives.iter().collect();
+            natives.sort_by_key(|native| Reverse(native.time));
+
+            writeln!(out)?;
+            writeln!(out, "{:>8} {:>10}  native", "calls", "ms")?;
+            for native in natives {
+                writeln!(out, "{:>8} {:>10.3}  {}", native.calls, millis(native.time), native.name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // One line per call stack with its self time in microseconds,
+    // the input format of flamegraph.pl and similar tools
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (stack, time) in self.stacks.iter() {
+            let micros = time.as_micros();
+            if micros > 0 {
+                writeln!(out, "{} {}", stack, micros)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn attribute_elapsed(&mut self) -> Instant {
+        let now = Instant::now();
+        let elapsed = now - self.last_event;
+        self.last_event = now;
+
+        match self.target {
+            Target::Nothing => (),
+            Target::Line { function_idx, line, stack_idx } => {
+                let function = &mut self.functions[function_idx];
+                function.self_time += elapsed;
+                function.lines.entry(line).or_default().time += elapsed;
+                self.stacks[stack_idx].1 += elapsed;
+            },
+            Target::Native { native_idx, stack_idx } => {
+                self.natives[native_idx].time += elapsed;
+                self.stacks[stack_idx].1 += elapsed;
+            },
+        }
+        now
+    }
+
+    fn leave_frame(&mut self, now: Instant) {
+        if let Some(frame) = self.frames.pop() {
+            if self.frames.iter().all(|outer| outer.function_idx != frame.function_idx) {
+                self.functions[frame.function_idx].total_time += now - frame.entered;
+            }
+        }
+    }
+
+    fn stack_idx(&mut self, name: &str) -> usize {
+        let stack = match self.frames.last() {
+            Some(frame) => format!("{};{}", self.stacks[frame.stack_idx].0, name),
+            None => name.to_string(),
+        };
+        if let Some(idx) = self.stack_idxs.get(&stack) {
+            return *idx;
+        }
+        self.stacks.push((stack.clone(), Duration::ZERO));
+        self.stack_idxs.insert(stack, self.stacks.len() - 1);
+        self.stacks.len() - 1
+    }
+
+    fn function_idx(&mut self, name: &str) -> usize {
+        if let Some(idx) = self.function_idxs.get(name) {
+            return *idx;
+        }
+        self.functions.push(FunctionProfile { name: name.to_string(), ..FunctionProfile::default() });
+        self.function_idxs.insert(name.to_string(), self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+
+    fn native_idx(&mut self, name: &str) -> usize {
+        if let Some(idx) = self.native_idxs.get(name) {
+            return *idx;
+        }
+        self.natives.push(NativeProfile { name: name.to_string(), ..NativeProfile::default() });
+        self.native_idxs.insert(name.to_string(), self.natives.len() - 1);
+        self.natives.len() - 1
+    }
+
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl VmObserver for Profiler {
+
+    fn on_instruction(&mut self, state: &VmState) {
+        self.attribute_elapsed();
+        let frame = match self.frames.last() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let (function_idx, stack_idx, line) = (frame.function_idx, frame.stack_idx, state.line());
+        let function = &mut self.functions[function_idx];
+        function.instructions += 1;
+        function.lines.entry(line).or_default().instructions += 1;
+        self.target = Target::Line { function_idx, line, stack_idx };
+    }
+
+    fn on_call(&mut self, state: &VmState) {
+        let now = self.attribute_elapsed();
+        let name = state.function_name();
+        let stack_idx = self.stack_idx(name);
+        let function_idx = self.function_idx(name);
+        self.functions[function_idx].calls += 1;
+        self.frames.push(Frame { function_idx, stack_idx, entered: now });
+        self.target = Target::Nothing;
+    }
+
+    fn on_return(&mut self, _state: &VmState) {
+        let now = self.attribute_elapsed();
+        self.leave_frame(now);
+        self.target = Target::Nothing;
+    }
+
+    fn on_native_call(&mut self, _state: &VmState, native: &NativeFunData) {
+        self.attribute_elapsed();
+        let stack_idx = self.stack_idx(&native.name);
+        let native_idx = self.native_idx(&native.name);
+        self.natives[native_idx].calls += 1;
+        self.target = Target::Native { native_idx, stack_idx };
+    }
+
+}
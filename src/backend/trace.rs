@@ -0,0 +1,104 @@
+use std::io::{self, Write};
+
+use super::{observer::{VmObserver, VmState}, util::disassemble_instruction};
+
+// Selects which instructions the VM traces and where the trace goes to.
+// Without filters every instruction is traced.
+pub struct TraceConfig {
+    functions: Vec<String>, // names of traced functions, "script" is the top level
+    lines: Option<(i32, i32)>, // traced source lines (inclusive)
+    sink: Box<dyn Write>,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceConfig {
+
+    pub fn new() -> TraceConfig {
+        TraceConfig::new_with_sink(Box::new(io::stderr()))
+    }
+
+    pub fn new_with_sink(sink: Box<dyn Write>) -> TraceConfig {
+        TraceConfig {
+            functions: vec![],
+            lines: None,
+            sink,
+        }
+    }
+
+    pub fn add_function(&mut self, name: &str) {
+        self.functions.push(name.to_string());
+    }
+
+    pub fn set_lines(&mut self, first: i32, last: i32) {
+        self.lines = Some((first, last));
+    }
+
+    pub fn matches(&self, fun_name: &str, line: i32) -> bool {
+        let fun_matches = self.functions.is_empty()
+            || self.functions.iter().any(|name| name == fun_name);
+        let line_matches = match self.lines {
+            Some((first, last)) => first <= line && line <= last,
+            None => true,
+        };
+        fun_matches && line_matches
+    }
+
+    pub fn sink(&mut self) -> &mut dyn Write {
+        self.sink.as_mut()
+    }
+
+}
+
+impl VmObserver for TraceConfig {
+
+    // Shows the stack (bottom to top) and the instruction to be executed
+    fn on_instruction(&mut self, state: &VmState) {
+
+        let fun_name = state.function_name();
+        let line = state.line();
+
+        if !self.matches(fun_name, line) {
+            return;
+        }
+
+        let instr = match state.chunk().read_instruction(state.ip()) {
+            Some((instr, _)) => disassemble_instruction(state.chunk(), &instr, state.heap()),
+            None => return,
+        };
+        let stack: String = state.stack()
+            .iter()
+            .map(|value| format!("[ {} ]", value.display(state.heap())))
+            .collect();
+
+        // Tracing must not affect the traced program, so write errors are ignored
+        let _ = writeln!(self.sink, "{}", format!("{:<16} {}", "", stack).trim_end());
+        let _ = writeln!(self.sink, "{:<16} {:04} {:>4}  {}", fun_name, state.ip(), line, instr);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::TraceConfig;
+
+    #[test]
+    fn filter_functions_and_lines() {
+
+        let mut config = TraceConfig::new_with_sink(Box::new(io::sink()));
+        assert!(config.matches("script", 1));
+
+        config.add_function("fib");
+        config.set_lines(3, 5);
+        assert!(config.matches("fib", 3));
+        assert!(config.matches("fib", 5));
+        assert!(!config.matches("fib", 6));
+        assert!(!config.matches("script", 4));
+    }
+
+}
@@ -1,8 +1,9 @@
 use super::instruction::Instruction;
 use super::chunk::Chunk;
+use super::heap::HeapManager;
 
 
-pub fn disassemble(chunk: &Chunk, name: &str) {
+pub fn disassemble(chunk: &Chunk, name: &str, heap: &HeapManager) {
     println!("== {} ==", name);
 
     let mut line_opt: Option<i32> = None;
@@ -19,19 +20,19 @@ pub fn disassemble(chunk: &Chunk, name: &str) {
         println!("{:04} {} {}", 
             offset, 
             line_info, 
-            disassemble_instruction(chunk, &instr));
+            disassemble_instruction(chunk, &instr, heap));
 
         line_opt = Some(curr_line);
     }
 
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, instr: &Instruction) -> String {
+pub fn disassemble_instruction(chunk: &Chunk, instr: &Instruction, heap: &HeapManager) -> String {
     match instr {
         Instruction::Constant { value_idx } => 
-            disassemble_constant(chunk, value_idx),
+            disassemble_constant(chunk, value_idx, heap),
         Instruction::ConstantLong { value_idx } => 
-            disassemble_constant_long(chunk, value_idx),
+            disassemble_constant_long(chunk, value_idx, heap),
         Instruction::DefineGlobal { global_idx } => 
             disassemble_def_global(chunk, global_idx),
         Instruction::GetGlobal { global_idx } => 
@@ -82,34 +83,36 @@ pub fn disassemble_instruction(chunk: &Chunk, instr: &Instruction) -> String {
             "OP_POP".to_string(),
         Instruction::Closure { value_idx } =>
             disassemble_closure(value_idx),
+        Instruction::AssertFailed { value_idx } =>
+            disassemble_assert_failed(chunk, value_idx, heap),
         
     } 
 }
 
 
-fn disassemble_constant(chunk: &Chunk, value_idx: &u8) -> String {
+fn disassemble_constant(chunk: &Chunk, value_idx: &u8, heap: &HeapManager) -> String {
     let value = chunk.read_value(*value_idx as usize).unwrap();
-    format!("{:<16} {:04} ({})", "OP_CONSTANT", value_idx, value)
+    format!("{:<16} {:04} ({})", "OP_CONSTANT", value_idx, value.display(heap))
 }
 
-fn disassemble_constant_long(chunk: &Chunk, value_idx: &u32) -> String {
+fn disassemble_constant_long(chunk: &Chunk, value_idx: &u32, heap: &HeapManager) -> String {
     let value = chunk.read_value(*value_idx as usize).unwrap();
-    format!("{:<16} {:04} ({})", "OP_CONSTANT_LONG", value_idx, value)
+    format!("{:<16} {:04} ({})", "OP_CONSTANT_LONG", value_idx, value.display(heap))
 }
 
 fn disassemble_def_global(chunk: &Chunk, global_idx: &u32) -> String {
-    let value = chunk.read_value(*global_idx as usize).unwrap();
-    format!("{:<16} {:04} ({})", "OP_DEFINE_GLOBAL", global_idx, value)
+    let name = chunk.get_global_name(*global_idx).unwrap_or("?");
+    format!("{:<16} {:04} ({})", "OP_DEFINE_GLOBAL", global_idx, name)
 }
 
 fn disassemble_get_global(chunk: &Chunk, global_idx: &u32) -> String {
-    let value = chunk.read_value(*global_idx as usize).unwrap();
-    format!("{:<16} {:04} ({})", "OP_GET_GLOBAL", global_idx, value)
+    let name = chunk.get_global_name(*global_idx).unwrap_or("?");
+    format!("{:<16} {:04} ({})", "OP_GET_GLOBAL", global_idx, name)
 }
 
 fn disassemble_set_global(chunk: &Chunk, global_idx: &u32) -> String {
-    let value = chunk.read_value(*global_idx as usize).unwrap();
-    format!("{:<16} {:04} ({})", "OP_SET_GLOBAL", global_idx, value)
+    let name = chunk.get_global_name(*global_idx).unwrap_or("?");
+    format!("{:<16} {:04} ({})", "OP_SET_GLOBAL", global_idx, name)
 }
 
 fn disassemble_get_local(local_idx: &u32) -> String {
@@ -140,6 +143,11 @@ fn disassemble_closure(value_idx: &u16) -> String {
     format!("{:<16} {:04}", "OP_CLOSURE", value_idx)
 }
 
+fn disassemble_assert_failed(chunk: &Chunk, value_idx: &u16, heap: &HeapManager) -> String {
+    let value = chunk.read_value(*value_idx as usize).unwrap();
+    format!("{:<16} {:04} ({})", "OP_ASSERT_FAILED", value_idx, value.display(heap))
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -169,7 +177,7 @@ mod tests {
         chunk.write(OpCode::ConstantLong as u8, 3);
         chunk.write_long(625, 3);
         
-        disassemble(&chunk, "test chunk");
+        disassemble(&chunk, "test chunk", &HeapManager::new());
     }
 
 }
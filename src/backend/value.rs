@@ -1,8 +1,8 @@
-use std::fmt::{Display, Debug};
+use std::fmt::Display;
 
-use super::{heap::HeapRef, objects::{FunData, NativeFunData, ClosureData}};
+use super::{heap::{HeapRef, HeapManager}, objects::{FunData, NativeFunData, ClosureData}};
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Value {
     Number(f64),
     Bool(bool),
@@ -13,39 +13,38 @@ pub enum Value {
     Closure(HeapRef<ClosureData>),
 }
 
-impl Display for Value {
+impl Value {
 
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            Self::Number(value) => write!(f, "{}", value),
-            Self::Bool(value) => write!(f, "{}", value),
-            Self::Nil => write!(f, "nil"),
-            Self::Str(value) => write!(f, "{}", value),
-            Self::Fun(value) => write!(f, "{}", value),
-            Self::NativeFun(value) => write!(f, "{}", value),
-            Self::Closure(value) => write!(f, "{}", value),
-        }
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
     }
-}
 
-impl Debug for Value {
-    
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Display::fmt(&self, f)
+    pub fn display<'a>(&'a self, heap: &'a HeapManager) -> DisplayValue<'a> {
+        DisplayValue { value: self, heap }
     }
+
+}
+
+pub struct DisplayValue<'a> {
+    value: &'a Value,
+    heap: &'a HeapManager,
 }
 
-impl Clone for Value {
-
-    fn clone(&self) -> Self {
-        match self {
-            Self::Number(val) => Self::Number(val.clone()),
-            Self::Bool(val) => Self::Bool(val.clone()),
-            Self::Nil => Self::Nil,
-            Self::Str(val) => Self::Str(val.clone()),
-            Self::Fun(val) => Self::Fun(val.clone()),
-            Self::NativeFun(val) => Self::NativeFun(val.clone()),
-            Self::Closure(val) => Self::Closure(val.clone()),
+impl Display for DisplayValue<'_> {
+
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let heap = self.heap;
+        match self.value {
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Nil => write!(f, "nil"),
+            Value::Str(value) => write!(f, "{}", value.get_str(heap)),
+            Value::Fun(value) => write!(f, "{}", heap.get_content(value)),
+            Value::NativeFun(value) => write!(f, "{}", heap.get_content(value)),
+            Value::Closure(value) => {
+                let closure = heap.get_content(value);
+                write!(f, "{}", heap.get_content(&closure.fun))
+            },
         }
     }
 }
@@ -58,12 +57,25 @@ mod tests {
     #[test]
     fn show() {
 
-        let hm = HeapManager::new_rc_refcell();
+        let mut hm = HeapManager::new();
         let fdata = FunData::new("say_hello", 1, Chunk::new());
-        let fdata = HeapManager::malloc(&hm, fdata);
+        let fdata = hm.malloc(fdata).unwrap();
         let f = Fun(fdata);
-        println!("{}", f);
+        assert_eq!(f.display(&hm).to_string(), "<fn say_hello/1>");
 
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn string_equality() {
+
+        // Strings are interned, so Lox equality is handle equality
+        let mut hm = HeapManager::new();
+        let s1 = Str(hm.intern("Hallo").unwrap());
+        let s2 = Str(hm.intern("Hallo").unwrap());
+        let s3 = Str(hm.intern("Welt").unwrap());
+
+        assert_eq!(s1, s2);
+        assert_ne!(s1, s3);
+    }
+
+}
@@ -1,15 +1,68 @@
-use std::{cell::{RefCell, RefMut, Ref}, collections::HashMap};
-use super::{instruction::Instruction, value::Value, util::disassemble_instruction, heap::HeapRef, objects::{FunData, NativeFunData, ClosureData}};
+use std::{cell::RefCell, rc::Rc, io::{self, Write}, time::{Duration, Instant}};
+use super::{instruction::{Instruction, OpCode}, chunk::Chunk, value::Value, heap::{HeapRef, HeapManager, HeapError}, objects::{FunData, NativeFunData, ClosureData, NativeContext, NativeError}, globals::GlobalTable, observer::{VmObserver, VmState}, interrupt::InterruptHandle};
+
+const STACK_INITIAL_CAPACITY: usize = 1024;
+const DEFAULT_MAX_FRAMES: usize = 1024;
+const DEFAULT_MAX_STACK: usize = 64 * 1024;
+const CALLSTACK_REPORT_EDGE: usize = 10; // frames reported at each end of a deep call stack
+const POLL_INTERVAL: u64 = 1024; // instructions between two looks at the clock and the interrupt handle
 
 #[derive(Debug, PartialEq)]
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    Interrupted, // stopped on request before the program finished
+}
+
+// Limits of the resources that a program may use. The instruction
+// and time limits apply to each run, None means unlimited. There is no
+// garbage collector, so the heap limits count every object allocated
+// since the last reset, including temporaries that are no longer reachable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmConfig {
+    pub max_frames: usize, // depth of nested calls, including the top level
+    pub max_stack: usize, // values on the stack, checked when a function is called
+    pub max_instructions: Option<u64>,
+    pub time_limit: Option<Duration>,
+    pub max_objects: Option<usize>, // objects on the heap
+    pub max_heap_bytes: Option<usize>, // approximate size of the objects on the heap
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+            max_instructions: None,
+            time_limit: None,
+            max_objects: None,
+            max_heap_bytes: None,
+        }
+    }
+}
+
+// Distinguishes the errors caused by exceeding a limit from errors of the program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Runtime,
+    Assertion, // of an assert statement or native
+    StackOverflow,
+    InstructionLimit,
+    TimeLimit,
+    HeapLimit,
+    Interrupted, // through the interrupt handle
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub line: i32,
 }
 
 pub struct CallFrame {
-    closure: ClosureData,
+    closure: HeapRef<ClosureData>,
     ip: usize, // <-- instruction pointer
     stack_base: usize, // <-- base offset in stack
     caller_line: i32, 
@@ -17,164 +70,363 @@ pub struct CallFrame {
 
 impl CallFrame {
 
-    pub fn new_top() -> CallFrame {
-        Self::new_top_with_func_data(FunData::new_top())
-    }
-
-    pub fn new_top_with_func_data(func_data: FunData) -> CallFrame {
-        CallFrame { 
-            closure: ClosureData::new(func_data), 
-            ip: 0, 
-            stack_base: 0, 
-            caller_line: 0,
-        }
-    }
-
-    pub fn new(closure: ClosureData, ip: usize, stack_base: usize, caller_line: i32) -> CallFrame {
+    pub fn new(closure: HeapRef<ClosureData>, ip: usize, stack_base: usize, caller_line: i32) -> CallFrame {
         CallFrame { closure, ip, stack_base, caller_line }
     }
 }
 
 pub struct VM {
+    heap: HeapManager,
     frames: Vec<CallFrame>,
-    stack: RefCell<Vec<Value>>,
-    globals: RefCell<HashMap<String, Value>>,
+    stack: Vec<Value>,
+    globals: Vec<Option<Value>>, // indexed by the slots of global_names
+    global_names: Rc<RefCell<GlobalTable>>,
+    script_args: Vec<String>,
+    observers: Vec<Box<dyn VmObserver>>,
+    output: Box<dyn Write>, // receives the output of print statements
+    config: VmConfig,
+    last_error: Option<RuntimeError>,
+    interrupt: InterruptHandle,
+    print_errors: bool, // to stderr, errors are available from last_error anyway
 }
 
 impl VM {
-    pub fn new() -> VM {
-        VM::new_with_frame(CallFrame::new_top())
+    // Global slots are shared with the compiler of the code to run
+    pub fn new(global_names: &Rc<RefCell<GlobalTable>>) -> VM {
+        let mut vm = VM {
+            heap: HeapManager::new(),
+            frames: vec![],
+            stack: Vec::with_capacity(STACK_INITIAL_CAPACITY),
+            globals: vec![],
+            global_names: global_names.clone(),
+            script_args: vec![],
+            observers: vec![],
+            output: Box::new(io::stdout()),
+            config: VmConfig::default(),
+            last_error: None,
+            interrupt: InterruptHandle::new(),
+            print_errors: true,
+        };
+        vm.set_top_fun(FunData::new_top()).expect("Empty heap has no limits.");
+        vm
+    }
+
+    pub fn set_script_args(&mut self, script_args: Vec<String>) {
+        self.script_args = script_args;
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn VmObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
     }
 
-    pub fn new_with_frame(frame: CallFrame) -> VM {
-        VM {
-            frames: vec![frame],
-            stack: RefCell::new(Vec::new()),
-            globals: RefCell::new(HashMap::new()),
+    pub fn set_print_errors(&mut self, print_errors: bool) {
+        self.print_errors = print_errors;
+    }
+
+    pub fn config(&self) -> VmConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: VmConfig) {
+        self.config = config;
+        self.heap.set_limits(config.max_objects, config.max_heap_bytes);
+    }
+
+    // Error that stopped the last run, if any
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.last_error.as_ref()
+    }
+
+    // Handle that stops the running program from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    pub fn set_interrupt_handle(&mut self, interrupt: InterruptHandle) {
+        self.interrupt = interrupt;
+    }
+
+    pub fn heap(&self) -> &HeapManager {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut HeapManager {
+        &mut self.heap
+    }
+
+    // The function becomes the only frame, the previous top-level function
+    // is freed since no value can refer to it
+    pub fn set_top_fun(&mut self, fun_data: FunData) -> Result<(), HeapError> {
+        if let Some(frame) = self.frames.first() {
+            let closure = frame.closure;
+            let fun = self.heap.get_content(&closure).fun;
+            self.heap.free(closure);
+            self.heap.free(fun);
         }
+        self.frames.clear();
+        let fun = self.heap.malloc(fun_data)?;
+        let closure = self.heap.malloc(ClosureData::new(fun))?;
+        self.frames = vec![CallFrame::new(closure, 0, 0, 0)];
+        self.stack.clear();
+        Ok(())
+    }
+
+    // Interrupts requested before the function starts are discarded
+    pub fn run_fun(&mut self, fun_data: FunData) -> InterpretResult {
+        self.interrupt.clear();
+        if let Err(error) = self.set_top_fun(fun_data) {
+            let message = error.to_string();
+            if self.print_errors {
+                eprintln!("{}", message);
+            }
+            self.last_error = Some(RuntimeError { kind: ErrorKind::HeapLimit, message, line: 0 });
+            return InterpretResult::RuntimeError;
+        }
+        self.run()
     }
 
     pub fn define_native_fun(&mut self, native: &HeapRef<NativeFunData>) {
-        let name = native.get_content().name;
-        self.globals.borrow_mut().insert(name, Value::NativeFun(native.clone()));
+        let name = &self.heap.get_content(native).name;
+        let global_idx = self.global_names.borrow_mut().resolve(name);
+        self.define_global(global_idx, Value::NativeFun(*native));
     }
 
-    fn current_fun(&self) -> Ref<FunData> {
-        let current_frame = self.frames.last().unwrap();
-        current_frame.closure.borrow_fun()
-    } 
+    pub fn get_global(&self, global_idx: usize) -> Option<Value> {
+        self.globals.get(global_idx).copied().flatten()
+    }
 
-    fn current_fun_mut(&mut self) -> RefMut<FunData> {
-        let current_frame = self.frames.last_mut().unwrap();
-        current_frame.closure.borrow_fun_mut()
+    fn define_global(&mut self, global_idx: usize, value: Value) {
+        if global_idx >= self.globals.len() {
+            self.globals.resize(global_idx + 1, None);
+        }
+        self.globals[global_idx] = Some(value);
+    }
+
+    fn fun_of_frame(&self, frame: &CallFrame) -> &FunData {
+        let closure = self.heap.get_content(&frame.closure);
+        self.heap.get_content(&closure.fun)
     }
 
-    fn current_ip(&self) -> usize {
+    pub(crate) fn current_fun(&self) -> &FunData {
         let current_frame = self.frames.last().unwrap();
-        let ret = current_frame.ip;
-        ret
+        self.fun_of_frame(current_frame)
     } 
 
+    fn current_fun_mut(&mut self) -> &mut FunData {
+        let current_frame = self.frames.last().unwrap();
+        let closure = self.heap.get_content(&current_frame.closure);
+        let fun = closure.fun;
+        self.heap.get_content_mut(&fun)
+    }
+
     fn current_base(&self) -> usize {
         let current_frame = self.frames.last().unwrap();
-        let ret = current_frame.stack_base;
-        ret
+        current_frame.stack_base
     } 
 
+    pub(crate) fn frame_base(&self) -> usize {
+        self.current_base()
+    }
+
+    pub(crate) fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    // Function, saved ip and stack base of a frame, 0 is the innermost one.
+    // The saved ip of the innermost frame is not up to date while running.
+    pub(crate) fn frame(&self, depth: usize) -> Option<(&FunData, usize, usize)> {
+        let idx = self.frames.len().checked_sub(depth + 1)?;
+        let frame = &self.frames[idx];
+        Some((self.fun_of_frame(frame), frame.ip, frame.stack_base))
+    }
+
     fn set_current_ip(&mut self, ip: usize) {
         let current_frame = self.frames.last_mut().unwrap();
         current_frame.ip = ip;
     }
+
+    // Chunk and instruction pointer of the current frame. The chunk is
+    // returned as a pointer, so that the run loop can keep it while it
+    // changes the VM.
+    fn load_frame(&self) -> (*const Chunk, usize) {
+        let current_frame = self.frames.last().unwrap();
+        (self.fun_of_frame(current_frame).chunk(), current_frame.ip)
+    }
     
     pub fn add_instruction(&mut self, instr: Instruction, line: i32) {
-        let mut fun = self.current_fun_mut();
-        let mut chunk = fun.borrow_chunk_mut();
+        let chunk = self.current_fun_mut().chunk_mut();
         chunk.write_instruction(instr, line);
     }
 
     pub fn add_value(&mut self, value: Value) -> usize {
-        let mut fun = self.current_fun_mut();
-        let mut chunk = fun.borrow_chunk_mut();
+        let chunk = self.current_fun_mut().chunk_mut();
         chunk.add_value(value)
     }
 
     pub fn run(&mut self) -> InterpretResult {
 
+        // The chunk and ip of the current frame are kept in locals
+        // and only synchronized with the frame on calls and returns
+        let (mut frame_chunk, mut ip) = self.load_frame();
+
+        self.last_error = None;
+        let max_instructions = self.config.max_instructions;
+        let deadline = self.config.time_limit.map(|limit| Instant::now() + limit);
+        let mut executed: u64 = 0;
+
+        if ip == 0 && self.frames.len() == 1 {
+            self.notify(ip, |observer, state| observer.on_call(state));
+        }
+
         loop {
 
-            let instr_offs_opt = {
-                let fun = self.current_fun();
-                let chunk = fun.borrow_chunk();
-                let ip = self.current_ip();
-                chunk.read_instruction(ip)
+            // SAFETY: the chunk belongs to a function of a frame. Functions are
+            // boxed on the heap, so they do not move, and they are neither
+            // freed nor changed while the VM runs.
+            let chunk = unsafe { &*frame_chunk };
+
+            let offset = ip;
+            let op_code = match chunk.read(offset).map(|byte| OpCode::try_from(*byte)) {
+                Some(Ok(op_code)) => op_code,
+                _ => break,
             };
+            ip += 1;
 
-            if instr_offs_opt.is_none() {
-                break;
+            executed += 1;
+            if let Some(result) = self.check_limits(offset, executed, max_instructions, deadline) {
+                return result;
             }
 
-            let (instr, next_offset) = instr_offs_opt.unwrap();
-
-            if cfg!(trace_run) {
-                let fun = self.current_fun();
-                let chunk = fun.borrow_chunk();
-                self.show_stack();
-                println!("{}", disassemble_instruction(&chunk, &instr));
+            if self.notify(offset, |observer, state| observer.on_instruction(state)) {
+                return InterpretResult::Interrupted;
             }
 
-            let offset = self.current_ip();
-            self.set_current_ip(next_offset);
-
-            let result = match  instr {
-                Instruction::Return => 
-                    self.interpret_return(),
-                Instruction::Constant { value_idx } => 
-                    self.interpret_constant(value_idx as usize),
-                Instruction::ConstantLong { value_idx } => 
-                    self.interpret_constant(value_idx as usize),
-                Instruction::DefineGlobal { global_idx } => 
-                    self.interpret_def_global(global_idx as usize, self.get_line(offset)),
-                Instruction::GetGlobal { global_idx } =>
-                    self.interpret_get_global(global_idx as usize, self.get_line(offset)),
-                Instruction::SetGlobal { global_idx } =>
-                    self.interpret_set_global(global_idx as usize, self.get_line(offset)),
-                Instruction::GetLocal { local_idx } =>
-                    self.interpret_get_local(local_idx as usize),
-                Instruction::SetLocal { local_idx } =>
-                    self.interpret_set_local(local_idx as usize),
-                Instruction::Nil =>
-                    self.interpret_nil(),
-                Instruction::True =>
-                    self.interpret_true(),
-                Instruction::False =>
-                    self.interpret_false(),
-                Instruction::Negate => 
-                    self.interpret_negate(self.get_line(offset)),
-                Instruction::Not =>
+            let result = match op_code {
+                OpCode::Return => {
+                    let result = self.interpret_return(offset);
+                    if result.is_none() {
+                        (frame_chunk, ip) = self.load_frame();
+                    }
+                    result
+                },
+                OpCode::Constant => {
+                    let value = *chunk.read_value(chunk.read_u8(ip) as usize).unwrap();
+                    ip += 1;
+                    self.push(&value);
+                    None
+                },
+                OpCode::ConstantLong => {
+                    let value = *chunk.read_value(chunk.read_u32(ip) as usize).unwrap();
+                    ip += 4;
+                    self.push(&value);
+                    None
+                },
+                OpCode::DefineGlobal => {
+                    let global_idx = chunk.read_u32(ip) as usize;
+                    ip += 4;
+                    self.interpret_def_global(global_idx)
+                },
+                OpCode::GetGlobal => {
+                    let global_idx = chunk.read_u32(ip) as usize;
+                    ip += 4;
+                    self.interpret_get_global(global_idx, offset)
+                },
+                OpCode::SetGlobal => {
+                    let global_idx = chunk.read_u32(ip) as usize;
+                    ip += 4;
+                    self.interpret_set_global(global_idx, offset)
+                },
+                OpCode::GetLocal => {
+                    let local_idx = chunk.read_u32(ip) as usize;
+                    ip += 4;
+                    self.interpret_get_local(local_idx)
+                },
+                OpCode::SetLocal => {
+                    let local_idx = chunk.read_u32(ip) as usize;
+                    ip += 4;
+                    self.interpret_set_local(local_idx)
+                },
+                OpCode::Nil => {
+                    self.push(&Value::Nil);
+                    None
+                },
+                OpCode::True => {
+                    self.push(&Value::Bool(true));
+                    None
+                },
+                OpCode::False => {
+                    self.push(&Value::Bool(false));
+                    None
+                },
+                OpCode::Negate => 
+                    self.interpret_negate(offset),
+                OpCode::Not =>
                     self.interpret_not(),
-                Instruction::Equal => 
+                OpCode::Equal => 
                     self.interpret_equal(),
-                Instruction::Add |
-                Instruction::Subtract |
-                Instruction::Multiply |
-                Instruction::Divide |
-                Instruction::Greater |
-                Instruction::Less => 
-                    self.interpret_binary(&instr, self.get_line(offset)),
-                Instruction::Print =>
+                OpCode::Add |
+                OpCode::Subtract |
+                OpCode::Multiply |
+                OpCode::Divide |
+                OpCode::Greater |
+                OpCode::Less => 
+                    self.interpret_binary(op_code, offset),
+                OpCode::Print =>
                     self.interpret_print(),
-                Instruction::Pop =>
-                    self.interpret_pop(),
-                Instruction::Jump { jump_distance } =>
-                    self.interpret_jump(offset, jump_distance), 
-                Instruction::JumpIfFalse { jump_distance } =>
-                    self.interpret_jump_if_false(offset, jump_distance), 
-                Instruction::Loop { jump_distance } =>
-                    self.interpret_loop(offset, jump_distance),
-                Instruction::Call { num_args } =>
-                    self.interpret_call(num_args, self.get_line(offset)),
-                Instruction::Closure { value_idx } =>
-                    self.interpret_closure(value_idx),
+                OpCode::Pop => {
+                    self.pop();
+                    None
+                },
+                OpCode::Jump => {
+                    ip = offset + chunk.read_u16(ip) as usize;
+                    None
+                },
+                OpCode::JumpIfFalse => {
+                    if self.peek(0).is_falsey() {
+                        ip = offset + chunk.read_u16(ip) as usize;
+                    } else {
+                        ip += 2;
+                    }
+                    None
+                },
+                OpCode::Loop => {
+                    ip = offset - chunk.read_u16(ip) as usize;
+                    None
+                },
+                OpCode::Call => {
+                    let num_args = chunk.read_u8(ip);
+                    ip += 1;
+                    self.set_current_ip(ip);
+                    let num_frames = self.frames.len();
+                    let result = self.interpret_call(num_args, offset);
+                    if self.frames.len() != num_frames {
+                        (frame_chunk, ip) = self.load_frame();
+                    }
+                    result
+                },
+                OpCode::Closure => {
+                    let value = *chunk.read_value(chunk.read_u16(ip) as usize).unwrap();
+                    ip += 2;
+                    self.push(&value);
+                    None
+                },
+                OpCode::AssertFailed => {
+                    let condition = *chunk.read_value(chunk.read_u16(ip) as usize).unwrap();
+                    ip += 2;
+                    self.interpret_assert_failed(&condition, offset)
+                },
             };
 
             if let Some(result) = result {
@@ -183,7 +435,7 @@ impl VM {
 
         }
 
-        if self.stack.borrow().is_empty() {
+        if self.stack.is_empty() {
             InterpretResult::Ok
         } else {
             InterpretResult::RuntimeError
@@ -191,409 +443,337 @@ impl VM {
         
     }
 
+    // Stops the run when a limit is exceeded or an interrupt was requested
+    #[inline]
+    fn check_limits(
+        &mut self, 
+        offset: usize, 
+        executed: u64, 
+        max_instructions: Option<u64>, 
+        deadline: Option<Instant>) -> Option<InterpretResult> {
+
+        if max_instructions.is_some_and(|max| executed > max) {
+            return self.report_error(offset, ErrorKind::InstructionLimit, "Instruction limit exceeded.");
+        }
+        // Looking at the clock is too slow to do it for every instruction
+        if !executed.is_multiple_of(POLL_INTERVAL) {
+            return None;
+        }
+        if self.interrupt.take() {
+            self.report_error(offset, ErrorKind::Interrupted, "Interrupted.");
+            return Some(InterpretResult::Interrupted);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return self.report_error(offset, ErrorKind::TimeLimit, "Time limit exceeded.");
+        }
+        None
+    }
+
     fn get_line(&self, offset: usize) -> i32 {
-        let fun = self.current_fun();
-        let chunk = fun.borrow_chunk();
+        let chunk = self.current_fun().chunk();
         chunk.get_line(offset).unwrap_or(1)
     }
 
-    fn show_stack(&self) {
-        println!();
-        println!("=== STACK TOP ===");
-        for value in self.stack.borrow().iter().rev() {
-            println!("{}", value);
+    // Observers are taken out of the VM while they are called,
+    // so that they can get a view of it. Returns whether an
+    // observer asked to stop the execution.
+    #[inline]
+    fn notify<F>(&mut self, ip: usize, callback: F) -> bool
+        where F: Fn(&mut dyn VmObserver, &VmState) {
+
+        if self.observers.is_empty() {
+            return false;
+        }
+
+        let mut observers = std::mem::take(&mut self.observers);
+        let state = VmState::new(self, ip);
+        for observer in observers.iter_mut() {
+            callback(observer.as_mut(), &state);
         }
-        println!("=== STACK BOTTOM ===");
+        let stop = state.stop_requested();
+        self.observers = observers;
+        stop
+    }
+
+    pub(crate) fn backtrace(&self, ip: usize) -> Vec<(String, i32)> {
+        self.callstack(self.current_fun().chunk().get_line(ip).unwrap_or(1))
     }
 
-    fn push(&self, value: &Value) {
-        self.stack.borrow_mut().push(value.clone());
+    // Names and values of all defined globals
+    pub(crate) fn defined_globals(&self) -> Vec<(String, Value)> {
+        let global_names = self.global_names.borrow();
+        (0..global_names.len())
+            .filter_map(|slot| Some((
+                global_names.get_name(slot)?.to_string(), 
+                self.get_global(slot)?)))
+            .collect()
     }
 
-    fn pop(&self) -> Value {
-        self.stack.borrow_mut().pop().unwrap()
+    #[inline]
+    fn push(&mut self, value: &Value) {
+        self.stack.push(*value);
     }
 
-    fn peek(&self, distance: usize) -> Option<Value> {
-        let stack = self.stack.borrow();
-        let index = stack.len() - distance - 1;
-        match stack.get(index) {
-            Some(value) => Some(value.clone()),
-            None => None,
-        } 
+    #[inline]
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    #[inline]
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - distance - 1]
+    }
+
+    // Replaces the top of the stack
+    #[inline]
+    fn set_top(&mut self, value: Value) {
+        let top = self.stack.len() - 1;
+        self.stack[top] = value;
+    }
+
+    // Errors are reported for the instruction at the given offset
+    // of the current function
+    fn runtime_error(&mut self, offset: usize, message: &str) -> Option<InterpretResult> {
+        self.report_error(offset, ErrorKind::Runtime, message)
+    }
+
+    fn report_error(&mut self, offset: usize, kind: ErrorKind, message: &str) -> Option<InterpretResult> {
+        let line = self.get_line(offset);
+        self.notify(offset, |observer, state| observer.on_error(state, message));
+        self.print_runtime_error(line, message);
+        self.last_error = Some(RuntimeError { kind, message: message.to_string(), line });
+        Some(InterpretResult::RuntimeError)
     }
 
     fn print_runtime_error(&self, line: i32, message: &str) {
-        eprintln!("{}", message);
-        self.print_callstack(line);
+        if self.print_errors {
+            eprintln!("{}", message);
+            self.print_callstack(line);
+        }
     }
 
+    // Only the innermost and outermost frames of a deep call stack are shown
     fn print_callstack(&self, line: i32) {
-        let mut call_line = line;
-        for frame in self.frames.iter().rev() {
-            let fun_data = frame
-                .closure
-                .fun_data
-                .as_ref()
-                .borrow();
-            let mut fun_name = fun_data.name.clone();
-            if fun_name.is_empty() {
-                fun_name = "script".to_string();
+        let callstack = self.callstack(line);
+        let omitted = callstack.len().saturating_sub(2 * CALLSTACK_REPORT_EDGE + 1);
+        for (i, (fun_name, call_line)) in callstack.iter().enumerate() {
+            if omitted > 0 && i >= CALLSTACK_REPORT_EDGE && i < CALLSTACK_REPORT_EDGE + omitted {
+                if i == CALLSTACK_REPORT_EDGE {
+                    eprintln!("... {} more frames ...", omitted);
+                }
+                continue;
+            }
+            let fun_name = if fun_name.is_empty() {
+                "script".to_string()
             } else {
-                fun_name = format!("{fun_name}()");
+                format!("{fun_name}()")
             };
             eprintln!("[line {}] in {}", call_line, fun_name);
+        }
+    }
+
+    // Function names and lines of all frames, innermost first
+    fn callstack(&self, line: i32) -> Vec<(String, i32)> {
+        let mut call_line = line;
+        let mut callstack = vec![];
+        for frame in self.frames.iter().rev() {
+            callstack.push((self.fun_of_frame(frame).name.clone(), call_line));
             call_line = frame.caller_line;
         }
+        callstack
     }
 
-    fn interpret_return(&mut self) -> Option<InterpretResult> {
+    fn interpret_return(&mut self, offset: usize) -> Option<InterpretResult> {
+
+        self.notify(offset, |observer, state| observer.on_return(state));
+
         if self.frames.len() == 1 {
-            return if self.stack.borrow().is_empty() {
+            return if self.stack.is_empty() {
                 Some(InterpretResult::Ok)
             } else {
                 Some(InterpretResult::RuntimeError)
             }    
         }
 
-        if self.frames.len() > 1 {
-            let frame = self.frames.pop().unwrap();
-            let result = self.pop(); 
-            let stack_size = self.stack.borrow().len();
-            let num_pops = stack_size - frame.stack_base;
-
-            for _ in 0..num_pops {
-                self.pop();
-            }
-
-            self.push(&result);
-        }
+        let frame = self.frames.pop().unwrap();
+        let result = self.pop(); 
+        self.stack.truncate(frame.stack_base);
+        self.push(&result);
 
         None
     }
 
-    fn interpret_constant(&self, value_idx: usize) -> Option<InterpretResult> {
-        let fun = self.current_fun();
-        let chunk = fun.borrow_chunk();
-        let value = chunk
-                .read_value(value_idx)
-                .unwrap();
-        self.push(value);
+    fn interpret_def_global(&mut self, global_idx: usize) -> Option<InterpretResult> {
+        let value = self.pop();
+        self.define_global(global_idx, value);
         None
     }
 
-    fn interpret_def_global(&self, global_idx: usize, line: i32) -> Option<InterpretResult> {
-        let fun = self.current_fun();
-        let chunk = fun.borrow_chunk();
-        let value = chunk
-                .read_value(global_idx)
-                .unwrap();
-
-        match value {
-            Value::Str(s) => {
-                let varname = s.get_content();
-                let value = self.peek(0).unwrap();
-                self.globals.borrow_mut().insert(varname, value);
-                self.pop();
-            },
-            _ => {
-                self.print_runtime_error(line, "Expected string value.");
-                return Some(InterpretResult::RuntimeError);
-            }
+    fn interpret_get_global(&mut self, global_idx: usize, offset: usize) -> Option<InterpretResult> {
+        if let Some(Some(value)) = self.globals.get(global_idx) {
+            let value = *value;
+            self.push(&value);
+            None
+        } else {
+            self.undefined_global_error(global_idx, offset)
         }
-        
-        None
     }
 
-    fn interpret_get_global(&self, global_idx: usize, line: i32) -> Option<InterpretResult> {
-        let fun = self.current_fun();
-        let chunk = fun.borrow_chunk();
-        let value = chunk
-            .read_value(global_idx)
-            .unwrap();
-
-        match value {
-            Value::Str(s) => {
-                let varname = s.get_content();
-                let globals = self.globals.borrow();
-                let varvalue = globals.get(&varname);
-                if varvalue.is_some() {
-                    self.push(varvalue.unwrap());
-                } else {
-                    self.print_runtime_error(line, 
-                        &format!("Undefined variable '{}'.", varname));
-                    return Some(InterpretResult::RuntimeError);
-                }
-            },
-            _ => {
-                self.print_runtime_error(line, "Expected string value.");
-                return Some(InterpretResult::RuntimeError);
-            }
+    fn interpret_set_global(&mut self, global_idx: usize, offset: usize) -> Option<InterpretResult> {
+        let new_value = self.peek(0);
+        if let Some(Some(value)) = self.globals.get_mut(global_idx) {
+            *value = new_value;
+            None
+        } else {
+            self.undefined_global_error(global_idx, offset)
         }
-        
-        None
     }
 
-    fn interpret_set_global(&self, global_idx: usize, line: i32) -> Option<InterpretResult> {
-        let fun = self.current_fun();
-        let chunk = fun.borrow_chunk();
-        let value = chunk
-            .read_value(global_idx)
-            .unwrap();
-
-        match value {
-            Value::Str(s) => {
-                let varname = s.get_manager().borrow().get_content(s).clone();
-                let mut globals = self.globals.borrow_mut();
-                if globals.contains_key(&varname) {
-                    let new_value = self.peek(0).unwrap();
-                    globals.insert(varname, new_value);
-                } else {
-                    self.print_runtime_error(line, 
-                        &format!("Undefined variable '{}'.", varname));
-                    return Some(InterpretResult::RuntimeError);
-                }
-            },
-            _ => {
-                self.print_runtime_error(line, "Expected string value.");
-                return Some(InterpretResult::RuntimeError);
-            }
-        }
-        
-        None
+    fn undefined_global_error(&mut self, global_idx: usize, offset: usize) -> Option<InterpretResult> {
+        let message = {
+            let global_names = self.global_names.borrow();
+            let varname = global_names.get_name(global_idx).unwrap_or("?");
+            format!("Undefined variable '{}'.", varname)
+        };
+        self.runtime_error(offset, &message)
     }
 
-    fn interpret_get_local(&self, local_idx: usize) -> Option<InterpretResult> {
-        let mut stack = self.stack.borrow_mut();
-        let absolute_idx = self.current_base() + local_idx;
-        let value = stack[absolute_idx].clone();
-        stack.push(value);
+    fn interpret_get_local(&mut self, local_idx: usize) -> Option<InterpretResult> {
+        let value = self.stack[self.current_base() + local_idx];
+        self.push(&value);
         None
     }
 
-    fn interpret_set_local(&self, local_idx: usize) -> Option<InterpretResult> {
-        let value = self.peek(0).unwrap();
+    fn interpret_set_local(&mut self, local_idx: usize) -> Option<InterpretResult> {
         let absolute_idx = self.current_base() + local_idx;
-        self.stack.borrow_mut()[absolute_idx] = value;
-        None
-    }
-
-    fn interpret_jump(&mut self, offset: usize, jump_distance: u16) -> Option<InterpretResult> {
-        self.set_current_ip(offset + jump_distance as usize);
-        None
-    }
-
-    fn interpret_jump_if_false(&mut self, offset: usize, jump_distance: u16) -> Option<InterpretResult> {
-        let condition = self.peek(0).unwrap();
-        if Self::is_falsey(&condition) {
-            self.set_current_ip(offset + jump_distance as usize);
-        }
-        None
-    }
-
-    fn interpret_loop(&mut self, offset: usize, jump_distance: u16) -> Option<InterpretResult> {
-        self.set_current_ip(offset - jump_distance as usize);
+        self.stack[absolute_idx] = self.peek(0);
         None
     }
 
-    fn interpret_call(&mut self, num_args: u8, line: i32) -> Option<InterpretResult> {
+    fn interpret_call(&mut self, num_args: u8, offset: usize) -> Option<InterpretResult> {
         
-        let (value, closure_idx) = {
-            let stack = self.stack.borrow();
-            let fun_idx = stack.len() - 1 - (num_args as usize);
-            (&stack[fun_idx].clone(), fun_idx)
-        };
+        let closure_idx = self.stack.len() - 1 - (num_args as usize);
+        let value = self.stack[closure_idx];
 
         match value {
             Value::Closure(closure) => {
-                let closure = closure.get_content();
-                let fun_data = closure
-                    .fun_data
-                    .as_ref()
-                    .borrow();
+                let arity = self.heap.get_content(&self.heap.get_content(&closure).fun).arity;
 
-                if fun_data.arity != num_args {
+                if arity != num_args {
                     let message = format!("Expected {} arguments but got {}",
-                        fun_data.arity, num_args);
-                    self.print_runtime_error(line, &message);
-                    return Some(InterpretResult::RuntimeError);    
+                        arity, num_args);
+                    return self.runtime_error(offset, &message);
+                }
+
+                if self.frames.len() >= self.config.max_frames || self.stack.len() > self.config.max_stack {
+                    return self.report_error(offset, ErrorKind::StackOverflow, "Stack overflow.");
                 }
 
                 let new_frame = CallFrame::new(
-                    closure.clone(), 0, closure_idx, line);
+                    closure, 0, closure_idx, self.get_line(offset));
                 self.frames.push(new_frame);
+                self.notify(0, |observer, state| observer.on_call(state));
             },
             Value::NativeFun(native_fun_data) => {
-                let native = native_fun_data.get_content();
+                let native = self.heap.get_content(&native_fun_data);
+                let (arity, native_fn) = (native.arity, native.fun);
 
-                if native.arity != num_args {
+                if arity != num_args {
                     let message = format!("Expected {} arguments but got {}",
-                        native.arity, num_args);
-                    self.print_runtime_error(line, &message);
-                    return Some(InterpretResult::RuntimeError);    
+                        arity, num_args);
+                    return self.runtime_error(offset, &message);
                 }
 
-                let args = self.pop_call_args(num_args);
-                self.pop(); // remove native function from stack 
-                let result = (native.fun)(args);
+                self.notify(offset, |observer, state| 
+                    observer.on_native_call(state, state.heap().get_content(&native_fun_data)));
+
+                let mut ctx = NativeContext {
+                    heap: &mut self.heap,
+                    script_args: &self.script_args,
+                };
+                let result = native_fn(&mut ctx, &self.stack[(closure_idx + 1)..]);
+                self.stack.truncate(closure_idx); // remove native function and args
                 match result {
                     Ok(value) => self.push(&value),
-                    Err(message) => {
-                        self.print_runtime_error(line, &message);
-                        return Some(InterpretResult::RuntimeError);
-                    }
+                    Err(NativeError::Message(message)) => return self.runtime_error(offset, &message),
+                    Err(NativeError::Assertion(message)) => return self.report_error(offset, ErrorKind::Assertion, &message),
+                    Err(NativeError::Heap(error)) => return self.report_error(offset, ErrorKind::HeapLimit, &error.to_string()),
                 }
             },
             _ => {
-                self.print_runtime_error(line, &format!("{} is not a function.", value));
-                return Some(InterpretResult::RuntimeError);
+                let message = format!("{} is not a function.", value.display(&self.heap));
+                return self.runtime_error(offset, &message);
             }
         }
 
         None
     }
 
-    fn interpret_closure(&self, value_idx: u16) -> Option<InterpretResult> {
-        self.interpret_constant(value_idx as usize)
-    }
-
-    fn pop_call_args(&self, num_args: u8) -> Vec<Value> {
-        let mut ret = vec![];
-        
-        for _ in 0..num_args {
-            let arg = self.pop();
-            ret.push(arg);
+    // The message given in the assert statement is on the stack, nil if there is none
+    fn interpret_assert_failed(&mut self, condition: &Value, offset: usize) -> Option<InterpretResult> {
+        let mut message = format!("Assertion failed on line {}: {}", self.get_line(offset), condition.display(&self.heap));
+        let user_message = self.pop();
+        if user_message != Value::Nil {
+            message = format!("{} ({})", message, user_message.display(&self.heap));
         }
-        
-        ret.reverse();
-        
-        ret
+        self.report_error(offset, ErrorKind::Assertion, &message)
     }
 
-    fn interpret_nil(&self) -> Option<InterpretResult> {
-        self.push(&Value::Nil);
-        None
-    }
-
-    fn interpret_true(&self) -> Option<InterpretResult> {
-        self.push(&Value::Bool(true));
-        None
-    }
-
-    fn interpret_false(&self) -> Option<InterpretResult> {
-        self.push(&Value::Bool(false));
-        None
-    }
-
-    fn interpret_negate(&self, line: i32) -> Option<InterpretResult> {
-        if let Some(value) = self.peek(0) {
-            if let Value::Number(x) = value {
-                self.pop();
-                self.push(&Value::Number(-x));
-                None
-            } else {
-                self.print_runtime_error(line, "Operand must be a number.");
-                Some(InterpretResult::RuntimeError)    
-            }
+    fn interpret_negate(&mut self, offset: usize) -> Option<InterpretResult> {
+        if let Value::Number(x) = self.peek(0) {
+            self.set_top(Value::Number(-x));
+            None
         } else {
-            Some(InterpretResult::RuntimeError)
+            self.runtime_error(offset, "Operand must be a number.")
         }
-
     }
 
-    fn interpret_not(&self) -> Option<InterpretResult> {
-        let value = self.pop();
-        self.push(&Value::Bool(Self::is_falsey(&value)));
+    fn interpret_not(&mut self) -> Option<InterpretResult> {
+        let value = self.peek(0);
+        self.set_top(Value::Bool(value.is_falsey()));
         None
     }
 
-    fn interpret_equal(&self) -> Option<InterpretResult> {
+    fn interpret_equal(&mut self) -> Option<InterpretResult> {
         let b = self.pop();
-        let a = self.pop();
-        self.push(&Value::Bool(a == b));
+        let a = self.peek(0);
+        self.set_top(Value::Bool(a == b));
         None
     }
 
-    fn interpret_print(&self) -> Option<InterpretResult> {
+    fn interpret_print(&mut self) -> Option<InterpretResult> {
         let value = self.pop();
-        println!("{}", value);
+        // Like tracing, a broken output must not stop the program
+        let _ = writeln!(self.output, "{}", value.display(&self.heap));
         None
     }
 
-    fn interpret_pop(&self) -> Option<InterpretResult> {
-        self.pop();
-        None
-    }
-
-    fn is_falsey(value: &Value) -> bool {
-        match value {
-            Value::Nil => true,
-            Value::Bool(false) => true,
-            _ => false
-        }
-    }
+    // Operands are replaced in place by the result
+    fn interpret_binary(&mut self, op_code: OpCode, offset: usize) -> Option<InterpretResult> {
 
-    fn interpret_binary(&self, instr: &Instruction, line: i32) -> Option<InterpretResult> {
-        
-        let val_b = self.peek(0).unwrap();
-        let val_a = self.peek(1).unwrap();
-        let mut a: f64 = 0.0;
-        let mut b: f64 = 0.0;
-        let mut new_string_opt: Option<HeapRef<String>> = None;
-
-        match (val_a, val_b) {
-            (Value::Number(aa), Value::Number(bb)) => {
-                (a, b) = (aa, bb);
-            },
-            (Value::Str(a_ref), Value::Str(b_ref)) => {
-                match instr { 
-                    Instruction::Add => new_string_opt = Some(a_ref.concat(&b_ref)),
-                    _ => {
-                        self.print_runtime_error(line, "Operator not supported for strings.");
-                        return Some(InterpretResult::RuntimeError);
-                    },
-                }
+        let result = match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => match op_code {
+                OpCode::Add => Value::Number(a + b),
+                OpCode::Subtract => Value::Number(a - b),
+                OpCode::Multiply => Value::Number(a * b),
+                OpCode::Divide => Value::Number(a / b),
+                OpCode::Greater => Value::Bool(a > b),
+                OpCode::Less => Value::Bool(a < b),
+                _ => return Some(InterpretResult::RuntimeError),
             },
-            _ => {
-                self.print_runtime_error(line, "Operands must be numbers.");
-                return Some(InterpretResult::RuntimeError);
+            (Value::Str(a_ref), Value::Str(b_ref)) => match op_code {
+                OpCode::Add => match a_ref.concat(&b_ref, &mut self.heap) {
+                    Ok(s_ref) => Value::Str(s_ref),
+                    Err(error) => return self.report_error(offset, ErrorKind::HeapLimit, &error.to_string()),
+                },
+                _ => return self.runtime_error(offset, "Operator not supported for strings."),
             },
-        }
+            _ => return self.runtime_error(offset, "Operands must be numbers."),
+        };
 
         self.pop();
-        self.pop();
-
-        match instr {
-            Instruction::Add => {
-                if new_string_opt.is_none() {
-                    self.push(&Value::Number(a + b));
-                } else {
-                    self.push(&Value::Str(new_string_opt.unwrap()));
-                }
-                None
-            },
-            Instruction::Subtract => {
-                self.push(&Value::Number(a - b));
-                None
-            },
-            Instruction::Multiply => {
-                self.push(&Value::Number(a * b));
-                None
-            },
-            Instruction::Divide => {
-                self.push(&Value::Number(a / b));
-                None
-            },
-            Instruction::Greater => {
-                self.push(&Value::Bool(a > b));
-                None
-            },
-            Instruction::Less => {
-                self.push(&Value::Bool(a < b));
-                None
-            },
-            _ => Some(InterpretResult::RuntimeError),
-        }
+        self.set_top(result);
+        None
     }
 
 }
@@ -607,7 +787,7 @@ mod tests {
     #[test]
     fn run() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
 
         let val1 = vm.add_value(Value::Number(42.));
         let val2 = vm.add_value(Value::Number(23.));
@@ -623,7 +803,7 @@ mod tests {
     #[test]
     fn run_negation() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
         
         let val = vm.add_value(Value::Number(42.)) as u8;
         
@@ -638,7 +818,7 @@ mod tests {
     #[test]
     fn run_addition() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
         
         let val1 = vm.add_value(Value::Number(1.)) as u8;
         let val2 = vm.add_value(Value::Number(2.)) as u8;
@@ -655,7 +835,7 @@ mod tests {
     #[test]
     fn run_addition_err() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
         
         let val1 = vm.add_value(Value::Number(1.)) as u8;
         let val2 = vm.add_value(Value::Bool(true)) as u8;
@@ -672,7 +852,7 @@ mod tests {
     #[test]
     fn run_subtraction() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
         
         let val1 = vm.add_value(Value::Number(1.)) as u8;
         let val2 = vm.add_value(Value::Number(2.)) as u8;
@@ -689,7 +869,7 @@ mod tests {
     #[test]
     fn run_multiplication() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
         
         let val1 = vm.add_value(Value::Number(2.)) as u8;
         let val2 = vm.add_value(Value::Number(3.)) as u8;
@@ -706,7 +886,7 @@ mod tests {
     #[test]
     fn run_division() {
 
-        let mut vm = VM::new();
+        let mut vm = VM::new(&GlobalTable::new_rc_refcell());
         
         let val1 = vm.add_value(Value::Number(2.)) as u8;
         let val2 = vm.add_value(Value::Number(3.)) as u8;
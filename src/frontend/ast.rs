@@ -0,0 +1,245 @@
+// Syntax tree of a Lox source, built by the parser. Nodes keep the spans of
+// their tokens, so that tools can point back into the source and the compiler
+// can attribute every instruction to the line of the token it belongs to.
+// Sources with syntax errors yield a partial tree.
+
+use super::token::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogicalOperator {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number { value: f64, span: Span },
+    Str { value: String, span: Span }, // without the quotes
+    Bool { value: bool, span: Span },
+    Nil { span: Span },
+    Variable(Identifier),
+    Assign { target: Identifier, value: Box<Expr> },
+    Unary { operator: UnaryOperator, operator_span: Span, operand: Box<Expr> },
+    Binary { left: Box<Expr>, operator: BinaryOperator, operator_span: Span, right: Box<Expr> },
+    Logical { left: Box<Expr>, operator: LogicalOperator, operator_span: Span, right: Box<Expr> },
+    Call { callee: Box<Expr>, args: Vec<Expr>, right_paren: Span },
+    Grouping { expr: Box<Expr>, left_paren: Span, right_paren: Span },
+    Invalid { span: Span }, // where the parser expected an expression
+}
+
+impl Expr {
+
+    pub fn first_span(&self) -> Span {
+        let mut expr = self;
+        loop {
+            match expr {
+                Expr::Number { span, .. } |
+                Expr::Str { span, .. } |
+                Expr::Bool { span, .. } |
+                Expr::Nil { span } |
+                Expr::Invalid { span } => return *span,
+                Expr::Variable(name) => return name.span,
+                Expr::Assign { target, .. } => return target.span,
+                Expr::Unary { operator_span, .. } => return *operator_span,
+                Expr::Binary { left, .. } |
+                Expr::Logical { left, .. } => expr = left,
+                Expr::Call { callee, .. } => expr = callee,
+                Expr::Grouping { left_paren, .. } => return *left_paren,
+            }
+        }
+    }
+
+    pub fn last_span(&self) -> Span {
+        match self {
+            Expr::Number { span, .. } |
+            Expr::Str { span, .. } |
+            Expr::Bool { span, .. } |
+            Expr::Nil { span } |
+            Expr::Invalid { span } => *span,
+            Expr::Variable(name) => name.span,
+            Expr::Assign { value, .. } => value.last_span(),
+            Expr::Unary { operand, .. } => operand.last_span(),
+            Expr::Binary { right, .. } |
+            Expr::Logical { right, .. } => right.last_span(),
+            Expr::Call { right_paren, .. } |
+            Expr::Grouping { right_paren, .. } => *right_paren,
+        }
+    }
+
+    // Moves the subexpressions out, leaving placeholders behind
+    fn take_operands(&mut self, operands: &mut Vec<Expr>) {
+        let mut take = |expr: &mut Box<Expr>| {
+            operands.push(std::mem::replace(expr.as_mut(), Expr::Nil { span: Span::default() }));
+        };
+        match self {
+            Expr::Assign { value, .. } => take(value),
+            Expr::Unary { operand, .. } => take(operand),
+            Expr::Binary { left, right, .. } |
+            Expr::Logical { left, right, .. } => {
+                take(left);
+                take(right);
+            },
+            Expr::Call { callee, args, .. } => {
+                take(callee);
+                operands.append(args);
+            },
+            Expr::Grouping { expr, .. } => take(expr),
+            _ => (),
+        }
+    }
+
+}
+
+// Dropping nested boxes recurses, which would overflow the stack for long
+// chains of operands like sums of thousands of terms, so trees are taken
+// apart in a loop
+impl Drop for Expr {
+
+    fn drop(&mut self) {
+        let mut operands = vec![];
+        self.take_operands(&mut operands);
+        while let Some(mut operand) = operands.pop() {
+            operand.take_operands(&mut operands);
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub left_brace: Span,
+    pub statements: Vec<Stmt>,
+    pub right_brace: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunDecl {
+    pub keyword: Span,
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub keyword: Span,
+    pub value: Expr,
+    pub colon: Span,
+    pub statements: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefaultCase {
+    pub keyword: Span,
+    pub colon: Span,
+    pub statements: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Print { keyword: Span, value: Expr, semicolon: Span },
+    Expression { expr: Expr, semicolon: Span },
+    Var { keyword: Span, name: Identifier, initializer: Option<Expr>, semicolon: Span },
+    Fun(FunDecl),
+    Block(Block),
+    If {
+        keyword: Span,
+        condition: Expr,
+        right_paren: Span,
+        then_branch: Box<Stmt>,
+        else_keyword: Option<Span>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While { keyword: Span, condition: Expr, right_paren: Span, body: Box<Stmt> },
+    For {
+        keyword: Span,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        condition_semicolon: Span,
+        increment: Option<Expr>,
+        right_paren: Span,
+        body: Box<Stmt>,
+    },
+    Switch {
+        keyword: Span,
+        subject: Expr,
+        left_brace: Span,
+        cases: Vec<Case>,
+        default: Option<DefaultCase>,
+        right_brace: Span,
+    },
+    Continue { keyword: Span, semicolon: Span },
+    Return { keyword: Span, value: Option<Expr>, semicolon: Span },
+    Assert { keyword: Span, condition: Expr, message: Option<Expr>, semicolon: Span },
+}
+
+impl Stmt {
+
+    pub fn first_span(&self) -> Span {
+        match self {
+            Stmt::Print { keyword, .. } |
+            Stmt::Var { keyword, .. } |
+            Stmt::If { keyword, .. } |
+            Stmt::While { keyword, .. } |
+            Stmt::For { keyword, .. } |
+            Stmt::Switch { keyword, .. } |
+            Stmt::Continue { keyword, .. } |
+            Stmt::Return { keyword, .. } |
+            Stmt::Assert { keyword, .. } => *keyword,
+            Stmt::Expression { expr, .. } => expr.first_span(),
+            Stmt::Fun(fun) => fun.keyword,
+            Stmt::Block(block) => block.left_brace,
+        }
+    }
+
+    pub fn last_span(&self) -> Span {
+        match self {
+            Stmt::Print { semicolon, .. } |
+            Stmt::Expression { semicolon, .. } |
+            Stmt::Var { semicolon, .. } |
+            Stmt::Continue { semicolon, .. } |
+            Stmt::Return { semicolon, .. } |
+            Stmt::Assert { semicolon, .. } => *semicolon,
+            Stmt::Fun(fun) => fun.body.right_brace,
+            Stmt::Block(block) => block.right_brace,
+            Stmt::If { then_branch, else_branch, .. } =>
+                else_branch.as_ref().unwrap_or(then_branch).last_span(),
+            Stmt::While { body, .. } |
+            Stmt::For { body, .. } => body.last_span(),
+            Stmt::Switch { right_brace, .. } => *right_brace,
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+    pub eof: Span,
+}
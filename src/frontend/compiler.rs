@@ -1,10 +1,12 @@
-use std::{collections::VecDeque, cell::{RefCell}, rc::Rc, ops::{DerefMut}};
-use crate::backend::{chunk::Chunk, instruction::Instruction, value::Value, heap::HeapManager, objects::{FunData, ClosureData}};
-use super::{scanner::Scanner, token::{Token, TokenType}, parse_rules::{Precedence, ParseRules, ParseFn}};
+use std::{cell::{RefCell}, rc::Rc, cmp::Ordering};
+use crate::backend::{chunk::Chunk, instruction::Instruction, value::Value, heap::HeapManager, objects::{FunData, ClosureData}, globals::GlobalTable};
+use super::{parser::Parser, token::Span, ast::{Program, Stmt, Expr, Block, FunDecl, Case, DefaultCase, Identifier, UnaryOperator, BinaryOperator, LogicalOperator}, symbols::{SourceIndex, SymbolKind, Diagnostic}};
 
 struct Local {
-    name: Token,
+    name: String,
     depth: usize, // scope depth
+    debug_idx: Option<usize>, // index into the debug table of the chunk
+    symbol_idx: Option<usize>, // index into the symbols of the source index
 }
 
 #[derive(Clone)]
@@ -13,181 +15,77 @@ struct Loop {
     continue_target: usize, // offset to jump to from continue
 }
 
+// Constant value whose load instruction occupies chunk[start..end],
+// values_len is the size of the constant pool before the load
+#[derive(Clone, Copy)]
+struct ConstantOperand {
+    start: usize,
+    end: usize,
+    values_len: usize,
+    value: Value,
+}
+
 struct Environment {
     pub locals: Vec<Local>,
     pub loops: Vec<Loop>,
     pub curr_depth: usize,
 }
 
+// Compiles the syntax tree of the parser to bytecode
 pub struct Compiler<'a> {
-    scanner: Scanner<'a>,
-    lookahead: VecDeque<Token>,
-    previous: Option<Token>,
-    current: Option<Token>,
-    had_error: bool,
-    panic_mode: bool,
-    parse_rules: ParseRules,
-    heap_manager: Rc<RefCell<HeapManager>>,
+    source: &'a str,
+    position: Span, // token that emitted instructions and errors belong to
+    heap: &'a mut HeapManager,
+    globals: Rc<RefCell<GlobalTable>>,
     envs: Vec<Environment>,
+    last_constant: Option<ConstantOperand>,
+    echo_expressions: bool, // print the values of top-level expression statements
+    print_errors: bool,
+    index: SourceIndex,
 }
 
 impl <'a> Compiler<'a> {
 
-    pub fn new(source: &'a str) -> Compiler {
-        Self::new_with_heap_mgr(source, &HeapManager::new_rc_refcell())
-    }
-
-    pub fn new_with_heap_mgr(source: &'a str, heap_manager: &Rc<RefCell<HeapManager>>) 
-        -> Compiler<'a> {
-
-        let mut ret = Compiler { 
-            scanner: Scanner::new(source),
-            lookahead: VecDeque::new(),
-            previous: None,
-            current: None,
-            had_error: false, 
-            panic_mode: false,
-            parse_rules: ParseRules::new(),
-            heap_manager: heap_manager.clone(),
+    // Global slots are shared with the VM that runs the compiled code
+    pub fn new(
+        source: &'a str,
+        heap: &'a mut HeapManager,
+        globals: &Rc<RefCell<GlobalTable>>) -> Compiler<'a> {
+
+        let mut ret = Compiler {
+            source,
+            position: Span::default(),
+            heap,
+            globals: globals.clone(),
             envs: vec![],
+            last_constant: None,
+            echo_expressions: false,
+            print_errors: true,
+            index: SourceIndex::default(),
         };
 
         ret.begin_env();
 
-        ret.init_parse_rules();
-
         ret
     }
 
-    fn init_parse_rules(&mut self) {
-
-        self.parse_rules.register(
-            TokenType::LeftParen,
-            grouping(),
-            call(),
-            Precedence::Call
-        );
-        self.parse_rules.register(
-            TokenType::Minus,
-            unary(),
-            binary(),
-            Precedence::Term
-        );
-        self.parse_rules.register(
-            TokenType::Plus,
-            None,
-            binary(),
-            Precedence::Term
-        );
-        self.parse_rules.register(
-            TokenType::Slash, 
-            None, 
-            binary(), 
-            Precedence::Factor
-        );
-        self.parse_rules.register(
-            TokenType::Star, 
-            None, 
-            binary(), 
-            Precedence::Factor
-        );
-        self.parse_rules.register(
-            TokenType::Number,
-            number(), 
-            None, 
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::Nil,
-            literal(),
-            None,
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::True,
-            literal(),
-            None,
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::False,
-            literal(),
-            None,
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::String,
-            string(),
-            None,
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::Identifier,
-            variable(),
-            None,
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::Bang, 
-            unary(), 
-            None, 
-            Precedence::None
-        );
-        self.parse_rules.register(
-            TokenType::BangEqual, 
-            None, 
-            binary(), 
-            Precedence::Equality
-        );
-        self.parse_rules.register(
-            TokenType::EqualEqual, 
-            None, 
-            binary(), 
-            Precedence::Equality
-        );
-        self.parse_rules.register(
-            TokenType::Greater, 
-            None, 
-            binary(), 
-            Precedence::Comparison
-        );
-        self.parse_rules.register(
-            TokenType::Greater, 
-            None, 
-            binary(), 
-            Precedence::Comparison
-        );
-        self.parse_rules.register(
-            TokenType::GreaterEqual, 
-            None, 
-            binary(), 
-            Precedence::Comparison
-        );
-        self.parse_rules.register(
-            TokenType::Less, 
-            None, 
-            binary(), 
-            Precedence::Comparison
-        );
-        self.parse_rules.register(
-            TokenType::LessEqual, 
-            None, 
-            binary(), 
-            Precedence::Comparison
-        );
-        self.parse_rules.register(
-            TokenType::And, 
-            None, 
-            and(), 
-            Precedence::And
-        );
-        self.parse_rules.register(
-            TokenType::Or, 
-            None, 
-            or(), 
-            Precedence::Or
-        );
+    // Used by the REPL to show the values of expressions entered by the user
+    pub fn set_echo_expressions(&mut self, echo: bool) {
+        self.echo_expressions = echo;
+    }
+
+    // Errors are always recorded in the source index
+    pub fn set_print_errors(&mut self, print_errors: bool) {
+        self.print_errors = print_errors;
+    }
+
+    // Symbols, references and diagnostics of the compiled source
+    pub fn source_index(&self) -> &SourceIndex {
+        &self.index
+    }
 
+    pub fn take_source_index(&mut self) -> SourceIndex {
+        std::mem::take(&mut self.index)
     }
 
     fn begin_env(&mut self) {
@@ -227,103 +125,121 @@ impl <'a> Compiler<'a> {
         self.envs.last_mut().unwrap().loops.as_mut()
     }
 
+    // Sources with syntax errors are compiled as well, so that the source
+    // index knows the names of the parts that could be parsed
     pub fn compile(&mut self) -> Option<FunData> {
-        
+
+        let mut parser = Parser::new(self.source);
+        let program = parser.parse();
+        self.index.diagnostics.extend(parser.take_diagnostics());
+
         let mut top = FunData::new_top();
-        
-        {
-            let mut chunk = top.borrow_chunk_mut();
-            let chunk = chunk.deref_mut();
-            self.had_error = false;
-            self.panic_mode = false;
-        
-            self.advance();
-        
-            while !self.is_match(TokenType::Eof) {
-                self.declaration(chunk);
+        self.compile_program(top.chunk_mut(), &program);
+
+        self.index.resolve_globals();
+
+        // Errors of the parser and of the compiler in the order of the source
+        self.index.diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
+        if self.print_errors {
+            for diagnostic in self.index.diagnostics.iter() {
+                eprintln!("{}", diagnostic.report);
             }
-        
-            self.end_compiler(chunk);
-        
         }
 
-        if !self.had_error {
+        if self.index.diagnostics.is_empty() {
             Some(top)
         } else {
             None
         }
     }
 
-    fn declaration(&mut self, chunk: &mut Chunk) {
+    fn compile_program(&mut self, chunk: &mut Chunk, program: &Program) {
 
-        if self.is_match(TokenType::Fun ) {
-            self.fun_declaration(chunk);
-        } else if self.is_match(TokenType::Var) {
-            self.var_declaration(chunk);
-        } else {
-            self.statement(chunk);
+        for stmt in program.statements.iter() {
+            self.statement(chunk, stmt);
         }
 
-        if self.panic_mode {
-            self.synchronize();
+        // The implicit return belongs to the last line with code rather
+        // than to the end of the file, which can be a line of its own
+        let line = chunk.size()
+            .checked_sub(1)
+            .and_then(|offset| chunk.get_line(offset))
+            .unwrap_or(1);
+        chunk.write_instruction(Instruction::Return, line);
+    }
+
+    fn statement(&mut self, chunk: &mut Chunk, stmt: &Stmt) {
+        match stmt {
+            Stmt::Print { value, semicolon, .. } =>
+                self.print_statement(chunk, value, *semicolon),
+            Stmt::Expression { expr, semicolon } =>
+                self.expr_statement(chunk, expr, *semicolon),
+            Stmt::Var { name, initializer, semicolon, .. } =>
+                self.var_declaration(chunk, name, initializer.as_ref(), *semicolon),
+            Stmt::Fun(fun) =>
+                self.fun_declaration(chunk, fun),
+            Stmt::Block(block) => {
+                self.begin_scope();
+                self.block(chunk, block);
+                self.end_scope(chunk);
+            },
+            Stmt::If { condition, right_paren, then_branch, else_branch, .. } =>
+                self.if_statement(chunk, condition, *right_paren, then_branch, else_branch.as_deref()),
+            Stmt::While { condition, right_paren, body, .. } =>
+                self.while_statement(chunk, condition, *right_paren, body),
+            Stmt::For { .. } =>
+                self.for_statement(chunk, stmt),
+            Stmt::Switch { subject, left_brace, cases, default, right_brace, .. } =>
+                self.switch_statement(chunk, subject, *left_brace, cases, default.as_ref(), *right_brace),
+            Stmt::Continue { keyword, semicolon } =>
+                self.continue_statement(chunk, *keyword, *semicolon),
+            Stmt::Return { keyword, value, semicolon } =>
+                self.return_statement(chunk, *keyword, value.as_ref(), *semicolon),
+            Stmt::Assert { keyword, condition, message, semicolon } =>
+                self.assert_statement(chunk, *keyword, condition, message.as_ref(), *semicolon),
         }
     }
 
-    fn fun_declaration(&mut self, chunk: &mut Chunk) {
+    fn fun_declaration(&mut self, chunk: &mut Chunk, fun: &FunDecl) {
 
-        self.consume(TokenType::Identifier, "Expect function name.");
-        let fun_name_tok = self.previous.as_ref().unwrap().clone();
+        self.at(fun.name.span);
 
-        let idx_opt = self.resolve_local_idx_w_min_depth(&fun_name_tok, self.current_depth());
-        if !idx_opt.is_none() {
+        let idx_opt = self.resolve_local_idx_w_min_depth(&fun.name.name, self.current_depth());
+        if idx_opt.is_some() {
             self.error("Already a variable with this name in scope");
             return;
         }
 
-        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        let params = fun.params.iter().map(|param| param.name.clone()).collect();
+        let symbol_idx = self.add_symbol(&fun.name, SymbolKind::Function { params });
 
-        // Parameters
-        let mut parameters: Vec<Token> = vec![];
-        if !self.check(TokenType::RightParen) {
-            loop {
-                self.consume(TokenType::Identifier, "Expect identifier as parameter.");
-                parameters.push(self.previous.as_ref().unwrap().clone());
-                if !self.is_match(TokenType::Comma) {
-                    break;
-                }
-            }
-        }
+        let closure = self.compile_fun_body(fun, symbol_idx);
 
-        if parameters.len() > u8::MAX as usize{
-            self.error(&format!("Number of parameters must not exceed {}", u8::MAX));
+        let value_idx = chunk.add_value(closure);
+        if value_idx > u16::MAX as usize {
+            self.error("Too many constants in one chunk.");
             return;
         }
-        
-        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
-        self.consume(TokenType::LeftBrace, "Expect '{' before body.");
-
-        let closure = self.compile_fun_body(&fun_name_tok, parameters);
+        self.emit_instruction(chunk, Instruction::Closure { value_idx: value_idx as u16 });
 
-        let value_idx = chunk.add_value(closure) as u16;
-        self.emit_instruction(chunk, Instruction::Closure { value_idx });
-    
-        self.define_variable(fun_name_tok, chunk);
+        self.define_variable(&fun.name, symbol_idx, chunk);
     }
 
-    fn compile_fun_body(&mut self, name: &Token, params: Vec<Token>) -> Value {
+    fn compile_fun_body(&mut self, fun: &FunDecl, symbol_idx: usize) -> Value {
 
         self.begin_env();
 
         let mut chunk = Chunk::new();
         self.begin_scope();
 
-        self.define_variable(name.clone(), &mut chunk);
+        self.define_variable(&fun.name, symbol_idx, &mut chunk);
 
-        for param in params.iter() {
-            self.define_variable(param.clone(), &mut chunk);
+        for param in fun.params.iter() {
+            let param_symbol_idx = self.add_symbol(param, SymbolKind::Parameter);
+            self.define_variable(param, param_symbol_idx, &mut chunk);
         }
 
-        self.block(&mut chunk);
+        self.block(&mut chunk, &fun.body);
 
         self.end_scope(&mut chunk);
 
@@ -332,157 +248,113 @@ impl <'a> Compiler<'a> {
 
         self.end_env();
 
-        let fun_data = FunData::new(name.get_lexeme(), params.len() as u8, chunk);
-        let closure_data = ClosureData::new(fun_data);
-        let closure_data = HeapManager::malloc(&self.heap_manager, closure_data);
-        
-        Value::Closure(closure_data)
+        let fun_data = FunData::new(&fun.name.name, fun.params.len() as u8, chunk);
+        let closure_data = self.heap
+            .malloc(fun_data)
+            .and_then(|fun_data| self.heap.malloc(ClosureData::new(fun_data)));
+
+        match closure_data {
+            Ok(closure_data) => Value::Closure(closure_data),
+            Err(error) => {
+                self.error(&error.to_string());
+                Value::Nil
+            }
+        }
     }
 
-    fn var_declaration(&mut self, chunk: &mut Chunk) {
+    fn var_declaration(&mut self, chunk: &mut Chunk, name: &Identifier, initializer: Option<&Expr>, semicolon: Span) {
 
-        self.consume(TokenType::Identifier, "Expect variable name.");
-        
-        let vartoken = self.previous.as_ref().unwrap().clone();
+        self.at(name.span);
 
-        let idx_opt = self.resolve_local_idx_w_min_depth(&vartoken, self.current_depth());
-        if !idx_opt.is_none() {
+        let idx_opt = self.resolve_local_idx_w_min_depth(&name.name, self.current_depth());
+        if idx_opt.is_some() {
             self.error("Already a variable with this name in scope");
             return;
         }
 
-        if self.is_match(TokenType::Equal) {
-            self.expression(chunk);
-        } else {
-            self.emit_instruction(chunk, Instruction::Nil);
+        match initializer {
+            Some(initializer) => self.expression(chunk, initializer),
+            None => self.emit_instruction(chunk, Instruction::Nil),
         }
 
-        self.consume(TokenType::Semicolon, 
-            "Expect ';' after variable declaration.");
+        self.at(semicolon);
 
-        self.define_variable(vartoken, chunk);        
+        let symbol_idx = self.add_symbol(name, SymbolKind::Variable);
+        self.define_variable(name, symbol_idx, chunk);
     }
 
-    fn define_variable(&mut self, name_tok: Token, chunk: &mut Chunk) {
+    fn define_variable(&mut self, name: &Identifier, symbol_idx: usize, chunk: &mut Chunk) {
 
         if self.current_depth() > 0 {
+            let slot = self.locals().len();
+            let debug_idx = chunk.begin_local(&name.name, slot);
             let local = Local{
-                name: name_tok,
+                name: name.name.clone(),
                 depth: self.current_depth(),
+                debug_idx: Some(debug_idx),
+                symbol_idx: Some(symbol_idx),
             };
             let locals = self.locals_mut();
             locals.push(local);
 
         } else {
-            let varname = self.create_varname(name_tok);
-            let global_idx = chunk.add_value(varname) as u32;
+            let global_idx = self.resolve_global_idx(&name.name, chunk);
             self.emit_instruction(chunk, Instruction::DefineGlobal { global_idx })
         }
 
-    } 
-
-    fn create_varname(&self, token: Token) -> Value {
-        let varname = token.get_lexeme();
-        let varname = HeapManager::malloc(&self.heap_manager, varname.to_string());
-        Value::Str(varname)
     }
 
-    fn synchronize(&mut self) {
-
-        self.panic_mode = false;
-
-        loop {
-            if let Some(token) = &self.current {
-                if token.get_token_type() == TokenType::Eof ||
-                    self.previous.as_ref().unwrap().get_token_type() == TokenType::Semicolon {
-                    return;
-                }
-                match token.get_token_type() {
-                    TokenType::Class |
-                    TokenType::Fun |
-                    TokenType::Var |
-                    TokenType::For |
-                    TokenType::If |
-                    TokenType::While |
-                    TokenType::Print |
-                    TokenType::Return =>
-                        return,
-                    _ => (),
-                }
-
-
-            } else {
-                return;
-            }
-
-            self.advance();
-        }
-
+    // Globals are the variables of the top-level scope
+    fn add_symbol(&mut self, name: &Identifier, kind: SymbolKind) -> usize {
+        let global = self.envs.len() == 1 && self.current_depth() == 0;
+        self.index.add_symbol(&name.name, kind, name.span, global)
     }
 
-    fn statement(&mut self, chunk: &mut Chunk) {
-        if self.is_match(TokenType::Print) {
-            self.print_statement(chunk);
-        } else if self.is_match(TokenType::For) {
-            self.for_statement(chunk);
-        } else if self.is_match(TokenType::If) {
-            self.if_statement(chunk);
-        } else if self.is_match(TokenType::While) {
-            self.while_statement(chunk);
-        } else if self.is_match(TokenType::Switch) {
-            self.switch_statement(chunk);
-        } else if self.is_match(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block(chunk);
-            self.end_scope(chunk);
-        } else if self.is_match(TokenType::Continue) {
-            self.continue_statement(chunk);
-        } else if self.is_match(TokenType::Return) {
-            self.return_statement(chunk);
-        } else {
-            self.expr_statement(chunk);
-        }
+    fn resolve_global_idx(&self, name: &str, chunk: &mut Chunk) -> u32 {
+        let global_idx = self.globals.borrow_mut().resolve(name) as u32;
+        chunk.add_global_name(global_idx, name);
+        global_idx
     }
 
-    fn return_statement(&mut self, chunk: &mut Chunk) {
+    fn return_statement(&mut self, chunk: &mut Chunk, keyword: Span, value: Option<&Expr>, semicolon: Span) {
 
         if self.envs.len() == 1 {
+            self.at(keyword);
             self.error("Can't return from top level code.");
             return;
         }
 
-        if self.is_match(TokenType::Semicolon) {
-            self.emit_instruction(chunk, Instruction::Nil);
-            
-        } else {
-            self.expression(chunk);
-            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        match value {
+            Some(value) => self.expression(chunk, value),
+            None => {
+                self.at(semicolon);
+                self.emit_instruction(chunk, Instruction::Nil);
+            },
         }
+        self.at(semicolon);
         self.emit_return(chunk);
     }
 
-    fn for_statement(&mut self, chunk: &mut Chunk) {
+    fn for_statement(&mut self, chunk: &mut Chunk, stmt: &Stmt) {
+
+        let Stmt::For { initializer, condition, condition_semicolon, increment, right_paren, body, .. } = stmt else {
+            return;
+        };
 
         self.begin_scope();
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
-        
         // Initializer clause:
-        if self.is_match(TokenType::Semicolon) {
-            // no initializer
-        } else if self.is_match(TokenType::Var) {
-            self.var_declaration(chunk);
-        } else {
-            self.expr_statement(chunk);
+        if let Some(initializer) = initializer {
+            self.statement(chunk, initializer);
         }
-        
+
         let loop_start = chunk.size();
         let mut cond_false_opt: Option<usize> = None;
 
         // Condition clause:
-        if !self.is_match(TokenType::Semicolon) {
-            self.expression(chunk);
-            self.consume(TokenType::Semicolon, "Expect ';'.");
+        if let Some(condition) = condition {
+            self.expression(chunk, condition);
+            self.at(*condition_semicolon);
             cond_false_opt = Some(chunk.size());
             self.emit_jump_if_false(chunk);
             self.emit_instruction(chunk, Instruction::Pop);
@@ -492,19 +364,18 @@ impl <'a> Compiler<'a> {
         let mut jump_opt: Option<usize> = None;
         let mut incr_opt: Option<usize> = None;
 
-        if !self.is_match(TokenType::RightParen) {
+        if let Some(increment) = increment {
 
+            self.at(*condition_semicolon);
             jump_opt = Some(chunk.size());
             self.emit_jump(chunk);
 
             incr_opt = Some(chunk.size());
-            self.expression(chunk);
+            self.expression(chunk, increment);
             self.emit_instruction(chunk, Instruction::Pop);
-            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
-        
-            let from = chunk.size();
-            let jump_distance = (from - loop_start) as u16;
-            self.emit_instruction(chunk, Instruction::Loop { jump_distance });
+
+            self.at(*right_paren);
+            self.emit_loop(chunk, loop_start);
         }
 
         let continue_target = if let Some(incr) = incr_opt {
@@ -513,132 +384,127 @@ impl <'a> Compiler<'a> {
             loop_start
         };
 
-        let depth = self.current_depth(); 
+        let depth = self.current_depth();
         let loops = self.loops_mut();
-        loops.push(Loop { 
-            depth, 
-            continue_target, 
+        loops.push(Loop {
+            depth,
+            continue_target,
         });
-    
-        if jump_opt.is_some() {
+
+        if let Some(jump) = jump_opt {
             let body = chunk.size();
-            self.update_forward_jump(chunk, jump_opt.unwrap(), body);
+            self.update_forward_jump(chunk, jump, body);
         }
 
-        self.statement(chunk);
+        self.statement(chunk, body);
+        self.at(body.last_span());
 
-        let jump_target = if incr_opt.is_some() {
-            incr_opt.unwrap()
-        } else {
-            loop_start
-        };
+        let jump_target = incr_opt.unwrap_or(loop_start);
 
-        let loop_end = chunk.size();
-        let jump_distance  = (loop_end - jump_target) as u16;
-        self.emit_instruction(chunk, Instruction::Loop { jump_distance });
+        self.emit_loop(chunk, jump_target);
 
-        if cond_false_opt.is_some() {
+        if let Some(cond_false) = cond_false_opt {
             let after_loop = chunk.size();
-            let cond_false = cond_false_opt.unwrap();
             self.update_forward_jump(chunk, cond_false, after_loop);
             self.emit_instruction(chunk, Instruction::Pop);
         }
 
         self.loops_mut().pop();
-        
+
         self.end_scope(chunk);
-        
+
     }
 
-    fn update_forward_jump(&self, chunk: &mut Chunk, from: usize, to: usize) {
-        let jump_delta = (to - from) as u16;
-        chunk.update_jump_offset(from, jump_delta);
+    // Distances of jumps have 16 bits
+    fn update_forward_jump(&mut self, chunk: &mut Chunk, from: usize, to: usize) {
+        match u16::try_from(to - from) {
+            Ok(jump_delta) => chunk.update_jump_offset(from, jump_delta),
+            Err(_) => self.error("Too much code to jump over."),
+        }
     }
 
-    fn if_statement(&mut self, chunk: &mut Chunk) {
+    fn if_statement(&mut self, chunk: &mut Chunk, condition: &Expr, right_paren: Span, then_branch: &Stmt, else_branch: Option<&Stmt>) {
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
-        self.expression(chunk);
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
-    
-        let offset_jump_if_false = chunk.size();        
+        self.expression(chunk, condition);
+        self.at(right_paren);
+
+        let offset_jump_if_false = chunk.size();
         self.emit_jump_if_false(chunk);
-        
+
         self.emit_instruction(chunk, Instruction::Pop);
-        self.statement(chunk); // then block
-        
+        self.statement(chunk, then_branch);
+        self.at(then_branch.last_span());
+
         let offset_jump = chunk.size();
         self.emit_jump(chunk);
 
         let offset_pop = chunk.size();
         self.emit_instruction(chunk, Instruction::Pop);
-        if self.is_match(TokenType::Else) {
-            self.statement(chunk); // else block
+        if let Some(else_branch) = else_branch {
+            self.statement(chunk, else_branch);
+            self.at(else_branch.last_span());
         }
 
         let offset_end = chunk.size();
 
-        let mut jump_delta = (offset_pop - offset_jump_if_false) as u16;
-        chunk.update_jump_offset(offset_jump_if_false, jump_delta);
-
-        jump_delta = (offset_end - offset_jump) as u16;
-        chunk.update_jump_offset(offset_jump, jump_delta);
+        self.update_forward_jump(chunk, offset_jump_if_false, offset_pop);
+        self.update_forward_jump(chunk, offset_jump, offset_end);
 
     }
 
-    fn while_statement(&mut self, chunk: &mut Chunk) {
+    fn while_statement(&mut self, chunk: &mut Chunk, condition: &Expr, right_paren: Span, body: &Stmt) {
 
         let loop_start = chunk.size();
 
         let depth = self.current_depth();
         let loops = self.loops_mut();
-        loops.push(Loop { 
-            depth, 
-            continue_target: loop_start, 
+        loops.push(Loop {
+            depth,
+            continue_target: loop_start,
         });
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
-        self.expression(chunk);
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
-    
-        let jump_if_false = chunk.size();        
+        self.expression(chunk, condition);
+        self.at(right_paren);
+
+        let jump_if_false = chunk.size();
         self.emit_jump_if_false(chunk);
         self.emit_instruction(chunk, Instruction::Pop);
-        self.statement(chunk);
- 
-        let loop_end = chunk.size();
-        self.emit_loop(chunk);
+        self.statement(chunk, body);
+        self.at(body.last_span());
+
+        self.emit_loop(chunk, loop_start);
 
         let end = chunk.size();
         self.emit_instruction(chunk, Instruction::Pop);
 
-        let mut jump_delta = (end - jump_if_false) as u16;
-        chunk.update_jump_offset(jump_if_false, jump_delta);    
-
-        jump_delta = (loop_end - loop_start) as u16;
-        chunk.update_jump_offset(loop_end, jump_delta);
+        self.update_forward_jump(chunk, jump_if_false, end);
 
         self.loops_mut().pop();
 
     }
 
-    fn switch_statement(&mut self, chunk: &mut Chunk) {
-
-        let switch_token = self.previous.clone().unwrap();
+    fn switch_statement(
+        &mut self,
+        chunk: &mut Chunk,
+        subject: &Expr,
+        left_brace: Span,
+        cases: &[Case],
+        default: Option<&DefaultCase>,
+        right_brace: Span) {
 
         self.begin_scope();
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
-        self.expression(chunk);
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
-        self.consume(TokenType::LeftBrace, "Expect '{'.");
+        self.expression(chunk, subject);
+        self.at(left_brace);
 
-        // Set expression value as local variable:
+        // Set expression value as local variable, its name can't clash with a variable
         let depth = self.current_depth();
         let locals = self.locals_mut();
-        locals.push(Local { 
-            name: switch_token, 
-            depth, 
+        locals.push(Local {
+            name: "switch".to_string(),
+            depth,
+            debug_idx: None, // hidden from the debugger
+            symbol_idx: None,
         });
         let local_idx = (locals.len() - 1) as u32;
         self.emit_instruction(chunk, Instruction::SetLocal { local_idx });
@@ -646,52 +512,48 @@ impl <'a> Compiler<'a> {
         let mut exit_jumps: Vec<usize> = vec![];
         let mut check_eq_opt: Option<usize> = None;
 
-        loop {
-            if self.is_match(TokenType::Case) {
-                if check_eq_opt.is_some() {
-                    let from = check_eq_opt.unwrap();
-                    let to = chunk.size();
-                    self.update_forward_jump(chunk, from, to);
-                    self.emit_instruction(chunk, Instruction::Pop);
-                }
-                self.expression(chunk);
-                self.emit_instruction(chunk, Instruction::GetLocal { local_idx });
-                self.emit_instruction(chunk, Instruction::Equal);
-                check_eq_opt = Some(chunk.size());
-                self.emit_jump_if_false(chunk);
+        for case in cases {
+            self.at(case.keyword);
+            if let Some(from) = check_eq_opt {
+                let to = chunk.size();
+                self.update_forward_jump(chunk, from, to);
                 self.emit_instruction(chunk, Instruction::Pop);
-                self.consume(TokenType::Colon, "Expect ':' after case expression.");
-                self.case_statements(chunk);
-                exit_jumps.push(chunk.size());
-                self.emit_jump(chunk);
-            } else if self.is_match(TokenType::Default) {
-                if check_eq_opt.is_some() {
-                    let from = check_eq_opt.unwrap();
-                    let to = chunk.size();
-                    self.update_forward_jump(chunk, from, to);
-                    self.emit_instruction(chunk, Instruction::Pop);
-                    check_eq_opt = None;
-                }
-                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
-                self.case_statements(chunk);
-                exit_jumps.push(chunk.size());
-                self.emit_jump(chunk);
-                break;
-            } else {
-                break;
             }
+            self.expression(chunk, &case.value);
+            self.emit_instruction(chunk, Instruction::GetLocal { local_idx });
+            self.emit_instruction(chunk, Instruction::Equal);
+            check_eq_opt = Some(chunk.size());
+            self.emit_jump_if_false(chunk);
+            self.emit_instruction(chunk, Instruction::Pop);
+            self.case_statements(chunk, &case.statements, case.colon);
+            exit_jumps.push(chunk.size());
+            self.emit_jump(chunk);
         }
 
-        self.consume(TokenType::RightBrace, "Expect '}' at end of switch statement.");
+        if let Some(default) = default {
+            self.at(default.keyword);
+            if let Some(from) = check_eq_opt.take() {
+                let to = chunk.size();
+                self.update_forward_jump(chunk, from, to);
+                self.emit_instruction(chunk, Instruction::Pop);
+            }
+            self.case_statements(chunk, &default.statements, default.colon);
+            exit_jumps.push(chunk.size());
+            self.emit_jump(chunk);
+        }
 
-        let exit = chunk.size();
+        self.at(right_brace);
 
-        if check_eq_opt.is_some() {
-            let from = check_eq_opt.unwrap();
-            self.update_forward_jump(chunk, from, exit);
+        // Only the last failed comparison leaves its result to be popped,
+        // the cases that were executed jump past the pop
+        if let Some(from) = check_eq_opt {
+            let to = chunk.size();
+            self.update_forward_jump(chunk, from, to);
             self.emit_instruction(chunk, Instruction::Pop);
         }
 
+        let exit = chunk.size();
+
         exit_jumps.iter().for_each(|exit_jump| {
             self.update_forward_jump(chunk, *exit_jump, exit);
         });
@@ -699,45 +561,14 @@ impl <'a> Compiler<'a> {
         self.end_scope(chunk);
     }
 
-    fn case_statements(&mut self, chunk: &mut Chunk) {
-        while 
-            !self.check(TokenType::Case) &&
-            !self.check(TokenType::Default) &&
-            !self.check(TokenType::RightBrace) {
-
-            self.statement(chunk);
+    fn case_statements(&mut self, chunk: &mut Chunk, statements: &[Stmt], colon: Span) {
+        self.at(colon);
+        for stmt in statements {
+            self.statement(chunk, stmt);
+            self.at(stmt.last_span());
         }
     }
 
-    fn and(&mut self, chunk: &mut Chunk, _can_assign: bool) {
-
-        let jump_if_false = chunk.size();
-        self.emit_jump_if_false(chunk);
-        self.emit_instruction(chunk, Instruction::Pop);
-        self.parse_precedence(Precedence::And, chunk);
-        let end = chunk.size();
-        let jump_delta = (end - jump_if_false) as u16;
-        chunk.update_jump_offset(jump_if_false, jump_delta);
-
-    }
-
-    fn or(&mut self, chunk: &mut Chunk, _can_assign: bool) {
-
-        let jump_if_false = chunk.size();
-        self.emit_jump_if_false(chunk);
-        let jump = chunk.size();
-        self.emit_jump(chunk);
-        let pop = chunk.size();
-        self.emit_instruction(chunk, Instruction::Pop);
-        self.parse_precedence(Precedence::Or, chunk);
-        let end = chunk.size();
-        let mut jump_delta = (pop - jump_if_false) as u16;
-        chunk.update_jump_offset(jump_if_false, jump_delta);
-        jump_delta = (end - jump) as u16;
-        chunk.update_jump_offset(jump, jump_delta);
-        
-    }
-
     fn begin_scope(&mut self) {
         self.set_current_depth(self.current_depth() + 1);
     }
@@ -750,18 +581,22 @@ impl <'a> Compiler<'a> {
     fn remove_locals(&mut self, chunk: &mut Chunk) {
         let curr_depth = self.current_depth();
         let locals = self.locals_mut();
-        let mut cnt_pops = 0;
+        let mut removed = vec![];
         while let Some(local) = locals.last() {
             if local.depth > curr_depth {
-                cnt_pops += 1;
+                removed.push(local.debug_idx);
                 locals.pop();
             } else {
                 break;
             }
-        } 
+        }
 
-        for _ in 0..cnt_pops {
+        // A local stays visible to the debugger until its pop is executed
+        for debug_idx in removed {
             self.emit_instruction(chunk, Instruction::Pop);
+            if let Some(debug_idx) = debug_idx {
+                chunk.end_local(debug_idx);
+            }
         }
 
     }
@@ -777,266 +612,394 @@ impl <'a> Compiler<'a> {
         }
     }
 
-    fn resolve_local_idx(&self, name: &Token) -> Option<usize> {
-        let name = name.get_lexeme();
+    fn resolve_local_idx(&self, name: &str) -> Option<usize> {
         let locals = self.locals();
         for (idx, local) in locals.iter().enumerate().rev() {
-            if local.name.get_lexeme() == name {
+            if local.name == name {
                 return Some(idx);
             }
         }
         None
     }
 
-    fn resolve_local_idx_w_min_depth(&self, name: &Token, min_depth: usize) -> Option<usize> {
-        let name = name.get_lexeme();
+    fn resolve_local_idx_w_min_depth(&self, name: &str, min_depth: usize) -> Option<usize> {
         let locals = self.locals();
         for (idx, local) in locals.iter().enumerate().rev() {
             if local.depth < min_depth {
                 break;
             }
-            if local.name.get_lexeme() == name {
+            if local.name == name {
                 return Some(idx);
             }
         }
         None
     }
 
-    fn block(&mut self, chunk: &mut Chunk) {
-        while !self.check(TokenType::RightBrace) && 
-            !self.check(TokenType::Eof) {
-            self.declaration(chunk);
+    fn block(&mut self, chunk: &mut Chunk, block: &Block) {
+        for stmt in block.statements.iter() {
+            self.statement(chunk, stmt);
         }
-
-        self.consume(TokenType::RightBrace, 
-            "Expect '}' after block.");
+        self.at(block.right_brace);
     }
 
-    fn print_statement(&mut self, chunk: &mut Chunk) {
-        self.expression(chunk);
-        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+    fn print_statement(&mut self, chunk: &mut Chunk, value: &Expr, semicolon: Span) {
+        self.expression(chunk, value);
+        self.at(semicolon);
         self.emit_instruction(chunk, Instruction::Print)
     }
 
-    fn continue_statement(&mut self, chunk: &mut Chunk) {
+    // The message is only evaluated when the assertion fails. The error
+    // shows the source text of the condition.
+    fn assert_statement(&mut self, chunk: &mut Chunk, keyword: Span, condition: &Expr, message: Option<&Expr>, semicolon: Span) {
+
+        let text = self.source_text(condition.first_span(), condition.last_span());
+        self.expression(chunk, condition);
+
+        let offset_jump_if_false = chunk.size();
+        self.emit_jump_if_false(chunk);
+        self.emit_instruction(chunk, Instruction::Pop);
+        let offset_jump = chunk.size();
+        self.emit_jump(chunk);
+
+        let offset_pop = chunk.size();
+        self.emit_instruction(chunk, Instruction::Pop);
+        match message {
+            Some(message) => self.expression(chunk, message),
+            None => self.emit_instruction(chunk, Instruction::Nil),
+        }
+        self.at(semicolon);
+
+        let value_idx = match self.heap.intern(&text) {
+            Ok(s_ref) => chunk.add_value(Value::Str(s_ref)),
+            Err(error) => {
+                self.error(&error.to_string());
+                return;
+            }
+        };
+        if value_idx > u16::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return;
+        }
+        chunk.write_instruction(Instruction::AssertFailed { value_idx: value_idx as u16 }, keyword.line);
+
+        let offset_end = chunk.size();
+        self.update_forward_jump(chunk, offset_jump_if_false, offset_pop);
+        self.update_forward_jump(chunk, offset_jump, offset_end);
+    }
+
+    // Source from the start of the first token to the end of the last one,
+    // with whitespace collapsed to single spaces
+    fn source_text(&self, first: Span, last: Span) -> String {
+        let start = source_offset(self.source, first.line, first.column);
+        let end = source_offset(self.source, last.line, last.column) + self.lexeme(last).len();
+        self.source
+            .get(start..end.max(start))
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    // Source text of a token, which may span several lines
+    fn lexeme(&self, span: Span) -> &str {
+        let rest = &self.source[source_offset(self.source, span.line, span.column)..];
+        let length = rest
+            .char_indices()
+            .nth(span.length)
+            .map_or(rest.len(), |(idx, _)| idx);
+        &rest[..length]
+    }
+
+    fn continue_statement(&mut self, chunk: &mut Chunk, keyword: Span, semicolon: Span) {
 
         let loops = self.loops();
         let last_loop = loops.last();
 
-        if last_loop.is_some() {
-            let loop_data = last_loop.unwrap().clone();
+        if let Some(loop_data) = last_loop {
+            let loop_data = loop_data.clone();
+            self.at(keyword);
             self.emit_pops_on_scope_exit(chunk, loop_data.depth);
-            let jump_distance = (chunk.size() - loop_data.continue_target) as u16;
-            self.emit_instruction(chunk, Instruction::Loop { jump_distance })
+            self.emit_loop(chunk, loop_data.continue_target);
         } else {
-            self.error_at_current("'continue' can only be used in a loop context.");
-            return;
+            self.at(semicolon);
+            self.error("'continue' can only be used in a loop context.");
         }
-        
-        self.consume(TokenType::Semicolon, "Expect ';' after continue.");
     }
 
-    fn expr_statement(&mut self, chunk: &mut Chunk) {
-        self.expression(chunk);
-        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
-        self.emit_instruction(chunk, Instruction::Pop);
+    fn expr_statement(&mut self, chunk: &mut Chunk, expr: &Expr, semicolon: Span) {
+        self.expression(chunk, expr);
+        self.at(semicolon);
+        if self.echo_expressions && self.envs.len() == 1 && self.current_depth() == 0 {
+            self.emit_instruction(chunk, Instruction::Print);
+        } else {
+            self.emit_instruction(chunk, Instruction::Pop);
+        }
     }
 
-    fn expression(&mut self, chunk: &mut Chunk) {
-        self.parse_precedence(Precedence::Assignment, chunk);
-    }
+    // Infix expressions nest in their left operands, e.g. long sums. The chain
+    // of left operands is compiled in a loop, so that its length is not limited
+    // by the native stack. Instructions are attributed to the last token that
+    // the parser had consumed when it recognized them.
+    fn expression(&mut self, chunk: &mut Chunk, expr: &Expr) {
 
-    fn number(&self, chunk: &mut Chunk, _can_assign: bool) {
-        if let Some(token) = &self.previous {
-            let x = token.get_lexeme().parse::<f64>().unwrap();
-            let value = Value::Number(x);
-            let value_idx = chunk.add_value(value);
-            self.emit_constant(chunk, value_idx);
-        } 
-    }
+        let mut infixes = vec![];
+        let mut operand = expr;
+        loop {
+            match operand {
+                Expr::Binary { left, .. } | Expr::Logical { left, .. } => {
+                    infixes.push(operand);
+                    operand = left;
+                },
+                Expr::Call { callee, .. } => {
+                    infixes.push(operand);
+                    operand = callee;
+                },
+                _ => break,
+            }
+        }
 
-    fn literal(&self, chunk: &mut Chunk, _can_assign: bool) {
-        if let Some(token) = &self.previous {
-            match token.get_token_type() {
-                TokenType::Nil => self.emit_instruction(chunk, Instruction::Nil),
-                TokenType::True => self.emit_instruction(chunk, Instruction::True),
-                TokenType::False => self.emit_instruction(chunk, Instruction::False),
+        let start = chunk.size();
+        self.operand(chunk, operand);
+
+        for infix in infixes.into_iter().rev() {
+            match infix {
+                Expr::Binary { operator, right, .. } => self.binary(chunk, start, *operator, right),
+                Expr::Logical { operator: LogicalOperator::And, operator_span, right, .. } =>
+                    self.and(chunk, *operator_span, right),
+                Expr::Logical { operator: LogicalOperator::Or, operator_span, right, .. } =>
+                    self.or(chunk, *operator_span, right),
+                Expr::Call { args, right_paren, .. } => self.call(chunk, args, *right_paren),
                 _ => (),
             }
         }
+
+        self.at(expr.last_span());
     }
 
-    fn string(&self, chunk: &mut Chunk, _can_assign: bool) {
-        if let Some(token) = &self.previous {
-            let s = token.get_lexeme();
-            let s = s[1..(s.len()-1)].to_string();
-            let s_ref = HeapManager::malloc(&self.heap_manager, s);
-            let value = Value::Str(s_ref);
-            let value_idx = chunk.add_value(value);
-            self.emit_constant(chunk, value_idx);
+    fn operand(&mut self, chunk: &mut Chunk, expr: &Expr) {
+        match expr {
+            Expr::Number { value, span } => {
+                self.at(*span);
+                self.emit_constant_value(chunk, Value::Number(*value));
+            },
+            Expr::Str { value, span } => {
+                self.at(*span);
+                self.string(chunk, value);
+            },
+            Expr::Bool { value, span } => {
+                self.at(*span);
+                self.emit_constant_value(chunk, Value::Bool(*value));
+            },
+            Expr::Nil { span } => {
+                self.at(*span);
+                self.emit_constant_value(chunk, Value::Nil);
+            },
+            Expr::Variable(name) => self.variable(chunk, name, None),
+            Expr::Assign { target, value } => self.variable(chunk, target, Some(value)),
+            Expr::Unary { operator, operand, .. } => self.unary(chunk, *operator, operand),
+            Expr::Grouping { expr, .. } => self.expression(chunk, expr),
+            Expr::Invalid { .. } => (), // the parser has reported the error
+            Expr::Binary { .. } | Expr::Logical { .. } | Expr::Call { .. } => self.expression(chunk, expr),
         }
     }
 
-    fn variable(&mut self, chunk: &mut Chunk, can_assign: bool) {
-        if let Some(token) = &self.previous {
+    fn string(&mut self, chunk: &mut Chunk, value: &str) {
+        match self.heap.intern(value) {
+            Ok(s_ref) => self.emit_constant_value(chunk, Value::Str(s_ref)),
+            Err(error) => self.error(&error.to_string()),
+        }
+    }
 
-            let mut local_idx: Option<u32> = None;
-            let mut global_idx: Option<u32> = None;
+    fn variable(&mut self, chunk: &mut Chunk, name: &Identifier, value: Option<&Expr>) {
 
-            if let Some(idx) = self.resolve_local_idx(token) {
-                local_idx = Some(idx as u32);
-            } else {
-                let s = token.get_lexeme().to_string();
-                let s_ref = HeapManager::malloc(&self.heap_manager, s);
-                let value = Value::Str(s_ref);
-                global_idx = Some(chunk.add_value(value) as u32);
-            }
+        self.at(name.span);
+
+        let mut local_idx: Option<u32> = None;
+        let mut global_idx: Option<u32> = None;
+
+        if let Some(idx) = self.resolve_local_idx(&name.name) {
+            local_idx = Some(idx as u32);
+            let symbol_idx = self.locals()[idx].symbol_idx;
+            self.index.add_reference(&name.name, name.span, symbol_idx);
+        } else {
+            global_idx = Some(self.resolve_global_idx(&name.name, chunk));
+            self.index.add_reference(&name.name, name.span, None);
+        }
 
-            if !can_assign || !self.is_match(TokenType::Equal) {
-                if local_idx.is_some() {
-                    self.emit_instruction(chunk, 
-                        Instruction::GetLocal {local_idx: local_idx.unwrap() });
+        match value {
+            None => {
+                if let Some(local_idx) = local_idx {
+                    self.emit_instruction(chunk,
+                        Instruction::GetLocal { local_idx });
                 } else {
-                    self.emit_instruction(chunk, 
+                    self.emit_instruction(chunk,
                         Instruction::GetGlobal {global_idx: global_idx.unwrap()});
                 }
-            } else {
-                self.expression(chunk);
-                if local_idx.is_some() {
-                    self.emit_instruction(chunk, 
-                        Instruction::SetLocal {local_idx: local_idx.unwrap() });
+            },
+            Some(value) => {
+                self.expression(chunk, value);
+                if let Some(local_idx) = local_idx {
+                    self.emit_instruction(chunk,
+                        Instruction::SetLocal { local_idx });
                 } else {
-                    self.emit_instruction(chunk, 
+                    self.emit_instruction(chunk,
                         Instruction::SetGlobal {global_idx: global_idx.unwrap()});
                 }
-            }
+            },
         }
     }
 
-    fn grouping(&mut self, chunk: &mut Chunk, _can_assign: bool) {
-        self.expression(chunk);
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    fn and(&mut self, chunk: &mut Chunk, operator_span: Span, right: &Expr) {
+
+        self.at(operator_span);
+        let jump_if_false = chunk.size();
+        self.emit_jump_if_false(chunk);
+        self.emit_instruction(chunk, Instruction::Pop);
+        self.expression(chunk, right);
+        let end = chunk.size();
+        self.update_forward_jump(chunk, jump_if_false, end);
+
+    }
+
+    fn or(&mut self, chunk: &mut Chunk, operator_span: Span, right: &Expr) {
+
+        self.at(operator_span);
+        let jump_if_false = chunk.size();
+        self.emit_jump_if_false(chunk);
+        let jump = chunk.size();
+        self.emit_jump(chunk);
+        let pop = chunk.size();
+        self.emit_instruction(chunk, Instruction::Pop);
+        self.expression(chunk, right);
+        let end = chunk.size();
+        self.update_forward_jump(chunk, jump_if_false, pop);
+        self.update_forward_jump(chunk, jump, end);
+
     }
 
-    fn binary(&mut self, chunk: &mut Chunk, _can_assign: bool) {
-        let operator_type = self.previous.as_ref().unwrap().get_token_type();
-        let next_prec = self.parse_rules
-            .get_parse_rule(&operator_type)
-            .precedence.clone()
-            .increment();
-        
-        // parse right hand side
-        self.parse_precedence(next_prec, chunk);
+    // The left operand starts at lhs_start and has been compiled
+    fn binary(&mut self, chunk: &mut Chunk, lhs_start: usize, operator: BinaryOperator, right: &Expr) {
+
+        let lhs = self.constant_operand(chunk, lhs_start);
 
-        match operator_type {
-            TokenType::Plus => self.emit_instruction(chunk, Instruction::Add),
-            TokenType::Minus => self.emit_instruction(chunk, Instruction::Subtract),
-            TokenType::Star => self.emit_instruction(chunk, Instruction::Multiply),
-            TokenType::Slash => self.emit_instruction(chunk, Instruction::Divide),
-            TokenType::BangEqual => {
+        let rhs_start = chunk.size();
+        self.expression(chunk, right);
+
+        if let (Some(a), Some(b)) = (lhs, self.constant_operand(chunk, rhs_start)) {
+            if let Some(value) = self.fold_binary(operator, &a.value, &b.value) {
+                self.remove_constant(chunk, a);
+                self.emit_constant_value(chunk, value);
+                return;
+            }
+        }
+
+        match operator {
+            BinaryOperator::Add => self.emit_instruction(chunk, Instruction::Add),
+            BinaryOperator::Subtract => self.emit_instruction(chunk, Instruction::Subtract),
+            BinaryOperator::Multiply => self.emit_instruction(chunk, Instruction::Multiply),
+            BinaryOperator::Divide => self.emit_instruction(chunk, Instruction::Divide),
+            BinaryOperator::NotEqual => {
                 self.emit_instruction(chunk, Instruction::Equal);
                 self.emit_instruction(chunk, Instruction::Not);
             },
-            TokenType::EqualEqual => self.emit_instruction(chunk, Instruction::Equal),
-            TokenType::Greater => self.emit_instruction(chunk, Instruction::Greater),
-            TokenType::GreaterEqual => {
+            BinaryOperator::Equal => self.emit_instruction(chunk, Instruction::Equal),
+            BinaryOperator::Greater => self.emit_instruction(chunk, Instruction::Greater),
+            BinaryOperator::GreaterEqual => {
                 self.emit_instruction(chunk, Instruction::Less);
                 self.emit_instruction(chunk, Instruction::Not);
             },
-            TokenType::Less => self.emit_instruction(chunk, Instruction::Less),
-            TokenType::LessEqual => {
+            BinaryOperator::Less => self.emit_instruction(chunk, Instruction::Less),
+            BinaryOperator::LessEqual => {
                 self.emit_instruction(chunk, Instruction::Greater);
                 self.emit_instruction(chunk, Instruction::Not);
             },
-            _ => (),
         }
     }
 
-    fn unary(&mut self, chunk: &mut Chunk, _can_assign: bool) {
-        let token_type = &self.previous
-            .as_ref()
-            .unwrap()
-            .get_token_type();
+    fn unary(&mut self, chunk: &mut Chunk, operator: UnaryOperator, operand: &Expr) {
 
-        self.parse_precedence(Precedence::Unary, chunk);
+        let operand_start = chunk.size();
+        self.expression(chunk, operand);
 
-        match token_type {
-            TokenType::Minus => self.emit_instruction(chunk, Instruction::Negate),
-            TokenType::Bang => self.emit_instruction(chunk, Instruction::Not),
-            _ => ()
+        if let Some(constant) = self.constant_operand(chunk, operand_start) {
+            let folded = match (operator, &constant.value) {
+                (UnaryOperator::Negate, Value::Number(x)) => Some(Value::Number(-x)),
+                (UnaryOperator::Not, value) => Some(Value::Bool(value.is_falsey())),
+                _ => None, // ill-typed operands are left to the VM to report
+            };
+            if let Some(value) = folded {
+                self.remove_constant(chunk, constant);
+                self.emit_constant_value(chunk, value);
+                return;
+            }
         }
-    } 
 
-    fn call(&mut self, chunk: &mut Chunk, _can_assign: bool) {
-        let num_args = self.arguments(chunk);
-        self.emit_instruction(chunk, Instruction::Call{ num_args });
+        match operator {
+            UnaryOperator::Negate => self.emit_instruction(chunk, Instruction::Negate),
+            UnaryOperator::Not => self.emit_instruction(chunk, Instruction::Not),
+        }
     }
 
-    fn arguments(&mut self, chunk: &mut Chunk) -> u8 {
-        let mut num_args = 0;
-        if !self.check(TokenType::RightParen) {
-            loop {
-                if num_args == u8::MAX {
-                    self.error(&format!("Can't have more than {num_args} arguments."));
-                    return 0;
-                }
-
-                self.expression(chunk);
-                num_args += 1;
-
-                if !self.is_match(TokenType::Comma) {
-                    break;
-                }
-            }
+    fn constant_operand(&self, chunk: &Chunk, start: usize) -> Option<ConstantOperand> {
+        match self.last_constant {
+            Some(constant) if constant.start == start && constant.end == chunk.size() =>
+                Some(constant),
+            _ => None,
         }
-
-        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
-
-        num_args
     }
 
-    fn parse_precedence(&mut self, prec: Precedence, chunk: &mut Chunk) {
-        self.advance();
-        let token_type = &self.previous.as_ref().unwrap().get_token_type();
-        let prefix_opt = self.parse_rules.get_parse_rule(token_type).prefix;
+    // Removes the code from the start of the constant on, which only loads
+    // operands, together with the pool entries added for them
+    fn remove_constant(&mut self, chunk: &mut Chunk, constant: ConstantOperand) {
+        chunk.truncate(constant.start);
+        chunk.truncate_values(constant.values_len);
+        self.last_constant = None;
+    }
 
-        if prefix_opt.is_none() {
-            self.error("Expect expression.");
-            return;
+    fn fold_binary(&mut self, operator: BinaryOperator, a: &Value, b: &Value) -> Option<Value> {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) =>
+                Self::fold_numbers(operator, *x, *y),
+            // Without room on the heap the VM reports the error when it concatenates
+            (Value::Str(s1), Value::Str(s2)) if operator == BinaryOperator::Add =>
+                s1.concat(s2, self.heap).ok().map(Value::Str),
+            _ => match operator {
+                BinaryOperator::Equal => Some(Value::Bool(a == b)),
+                BinaryOperator::NotEqual => Some(Value::Bool(a != b)),
+                _ => None, // ill-typed operands are left to the VM to report
+            },
         }
+    }
 
-        let prefix = prefix_opt.unwrap();
-        let can_assign = prec <= Precedence::Assignment;
-        prefix(self, chunk, can_assign);
-
-        while let Some(token) = &self.current {
-            let token_type = token.get_token_type();
-            let curr_prec = self.parse_rules
-                .get_parse_rule(&token_type)
-                .precedence;
-
-            if curr_prec < prec {
-                break;
-            }
-
-            self.advance();
-
-            let infix = self.parse_rules
-                .get_parse_rule(&token_type)
-                .infix
-                .unwrap();
-
-            infix(self, chunk, can_assign);
-        }
+    fn fold_numbers(operator: BinaryOperator, x: f64, y: f64) -> Option<Value> {
+        // '>=' and '<=' are compiled as negated '<' and '>',
+        // so NaN operands have to yield true here as well
+        let ordering = x.partial_cmp(&y);
+        let value = match operator {
+            BinaryOperator::Add => Value::Number(x + y),
+            BinaryOperator::Subtract => Value::Number(x - y),
+            BinaryOperator::Multiply => Value::Number(x * y),
+            BinaryOperator::Divide => Value::Number(x / y),
+            BinaryOperator::Equal => Value::Bool(x == y),
+            BinaryOperator::NotEqual => Value::Bool(x != y),
+            BinaryOperator::Greater => Value::Bool(x > y),
+            BinaryOperator::GreaterEqual => Value::Bool(ordering != Some(Ordering::Less)),
+            BinaryOperator::Less => Value::Bool(x < y),
+            BinaryOperator::LessEqual => Value::Bool(ordering != Some(Ordering::Greater)),
+        };
+        Some(value)
+    }
 
-        if can_assign && self.is_match(TokenType::Equal) {
-            self.error("Invalid assignment target.");
+    fn call(&mut self, chunk: &mut Chunk, args: &[Expr], right_paren: Span) {
+        for arg in args {
+            self.expression(chunk, arg);
         }
-
+        self.at(right_paren);
+        self.emit_instruction(chunk, Instruction::Call{ num_args: args.len() as u8 });
     }
 
-    fn end_compiler(&self, chunk: &mut Chunk) {
-        self.emit_return(chunk);
+    fn at(&mut self, span: Span) {
+        self.position = span;
     }
 
     fn emit_return(&self, chunk: &mut Chunk) {
@@ -1052,163 +1015,64 @@ impl <'a> Compiler<'a> {
         self.emit_instruction(chunk, instr);
     }
 
+    fn emit_constant_value(&mut self, chunk: &mut Chunk, value: Value) {
+        let start = chunk.size();
+        let values_len = chunk.num_values();
+        match value {
+            Value::Nil => self.emit_instruction(chunk, Instruction::Nil),
+            Value::Bool(true) => self.emit_instruction(chunk, Instruction::True),
+            Value::Bool(false) => self.emit_instruction(chunk, Instruction::False),
+            _ => {
+                let value_idx = chunk.add_value(value);
+                self.emit_constant(chunk, value_idx);
+            }
+        }
+        self.last_constant = Some(ConstantOperand {
+            start,
+            end: chunk.size(),
+            values_len,
+            value,
+        });
+    }
+
     fn emit_instruction(&self, chunk: &mut Chunk, instr: Instruction) {
-        let line = if let Some(token) = &self.previous {
-            token.get_line()
-        } else {
-            1
-        };
-        chunk.write_instruction(instr, line);
+        chunk.write_instruction(instr, self.position.line);
     }
 
     fn emit_jump(&self, chunk: &mut Chunk) {
         self.emit_instruction(chunk, Instruction::Jump { jump_distance: 0 });
-    } 
+    }
 
     fn emit_jump_if_false(&self, chunk: &mut Chunk) {
         self.emit_instruction(chunk, Instruction::JumpIfFalse { jump_distance: 0 });
-    } 
-
-    fn emit_loop(&self, chunk: &mut Chunk) {
-        self.emit_instruction(chunk, Instruction::Loop { jump_distance: 0 });
-    } 
-
-    fn consume(&mut self, expected_type: TokenType, message: &str) {
-        if let Some(current) = &self.current { 
-            if current.get_token_type() == expected_type {
-                self.advance();
-                return ;
-            }
-        }
-        self.error_at_current(message);
-    }
-
-    fn is_match(&mut self, expected_type: TokenType) -> bool {
-        if self.check(expected_type) {
-            self.advance();
-            true
-        } else {
-            false
-        }
     }
 
-    fn check(&self, expected_type: TokenType) -> bool {
-        if let Some(current) = &self.current {
-            if current.get_token_type() == expected_type {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+    // Jumps back to the start of the loop
+    fn emit_loop(&mut self, chunk: &mut Chunk, loop_start: usize) {
+        match u16::try_from(chunk.size() - loop_start) {
+            Ok(jump_distance) => self.emit_instruction(chunk, Instruction::Loop { jump_distance }),
+            Err(_) => self.error("Loop body too large."),
         }
     }
 
-    fn advance(&mut self) {
-        self.previous = self.current.clone();
-
-        loop {
-            if let Some(next_token) = self.scan_next_token() {
-                self.current = Some(next_token);
-                if self.current.as_ref().unwrap().get_token_type() != TokenType::Error {
-                    break;
-                } else {
-                    self.error_at_current("a scan error occurred");
-                }
-            } else {
-                self.current = None;
-                break;
-            }
-        }
-    }
-
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(&self.current.clone(), message);     
-    }
-
+    // Errors of the compiler are reported at the token it is compiling
     fn error(&mut self, message: &str) {
-        self.error_at(&self.previous.clone(), message);
-    }
-
-    fn error_at(&mut self, token_opt: &Option<Token>, message: &str) {
-
-        if self.panic_mode {
-            return;
-        }
-        self.panic_mode = true;
-
-        if let Some(token) = token_opt {
-            eprint!("[line {}] Error", token.get_line());
-            match token.get_token_type() {
-                TokenType::Eof => eprint!(" at end"),
-                TokenType::Error => (),
-                _ => eprint!(" at '{}'", token.get_lexeme()),
-            }
-            eprintln!(": {}", message);
-        } else {
-            eprintln!("Error: {}", message);
-        }
-        
-        self.had_error = true;
-    }
-
-    fn scan_next_token(&mut self) -> Option<Token> {
-        if self.lookahead.is_empty() {
-            self.scanner.next()
-        } else {
-            Some(self.lookahead.pop_front().unwrap())
-        }
-    }
-
-    fn _peek(&mut self, idx: usize) -> Option<Token> {
-        while idx + 1 > self.lookahead.len() {
-            if let Some(token) = self.scanner.next() {
-                self.lookahead.push_back(token);
-            } else {
-                return None;
-            }
-        }
-        Some(self.lookahead[idx].clone())
+        let location = format!(" at '{}'", self.lexeme(self.position));
+        self.index.diagnostics.push(Diagnostic::new(self.position, &location, message));
     }
 
 }
 
-fn grouping() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.grouping(chunk, can_assign))
+// Byte offset of a position with 1-based line and column (in characters)
+pub(crate) fn source_offset(source: &str, line: i32, column: i32) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take((line - 1).max(0) as usize)
+        .map(str::len)
+        .sum();
+    let rest = &source[line_start..];
+    line_start + rest
+        .char_indices()
+        .nth((column - 1).max(0) as usize)
+        .map_or(rest.len(), |(idx, _)| idx)
 }
-
-fn binary() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.binary(chunk, can_assign))
-}
-
-fn unary() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.unary(chunk, can_assign))
-}
-
-fn number() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.number(chunk, can_assign))
-}
-
-fn literal() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.literal(chunk, can_assign))
-}
-
-fn string() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.string(chunk, can_assign))
-}
-
-fn variable() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.variable(chunk, can_assign))
-}
-
-fn and() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.and(chunk, can_assign))
-}
-
-fn or() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign| comp.or(chunk, can_assign))
-}
-
-fn call() -> Option<ParseFn> {
-    Some(|comp, chunk, can_assign|{ comp.call(chunk, can_assign)})
-}
\ No newline at end of file
@@ -0,0 +1,539 @@
+// Debug Adapter Protocol server, see https://microsoft.github.io/debug-adapter-protocol/
+
+use std::{io::{self, BufRead, Write}, rc::Rc, cell::RefCell, fs, path::Path, collections::BTreeSet};
+use crate::backend::{InterpretResult, observer::{VmObserver, VmState}, heap::HeapManager, globals::GlobalTable, objects::FunData, value::Value};
+use super::{json::{Json, read_message, write_message}, interpreter::Session, compiler::Compiler, debugger::{PauseControl, PauseReason, Mode, Breakpoint}};
+
+const THREAD_ID: i64 = 1; // the VM only has a single thread
+const GLOBALS_REFERENCE: usize = 1; // the locals of frame i have the reference i + 2
+
+// Sends and receives the messages of the protocol
+struct Connection {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    seq: i64,
+}
+
+impl Connection {
+
+    fn send(&mut self, message_type: &str, mut members: Vec<(&str, Json)>) {
+        self.seq += 1;
+        members.insert(0, ("seq", self.seq.into()));
+        members.insert(1, ("type", message_type.into()));
+        if write_message(self.output.as_mut(), &Json::object(members)).is_err() {
+            eprintln!("Could not send message to the client.");
+        }
+    }
+
+    fn send_response(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut members = vec![
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", result.is_ok().into()),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+        match result {
+            Ok(Json::Null) => (),
+            Ok(body) => members.push(("body", body)),
+            Err(message) => members.push(("message", message.into())),
+        }
+        self.send("response", members);
+    }
+
+    fn send_event(&mut self, event: &str, body: Json) {
+        let mut members = vec![("event", event.into())];
+        if body != Json::Null {
+            members.push(("body", body));
+        }
+        self.send("event", members);
+    }
+
+    fn send_output(&mut self, category: &str, output: &str) {
+        self.send_event("output", Json::object(vec![
+            ("category", category.into()),
+            ("output", output.into()),
+        ]));
+    }
+
+}
+
+// Turns the output of print statements into output events, line by line
+struct OutputEvents {
+    connection: Rc<RefCell<Connection>>,
+    buffer: Vec<u8>,
+}
+
+impl Write for OutputEvents {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if let Some(last_newline) = self.buffer.iter().rposition(|b| *b == b'\n') {
+            let lines: Vec<u8> = self.buffer.drain(..=last_newline).collect();
+            self.connection.borrow_mut().send_output("stdout", &String::from_utf8_lossy(&lines));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let rest = std::mem::take(&mut self.buffer);
+            self.connection.borrow_mut().send_output("stdout", &String::from_utf8_lossy(&rest));
+        }
+        Ok(())
+    }
+
+}
+
+impl Drop for OutputEvents {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+struct Launch {
+    source: String,
+    script_args: Vec<String>,
+}
+
+// What happens after a request was handled
+enum Flow {
+    Wait, // for the next request
+    Start, // the program
+    Resume, // the paused program
+    Disconnect,
+}
+
+struct DapServer {
+    connection: Rc<RefCell<Connection>>,
+    control: PauseControl,
+    program: Option<String>, // path of the launched script
+    launch: Option<Launch>, // until the program is started
+    code_lines: Option<BTreeSet<i32>>, // of the launched program
+    line_breakpoints: Vec<(i64, i32)>, // ids and requested lines
+    next_breakpoint_id: i64,
+    configured: bool,
+    stop_on_entry: bool,
+    disconnected: bool,
+}
+
+impl DapServer {
+
+    fn new(connection: &Rc<RefCell<Connection>>) -> DapServer {
+        DapServer {
+            connection: connection.clone(),
+            control: PauseControl::new(Mode::Continue),
+            program: None,
+            launch: None,
+            code_lines: None,
+            line_breakpoints: vec![],
+            next_breakpoint_id: 1,
+            configured: false,
+            stop_on_entry: false,
+            disconnected: false,
+        }
+    }
+
+    // Handles requests until the program is started or resumed,
+    // false after a disconnect or at the end of the input
+    fn message_loop(&mut self, state: Option<&VmState>) -> bool {
+
+        loop {
+            let request = read_message(self.connection.borrow_mut().input.as_mut());
+            let request = match request {
+                Some(Ok(request)) => request,
+                Some(Err(error)) => {
+                    // Answers the unreadable request without its seq and command
+                    self.connection.borrow_mut().send_response(&Json::Null, Err(error));
+                    continue;
+                },
+                None => break,
+            };
+            match self.handle_request(&request, state) {
+                Flow::Wait => (),
+                Flow::Start | Flow::Resume => return true,
+                Flow::Disconnect => break,
+            }
+        }
+
+        self.disconnected = true;
+        false
+    }
+
+    // Requests that inspect or resume the program need the state of the paused VM
+    fn handle_request(&mut self, request: &Json, state: Option<&VmState>) -> Flow {
+
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let null = Json::Null;
+        let args = request.get("arguments").unwrap_or(&null);
+
+        let (result, flow) = match (command, state) {
+            ("initialize", _) => (Ok(capabilities()), Flow::Wait),
+            ("launch", _) => match self.launch(args) {
+                Ok(()) if self.configured => (Ok(Json::Null), Flow::Start),
+                Ok(()) => (Ok(Json::Null), Flow::Wait),
+                Err(message) => (Err(message), Flow::Wait),
+            },
+            ("configurationDone", _) => {
+                self.configured = true;
+                let flow = if self.launch.is_some() { Flow::Start } else { Flow::Wait };
+                (Ok(Json::Null), flow)
+            },
+            ("setBreakpoints", _) => (Ok(self.set_breakpoints(args)), Flow::Wait),
+            ("setFunctionBreakpoints", _) => (Ok(self.set_function_breakpoints(args)), Flow::Wait),
+            ("setExceptionBreakpoints", _) =>
+                (Ok(Json::object(vec![("breakpoints", Json::Array(vec![]))])), Flow::Wait),
+            ("threads", _) => {
+                let thread = Json::object(vec![("id", THREAD_ID.into()), ("name", "main".into())]);
+                (Ok(Json::object(vec![("threads", vec![thread].into())])), Flow::Wait)
+            },
+            ("stackTrace", Some(state)) => (Ok(self.stack_trace(state, args)), Flow::Wait),
+            ("scopes", Some(_)) => (Ok(scopes(args)), Flow::Wait),
+            ("variables", Some(state)) => (Ok(variables(state, args)), Flow::Wait),
+            ("continue", Some(_)) => {
+                self.control.mode = Mode::Continue;
+                (Ok(Json::object(vec![("allThreadsContinued", true.into())])), Flow::Resume)
+            },
+            ("next", Some(state)) => {
+                self.control.mode = Mode::Next { depth: state.depth() };
+                (Ok(Json::Null), Flow::Resume)
+            },
+            ("stepIn", Some(_)) => {
+                self.control.mode = Mode::Step;
+                (Ok(Json::Null), Flow::Resume)
+            },
+            ("stepOut", Some(state)) => {
+                self.control.mode = Mode::Finish { depth: state.depth() };
+                (Ok(Json::Null), Flow::Resume)
+            },
+            ("stackTrace" | "scopes" | "variables" | "continue" | "next" | "stepIn" | "stepOut", None) =>
+                (Err("The program is not paused.".to_string()), Flow::Wait),
+            ("disconnect" | "terminate", _) => (Ok(Json::Null), Flow::Disconnect),
+            _ => (Err(format!("Unsupported request '{}'.", command)), Flow::Wait),
+        };
+
+        let initialized = command == "initialize" && result.is_ok();
+        let mut connection = self.connection.borrow_mut();
+        connection.send_response(request, result);
+        if initialized {
+            connection.send_event("initialized", Json::Null);
+        }
+
+        flow
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+
+        if self.program.is_some() {
+            return Err("A program was already launched.".to_string());
+        }
+        let program = args.get("program")
+            .and_then(Json::as_str)
+            .ok_or_else(|| "Expect 'program' to launch.".to_string())?;
+        let source = fs::read_to_string(program)
+            .map_err(|_| format!("Could not read file {}", program))?;
+        let script_args = args.get("args")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|arg| Some(arg.as_str()?.to_string()))
+            .collect();
+
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+        if self.stop_on_entry {
+            self.control.mode = Mode::Step;
+        }
+        self.program = Some(program.to_string());
+        self.code_lines = Some(code_lines(&source));
+        self.launch = Some(Launch { source, script_args });
+
+        // Breakpoints set before the launch can be verified now
+        for breakpoint in self.update_line_breakpoints() {
+            let body = Json::object(vec![("reason", "changed".into()), ("breakpoint", breakpoint)]);
+            self.connection.borrow_mut().send_event("breakpoint", body);
+        }
+        Ok(())
+    }
+
+    // Replaces the line breakpoints, there is only one source
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+
+        let lines: Vec<i64> = args.get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line")?.as_i64())
+            .collect();
+        self.line_breakpoints = lines
+            .iter()
+            .map(|line| {
+                self.next_breakpoint_id += 1;
+                (self.next_breakpoint_id - 1, *line as i32)
+            })
+            .collect();
+        let breakpoints = self.update_line_breakpoints();
+
+        Json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // Moves the requested lines to the next line with code. Until the
+    // program is launched, its lines are unknown and nothing is verified.
+    fn update_line_breakpoints(&mut self) -> Vec<Json> {
+
+        self.control.breakpoints.retain(|breakpoint| matches!(breakpoint, Breakpoint::Function(_)));
+
+        let mut breakpoints = vec![];
+        for (id, line) in self.line_breakpoints.iter() {
+            let code_line = self.code_lines
+                .as_ref()
+                .and_then(|code_lines| code_lines.range(line..).next());
+            let breakpoint = match code_line {
+                Some(code_line) => {
+                    self.control.breakpoints.push(Breakpoint::Line(*code_line));
+                    vec![("id", (*id).into()), ("verified", true.into()), ("line", (*code_line).into())]
+                },
+                None => {
+                    let message = match self.code_lines {
+                        Some(_) => "No code at or after this line.",
+                        None => "The program has not been launched yet.",
+                    };
+                    vec![("id", (*id).into()), ("verified", false.into()), ("line", (*line).into()), ("message", message.into())]
+                },
+            };
+            breakpoints.push(Json::object(breakpoint));
+        }
+        breakpoints
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Json) -> Json {
+
+        self.control.breakpoints.retain(|breakpoint| matches!(breakpoint, Breakpoint::Line(_)));
+
+        let names: Vec<&str> = args.get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("name")?.as_str())
+            .collect();
+        let breakpoints = names
+            .iter()
+            .map(|name| {
+                self.control.breakpoints.push(Breakpoint::Function(name.to_string()));
+                Json::object(vec![("verified", true.into())])
+            })
+            .collect::<Vec<Json>>();
+
+        Json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // Frame ids count from the innermost frame
+    fn stack_trace(&self, state: &VmState, args: &Json) -> Json {
+
+        let backtrace = state.backtrace();
+        let start = args.get("startFrame").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+        let levels = match args.get("levels").and_then(Json::as_i64) {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => backtrace.len(),
+        };
+
+        let frames: Vec<Json> = backtrace
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(id, (fun_name, line))| Json::object(vec![
+                ("id", id.into()),
+                ("name", if fun_name.is_empty() { "script" } else { fun_name }.into()),
+                ("source", self.source()),
+                ("line", (*line).into()),
+                ("column", 1.into()),
+            ]))
+            .collect();
+
+        Json::object(vec![
+            ("stackFrames", frames.into()),
+            ("totalFrames", backtrace.len().into()),
+        ])
+    }
+
+    fn source(&self) -> Json {
+        let program = self.program.as_deref().unwrap_or_default();
+        let path = fs::canonicalize(program)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| program.to_string());
+        let name = Path::new(program)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Json::object(vec![("name", name.into()), ("path", path.into())])
+    }
+
+    // Informs the client and handles its requests until the program is resumed
+    fn pause(&mut self, state: &VmState, reason: &str, text: Option<&str>) {
+
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.connection.borrow_mut().send_event("stopped", Json::object(body));
+
+        if !self.message_loop(Some(state)) {
+            state.stop();
+        }
+    }
+
+    // The result of the program is only reported to a connected client
+    fn finish(&mut self, result: InterpretResult) {
+
+        if self.disconnected {
+            return;
+        }
+
+        let exit_code = match result {
+            InterpretResult::Ok => 0,
+            InterpretResult::CompileError => 65,
+            InterpretResult::RuntimeError => 70,
+            InterpretResult::Interrupted => 130,
+        };
+        let mut connection = self.connection.borrow_mut();
+        if result == InterpretResult::CompileError {
+            connection.send_output("stderr", "Could not compile the program.\n");
+        }
+        connection.send_event("exited", Json::object(vec![("exitCode", exit_code.into())]));
+        connection.send_event("terminated", Json::Null);
+    }
+
+}
+
+impl VmObserver for DapServer {
+
+    fn on_instruction(&mut self, state: &VmState) {
+        if let Some(reason) = self.control.should_pause(state) {
+            let reason = if std::mem::take(&mut self.stop_on_entry) {
+                "entry"
+            } else {
+                match reason {
+                    PauseReason::Step => "step",
+                    PauseReason::Breakpoint => "breakpoint",
+                }
+            };
+            self.pause(state, reason, None);
+        }
+    }
+
+    fn on_call(&mut self, state: &VmState) {
+        self.control.on_call(state);
+    }
+
+    // Allows to inspect the state that led to the error
+    fn on_error(&mut self, state: &VmState, message: &str) {
+        self.connection.borrow_mut().send_output("stderr", &format!("{}\n", message));
+        self.pause(state, "exception", Some(message));
+    }
+
+}
+
+// Lines with code in the script and all functions in it,
+// none if it does not compile
+fn code_lines(source: &str) -> BTreeSet<i32> {
+
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
+    compiler.set_print_errors(false);
+
+    let mut lines = BTreeSet::new();
+    if let Some(top) = compiler.compile() {
+        add_code_lines(&top, &heap, &mut lines);
+    }
+    lines
+}
+
+fn add_code_lines(fun: &FunData, heap: &HeapManager, lines: &mut BTreeSet<i32>) {
+
+    let chunk = fun.chunk();
+    lines.extend(chunk.code_lines());
+
+    let mut value_idx = 0;
+    while let Some(value) = chunk.read_value(value_idx) {
+        match value {
+            Value::Closure(closure) => add_code_lines(heap.get_content(&heap.get_content(closure).fun), heap, lines),
+            Value::Fun(fun) => add_code_lines(heap.get_content(fun), heap, lines),
+            _ => (),
+        }
+        value_idx += 1;
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsFunctionBreakpoints", true.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+fn scopes(args: &Json) -> Json {
+    let frame_id = args.get("frameId").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+    let scope = |name: &str, reference: usize| Json::object(vec![
+        ("name", name.into()),
+        ("variablesReference", reference.into()),
+        ("expensive", false.into()),
+    ]);
+    Json::object(vec![("scopes", vec![
+        scope("Locals", frame_id + 2),
+        scope("Globals", GLOBALS_REFERENCE),
+    ].into())])
+}
+
+fn variables(state: &VmState, args: &Json) -> Json {
+
+    let reference = args.get("variablesReference").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+    let variables = match reference {
+        0 => vec![],
+        GLOBALS_REFERENCE => state.globals(),
+        _ => state.frame_locals(reference - 2)
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+    };
+
+    let variables: Vec<Json> = variables
+        .iter()
+        .map(|(name, value)| Json::object(vec![
+            ("name", name.as_str().into()),
+            ("value", value.display(state.heap()).to_string().into()),
+            ("variablesReference", 0.into()),
+        ]))
+        .collect();
+
+    Json::object(vec![("variables", variables.into())])
+}
+
+// Serves a single debug session, the program is started after the
+// client has sent both the launch and the configurationDone request
+pub fn serve(input: Box<dyn BufRead>, output: Box<dyn Write>) {
+
+    let connection = Rc::new(RefCell::new(Connection { input, output, seq: 0 }));
+    let server = Rc::new(RefCell::new(DapServer::new(&connection)));
+
+    if !server.borrow_mut().message_loop(None) {
+        return;
+    }
+    let launch = server.borrow_mut().launch.take().unwrap();
+
+    let mut session = Session::new();
+    session.set_script_args(launch.script_args);
+    session.set_output(Box::new(OutputEvents { connection: connection.clone(), buffer: vec![] }));
+    session.add_observer(Box::new(server.clone()));
+    let result = session.interpret(&launch.source);
+    drop(session); // flushes the output
+
+    server.borrow_mut().finish(result);
+
+    // Only disconnecting is left to do
+    if !server.borrow().disconnected {
+        server.borrow_mut().message_loop(None);
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{BufRead, Write};
+use crate::backend::{observer::{VmObserver, VmState}, value::Value};
+
+const HELP: &str = "\
+  break <line>|<fn>   (b) set a breakpoint
+  delete <n>          (d) delete breakpoint n
+  breakpoints         list the breakpoints
+  step                (s) run to the next line, entering calls
+  next                (n) run to the next line, stepping over calls
+  finish              (f) run until the current function returns
+  continue            (c) run to the next breakpoint
+  backtrace           (bt) show the call stack
+  locals              show the local variables
+  globals             show the global variables
+  print <name>        (p) show a variable
+  list                (l) show the source around the current line
+  quit                (q) stop the program";
+
+pub(crate) enum Breakpoint {
+    Line(i32),
+    Function(String),
+}
+
+// When to pause the next time (besides breakpoints)
+pub(crate) enum Mode {
+    Step,
+    Next { depth: usize },
+    Finish { depth: usize },
+    Continue,
+}
+
+pub(crate) enum PauseReason {
+    Step,
+    Breakpoint,
+}
+
+// Decides where the execution pauses, shared by the debugger frontends
+pub(crate) struct PauseControl {
+    pub(crate) breakpoints: Vec<Breakpoint>,
+    pub(crate) mode: Mode,
+    pause_on_entry: bool, // a function breakpoint was hit
+    last_line: i32,
+    last_depth: usize,
+}
+
+impl PauseControl {
+
+    pub(crate) fn new(mode: Mode) -> PauseControl {
+        PauseControl {
+            breakpoints: vec![],
+            mode,
+            pause_on_entry: false,
+            last_line: 0,
+            last_depth: 0,
+        }
+    }
+
+    // To be called before each instruction
+    pub(crate) fn should_pause(&mut self, state: &VmState) -> Option<PauseReason> {
+
+        let line = state.line();
+        let depth = state.depth();
+        let new_line = line != self.last_line || depth != self.last_depth;
+        self.last_line = line;
+        self.last_depth = depth;
+
+        let mode_pause = match self.mode {
+            Mode::Step => new_line,
+            Mode::Next { depth: start_depth } => new_line && depth <= start_depth,
+            Mode::Finish { depth: start_depth } => depth < start_depth,
+            Mode::Continue => false,
+        };
+        let breakpoint_hit = new_line && self.breakpoints
+            .iter()
+            .any(|breakpoint| matches!(breakpoint, Breakpoint::Line(bp_line) if *bp_line == line));
+
+        if breakpoint_hit || std::mem::take(&mut self.pause_on_entry) {
+            Some(PauseReason::Breakpoint)
+        } else if mode_pause {
+            Some(PauseReason::Step)
+        } else {
+            None
+        }
+    }
+
+    // To be called when a function is entered
+    pub(crate) fn on_call(&mut self, state: &VmState) {
+        let fun_name = state.function_name();
+        if self.breakpoints.iter().any(|bp| matches!(bp, Breakpoint::Function(name) if name == fun_name)) {
+            self.pause_on_entry = true;
+        }
+    }
+
+}
+
+// Line-oriented debugger that takes its commands from the input.
+// It pauses before the first line of the program.
+pub struct Debugger {
+    source_lines: Vec<String>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    control: PauseControl,
+}
+
+impl Debugger {
+
+    pub fn new(source: &str, input: Box<dyn BufRead>, output: Box<dyn Write>) -> Debugger {
+        Debugger {
+            source_lines: source.lines().map(|line| line.to_string()).collect(),
+            input,
+            output,
+            control: PauseControl::new(Mode::Step),
+        }
+    }
+
+    fn show_location(&mut self, state: &VmState) {
+        let line = state.line();
+        let _ = writeln!(self.output, "[line {}] in {}", line, state.function_name());
+        self.show_source_line(line, true);
+    }
+
+    fn show_source_line(&mut self, line: i32, current: bool) {
+        if let Some(text) = self.source_lines.get((line - 1) as usize) {
+            let marker = if current { "->" } else { "  " };
+            let _ = writeln!(self.output, "{} {:>4} | {}", marker, line, text);
+        }
+    }
+
+    // Reads commands until one of them resumes the execution
+    fn command_loop(&mut self, state: &VmState) {
+
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    state.stop();
+                    return;
+                },
+                Ok(_) => (),
+            }
+
+            let (command, arg) = match line.trim().split_once(char::is_whitespace) {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (line.trim(), ""),
+            };
+
+            match command {
+                "s" | "step" => {
+                    self.control.mode = Mode::Step;
+                    return;
+                },
+                "n" | "next" => {
+                    self.control.mode = Mode::Next { depth: state.depth() };
+                    return;
+                },
+                "f" | "finish" => {
+                    self.control.mode = Mode::Finish { depth: state.depth() };
+                    return;
+                },
+                "c" | "continue" => {
+                    self.control.mode = Mode::Continue;
+                    return;
+                },
+                "q" | "quit" => {
+                    state.stop();
+                    return;
+                },
+                "b" | "break" => self.add_breakpoint(arg),
+                "d" | "delete" => self.delete_breakpoint(arg),
+                "breakpoints" => self.list_breakpoints(),
+                "bt" | "backtrace" => {
+                    for (i, (fun_name, line)) in state.backtrace().iter().enumerate() {
+                        let fun_name = if fun_name.is_empty() { "script" } else { fun_name };
+                        let _ = writeln!(self.output, "#{} {} at line {}", i, fun_name, line);
+                    }
+                },
+                "locals" => {
+                    for (name, value) in state.locals() {
+                        self.show_variable(state, name, &value);
+                    }
+                },
+                "globals" => {
+                    for (name, value) in state.globals() {
+                        self.show_variable(state, &name, &value);
+                    }
+                },
+                "p" | "print" => self.print_variable(state, arg),
+                "l" | "list" => {
+                    let current = state.line();
+                    for line in (current - 3).max(1)..=(current + 3) {
+                        self.show_source_line(line, line == current);
+                    }
+                },
+                "h" | "help" => {
+                    let _ = writeln!(self.output, "{}", HELP);
+                },
+                "" => (),
+                _ => {
+                    let _ = writeln!(self.output, "Unknown command '{}'. Type help for a list of commands.", command);
+                },
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, arg: &str) {
+        let breakpoint = match arg.parse() {
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) if !arg.is_empty() => Breakpoint::Function(arg.to_string()),
+            Err(_) => {
+                let _ = writeln!(self.output, "Expect line number or function name.");
+                return;
+            }
+        };
+        self.control.breakpoints.push(breakpoint);
+        let _ = writeln!(self.output, "Breakpoint {} set.", self.control.breakpoints.len());
+    }
+
+    fn delete_breakpoint(&mut self, arg: &str) {
+        match arg.parse::<usize>() {
+            Ok(number) if number >= 1 && number <= self.control.breakpoints.len() => {
+                self.control.breakpoints.remove(number - 1);
+            },
+            _ => {
+                let _ = writeln!(self.output, "No breakpoint '{}'.", arg);
+            }
+        }
+    }
+
+    fn list_breakpoints(&mut self) {
+        for (i, breakpoint) in self.control.breakpoints.iter().enumerate() {
+            let _ = match breakpoint {
+                Breakpoint::Line(line) => writeln!(self.output, "{}: line {}", i + 1, line),
+                Breakpoint::Function(name) => writeln!(self.output, "{}: function {}", i + 1, name),
+            };
+        }
+    }
+
+    // Locals shadow globals
+    fn print_variable(&mut self, state: &VmState, name: &str) {
+        let local = state.locals().into_iter().rev().find(|(local, _)| *local == name);
+        let value = match local {
+            Some((_, value)) => Some(value),
+            None => state.globals()
+                .into_iter()
+                .find(|(global, _)| global == name)
+                .map(|(_, value)| value),
+        };
+        match value {
+            Some(value) => self.show_variable(state, name, &value),
+            None => {
+                let _ = writeln!(self.output, "No variable '{}'.", name);
+            }
+        }
+    }
+
+    fn show_variable(&mut self, state: &VmState, name: &str, value: &Value) {
+        let _ = writeln!(self.output, "{} = {}", name, value.display(state.heap()));
+    }
+
+}
+
+impl VmObserver for Debugger {
+
+    fn on_instruction(&mut self, state: &VmState) {
+        if self.control.should_pause(state).is_some() {
+            self.show_location(state);
+            self.command_loop(state);
+        }
+    }
+
+    fn on_call(&mut self, state: &VmState) {
+        self.control.on_call(state);
+    }
+
+    // Allows to inspect the state that led to the error
+    fn on_error(&mut self, state: &VmState, message: &str) {
+        let _ = writeln!(self.output, "Runtime error: {}", message);
+        self.show_location(state);
+        self.command_loop(state);
+    }
+
+}
@@ -0,0 +1,791 @@
+// Formats Lox sources with four spaces of indentation, braces on the line of
+// their statement, spaces around binary operators and lines that are wrapped
+// when they get longer than MAX_WIDTH. Comments stay in front of or behind
+// the statement they belong to, comments in the middle of a statement move
+// in front of it. Blank lines between statements are kept, but at most one.
+//
+// Only sources that compile are formatted, and the result has to compile to
+// the same bytecode as the original, so formatting never changes what a
+// program does.
+
+use std::{fs, io::{self, Write}};
+use crate::backend::{heap::HeapManager, globals::GlobalTable, chunk::Chunk, instruction::Instruction, objects::FunData, value::Value, util::disassemble_instruction};
+use super::{compiler::Compiler, parser::Parser, scanner::Scanner, interpreter::read_source, token::{Span, TokenType}, ast::{Program, Stmt, Expr, Block, UnaryOperator, BinaryOperator, LogicalOperator}};
+
+const MAX_WIDTH: usize = 100;
+const INDENT_WIDTH: usize = 4;
+
+// Layout of an expression or a statement header, whose lines are only
+// broken where a group does not fit on the line
+enum Doc {
+    Text(String),
+    Line, // a space, or a line break
+    SoftLine, // nothing, or a line break
+    Concat(Vec<Doc>),
+    Group(Vec<Doc>), // all of its lines break, or none
+    Indent(Vec<Doc>), // lines breaking inside are indented one more level
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+// Appends the doc to the output, whose last line has the given length
+fn render(doc: &Doc, indent: usize, mut column: usize, out: &mut String) {
+
+    let mut stack = vec![(indent, Mode::Break, doc)];
+
+    while let Some((indent, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(text) => {
+                out.push_str(text);
+                column = match text.rfind('\n') {
+                    Some(idx) => text[idx + 1..].chars().count(),
+                    None => column + text.chars().count(),
+                };
+            },
+            Doc::Line | Doc::SoftLine if mode == Mode::Flat => {
+                if let Doc::Line = doc {
+                    out.push(' ');
+                    column += 1;
+                }
+            },
+            Doc::Line | Doc::SoftLine => {
+                out.push('\n');
+                out.extend(std::iter::repeat_n(' ', indent));
+                column = indent;
+            },
+            Doc::Concat(docs) =>
+                stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+            Doc::Indent(docs) =>
+                stack.extend(docs.iter().rev().map(|doc| (indent + INDENT_WIDTH, mode, doc))),
+            Doc::Group(docs) => {
+                let width = MAX_WIDTH as isize - column as isize;
+                let mode = if mode == Mode::Flat || fits(width, docs, &stack) { Mode::Flat } else { Mode::Break };
+                stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+            },
+        }
+    }
+}
+
+// Whether the docs fit flat into the width, together with what follows
+// them up to the next line break
+fn fits(mut width: isize, docs: &[Doc], rest: &[(usize, Mode, &Doc)]) -> bool {
+
+    let mut stack: Vec<(Mode, &Doc)> = docs.iter().rev().map(|doc| (Mode::Flat, doc)).collect();
+    let mut rest = rest.iter().rev();
+
+    while width >= 0 {
+        let (mode, doc) = match stack.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some((_, mode, doc)) => (*mode, *doc),
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(text) => match text.find('\n') {
+                Some(idx) => return width >= text[..idx].chars().count() as isize,
+                None => width -= text.chars().count() as isize,
+            },
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => return true,
+            Doc::Line => width -= 1,
+            Doc::SoftLine => (),
+            Doc::Concat(docs) | Doc::Group(docs) | Doc::Indent(docs) =>
+                stack.extend(docs.iter().rev().map(|doc| (mode, doc))),
+        }
+    }
+
+    false
+}
+
+// Length of the doc on a single line, None if it contains a line break
+fn flat_width(doc: &Doc) -> Option<usize> {
+
+    let mut width = 0;
+    let mut stack = vec![doc];
+
+    while let Some(doc) = stack.pop() {
+        match doc {
+            Doc::Text(text) if text.contains('\n') => return None,
+            Doc::Text(text) => width += text.chars().count(),
+            Doc::Line => width += 1,
+            Doc::SoftLine => (),
+            Doc::Concat(docs) | Doc::Group(docs) | Doc::Indent(docs) => stack.extend(docs.iter().rev()),
+        }
+    }
+
+    Some(width)
+}
+
+fn text(text: &str) -> Doc {
+    Doc::Text(text.to_string())
+}
+
+struct Comment {
+    span: Span,
+    text: String,
+    trails: Option<Span>, // token before the comment on the same line
+}
+
+fn is_before(span: Span, other: Span) -> bool {
+    (span.line, span.column) < (other.line, other.column)
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    lines: Vec<&'a str>,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    out: String,
+    trailing_comments: Vec<usize>, // offsets into out where trailing comments start
+    indent: usize,
+    last_line: i32, // source line of what was written last, 0 at the start of a block
+}
+
+impl <'a> Formatter<'a> {
+
+    fn new(source: &'a str) -> Formatter<'a> {
+
+        let mut comments = vec![];
+        let mut previous: Option<Span> = None;
+        for token in Scanner::new_with_comments(source) {
+            if token.get_token_type() == TokenType::Comment {
+                comments.push(Comment {
+                    span: token.span(),
+                    text: token.get_lexeme().trim_end().to_string(),
+                    trails: previous.filter(|span| span.line == token.get_line()),
+                });
+            } else {
+                previous = Some(token.span());
+            }
+        }
+
+        Formatter {
+            source,
+            lines: source.lines().collect(),
+            comments,
+            next_comment: 0,
+            out: String::new(),
+            trailing_comments: vec![],
+            indent: 0,
+            last_line: 0,
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        for stmt in program.statements.iter() {
+            self.statement(stmt);
+        }
+        while self.next_comment < self.comments.len() {
+            self.comment_line();
+        }
+    }
+
+    // Trailing comments of consecutive lines start in the same column
+    fn align_trailing_comments(&self) -> String {
+
+        let mut lines: Vec<(&str, Option<&str>)> = vec![];
+        let mut offsets = self.trailing_comments.iter().peekable();
+        let mut line_start = 0;
+        for line in self.out.split_inclusive('\n') {
+            let line_end = line_start + line.len();
+            match offsets.next_if(|offset| **offset < line_end) {
+                Some(offset) => {
+                    let (code, comment) = line.split_at(offset - line_start);
+                    lines.push((code, Some(comment)));
+                },
+                None => lines.push((line, None)),
+            }
+            line_start = line_end;
+        }
+
+        let mut aligned = String::new();
+        for run in lines.chunk_by(|a, b| a.1.is_some() == b.1.is_some()) {
+            let width = run.iter().map(|(code, _)| code.chars().count()).max().unwrap_or(0);
+            for (code, comment) in run {
+                aligned.push_str(code);
+                if let Some(comment) = comment {
+                    aligned.extend(std::iter::repeat_n(' ', width + 1 - code.chars().count()));
+                    aligned.push_str(comment);
+                }
+            }
+        }
+        aligned
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+
+        self.comment_lines_before(stmt.first_span());
+
+        let open = match stmt {
+            Stmt::Block(block) => {
+                self.begin_line(block.left_brace.line);
+                self.block(block, block.right_brace)
+            },
+            Stmt::Fun(fun) => {
+                self.begin_line(fun.keyword.line);
+                let params = fun.params.iter().map(|param| text(&param.name)).collect();
+                self.write_doc(Doc::Concat(vec![
+                    text(&format!("fun {}(", fun.name.name)),
+                    list(params),
+                    text(") "),
+                ]));
+                self.block(&fun.body, fun.body.right_brace)
+            },
+            Stmt::If { keyword, condition, then_branch, else_keyword, else_branch, .. } => {
+                self.begin_line(keyword.line);
+                self.if_statement(condition, then_branch, else_keyword.zip(else_branch.as_deref()))
+            },
+            Stmt::While { keyword, condition, body, .. } => {
+                self.begin_line(keyword.line);
+                self.write_doc(Doc::Concat(vec![text("while ("), self.expr(condition), text(")")]));
+                self.body(body, body.last_span())
+            },
+            Stmt::For { keyword, initializer, condition, increment, body, .. } => {
+                self.begin_line(keyword.line);
+                let mut header = vec![text("for (")];
+                match initializer {
+                    Some(initializer) => header.push(self.simple_statement(initializer)),
+                    None => header.push(text(";")),
+                }
+                if let Some(condition) = condition {
+                    header.extend([text(" "), self.expr(condition)]);
+                }
+                header.push(text(";"));
+                if let Some(increment) = increment {
+                    header.extend([text(" "), self.expr(increment)]);
+                }
+                header.push(text(")"));
+                self.write_doc(Doc::Concat(header));
+                self.body(body, body.last_span())
+            },
+            Stmt::Switch { keyword, subject, left_brace, cases, default, right_brace } => {
+                self.begin_line(keyword.line);
+                self.write_doc(Doc::Concat(vec![text("switch ("), self.expr(subject), text(") {")]));
+                self.end_line(*left_brace);
+                self.indent += 1;
+                self.last_line = 0;
+                for case in cases.iter() {
+                    self.comment_lines_before(case.colon);
+                    self.begin_line(case.keyword.line);
+                    self.write_doc(Doc::Concat(vec![text("case "), self.expr(&case.value), text(":")]));
+                    self.case_statements(&case.statements, case.colon);
+                }
+                if let Some(default) = default {
+                    self.comment_lines_before(default.colon);
+                    self.begin_line(default.keyword.line);
+                    self.out.push_str("default:");
+                    self.case_statements(&default.statements, default.colon);
+                }
+                self.comment_lines_before(*right_brace);
+                self.indent -= 1;
+                self.last_line = 0;
+                self.begin_line(right_brace.line);
+                self.out.push('}');
+                true
+            },
+            _ => {
+                // Comments inside of simple statements go in front of them
+                self.comment_lines_before(stmt.last_span());
+                self.begin_line(stmt.first_span().line);
+                let doc = self.simple_statement(stmt);
+                self.write_doc(doc);
+                true
+            },
+        };
+
+        if open {
+            self.end_line(stmt.last_span());
+        }
+    }
+
+    // Writes the rest of an if statement after "if" or "else if". Comments
+    // between the then branch and "else" move to the end of the branch.
+    // Returns whether the last line still needs to be ended.
+    fn if_statement(&mut self, condition: &Expr, then_branch: &Stmt, else_part: Option<(Span, &Stmt)>) -> bool {
+
+        self.write_doc(Doc::Concat(vec![text("if ("), self.expr(condition), text(")")]));
+
+        let (else_keyword, else_branch) = match else_part {
+            Some(else_part) => else_part,
+            None => return self.body(then_branch, then_branch.last_span()),
+        };
+
+        if let Stmt::Block(block) = then_branch {
+            self.out.push(' ');
+            self.block(block, else_keyword);
+            self.out.push_str(" else");
+        } else {
+            if self.body(then_branch, then_branch.last_span()) {
+                self.end_line(then_branch.last_span());
+            }
+            self.comment_lines_before(else_keyword);
+            self.last_line = 0;
+            self.begin_line(else_keyword.line);
+            self.out.push_str("else");
+        }
+
+        match else_branch {
+            Stmt::If { condition, then_branch, else_keyword, else_branch, .. } => {
+                self.out.push(' ');
+                self.if_statement(condition, then_branch, else_keyword.zip(else_branch.as_deref()))
+            },
+            _ => self.body(else_branch, else_branch.last_span()),
+        }
+    }
+
+    // Writes the body of an if, else, while or for after its header. Blocks
+    // start on the line of the header, so do short statements if they fit.
+    // Returns whether the last line still needs to be ended.
+    fn body(&mut self, body: &Stmt, close: Span) -> bool {
+
+        if let Stmt::Block(block) = body {
+            self.out.push(' ');
+            return self.block(block, close);
+        }
+
+        if is_simple(body) && !self.has_comment_before(body.last_span()) {
+            let doc = self.simple_statement(body);
+            let fits = flat_width(&doc).is_some_and(|width| self.column() + 1 + width <= MAX_WIDTH);
+            if fits {
+                self.out.push(' ');
+                self.write_doc(doc);
+                return true;
+            }
+        }
+
+        self.out.push('\n');
+        self.indent += 1;
+        self.last_line = 0;
+        self.statement(body);
+        self.indent -= 1;
+        false
+    }
+
+    // Writes the block up to "}", comments in front of close end up inside
+    fn block(&mut self, block: &Block, close: Span) -> bool {
+
+        if block.statements.is_empty() && !self.has_comment_before(close) {
+            self.out.push_str("{}");
+            return true;
+        }
+
+        self.out.push('{');
+        self.end_line(block.left_brace);
+        self.indent += 1;
+        self.last_line = 0;
+        for stmt in block.statements.iter() {
+            self.statement(stmt);
+        }
+        self.comment_lines_before(close);
+        self.indent -= 1;
+        self.last_line = 0;
+        self.begin_line(block.right_brace.line);
+        self.out.push('}');
+        true
+    }
+
+    fn case_statements(&mut self, statements: &[Stmt], colon: Span) {
+        self.end_line(colon);
+        self.indent += 1;
+        self.last_line = 0;
+        for stmt in statements.iter() {
+            self.statement(stmt);
+        }
+        self.indent -= 1;
+    }
+
+    fn simple_statement(&self, stmt: &Stmt) -> Doc {
+        match stmt {
+            Stmt::Print { value, .. } =>
+                Doc::Concat(vec![text("print "), self.expr(value), text(";")]),
+            Stmt::Expression { expr, .. } =>
+                Doc::Concat(vec![self.expr(expr), text(";")]),
+            Stmt::Var { name, initializer: Some(initializer), .. } =>
+                Doc::Concat(vec![text(&format!("var {} = ", name.name)), self.expr(initializer), text(";")]),
+            Stmt::Var { name, initializer: None, .. } =>
+                text(&format!("var {};", name.name)),
+            Stmt::Continue { .. } =>
+                text("continue;"),
+            Stmt::Return { value: Some(value), .. } =>
+                Doc::Concat(vec![text("return "), self.expr(value), text(";")]),
+            Stmt::Return { value: None, .. } =>
+                text("return;"),
+            Stmt::Assert { condition, message: Some(message), .. } =>
+                Doc::Group(vec![
+                    text("assert "),
+                    self.expr(condition),
+                    text(","),
+                    Doc::Indent(vec![Doc::Line, self.expr(message)]),
+                    text(";"),
+                ]),
+            Stmt::Assert { condition, message: None, .. } =>
+                Doc::Concat(vec![text("assert "), self.expr(condition), text(";")]),
+            _ => unreachable!("not a simple statement"),
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> Doc {
+        match expr {
+            Expr::Number { span, .. } |
+            Expr::Str { span, .. } |
+            Expr::Invalid { span } => text(self.lexeme(*span)),
+            Expr::Bool { value, .. } => text(if *value { "true" } else { "false" }),
+            Expr::Nil { .. } => text("nil"),
+            Expr::Variable(name) => text(&name.name),
+            Expr::Assign { target, value } =>
+                Doc::Concat(vec![text(&format!("{} = ", target.name)), self.expr(value)]),
+            Expr::Unary { operator, operand, .. } => {
+                let operator = match operator {
+                    UnaryOperator::Negate => "-",
+                    UnaryOperator::Not => "!",
+                };
+                Doc::Concat(vec![text(operator), self.expr(operand)])
+            },
+            Expr::Binary { .. } |
+            Expr::Logical { .. } => self.operands(expr),
+            Expr::Call { callee, args, .. } => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect();
+                Doc::Concat(vec![self.expr(callee), text("("), list(args), text(")")])
+            },
+            Expr::Grouping { expr, .. } =>
+                Doc::Concat(vec![text("("), self.expr(expr), text(")")]),
+        }
+    }
+
+    // Chains of operators of the same precedence like a + b - c are laid out
+    // together and broken after the operators. The left operands are walked
+    // in a loop, as they can be nested deeply.
+    fn operands(&self, expr: &Expr) -> Doc {
+
+        let precedence = operator(expr).map(|(_, precedence)| precedence);
+
+        let mut right_operands = vec![];
+        let mut left = expr;
+        while let Expr::Binary { left: next, right, .. } | Expr::Logical { left: next, right, .. } = left {
+            match operator(left) {
+                Some((symbol, operator_precedence)) if Some(operator_precedence) == precedence => {
+                    right_operands.push((symbol, right));
+                    left = next;
+                },
+                _ => break,
+            }
+        }
+
+        let mut rest = vec![];
+        for (symbol, right) in right_operands.into_iter().rev() {
+            rest.extend([text(&format!(" {}", symbol)), Doc::Line, self.expr(right)]);
+        }
+
+        Doc::Group(vec![self.expr(left), Doc::Indent(rest)])
+    }
+
+    fn lexeme(&self, span: Span) -> &str {
+        let rest = &self.source[super::compiler::source_offset(self.source, span.line, span.column)..];
+        let length = rest
+            .char_indices()
+            .nth(span.length)
+            .map_or(rest.len(), |(idx, _)| idx);
+        &rest[..length]
+    }
+
+    fn write_doc(&mut self, doc: Doc) {
+        let column = self.column();
+        render(&doc, self.indent * INDENT_WIDTH, column, &mut self.out);
+    }
+
+    fn column(&self) -> usize {
+        let line_start = self.out.rfind('\n').map_or(0, |idx| idx + 1);
+        self.out[line_start..].chars().count()
+    }
+
+    // Starts a line for what starts on the source line, keeping a blank
+    // line in front of it
+    fn begin_line(&mut self, line: i32) {
+        if self.last_line > 0 && (self.last_line + 1..line).any(|line| self.is_blank(line)) {
+            self.out.push('\n');
+        }
+        self.out.extend(std::iter::repeat_n(' ', self.indent * INDENT_WIDTH));
+        self.last_line = line;
+    }
+
+    // Ends the line after the token, with the comment that follows it
+    fn end_line(&mut self, token: Span) {
+        if let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.trails == Some(token) {
+                self.trailing_comments.push(self.out.len());
+                self.out.push_str(&comment.text);
+                self.next_comment += 1;
+            }
+        }
+        self.out.push('\n');
+        self.last_line = self.last_line.max(token.line);
+    }
+
+    fn is_blank(&self, line: i32) -> bool {
+        self.lines.get(line as usize - 1).is_some_and(|text| text.trim().is_empty())
+    }
+
+    fn has_comment_before(&self, span: Span) -> bool {
+        self.comments
+            .get(self.next_comment)
+            .is_some_and(|comment| is_before(comment.span, span))
+    }
+
+    fn comment_lines_before(&mut self, span: Span) {
+        while self.has_comment_before(span) {
+            self.comment_line();
+        }
+    }
+
+    fn comment_line(&mut self) {
+        let comment = &self.comments[self.next_comment];
+        let (line, text) = (comment.span.line, comment.text.clone());
+        self.begin_line(line);
+        self.out.push_str(&text);
+        self.out.push('\n');
+        self.next_comment += 1;
+    }
+
+}
+
+// Comma separated items in parentheses, one per line if they do not fit
+fn list(items: Vec<Doc>) -> Doc {
+
+    if items.is_empty() {
+        return Doc::Concat(vec![]);
+    }
+
+    let mut inner = vec![Doc::SoftLine];
+    for (idx, item) in items.into_iter().enumerate() {
+        if idx > 0 {
+            inner.extend([text(","), Doc::Line]);
+        }
+        inner.push(item);
+    }
+
+    Doc::Group(vec![Doc::Indent(inner), Doc::SoftLine])
+}
+
+fn is_simple(stmt: &Stmt) -> bool {
+    matches!(stmt,
+        Stmt::Print { .. } | Stmt::Expression { .. } | Stmt::Var { .. } |
+        Stmt::Continue { .. } | Stmt::Return { .. } | Stmt::Assert { .. })
+}
+
+// Symbol and precedence of binary and logical operators
+fn operator(expr: &Expr) -> Option<(&'static str, u8)> {
+    match expr {
+        Expr::Logical { operator, .. } => Some(match operator {
+            LogicalOperator::Or => ("or", 1),
+            LogicalOperator::And => ("and", 2),
+        }),
+        Expr::Binary { operator, .. } => Some(match operator {
+            BinaryOperator::Equal => ("==", 3),
+            BinaryOperator::NotEqual => ("!=", 3),
+            BinaryOperator::Greater => (">", 4),
+            BinaryOperator::GreaterEqual => (">=", 4),
+            BinaryOperator::Less => ("<", 4),
+            BinaryOperator::LessEqual => ("<=", 4),
+            BinaryOperator::Add => ("+", 5),
+            BinaryOperator::Subtract => ("-", 5),
+            BinaryOperator::Multiply => ("*", 6),
+            BinaryOperator::Divide => ("/", 6),
+        }),
+        _ => None,
+    }
+}
+
+// Formats the source, fails with the errors of the compiler if it does not
+// compile. Refuses to return a formatted source that compiles differently.
+pub fn format_source(source: &str) -> Result<String, Vec<String>> {
+
+    let before = bytecode(source)?;
+
+    let program = Parser::new(source).parse();
+    let mut formatter = Formatter::new(source);
+    formatter.program(&program);
+    let formatted = formatter.align_trailing_comments();
+
+    match bytecode(&formatted) {
+        Ok(after) if after == before => Ok(formatted),
+        _ => Err(vec!["Formatting would change the meaning of the program.".to_string()]),
+    }
+}
+
+// Instructions of the script and its functions without their lines
+fn bytecode(source: &str) -> Result<Vec<String>, Vec<String>> {
+
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
+    compiler.set_print_errors(false);
+    let top = compiler.compile();
+    let index = compiler.take_source_index();
+
+    match top {
+        Some(top) => {
+            let mut instructions = vec![];
+            list_instructions(top.chunk(), &heap, &mut instructions);
+            Ok(instructions)
+        },
+        None => Err(index.diagnostics.into_iter().map(|diagnostic| diagnostic.report).collect()),
+    }
+}
+
+fn list_instructions(chunk: &Chunk, heap: &HeapManager, instructions: &mut Vec<String>) {
+
+    for (instr, _) in chunk.instruction_iter() {
+        instructions.push(match instr {
+            // The condition is shown as written, which may differ in spacing
+            Instruction::AssertFailed { value_idx } => {
+                let condition = chunk.read_value(value_idx as usize).unwrap().display(heap).to_string();
+                let tokens: Vec<String> = Scanner::new(&condition)
+                    .map(|token| token.get_lexeme().to_string())
+                    .collect();
+                format!("OP_ASSERT_FAILED {}", tokens.join(" "))
+            },
+            _ => disassemble_instruction(chunk, &instr, heap),
+        });
+    }
+
+    let mut value_idx = 0;
+    while let Some(value) = chunk.read_value(value_idx) {
+        let fun_opt: Option<&FunData> = match value {
+            Value::Closure(closure) => Some(heap.get_content(&heap.get_content(closure).fun)),
+            Value::Fun(fun) => Some(heap.get_content(fun)),
+            _ => None,
+        };
+        if let Some(fun) = fun_opt {
+            instructions.push(format!("== {} ==", fun));
+            list_instructions(fun.chunk(), heap, instructions);
+        }
+        value_idx += 1;
+    }
+}
+
+// Formats the files in place, or with check only lists the files that are
+// not formatted. The path "-" formats the standard input to the standard
+// output.
+pub fn format_files(file_paths: &[String], check: bool) -> Result<(), i32> {
+
+    let mut num_failed = 0;
+    let mut num_unformatted = 0;
+
+    for file_path in file_paths.iter() {
+        let source = read_source(file_path)?;
+        let formatted = match format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(messages) => {
+                num_failed += 1;
+                eprintln!("Could not format {}:", file_path);
+                for message in messages {
+                    eprintln!("{}", message);
+                }
+                continue;
+            }
+        };
+
+        if formatted == source {
+            if !check && file_path == "-" {
+                print!("{}", formatted);
+            }
+            continue;
+        }
+
+        if check {
+            num_unformatted += 1;
+            println!("{}", file_path);
+        } else if file_path == "-" {
+            print!("{}", formatted);
+        } else if fs::write(file_path, formatted).is_err() {
+            eprintln!("Could not write file {}", file_path);
+            return Err(74);
+        }
+    }
+
+    let _ = io::stdout().flush();
+
+    if num_failed > 0 {
+        Err(65)
+    } else if num_unformatted > 0 {
+        Err(1)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::format_source;
+
+    #[test]
+    fn format_layout() {
+
+        let source = "fun add(a,b){return a+b;}\nvar x=add(1,2);if(x>2)print x;else{print -x;}\nfor(var i=0;i<2;i=i+1){}\nswitch(x){case 3:print \"three\";default:print nil;}";
+
+        assert_eq!(format_source(source).unwrap(), "\
+fun add(a, b) {
+    return a + b;
+}
+var x = add(1, 2);
+if (x > 2) print x;
+else {
+    print -x;
+}
+for (var i = 0; i < 2; i = i + 1) {}
+switch (x) {
+    case 3:
+        print \"three\";
+    default:
+        print nil;
+}
+");
+    }
+
+    #[test]
+    fn keep_comments_and_blank_lines() {
+
+        let source = "#!/usr/bin/env rlox\n// sum\nvar a = 1; // one\nvar bc = 2;    // two\n\n\n\nprint a +\n// inside\nbc;\n{ // block\n}\n// end\n";
+
+        assert_eq!(format_source(source).unwrap(), "\
+#!/usr/bin/env rlox
+// sum
+var a = 1;  // one
+var bc = 2; // two
+
+// inside
+print a + bc;
+{ // block
+}
+// end
+");
+    }
+
+    #[test]
+    fn wrap_long_lines() {
+
+        let args: Vec<String> = (0..12).map(|idx| format!("argument{}", idx)).collect();
+        let source = format!("fun f({}) {{ print {}; }}", args.join(", "), args.join(" + "));
+
+        assert_eq!(format_source(&source).unwrap(), format!("\
+fun f(
+    {}
+) {{
+    print {};
+}}
+", args.join(",\n    "), args.join(" +\n        ")));
+    }
+
+    #[test]
+    fn refuse_sources_with_errors() {
+        assert_eq!(format_source("print 1").unwrap_err(), vec!["[line 1] Error at end: Expect ';' after value.".to_string()]);
+        assert_eq!(
+            format_source("assert 1 > // why\n 0;").unwrap_err(),
+            vec!["Formatting would change the meaning of the program.".to_string()]);
+    }
+
+}
@@ -0,0 +1,152 @@
+use std::{env, fs::{self, OpenOptions}, io::Write, path::PathBuf};
+
+const MAX_ENTRIES: usize = 1000;
+
+// Input history of the REPL. Entries are numbered starting with 1
+// and are appended to the history file (if any) as they are added.
+pub struct History {
+    entries: Vec<String>,
+    file_path: Option<PathBuf>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+
+    pub fn new() -> History {
+        History {
+            entries: vec![],
+            file_path: None,
+        }
+    }
+
+    // Loads the history from the file, which is created on the first
+    // added entry if it does not exist yet
+    pub fn new_with_file(file_path: PathBuf) -> History {
+
+        let mut entries: Vec<String> = fs::read_to_string(&file_path)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(unescape)
+            .collect();
+
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..(entries.len() - MAX_ENTRIES));
+            let content: String = entries
+                .iter()
+                .map(|entry| escape(entry) + "\n")
+                .collect();
+            let _ = fs::write(&file_path, content);
+        }
+
+        History {
+            entries,
+            file_path: Some(file_path),
+        }
+    }
+
+    // $RLOX_HISTORY or ~/.rlox_history
+    pub fn default_file_path() -> Option<PathBuf> {
+        if let Ok(file_path) = env::var("RLOX_HISTORY") {
+            return Some(PathBuf::from(file_path));
+        }
+        env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".rlox_history"))
+    }
+
+    pub fn add(&mut self, entry: &str) {
+
+        let entry = entry.trim_end();
+        if entry.trim().is_empty() || self.last() == Some(entry) {
+            return;
+        }
+
+        self.entries.push(entry.to_string());
+
+        if let Some(file_path) = &self.file_path {
+            // History is a convenience, so failing to save it is not an error
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(file_path) {
+                let _ = writeln!(file, "{}", escape(entry));
+            }
+        }
+    }
+
+    pub fn get(&self, number: usize) -> Option<&str> {
+        if number == 0 {
+            return None;
+        }
+        self.entries.get(number - 1).map(|entry| entry.as_str())
+    }
+
+    pub fn last(&self) -> Option<&str> {
+        self.entries.last().map(|entry| entry.as_str())
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+}
+
+// Multi-line entries are stored on a single line of the history file
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            match chars.next() {
+                Some('n') => entry.push('\n'),
+                Some(other) => entry.push(other),
+                None => entry.push('\\'),
+            }
+        } else {
+            entry.push(ch);
+        }
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use super::{History, escape, unescape};
+
+    #[test]
+    fn escape_multi_line_entries() {
+
+        let entry = "fun f() {\n    print \"a\\\\b\";\n}";
+        assert_eq!(escape(entry).lines().count(), 1);
+        assert_eq!(unescape(&escape(entry)), entry);
+    }
+
+    #[test]
+    fn persist_history() {
+
+        let file_path = env::temp_dir().join(format!("rlox_history_test_{}", process::id()));
+        let _ = fs::remove_file(&file_path);
+
+        let mut history = History::new_with_file(file_path.clone());
+        history.add("var x = 1;\n");
+        history.add("var x = 1;");
+        history.add("{\n  print x;\n}");
+        assert_eq!(history.entries().len(), 2);
+
+        let history = History::new_with_file(file_path.clone());
+        assert_eq!(history.get(1), Some("var x = 1;"));
+        assert_eq!(history.last(), Some("{\n  print x;\n}"));
+        assert_eq!(history.get(0), None);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+}
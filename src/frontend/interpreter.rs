@@ -1,27 +1,430 @@
-use std::{io::{self, Read}, path::Path, fs::File, cell::RefCell, rc::Rc};
-use crate::{backend::{InterpretResult, heap::{HeapManager, HeapRef}, vm::{VM, CallFrame}, objects::{NativeFn, NativeFunData}, native}, frontend::compiler::Compiler};
+use std::{io::{self, Read, Write}, path::Path, fs::File, rc::Rc, cell::RefCell, time::Instant};
+#[cfg(unix)]
+use std::{sync::OnceLock, os::raw::{c_int, c_void}};
+use crate::{backend::{InterpretResult, vm::{VM, VmConfig, RuntimeError}, value::Value, chunk::Chunk, heap::HeapManager, objects::{NativeFn, NativeFunData, FunData}, native, globals::GlobalTable, util::disassemble, observer::VmObserver, interrupt::InterruptHandle}, frontend::compiler::Compiler};
+use super::{history::History, scanner::Scanner, token::TokenType, symbols::Diagnostic};
 
-pub fn repl() {
+// Interpreter state that survives between calls of interpret, 
+// so that each source snippet sees the globals of the previous ones
+pub struct Session {
+    globals: Rc<RefCell<GlobalTable>>,
+    vm: VM,
+    echo_expressions: bool,
+    print_errors: bool,
+    compile_errors: Vec<Diagnostic>, // of the last interpreted source
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Collects the output written to it instead of printing it. Clones share
+// the text, so a clone can be passed to set_output and the text read afterwards.
+#[derive(Clone, Default)]
+pub struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl CapturedOutput {
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).to_string()
+    }
+
+}
+
+impl Write for CapturedOutput {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+}
+
+impl Session {
+
+    pub fn new() -> Session {
+        let globals = GlobalTable::new_rc_refcell();
+        let mut vm = VM::new(&globals);
+        set_native_functions(&mut vm);
+        Session { globals, vm, echo_expressions: false, print_errors: true, compile_errors: vec![] }
+    }
+
+    // Discards all globals and heap objects
+    pub fn reset(&mut self) {
+        let echo_expressions = self.echo_expressions;
+        let print_errors = self.print_errors;
+        let config = self.vm.config();
+        let interrupt = self.vm.interrupt_handle();
+        *self = Session::new();
+        self.echo_expressions = echo_expressions;
+        self.set_print_errors(print_errors);
+        self.vm.set_config(config);
+        self.vm.set_interrupt_handle(interrupt);
+    }
+
+    pub fn set_echo_expressions(&mut self, echo: bool) {
+        self.echo_expressions = echo;
+    }
+
+    // Whether compile and runtime errors are reported on stderr, 
+    // they are available from compile_errors and last_error anyway
+    pub fn set_print_errors(&mut self, print_errors: bool) {
+        self.print_errors = print_errors;
+        self.vm.set_print_errors(print_errors);
+    }
+
+    // Arguments that the script can query with the natives argc and args
+    pub fn set_script_args(&mut self, script_args: Vec<String>) {
+        self.vm.set_script_args(script_args);
+    }
+
+    pub fn set_vm_config(&mut self, config: VmConfig) {
+        self.vm.set_config(config);
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn VmObserver>) {
+        self.vm.add_observer(observer);
+    }
+
+    // Stops the source that is being interpreted, the session stays usable
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.vm.interrupt_handle()
+    }
+
+    // Error that stopped the last interpreted source, if any
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.vm.last_error()
+    }
+
+    pub fn compile_errors(&self) -> &[Diagnostic] {
+        &self.compile_errors
+    }
+
+    // Where print statements write to, standard output by default
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.vm.set_output(output);
+    }
+
+    // Compiles the source as a new top-level function and runs it
+    // against the existing globals and heap
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        let mut compiler = Compiler::new(source, self.vm.heap_mut(), &self.globals);
+        compiler.set_echo_expressions(self.echo_expressions);
+        compiler.set_print_errors(self.print_errors);
+
+        let fun_data = compiler.compile();
+        self.compile_errors = compiler.take_source_index().diagnostics;
+
+        if let Some(func_data) = fun_data {
+            self.vm.run_fun(func_data)
+        } else {
+            InterpretResult::CompileError
+        }
+    }
+
+    // Names and values of all defined globals
+    pub fn globals(&self) -> Vec<(String, String)> {
+        self.vm.defined_globals()
+            .into_iter()
+            .map(|(name, value)| (name, value.display(self.vm.heap()).to_string()))
+            .collect()
+    }
+
+    // Prints the bytecode of the function stored in the given global
+    pub fn disassemble(&self, name: &str) -> Result<(), String> {
+        let slot = self.globals.borrow().get_slot(name);
+        let heap = self.vm.heap();
+        let fun = match slot.and_then(|slot| self.vm.get_global(slot)) {
+            Some(Value::Closure(closure)) => heap.get_content(&heap.get_content(&closure).fun),
+            Some(Value::Fun(fun)) => heap.get_content(&fun),
+            Some(Value::NativeFun(_)) => return Err(format!("'{}' is a native function.", name)),
+            Some(_) => return Err(format!("'{}' is not a function.", name)),
+            None => return Err(format!("Undefined variable '{}'.", name)),
+        };
+        disassemble(fun.chunk(), &fun.to_string(), heap);
+        Ok(())
+    }
+
+}
+
+const REPL_HELP: &str = "\
+Lox statements are executed, the values of expression statements are printed.
+
+  :dis <fn>       disassemble a function
+  :globals        list the defined globals
+  :load <file>    run a file in this session
+  :time <code>    run code and show the elapsed time
+  :reset          forget all definitions
+  :help           show this help
+
+  !h              list the input history
+  !!              repeat the last input
+  !n              repeat input number n
+
+Ctrl-C stops the running code, Ctrl-D ends the session.";
+
+// History and meta commands are not valid Lox, 
+// so they cannot be confused with statements
+pub fn repl(config: VmConfig) {
+
+    let mut session = Session::new();
+    session.set_echo_expressions(true);
+    session.set_vm_config(config);
+    interrupt_on_ctrl_c(session.interrupt_handle());
+
+    let mut history = match History::default_file_path() {
+        Some(file_path) => History::new_with_file(file_path),
+        None => History::new(),
+    };
+
+    while let Some(input) = read_input() {
+
+        let command = input.trim();
+
+        let source = if command == "!h" {
+            for (i, entry) in history.entries().iter().enumerate() {
+                println!("{:>4}  {}", i + 1, entry.replace('\n', "\n      "));
+            }
+            continue;
+        } else if command == "!!" || is_history_number(command) {
+            let entry = if command == "!!" {
+                history.last()
+            } else {
+                command[1..].parse().ok().and_then(|number| history.get(number))
+            };
+            match entry {
+                Some(entry) => {
+                    println!("{}", entry);
+                    entry.to_string()
+                },
+                None => {
+                    eprintln!("No such history entry.");
+                    continue;
+                }
+            }
+        } else {
+            input
+        };
+
+        history.add(&source);
+        if source.trim_start().starts_with(':') {
+            run_command(&mut session, source.trim());
+        } else {
+            session.interpret(&source);
+        }
+    }
+
+    println!();
+
+}
+
+// Ctrl-C stops the running code instead of the process. At the prompt
+// it has no effect, the input is ended with Ctrl-D.
+#[cfg(unix)]
+fn interrupt_on_ctrl_c(handle: InterruptHandle) {
+
+    const SIGINT: c_int = 2;
+    static CTRL_C_HANDLE: OnceLock<InterruptHandle> = OnceLock::new();
+
+    extern "C" {
+        // Returns the previous handler, which may also be SIG_DFL or SIG_ERR
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> *const c_void;
+    }
+
+    extern "C" fn on_sigint(_signum: c_int) {
+        // Only touches atomics, so it is safe to run in a signal handler
+        if let Some(handle) = CTRL_C_HANDLE.get() {
+            handle.interrupt();
+        }
+    }
+
+    if CTRL_C_HANDLE.set(handle).is_ok() {
+        // SAFETY: the handler is async-signal-safe, see above
+        unsafe {
+            signal(SIGINT, on_sigint);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn interrupt_on_ctrl_c(_handle: InterruptHandle) {}
+
+fn run_command(session: &mut Session, command: &str) {
+
+    let (name, arg) = match command.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+
+    match (name, arg) {
+        (":dis", fun_name) if !fun_name.is_empty() => {
+            if let Err(message) = session.disassemble(fun_name) {
+                eprintln!("{}", message);
+            }
+        },
+        (":globals", "") => {
+            for (name, value) in session.globals() {
+                println!("{} = {}", name, value);
+            }
+        },
+        // Loaded files behave as if they were run, without echoed expressions
+        (":load", file_path) if !file_path.is_empty() => {
+            if let Ok(source) = read_source(file_path) {
+                let echo_expressions = session.echo_expressions;
+                session.set_echo_expressions(false);
+                session.interpret(&source);
+                session.set_echo_expressions(echo_expressions);
+            }
+        },
+        (":time", code) if !code.is_empty() => {
+            // Allow to time a bare expression
+            let mut source = code.to_string();
+            if !source.ends_with(';') && !source.ends_with('}') {
+                source.push(';');
+            }
+            let start = Instant::now();
+            session.interpret(&source);
+            println!("Elapsed: {:.3?}", start.elapsed());
+        },
+        (":reset", "") => session.reset(),
+        (":help", "") => println!("{}", REPL_HELP),
+        _ => eprintln!("Unknown command '{}'. Type :help for a list of commands.", command),
+    }
+}
+
+fn is_history_number(command: &str) -> bool {
+    command.len() > 1 
+        && command.starts_with('!') 
+        && command[1..].chars().all(|ch| ch.is_ascii_digit())
+}
+
+// Reads lines until the input is complete. Returns None at the end of input.
+fn read_input() -> Option<String> {
 
     let stdin = io::stdin();
-    let mut line = String::new();
+    let mut input = String::new();
+    let mut prompt = "> ";
 
     loop {
-        print!("> ");
-        line.clear();
+        print!("{}", prompt);
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
         match stdin.read_line(&mut line) {
-            Ok(_) => {
-                interpret(&line);
-                ()
-            },
-            Err(_) => break,
+            Ok(0) | Err(_) => return if input.is_empty() { None } else { Some(input) },
+            Ok(_) => input.push_str(&line),
+        }
+
+        if is_complete_input(&input) {
+            return Some(input);
+        }
+        prompt = "... ";
+    }
+}
+
+// Input is incomplete while it contains unclosed parentheses, 
+// braces or strings
+pub fn is_complete_input(source: &str) -> bool {
+
+    let mut depth = 0;
+
+    for token in Scanner::new(source) {
+        match token.get_token_type() {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            TokenType::Error if token.get_lexeme() == "Unterminated string." => return false,
+            _ => (),
         }
     }
 
+    depth <= 0
 }
 
 pub fn run_file(file_path: &str) -> Result<(), i32>{
 
+    let source = read_source(file_path)?;
+    run_script(&source, vec![], VmConfig::default(), vec![])
+    
+}
+
+pub fn run_script(
+    source: &str, 
+    script_args: Vec<String>, 
+    config: VmConfig,
+    observers: Vec<Box<dyn VmObserver>>) -> Result<(), i32> {
+
+    let mut session = Session::new();
+    session.set_script_args(script_args);
+    session.set_vm_config(config);
+    observers.into_iter().for_each(|observer| session.add_observer(observer));
+
+    match session.interpret(source) {
+        InterpretResult::Ok => Ok(()),
+        InterpretResult::CompileError => Err(65),
+        InterpretResult::RuntimeError => Err(70),
+        InterpretResult::Interrupted => Err(130),
+    }
+}
+
+// Prints the bytecode of the script and of all functions defined in it
+pub fn disassemble_script(source: &str) -> Result<(), i32> {
+
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
+    let top = compiler.compile().ok_or(65)?;
+
+    disassemble(top.chunk(), "<script>", &heap);
+    disassemble_functions(top.chunk(), &heap);
+
+    Ok(())
+}
+
+fn disassemble_functions(chunk: &Chunk, heap: &HeapManager) {
+
+    let mut value_idx = 0;
+
+    while let Some(value) = chunk.read_value(value_idx) {
+        let fun_opt: Option<&FunData> = match value {
+            Value::Closure(closure) => Some(heap.get_content(&heap.get_content(closure).fun)),
+            Value::Fun(fun) => Some(heap.get_content(fun)),
+            _ => None,
+        };
+        if let Some(fun) = fun_opt {
+            println!();
+            disassemble(fun.chunk(), &fun.to_string(), heap);
+            disassemble_functions(fun.chunk(), heap);
+        }
+        value_idx += 1;
+    }
+}
+
+pub fn dump_tokens(source: &str) {
+    for token in Scanner::new(source) {
+        let token_type = format!("{:?}", token.get_token_type());
+        println!("{:>4} {:<14} {}", token.get_line(), token_type, token.get_lexeme());
+    }
+}
+
+// Reads the file or, if the path is "-", the standard input
+pub fn read_source(file_path: &str) -> Result<String, i32> {
+
+    if file_path == "-" {
+        let mut source = String::new();
+        return match io::stdin().read_to_string(&mut source) {
+            Ok(_) => Ok(source),
+            Err(_) => {
+                eprintln!("Could not read standard input");
+                Err(74)
+            }
+        };
+    }
+
     let mut file = match File::open(Path::new(file_path)) {
         Ok(file) => file,
         Err(_) => {
@@ -39,60 +442,39 @@ pub fn run_file(file_path: &str) -> Result<(), i32>{
             return Err(74);
         }
     }
-    
-    match interpret(&source) {
-        InterpretResult::Ok => Ok(()),
-        InterpretResult::CompileError => Err(65),
-        InterpretResult::RuntimeError => Err(70),
-    }
-    
+
+    Ok(source)
 }
 
 pub fn interpret(source: &str) -> InterpretResult {
-
-    let heap_manager = HeapManager::new_rc_refcell();
-    let mut compiler = Compiler::new_with_heap_mgr(source, &heap_manager);
-
-    let ret = if let Some(func_data) = compiler.compile() {
-        let frame = CallFrame::new_top_with_func_data(func_data);
-        let mut vm = VM::new_with_frame(frame);
-        set_native_functions(&mut vm, &heap_manager);    
-        vm.run()
-    } else {
-        heap_manager.borrow_mut().free_all();
-        InterpretResult::CompileError
-    };
-    
-    heap_manager.borrow_mut().free_all();
-
-    ret
+    Session::new().interpret(source)
 }
 
-fn set_native_functions(vm: &mut VM, hm: &Rc<RefCell<HeapManager>>) {
-
-    vm.define_native_fun(&new_native(
-        hm, 
-        "sqrt", 
-        1,
-        native::sqrt 
-    ));
-
-    vm.define_native_fun(&new_native(
-        hm, 
-        "concat", 
-        2,
-        native::concat 
-    ));
+// Name and arity of the functions that are predefined in every session
+pub const NATIVE_FUNCTIONS: [(&str, u8, NativeFn); 7] = [
+    ("sqrt", 1, native::sqrt),
+    ("concat", 2, native::concat),
+    ("argc", 0, native::argc),
+    ("args", 0, native::args),
+    ("arg", 1, native::arg),
+    ("assert_eq", 2, native::assert_eq),
+    ("assert_ne", 2, native::assert_ne),
+];
 
+fn set_native_functions(vm: &mut VM) {
+    for (name, arity, native_fn) in NATIVE_FUNCTIONS {
+        define_native(vm, name, arity, native_fn);
+    }
 }
 
-fn new_native(
-    hm: &Rc<RefCell<HeapManager>>, 
+fn define_native(
+    vm: &mut VM, 
     name: &str, 
     arity: u8, 
-    native_fn: NativeFn) -> HeapRef<NativeFunData> {
+    native_fn: NativeFn) {
 
+    // Natives are defined before any limits of the heap are set
     let native = NativeFunData::new(name, arity, native_fn);
-    HeapManager::malloc(hm, native)
+    let native = vm.heap_mut().malloc(native).expect("Heap without limits.");
+    vm.define_native_fun(&native);
 }
-
@@ -0,0 +1,471 @@
+// Minimal JSON support for the editor protocols
+
+use std::{fmt, iter::Peekable, str::Chars, io::{self, BufRead, Read, Write}};
+
+// Larger messages are rejected instead of allocating a buffer for them
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+// Deeper arrays and objects would overflow the stack of the recursive parser
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>), // keeps the order of the members
+}
+
+impl Json {
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: text.chars().peekable(), depth: 0 };
+        let json = parser.parse_value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(json),
+            Some(c) => Err(format!("Unexpected '{}' after JSON value.", c)),
+        }
+    }
+
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect())
+    }
+
+    // Member of an object, None for other values
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(member_key, _)| member_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Self {
+        Json::Number(n)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::Str(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(elements: Vec<Json>) -> Self {
+        Json::Array(elements)
+    }
+}
+
+// Compact serialization
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::Str(s) => write_string(f, s),
+            Json::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// Reads a message with a Content-Length header as used by DAP and LSP.
+// Messages that are too large or not valid JSON are an error, None at the end of the input.
+pub(crate) fn read_message(input: &mut dyn BufRead) -> Option<Result<Json, String>> {
+
+    loop {
+        let mut content_length = None;
+        loop {
+            let mut line = String::new();
+            if input.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    content_length = value.trim().parse().ok();
+                }
+            }
+        }
+
+        let content_length = match content_length {
+            Some(content_length) => content_length,
+            None => {
+                eprintln!("Message without Content-Length header.");
+                continue;
+            }
+        };
+        if content_length > MAX_CONTENT_LENGTH {
+            // Skips the body to stay in sync with the following messages
+            io::copy(&mut input.take(content_length as u64), &mut io::sink()).ok()?;
+            return Some(Err(format!("Message of {} bytes exceeds the limit of {} bytes.", content_length, MAX_CONTENT_LENGTH)));
+        }
+        let mut body = vec![0; content_length];
+        input.read_exact(&mut body).ok()?;
+
+        return Some(Json::parse(&String::from_utf8_lossy(&body))
+            .map_err(|error| format!("Invalid message: {}", error)));
+    }
+}
+
+pub(crate) fn write_message(output: &mut dyn Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    depth: usize, // of the arrays and objects being parsed
+}
+
+impl <'a> Parser<'a> {
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expect '{}' but found '{}'.", expected, c)),
+            None => Err(format!("Expect '{}' but found end of input.", expected)),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some('[' | '{')) && self.depth >= MAX_DEPTH {
+            return Err("Too deeply nested.".to_string());
+        }
+        match self.chars.peek() {
+            Some('n') => self.parse_literal("null", Json::Null),
+            Some('t') => self.parse_literal("true", Json::Bool(true)),
+            Some('f') => self.parse_literal("false", Json::Bool(false)),
+            Some('"') => Ok(Json::Str(self.parse_string()?)),
+            Some('[') => self.nested(Parser::parse_array),
+            Some('{') => self.nested(Parser::parse_object),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) => Err(format!("Unexpected '{}'.", c)),
+            None => Err("Unexpected end of input.".to_string()),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json, String>) -> Result<Json, String> {
+        self.depth += 1;
+        let json = parse(self);
+        self.depth -= 1;
+        json
+    }
+
+    fn parse_literal(&mut self, literal: &str, json: Json) -> Result<Json, String> {
+        for expected in literal.chars() {
+            self.expect(expected)?;
+        }
+        Ok(json)
+    }
+
+    fn parse_number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                text.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| format!("Invalid number '{}'.", text))
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.parse_escape()?),
+                Some(c) => s.push(c),
+                None => return Err("Unterminated string.".to_string()),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, String> {
+        match self.chars.next() {
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('/') => Ok('/'),
+            Some('b') => Ok('\u{8}'),
+            Some('f') => Ok('\u{c}'),
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('u') => {
+                let high = self.parse_hex4()?;
+                if !(0xd800..0xdc00).contains(&high) {
+                    return char::from_u32(high).ok_or_else(|| "Invalid unicode escape.".to_string());
+                }
+                // Surrogate pair
+                self.expect('\\')?;
+                self.expect('u')?;
+                let low = self.parse_hex4()?;
+                if !(0xdc00..0xe000).contains(&low) {
+                    return Err("Invalid surrogate pair.".to_string());
+                }
+                char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+                    .ok_or_else(|| "Invalid unicode escape.".to_string())
+            },
+            Some(c) => Err(format!("Invalid escape '\\{}'.", c)),
+            None => Err("Unterminated string.".to_string()),
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.chars.next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| "Invalid unicode escape.".to_string())?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn parse_array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut elements = vec![];
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => (),
+                Some(']') => return Ok(Json::Array(elements)),
+                _ => return Err("Expect ',' or ']' in array.".to_string()),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            members.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => (),
+                Some('}') => return Ok(Json::Object(members)),
+                _ => return Err("Expect ',' or '}' in object.".to_string()),
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use super::{Json, read_message, write_message, MAX_CONTENT_LENGTH, MAX_DEPTH};
+
+    #[test]
+    fn parse_and_serialize() {
+
+        let text = r#" { "seq": 1, "args": ["a", -2.5e1, true, null], "nested": {"s": "x\"\nä😀"} } "#;
+        let json = Json::parse(text).unwrap();
+
+        assert_eq!(json.get("seq").and_then(Json::as_i64), Some(1));
+        assert_eq!(json.get("args").and_then(Json::as_array).map(|args| args.len()), Some(4));
+        assert_eq!(json.get("args").unwrap().as_array().unwrap()[1].as_f64(), Some(-25.0));
+        assert_eq!(json.get("nested").and_then(|n| n.get("s")).and_then(Json::as_str), Some("x\"\nä😀"));
+        assert_eq!(json.get("missing"), None);
+
+        assert_eq!(
+            json.to_string(),
+            r#"{"seq":1,"args":["a",-25,true,null],"nested":{"s":"x\"\nä😀"}}"#);
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+        assert_eq!(Json::parse(r#""\ud83d\ude00\u00e4""#), Ok(Json::from("😀ä")));
+    }
+
+    #[test]
+    fn read_and_write_messages() {
+
+        let mut buffer = vec![];
+        write_message(&mut buffer, &Json::object(vec![("seq", 1.into())])).unwrap();
+        write_message(&mut buffer, &Json::from("ä")).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), "Content-Length: 9\r\n\r\n{\"seq\":1}Content-Length: 4\r\n\r\n\"ä\"");
+
+        let mut input = &buffer[..];
+        assert_eq!(read_message(&mut input), Some(Ok(Json::object(vec![("seq", 1.into())]))));
+        assert_eq!(read_message(&mut input), Some(Ok(Json::from("ä"))));
+        assert_eq!(read_message(&mut input), None);
+    }
+
+    #[test]
+    fn reject_invalid_messages() {
+        let mut input = "Content-Length: 3\r\n\r\n{1}Content-Length: 4\r\n\r\ntrue".as_bytes();
+        assert!(matches!(read_message(&mut input), Some(Err(_))));
+        assert_eq!(read_message(&mut input), Some(Ok(Json::from(true))));
+
+        // The body of a too large message is skipped without being stored
+        let header = format!("Content-Length: {}\r\n\r\n", MAX_CONTENT_LENGTH + 1);
+        let mut input = header.as_bytes().chain(io::repeat(b' ').take(MAX_CONTENT_LENGTH as u64 + 1)).chain(&b"Content-Length: 1\r\n\r\n1"[..]);
+        let mut input = io::BufReader::new(&mut input);
+        assert_eq!(read_message(&mut input), Some(Err(format!(
+            "Message of {} bytes exceeds the limit of {} bytes.", MAX_CONTENT_LENGTH + 1, MAX_CONTENT_LENGTH))));
+        assert_eq!(read_message(&mut input), Some(Ok(Json::from(1.0))));
+    }
+
+    #[test]
+    fn reject_invalid_json() {
+        assert!(Json::parse("").is_err());
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("\"open").is_err());
+        assert!(Json::parse("1 2").is_err());
+        assert!(Json::parse("nul").is_err());
+    }
+
+    #[test]
+    fn limit_nesting_depth() {
+        let nested = |depth: usize| format!("{}{}", "[{\"a\":".repeat(depth / 2), "}]".repeat(depth / 2));
+        assert!(Json::parse(&format!("{}1{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH))).is_ok());
+        assert_eq!(Json::parse(&format!("{}1{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1))), Err("Too deeply nested.".to_string()));
+        assert!(Json::parse(&nested(1_000_000)).is_err());
+    }
+
+}
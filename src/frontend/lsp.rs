@@ -0,0 +1,317 @@
+// Language Server Protocol server, see https://microsoft.github.io/language-server-protocol/
+
+use std::{io::{BufRead, Write}, collections::HashMap};
+use crate::backend::{heap::HeapManager, globals::GlobalTable};
+use super::{json::{Json, read_message, write_message}, compiler::Compiler, symbols::{SourceIndex, Symbol, SymbolKind}, scanner::KEYWORDS, interpreter::NATIVE_FUNCTIONS, token::Span};
+
+// Kinds of the protocol
+const SYMBOL_KIND_FUNCTION: i32 = 12;
+const SYMBOL_KIND_VARIABLE: i32 = 13;
+const COMPLETION_KIND_FUNCTION: i32 = 3;
+const COMPLETION_KIND_VARIABLE: i32 = 6;
+const COMPLETION_KIND_KEYWORD: i32 = 14;
+const SEVERITY_ERROR: i32 = 1;
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+
+// Open document together with the result of compiling it
+struct Document {
+    lines: Vec<String>,
+    index: SourceIndex,
+}
+
+impl Document {
+
+    fn new(text: &str) -> Document {
+        let mut heap = HeapManager::new();
+        let mut compiler = Compiler::new(text, &mut heap, &GlobalTable::new_rc_refcell());
+        compiler.set_print_errors(false);
+        compiler.compile();
+        Document {
+            lines: text.lines().map(|line| line.to_string()).collect(),
+            index: compiler.take_source_index(),
+        }
+    }
+
+    // The protocol counts lines from 0 and characters in UTF-16 code units
+    fn position(&self, line: i32, column: i32) -> Json {
+        let text = self.lines.get((line - 1).max(0) as usize).map(String::as_str).unwrap_or("");
+        let character: usize = text
+            .chars()
+            .take((column - 1).max(0) as usize)
+            .map(char::len_utf16)
+            .sum();
+        Json::object(vec![("line", (line - 1).max(0).into()), ("character", character.into())])
+    }
+
+    fn range(&self, span: &Span) -> Json {
+        Json::object(vec![
+            ("start", self.position(span.line, span.column)),
+            ("end", self.position(span.line, span.column + span.length as i32)),
+        ])
+    }
+
+    // Line and column of a position of the protocol
+    fn line_and_column(&self, position: &Json) -> Option<(i32, i32)> {
+        let line = position.get("line")?.as_i64()?;
+        let character = position.get("character")?.as_i64()?;
+        let text = self.lines.get(line as usize).map(String::as_str).unwrap_or("");
+        let mut units = 0;
+        let column = text
+            .chars()
+            .take_while(|c| {
+                units += c.len_utf16() as i64;
+                units <= character
+            })
+            .count();
+        Some((line as i32 + 1, column as i32 + 1))
+    }
+
+    fn symbol_at(&self, position: &Json) -> Option<&Symbol> {
+        let (line, column) = self.line_and_column(position)?;
+        let symbol_idx = self.index.symbol_at(line, column)?;
+        self.index.symbols.get(symbol_idx)
+    }
+
+}
+
+struct LanguageServer {
+    output: Box<dyn Write>,
+    documents: HashMap<String, Document>, // by uri
+    shutdown: bool,
+}
+
+impl LanguageServer {
+
+    fn send(&mut self, members: Vec<(&str, Json)>) {
+        let mut message = vec![("jsonrpc", "2.0".into())];
+        message.extend(members);
+        if write_message(self.output.as_mut(), &Json::object(message)).is_err() {
+            eprintln!("Could not send message to the client.");
+        }
+    }
+
+    fn handle_request(&mut self, id: Json, method: &str, params: &Json) {
+        let result = match method {
+            "initialize" => Some(capabilities()),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Json::Null)
+            },
+            "textDocument/documentSymbol" => self.document(params).map(|(uri, document)| document_symbols(uri, document)),
+            "textDocument/definition" => self.document(params).map(|(uri, document)| definition(uri, document, params)),
+            "textDocument/hover" => self.document(params).map(|(_, document)| hover(document, params)),
+            "textDocument/completion" => self.document(params).map(|(_, document)| completion(document)),
+            _ => None,
+        };
+
+        match result {
+            Some(result) => self.send(vec![("id", id), ("result", result)]),
+            None => {
+                let error = Json::object(vec![
+                    ("code", METHOD_NOT_FOUND.into()),
+                    ("message", format!("Unsupported request '{}' or unknown document.", method).into()),
+                ]);
+                self.send(vec![("id", id), ("error", error)]);
+            }
+        }
+    }
+
+    // Notifications get no answer, unknown ones are ignored
+    fn handle_notification(&mut self, method: &str, params: &Json) {
+        let uri = params.get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string();
+
+        // Documents are always synchronized as a whole
+        let text = match method {
+            "textDocument/didOpen" => params.get("textDocument").and_then(|document| document.get("text")),
+            "textDocument/didChange" => params.get("contentChanges")
+                .and_then(Json::as_array)
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text")),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri);
+                return;
+            },
+            _ => return,
+        };
+
+        if let Some(text) = text.and_then(Json::as_str) {
+            self.documents.insert(uri.clone(), Document::new(text));
+            self.publish_diagnostics(&uri);
+        }
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let diagnostics: Vec<Json> = match self.documents.get(uri) {
+            Some(document) => document.index.diagnostics
+                .iter()
+                .map(|diagnostic| Json::object(vec![
+                    ("range", document.range(&diagnostic.span)),
+                    ("severity", SEVERITY_ERROR.into()),
+                    ("source", "rlox".into()),
+                    ("message", diagnostic.message.as_str().into()),
+                ]))
+                .collect(),
+            None => vec![],
+        };
+        self.send(vec![
+            ("method", "textDocument/publishDiagnostics".into()),
+            ("params", Json::object(vec![("uri", uri.into()), ("diagnostics", diagnostics.into())])),
+        ]);
+    }
+
+    fn document<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        Some((uri, self.documents.get(uri)?))
+    }
+
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("capabilities", Json::object(vec![
+            ("textDocumentSync", 1.into()),
+            ("documentSymbolProvider", true.into()),
+            ("definitionProvider", true.into()),
+            ("hoverProvider", true.into()),
+            ("completionProvider", Json::object(vec![])),
+        ])),
+        ("serverInfo", Json::object(vec![
+            ("name", "rlox".into()),
+            ("version", env!("CARGO_PKG_VERSION").into()),
+        ])),
+    ])
+}
+
+// All functions and the global variables
+fn document_symbols(uri: &str, document: &Document) -> Json {
+    let symbols: Vec<Json> = document.index.symbols
+        .iter()
+        .filter_map(|symbol| {
+            let kind = match symbol.kind {
+                SymbolKind::Function { .. } => SYMBOL_KIND_FUNCTION,
+                SymbolKind::Variable if symbol.global => SYMBOL_KIND_VARIABLE,
+                _ => return None,
+            };
+            Some(Json::object(vec![
+                ("name", symbol.name.as_str().into()),
+                ("kind", kind.into()),
+                ("location", location(uri, document, &symbol.span)),
+            ]))
+        })
+        .collect();
+    symbols.into()
+}
+
+fn definition(uri: &str, document: &Document, params: &Json) -> Json {
+    match params.get("position").and_then(|position| document.symbol_at(position)) {
+        Some(symbol) => location(uri, document, &symbol.span),
+        None => Json::Null,
+    }
+}
+
+fn location(uri: &str, document: &Document, span: &Span) -> Json {
+    Json::object(vec![("uri", uri.into()), ("range", document.range(span))])
+}
+
+fn hover(document: &Document, params: &Json) -> Json {
+
+    let position = params.get("position").unwrap_or(&Json::Null);
+    let (signature, description) = match document.symbol_at(position) {
+        Some(Symbol { name, kind: SymbolKind::Function { params }, .. }) =>
+            (format!("fun {}({})", name, params.join(", ")), arguments(params.len())),
+        Some(Symbol { name, kind: SymbolKind::Variable, global, .. }) =>
+            (format!("var {}", name), if *global { "Global variable." } else { "Local variable." }.to_string()),
+        Some(Symbol { name, kind: SymbolKind::Parameter, .. }) =>
+            (name.clone(), "Parameter.".to_string()),
+        None => {
+            let native = document
+                .line_and_column(position)
+                .and_then(|(line, column)| document.index.reference_at(line, column))
+                .and_then(|reference| NATIVE_FUNCTIONS.iter().find(|(name, _, _)| *name == reference.name));
+            match native {
+                Some((name, arity, _)) =>
+                    (format!("fun {}", name), format!("Native function. {}", arguments(*arity as usize))),
+                None => return Json::Null,
+            }
+        },
+    };
+
+    let contents = format!("```lox\n{}\n```\n{}", signature, description);
+    Json::object(vec![("contents", Json::object(vec![
+        ("kind", "markdown".into()),
+        ("value", contents.into()),
+    ]))])
+}
+
+fn arguments(arity: usize) -> String {
+    match arity {
+        1 => "Takes 1 argument.".to_string(),
+        _ => format!("Takes {} arguments.", arity),
+    }
+}
+
+// Keywords, natives and all names defined in the document
+fn completion(document: &Document) -> Json {
+
+    let mut items: Vec<(String, i32)> = KEYWORDS
+        .iter()
+        .map(|(keyword, _)| (keyword.to_string(), COMPLETION_KIND_KEYWORD))
+        .collect();
+    items.extend(NATIVE_FUNCTIONS
+        .iter()
+        .map(|(name, _, _)| (name.to_string(), COMPLETION_KIND_FUNCTION)));
+    for symbol in document.index.symbols.iter() {
+        let kind = match symbol.kind {
+            SymbolKind::Function { .. } => COMPLETION_KIND_FUNCTION,
+            _ => COMPLETION_KIND_VARIABLE,
+        };
+        if !items.iter().any(|(name, _)| *name == symbol.name) {
+            items.push((symbol.name.clone(), kind));
+        }
+    }
+
+    let items: Vec<Json> = items
+        .into_iter()
+        .map(|(label, kind)| Json::object(vec![("label", label.into()), ("kind", kind.into())]))
+        .collect();
+    items.into()
+}
+
+// Serves until the exit notification or the end of the input,
+// fails if the client did not shut the server down before
+pub fn serve(mut input: Box<dyn BufRead>, output: Box<dyn Write>) -> Result<(), i32> {
+
+    let mut server = LanguageServer { output, documents: HashMap::new(), shutdown: false };
+
+    while let Some(message) = read_message(input.as_mut()) {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                // The id of an unreadable request is unknown
+                let error = Json::object(vec![("code", PARSE_ERROR.into()), ("message", error.into())]);
+                server.send(vec![("id", Json::Null), ("error", error)]);
+                continue;
+            }
+        };
+        let method = message.get("method").and_then(Json::as_str).unwrap_or_default();
+        let params = message.get("params").unwrap_or(&Json::Null);
+        match (method, message.get("id")) {
+            ("exit", _) => break,
+            ("", _) => (), // a response, but the server sends no requests
+            (_, Some(id)) => server.handle_request(id.clone(), method, params),
+            (_, None) => server.handle_notification(method, params),
+        }
+    }
+
+    if server.shutdown {
+        Ok(())
+    } else {
+        Err(1)
+    }
+}
@@ -2,4 +2,15 @@ pub mod interpreter;
 pub mod scanner;
 pub mod token;
 pub mod compiler;
-pub mod parse_rules;
\ No newline at end of file
+pub mod parse_rules;
+pub mod parser;
+pub mod ast;
+pub mod history;
+pub mod debugger;
+pub mod json;
+pub mod dap;
+pub mod symbols;
+pub mod lsp;
+pub mod test_runner;
+pub mod reference;
+pub mod formatter;
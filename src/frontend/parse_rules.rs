@@ -1,8 +1,6 @@
 use std::collections::HashMap;
 
-use crate::backend::chunk::Chunk;
-
-use super::{token::TokenType, compiler::Compiler};
+use super::{token::TokenType, parser::Parser, ast::Expr};
 
 #[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
 pub enum Precedence {
@@ -26,7 +24,7 @@ impl Precedence {
             let value = *self as u8;
             Precedence::try_from(value + 1).unwrap()
         } else {
-            self.clone()
+            *self
         }
     }
     
@@ -54,11 +52,13 @@ impl TryFrom<u8> for Precedence {
     }
 }
 
-pub type ParseFn = fn (&mut Compiler, chunk: &mut Chunk, can_assign: bool) -> ();
+pub type PrefixFn = fn (&mut Parser, can_assign: bool) -> Expr;
+
+pub type InfixFn = fn (&mut Parser, left: Expr) -> Expr;
 
 pub struct ParseRule {
-    pub prefix: Option<ParseFn>,
-    pub infix: Option<ParseFn>,
+    pub prefix: Option<PrefixFn>,
+    pub infix: Option<InfixFn>,
     pub precedence: Precedence,
 }
 
@@ -66,6 +66,12 @@ pub struct ParseRules {
     rules: HashMap<TokenType, ParseRule>,
 }
 
+impl Default for ParseRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ParseRules {
 
     pub fn new() -> ParseRules {
@@ -78,8 +84,8 @@ impl ParseRules {
 
     pub fn register(&mut self,
         token_type: TokenType, 
-        prefix: Option<ParseFn>,
-        infix: Option<ParseFn>,
+        prefix: Option<PrefixFn>,
+        infix: Option<InfixFn>,
         precedence: Precedence 
     ) {
         self.rules.insert(
@@ -0,0 +1,946 @@
+use super::{
+    scanner::Scanner,
+    token::{Token, TokenType, Span},
+    parse_rules::{Precedence, ParseRules, PrefixFn, InfixFn},
+    symbols::Diagnostic,
+    ast::{Program, Stmt, Expr, Block, FunDecl, Case, DefaultCase, Identifier, UnaryOperator, BinaryOperator, LogicalOperator},
+};
+
+// Deeper nesting of expressions and statements would overflow the stack
+// of the recursive passes over the tree
+const MAX_DEPTH: usize = 256;
+
+// Builds the syntax tree of a source. After a syntax error the parser skips to
+// the next statement and goes on, so that all errors of a source are reported.
+pub struct Parser<'a> {
+    scanner: Scanner<'a>,
+    previous: Option<Token>,
+    current: Option<Token>,
+    panic_mode: bool,
+    parse_rules: ParseRules,
+    diagnostics: Vec<Diagnostic>,
+    expr_depth: usize,
+    stmt_depth: usize, // of the nested statements and function bodies
+}
+
+impl <'a> Parser<'a> {
+
+    pub fn new(source: &'a str) -> Parser<'a> {
+
+        let mut ret = Parser {
+            scanner: Scanner::new(source),
+            previous: None,
+            current: None,
+            panic_mode: false,
+            parse_rules: ParseRules::new(),
+            diagnostics: vec![],
+            expr_depth: 0,
+            stmt_depth: 0,
+        };
+
+        ret.init_parse_rules();
+
+        ret
+    }
+
+    // Syntax errors, in the order of the source
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    fn init_parse_rules(&mut self) {
+
+        self.parse_rules.register(
+            TokenType::LeftParen,
+            grouping(),
+            call(),
+            Precedence::Call
+        );
+        self.parse_rules.register(
+            TokenType::Minus,
+            unary(),
+            binary(),
+            Precedence::Term
+        );
+        self.parse_rules.register(
+            TokenType::Plus,
+            None,
+            binary(),
+            Precedence::Term
+        );
+        self.parse_rules.register(
+            TokenType::Slash,
+            None,
+            binary(),
+            Precedence::Factor
+        );
+        self.parse_rules.register(
+            TokenType::Star,
+            None,
+            binary(),
+            Precedence::Factor
+        );
+        self.parse_rules.register(
+            TokenType::Number,
+            number(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::Nil,
+            literal(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::True,
+            literal(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::False,
+            literal(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::String,
+            string(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::Identifier,
+            variable(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::Bang,
+            unary(),
+            None,
+            Precedence::None
+        );
+        self.parse_rules.register(
+            TokenType::BangEqual,
+            None,
+            binary(),
+            Precedence::Equality
+        );
+        self.parse_rules.register(
+            TokenType::EqualEqual,
+            None,
+            binary(),
+            Precedence::Equality
+        );
+        self.parse_rules.register(
+            TokenType::Greater,
+            None,
+            binary(),
+            Precedence::Comparison
+        );
+        self.parse_rules.register(
+            TokenType::GreaterEqual,
+            None,
+            binary(),
+            Precedence::Comparison
+        );
+        self.parse_rules.register(
+            TokenType::Less,
+            None,
+            binary(),
+            Precedence::Comparison
+        );
+        self.parse_rules.register(
+            TokenType::LessEqual,
+            None,
+            binary(),
+            Precedence::Comparison
+        );
+        self.parse_rules.register(
+            TokenType::And,
+            None,
+            and(),
+            Precedence::And
+        );
+        self.parse_rules.register(
+            TokenType::Or,
+            None,
+            or(),
+            Precedence::Or
+        );
+
+    }
+
+    pub fn parse(&mut self) -> Program {
+
+        self.panic_mode = false;
+
+        self.advance();
+
+        let mut statements = vec![];
+        while !self.is_match(TokenType::Eof) {
+            statements.extend(self.declaration());
+        }
+
+        Program { statements, eof: self.previous_span() }
+    }
+
+    // None for a function declaration too broken to keep
+    fn declaration(&mut self) -> Option<Stmt> {
+
+        let stmt = if self.is_match(TokenType::Fun) {
+            self.fun_declaration()
+        } else if self.is_match(TokenType::Var) {
+            Some(self.var_declaration())
+        } else {
+            Some(self.statement())
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+
+        stmt
+    }
+
+    fn fun_declaration(&mut self) -> Option<Stmt> {
+
+        let keyword = self.previous_span();
+
+        self.consume(TokenType::Identifier, "Expect function name.");
+        let name = self.identifier();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+
+        // Parameters
+        let mut params: Vec<Identifier> = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.consume(TokenType::Identifier, "Expect identifier as parameter.");
+                params.push(self.identifier());
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        if params.len() > u8::MAX as usize {
+            self.error(&format!("Number of parameters must not exceed {}", u8::MAX));
+            return None;
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+
+        if self.stmt_depth == MAX_DEPTH {
+            self.too_much_nesting();
+            return None;
+        }
+        self.consume(TokenType::LeftBrace, "Expect '{' before body.");
+
+        self.stmt_depth += 1;
+        let body = self.block();
+        self.stmt_depth -= 1;
+
+        Some(Stmt::Fun(FunDecl { keyword, name, params, body }))
+    }
+
+    fn var_declaration(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let name = self.identifier();
+
+        let initializer = if self.is_match(TokenType::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+
+        self.consume(TokenType::Semicolon,
+            "Expect ';' after variable declaration.");
+
+        Stmt::Var { keyword, name, initializer, semicolon: self.previous_span() }
+    }
+
+    fn synchronize(&mut self) {
+
+        self.panic_mode = false;
+
+        loop {
+            if let Some(token) = &self.current {
+                if token.get_token_type() == TokenType::Eof ||
+                    self.previous.as_ref().unwrap().get_token_type() == TokenType::Semicolon {
+                    return;
+                }
+                match token.get_token_type() {
+                    TokenType::Class |
+                    TokenType::Fun |
+                    TokenType::Var |
+                    TokenType::For |
+                    TokenType::If |
+                    TokenType::While |
+                    TokenType::Print |
+                    TokenType::Assert |
+                    TokenType::Return =>
+                        return,
+                    _ => (),
+                }
+
+
+            } else {
+                return;
+            }
+
+            self.advance();
+        }
+
+    }
+
+    // Nested statements are all parsed through here, so that their depth is limited
+    fn statement(&mut self) -> Stmt {
+        if self.stmt_depth == MAX_DEPTH {
+            let span = self.current.as_ref().map(Token::span).unwrap_or_default();
+            self.too_much_nesting();
+            return Stmt::Expression { expr: Expr::Invalid { span }, semicolon: span };
+        }
+        self.stmt_depth += 1;
+        let stmt = self.parse_statement();
+        self.stmt_depth -= 1;
+        stmt
+    }
+
+    fn parse_statement(&mut self) -> Stmt {
+        if self.is_match(TokenType::Print) {
+            self.print_statement()
+        } else if self.is_match(TokenType::Assert) {
+            self.assert_statement()
+        } else if self.is_match(TokenType::For) {
+            self.for_statement()
+        } else if self.is_match(TokenType::If) {
+            self.if_statement()
+        } else if self.is_match(TokenType::While) {
+            self.while_statement()
+        } else if self.is_match(TokenType::Switch) {
+            self.switch_statement()
+        } else if self.is_match(TokenType::LeftBrace) {
+            Stmt::Block(self.block())
+        } else if self.is_match(TokenType::Continue) {
+            self.continue_statement()
+        } else if self.is_match(TokenType::Return) {
+            self.return_statement()
+        } else {
+            self.expr_statement()
+        }
+    }
+
+    fn return_statement(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        let value = if self.is_match(TokenType::Semicolon) {
+            None
+        } else {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            Some(value)
+        };
+
+        Stmt::Return { keyword, value, semicolon: self.previous_span() }
+    }
+
+    fn for_statement(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+
+        // Initializer clause:
+        let initializer = if self.is_match(TokenType::Semicolon) {
+            None
+        } else if self.is_match(TokenType::Var) {
+            Some(Box::new(self.var_declaration()))
+        } else {
+            Some(Box::new(self.expr_statement()))
+        };
+
+        // Condition clause:
+        let condition = if self.is_match(TokenType::Semicolon) {
+            None
+        } else {
+            let condition = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';'.");
+            Some(condition)
+        };
+        let condition_semicolon = self.previous_span();
+
+        // Increment clause:
+        let increment = if self.is_match(TokenType::RightParen) {
+            None
+        } else {
+            let increment = self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+            Some(increment)
+        };
+        let right_paren = self.previous_span();
+
+        let body = Box::new(self.statement());
+
+        Stmt::For { keyword, initializer, condition, condition_semicolon, increment, right_paren, body }
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let right_paren = self.previous_span();
+
+        let then_branch = Box::new(self.statement());
+        let (else_keyword, else_branch) = if self.is_match(TokenType::Else) {
+            (Some(self.previous_span()), Some(Box::new(self.statement())))
+        } else {
+            (None, None)
+        };
+
+        Stmt::If { keyword, condition, right_paren, then_branch, else_keyword, else_branch }
+    }
+
+    fn while_statement(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let right_paren = self.previous_span();
+
+        let body = Box::new(self.statement());
+
+        Stmt::While { keyword, condition, right_paren, body }
+    }
+
+    fn switch_statement(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        let subject = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.consume(TokenType::LeftBrace, "Expect '{'.");
+        let left_brace = self.previous_span();
+
+        let mut cases = vec![];
+        let mut default = None;
+
+        loop {
+            if self.is_match(TokenType::Case) {
+                let keyword = self.previous_span();
+                let value = self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after case expression.");
+                let colon = self.previous_span();
+                let statements = self.case_statements();
+                cases.push(Case { keyword, value, colon, statements });
+            } else if self.is_match(TokenType::Default) {
+                let keyword = self.previous_span();
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                let colon = self.previous_span();
+                let statements = self.case_statements();
+                default = Some(DefaultCase { keyword, colon, statements });
+                break;
+            } else {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' at end of switch statement.");
+
+        Stmt::Switch { keyword, subject, left_brace, cases, default, right_brace: self.previous_span() }
+    }
+
+    fn case_statements(&mut self) -> Vec<Stmt> {
+        let mut statements = vec![];
+        while
+            !self.check(TokenType::Case) &&
+            !self.check(TokenType::Default) &&
+            !self.check(TokenType::RightBrace) &&
+            !self.check(TokenType::Eof) {
+
+            statements.push(self.statement());
+        }
+        statements
+    }
+
+    fn block(&mut self) -> Block {
+
+        let left_brace = self.previous_span();
+
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) &&
+            !self.check(TokenType::Eof) {
+            statements.extend(self.declaration());
+        }
+
+        self.consume(TokenType::RightBrace,
+            "Expect '}' after block.");
+
+        Block { left_brace, statements, right_brace: self.previous_span() }
+    }
+
+    fn print_statement(&mut self) -> Stmt {
+        let keyword = self.previous_span();
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        Stmt::Print { keyword, value, semicolon: self.previous_span() }
+    }
+
+    fn assert_statement(&mut self) -> Stmt {
+
+        let keyword = self.previous_span();
+
+        let condition = self.expression();
+        let message = if self.is_match(TokenType::Comma) {
+            Some(self.expression())
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after assertion.");
+
+        Stmt::Assert { keyword, condition, message, semicolon: self.previous_span() }
+    }
+
+    // The error is reported by the compiler, which knows the enclosing loops
+    fn continue_statement(&mut self) -> Stmt {
+        let keyword = self.previous_span();
+        self.consume(TokenType::Semicolon, "Expect ';' after continue.");
+        Stmt::Continue { keyword, semicolon: self.previous_span() }
+    }
+
+    fn expr_statement(&mut self) -> Stmt {
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        Stmt::Expression { expr, semicolon: self.previous_span() }
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn number(&mut self) -> Expr {
+        let token = self.previous.as_ref().unwrap();
+        let value = token.get_lexeme().parse::<f64>().unwrap();
+        Expr::Number { value, span: token.span() }
+    }
+
+    fn literal(&mut self) -> Expr {
+        let token = self.previous.as_ref().unwrap();
+        let span = token.span();
+        match token.get_token_type() {
+            TokenType::True => Expr::Bool { value: true, span },
+            TokenType::False => Expr::Bool { value: false, span },
+            _ => Expr::Nil { span },
+        }
+    }
+
+    fn string(&mut self) -> Expr {
+        let token = self.previous.as_ref().unwrap();
+        let s = token.get_lexeme();
+        Expr::Str { value: s[1..(s.len()-1)].to_string(), span: token.span() }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let name = self.identifier();
+        if can_assign && self.is_match(TokenType::Equal) {
+            let value = self.expression();
+            Expr::Assign { target: name, value: Box::new(value) }
+        } else {
+            Expr::Variable(name)
+        }
+    }
+
+    fn grouping(&mut self) -> Expr {
+        let left_paren = self.previous_span();
+        let expr = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        Expr::Grouping { expr: Box::new(expr), left_paren, right_paren: self.previous_span() }
+    }
+
+    fn binary(&mut self, left: Expr) -> Expr {
+        let operator_type = self.previous.as_ref().unwrap().get_token_type();
+        let operator_span = self.previous_span();
+        let next_prec = self.parse_rules
+            .get_parse_rule(&operator_type)
+            .precedence
+            .increment();
+
+        let right = self.parse_precedence(next_prec);
+
+        let operator = match operator_type {
+            TokenType::Plus => BinaryOperator::Add,
+            TokenType::Minus => BinaryOperator::Subtract,
+            TokenType::Star => BinaryOperator::Multiply,
+            TokenType::Slash => BinaryOperator::Divide,
+            TokenType::BangEqual => BinaryOperator::NotEqual,
+            TokenType::EqualEqual => BinaryOperator::Equal,
+            TokenType::Greater => BinaryOperator::Greater,
+            TokenType::GreaterEqual => BinaryOperator::GreaterEqual,
+            TokenType::Less => BinaryOperator::Less,
+            _ => BinaryOperator::LessEqual,
+        };
+
+        Expr::Binary { left: Box::new(left), operator, operator_span, right: Box::new(right) }
+    }
+
+    fn unary(&mut self) -> Expr {
+        let operator = match self.previous.as_ref().unwrap().get_token_type() {
+            TokenType::Minus => UnaryOperator::Negate,
+            _ => UnaryOperator::Not,
+        };
+        let operator_span = self.previous_span();
+        let operand = self.parse_precedence(Precedence::Unary);
+        Expr::Unary { operator, operator_span, operand: Box::new(operand) }
+    }
+
+    fn logical(&mut self, left: Expr, operator: LogicalOperator, prec: Precedence) -> Expr {
+        let operator_span = self.previous_span();
+        let right = self.parse_precedence(prec);
+        Expr::Logical { left: Box::new(left), operator, operator_span, right: Box::new(right) }
+    }
+
+    fn call(&mut self, callee: Expr) -> Expr {
+        let args = self.arguments();
+        Expr::Call { callee: Box::new(callee), args, right_paren: self.previous_span() }
+    }
+
+    fn arguments(&mut self) -> Vec<Expr> {
+        let mut args = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if args.len() == u8::MAX as usize {
+                    self.error(&format!("Can't have more than {} arguments.", args.len()));
+                    return vec![];
+                }
+
+                args.push(self.expression());
+
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+
+        args
+    }
+
+    // Nested expressions are all parsed through here, so that their depth is limited
+    fn parse_precedence(&mut self, prec: Precedence) -> Expr {
+        if self.expr_depth == MAX_DEPTH {
+            self.error_at_current("Too much nesting.");
+            return Expr::Invalid { span: self.current.as_ref().map(Token::span).unwrap_or_default() };
+        }
+        self.expr_depth += 1;
+        let expr = self.parse_operators(prec);
+        self.expr_depth -= 1;
+        expr
+    }
+
+    fn parse_operators(&mut self, prec: Precedence) -> Expr {
+        self.advance();
+        let token_type = &self.previous.as_ref().unwrap().get_token_type();
+        let prefix_opt = self.parse_rules.get_parse_rule(token_type).prefix;
+
+        let Some(prefix) = prefix_opt else {
+            self.error("Expect expression.");
+            return Expr::Invalid { span: self.previous_span() };
+        };
+
+        let can_assign = prec <= Precedence::Assignment;
+        let mut expr = prefix(self, can_assign);
+
+        while let Some(token) = &self.current {
+            let token_type = token.get_token_type();
+            let curr_prec = self.parse_rules
+                .get_parse_rule(&token_type)
+                .precedence;
+
+            if curr_prec < prec {
+                break;
+            }
+
+            self.advance();
+
+            let infix = self.parse_rules
+                .get_parse_rule(&token_type)
+                .infix
+                .unwrap();
+
+            expr = infix(self, expr);
+        }
+
+        if can_assign && self.is_match(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+
+        expr
+    }
+
+    // The name of a declaration or variable that was just consumed
+    fn identifier(&self) -> Identifier {
+        let token = self.previous.as_ref().unwrap();
+        Identifier { name: token.get_lexeme().to_string(), span: token.span() }
+    }
+
+    // A token that was expected but is missing gets the span of the one before
+    fn previous_span(&self) -> Span {
+        self.previous.as_ref().map(Token::span).unwrap_or_default()
+    }
+
+    fn consume(&mut self, expected_type: TokenType, message: &str) {
+        if let Some(current) = &self.current {
+            if current.get_token_type() == expected_type {
+                self.advance();
+                return ;
+            }
+        }
+        self.error_at_current(message);
+    }
+
+    fn is_match(&mut self, expected_type: TokenType) -> bool {
+        if self.check(expected_type) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check(&self, expected_type: TokenType) -> bool {
+        if let Some(current) = &self.current {
+            current.get_token_type() == expected_type
+        } else {
+            false
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        // The last token of the scanner is Eof, which stays current
+        while let Some(next_token) = self.scanner.next() {
+            let is_error = next_token.get_token_type() == TokenType::Error;
+            self.current = Some(next_token);
+            if !is_error {
+                break;
+            }
+            self.error_at_current("a scan error occurred");
+        }
+    }
+
+    // Skips the statement or function body at the current token without
+    // recursing into it. The brackets are matched, so that the enclosing
+    // statements are parsed as usual and report no further errors.
+    fn too_much_nesting(&mut self) {
+
+        self.error_at_current("Too much nesting.");
+
+        let mut open_brackets = 0;
+        while let Some(token_type) = self.current.as_ref().map(Token::get_token_type) {
+            match token_type {
+                TokenType::Eof => break,
+                TokenType::RightParen | TokenType::RightBrace if open_brackets == 0 => break,
+                TokenType::LeftParen | TokenType::LeftBrace => open_brackets += 1,
+                TokenType::RightParen | TokenType::RightBrace => open_brackets -= 1,
+                _ => (),
+            }
+            self.advance();
+
+            // The statement ends with a ';' or '}' outside of brackets unless an else follows
+            let is_end = matches!(token_type, TokenType::Semicolon | TokenType::RightBrace) && open_brackets == 0;
+            if is_end && !self.check(TokenType::Else) {
+                break;
+            }
+        }
+
+        self.panic_mode = false;
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(&self.current.clone(), message);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(&self.previous.clone(), message);
+    }
+
+    fn error_at(&mut self, token_opt: &Option<Token>, message: &str) {
+
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+
+        let diagnostic = match token_opt {
+            Some(token) => {
+                let location = match token.get_token_type() {
+                    TokenType::Eof => " at end".to_string(),
+                    TokenType::Error => String::new(),
+                    _ => format!(" at '{}'", token.get_lexeme()),
+                };
+                Diagnostic::new(token.span(), &location, message)
+            },
+            None => Diagnostic::new(Span::default(), "", message),
+        };
+
+        self.diagnostics.push(diagnostic);
+    }
+
+}
+
+fn grouping() -> Option<PrefixFn> {
+    Some(|parser, _can_assign| parser.grouping())
+}
+
+fn binary() -> Option<InfixFn> {
+    Some(|parser, left| parser.binary(left))
+}
+
+fn unary() -> Option<PrefixFn> {
+    Some(|parser, _can_assign| parser.unary())
+}
+
+fn number() -> Option<PrefixFn> {
+    Some(|parser, _can_assign| parser.number())
+}
+
+fn literal() -> Option<PrefixFn> {
+    Some(|parser, _can_assign| parser.literal())
+}
+
+fn string() -> Option<PrefixFn> {
+    Some(|parser, _can_assign| parser.string())
+}
+
+fn variable() -> Option<PrefixFn> {
+    Some(|parser, can_assign| parser.variable(can_assign))
+}
+
+fn and() -> Option<InfixFn> {
+    Some(|parser, left| parser.logical(left, LogicalOperator::And, Precedence::And))
+}
+
+fn or() -> Option<InfixFn> {
+    Some(|parser, left| parser.logical(left, LogicalOperator::Or, Precedence::Or))
+}
+
+fn call() -> Option<InfixFn> {
+    Some(|parser, callee| parser.call(callee))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frontend::{ast::{Stmt, Expr, BinaryOperator, Identifier}, token::Span};
+    use super::{Parser, MAX_DEPTH};
+
+    #[test]
+    fn parse_spans() {
+
+        let mut parser = Parser::new("var total = 1 +\n  f(x);");
+        let program = parser.parse();
+
+        assert!(parser.diagnostics().is_empty());
+        assert_eq!(program.eof, Span::new(2, 8, 1));
+
+        let Stmt::Var { name, initializer: Some(Expr::Binary { left, operator, right, .. }), semicolon, .. } = &program.statements[0] else {
+            panic!("unexpected tree {:?}", program.statements);
+        };
+        assert_eq!(name, &Identifier { name: "total".to_string(), span: Span::new(1, 5, 5) });
+        assert_eq!(**left, Expr::Number { value: 1.0, span: Span::new(1, 13, 1) });
+        assert_eq!(*operator, BinaryOperator::Add);
+        assert_eq!(right.first_span(), Span::new(2, 3, 1));
+        assert_eq!(right.last_span(), Span::new(2, 6, 1));
+        assert_eq!(*semicolon, Span::new(2, 7, 1));
+    }
+
+    #[test]
+    fn recover_from_errors() {
+
+        let mut parser = Parser::new("print (;\nvar a = 1;\nfun f(x) { x = ; }");
+        let program = parser.parse();
+
+        let messages: Vec<&str> = parser.diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.report.as_str())
+            .collect();
+        assert_eq!(messages, [
+            "[line 1] Error at ';': Expect expression.",
+            "[line 3] Error at ';': Expect expression.",
+        ]);
+
+        // The broken statements are kept as far as they could be parsed
+        assert_eq!(program.statements.len(), 3);
+        assert!(matches!(&program.statements[1], Stmt::Var { name, .. } if name.name == "a"));
+        assert!(matches!(&program.statements[2], Stmt::Fun(fun) if fun.params.len() == 1));
+    }
+
+    #[test]
+    fn limit_nesting_depth() {
+        for prefix in ["(", "-", "!", "x = ", "f(", "1 + ("] {
+            let source = format!("print {}1;\nprint 2;", prefix.repeat(50_000));
+            let mut parser = Parser::new(&source);
+            let program = parser.parse();
+            assert_eq!(parser.diagnostics().len(), 1, "{}", prefix);
+            assert!(parser.diagnostics()[0].report.ends_with("Too much nesting."));
+            assert_eq!(program.statements.len(), 2);
+        }
+
+        let source = format!("print {}1{};", "(".repeat(MAX_DEPTH - 1), ")".repeat(MAX_DEPTH - 1));
+        let mut parser = Parser::new(&source);
+        parser.parse();
+        assert!(parser.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn limit_nesting_depth_of_statements() {
+        let sources = [
+            format!("{}{}", "{".repeat(100_000), "}".repeat(100_000)),
+            format!("{}print 1;", "if (true) ".repeat(50_000)),
+            format!("{}print 1; else print 2;", "if (true) print 0; else ".repeat(50_000)),
+            format!("{}print 1;", "while (false) ".repeat(50_000)),
+            format!("{}print 1;", "for (var i = 0; i < 1; i = i + 1) ".repeat(50_000)),
+            format!("{}{}", "fun f() { ".repeat(50_000), "}".repeat(50_000)),
+        ];
+        for source in sources {
+            let source = format!("{}\nprint 2;", source);
+            let mut parser = Parser::new(&source);
+            let program = parser.parse();
+            assert_eq!(parser.diagnostics().len(), 1, "{}", &source[..20]);
+            assert!(parser.diagnostics()[0].report.ends_with("Too much nesting."));
+            assert_eq!(program.statements.len(), 2);
+        }
+
+        let source = format!("{}{}", "{".repeat(MAX_DEPTH), "}".repeat(MAX_DEPTH));
+        let mut parser = Parser::new(&source);
+        parser.parse();
+        assert!(parser.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn long_chains_of_operands() {
+        let source = format!("print {}1;", "x + ".repeat(100_000));
+        let mut parser = Parser::new(&source);
+        let program = parser.parse();
+        assert!(parser.diagnostics().is_empty());
+        assert_eq!(program.statements[0].first_span(), Span::new(1, 1, 5));
+    }
+
+}
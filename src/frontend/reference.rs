@@ -0,0 +1,970 @@
+// Tree-walking interpreter of the language that the bytecode compiler supports.
+// It has a parser and an evaluator of its own and shares only the scanner, the
+// values and the native functions with the compiler and the VM. Running both
+// on the same programs and comparing output and errors finds the bugs of either.
+
+use std::{collections::HashMap, io::{self, Write}, rc::Rc};
+use crate::backend::{chunk::Chunk, heap::{HeapManager, HeapRef}, objects::{FunData, NativeFunData, NativeContext, NativeError}, value::Value, vm::{InterpretResult, VmConfig, RuntimeError, ErrorKind}};
+use super::{scanner::Scanner, token::{Token, TokenType}, compiler::source_offset, interpreter::NATIVE_FUNCTIONS};
+
+// The nesting limit of the compiler, deeper programs would overflow the stack here too
+const MAX_DEPTH: usize = 256;
+
+// Lines are those the VM reports errors on, the line of the
+// last token that was parsed when the instruction was emitted
+enum Expr {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+    Nil,
+    Variable { name: String, line: i32 },
+    Assign { name: String, value: Box<Expr>, line: i32 },
+    Unary { operator: TokenType, operand: Box<Expr>, line: i32 },
+    Binary { operator: TokenType, left: Box<Expr>, right: Box<Expr>, line: i32 },
+    Logical { operator: TokenType, left: Box<Expr>, right: Box<Expr> },
+    Call { callee: Box<Expr>, args: Vec<Expr>, line: i32 },
+    Grouping(Box<Expr>), // no assignment target
+}
+
+enum Stmt {
+    Print(Expr),
+    Expression(Expr),
+    Var { name: String, initializer: Option<Expr> },
+    Fun(Rc<Function>),
+    Block(Vec<Stmt>),
+    If { condition: Expr, then_branch: Box<Stmt>, else_branch: Option<Box<Stmt>> },
+    While { condition: Expr, body: Box<Stmt> },
+    For { initializer: Option<Box<Stmt>>, condition: Option<Expr>, increment: Option<Expr>, body: Box<Stmt> },
+    Switch { subject: Expr, cases: Vec<(Expr, Vec<Stmt>)>, default: Option<Vec<Stmt>> },
+    Continue,
+    Return(Option<Expr>),
+    Assert { condition: Expr, message: Option<Expr>, text: String, line: i32 },
+}
+
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+// Names declared in the scopes of a function, to report the same
+// errors as the compiler. The top level has no names at depth 0.
+struct FunctionScopes {
+    scopes: Vec<Vec<String>>,
+    loops: usize,
+}
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    source: &'a str,
+    current: Token,
+    previous: Token,
+    functions: Vec<FunctionScopes>,
+    expr_depth: usize,
+    stmt_depth: usize, // of the nested statements and function bodies
+}
+
+type ParseResult<T> = Result<T, String>;
+
+impl<'a> Parser<'a> {
+
+    fn new(source: &'a str) -> Parser<'a> {
+        let eof = Token::new(TokenType::Eof, String::new(), 1, 1);
+        Parser {
+            scanner: Scanner::new(source),
+            source,
+            current: eof.clone(),
+            previous: eof,
+            functions: vec![FunctionScopes { scopes: vec![], loops: 0 }],
+            expr_depth: 0,
+            stmt_depth: 0,
+        }
+    }
+
+    // Stops at the first error, reported like the compiler does
+    fn parse(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.advance()?;
+        let mut statements = vec![];
+        while !self.is_match(TokenType::Eof)? {
+            statements.push(self.declaration()?);
+        }
+        Ok(statements)
+    }
+
+    fn declaration(&mut self) -> ParseResult<Stmt> {
+        if self.is_match(TokenType::Fun)? {
+            self.fun_declaration()
+        } else if self.is_match(TokenType::Var)? {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn fun_declaration(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::Identifier, "Expect function name.")?;
+        let name = self.previous.get_lexeme().to_string();
+        self.check_redeclaration(&name)?;
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        let mut params = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.consume(TokenType::Identifier, "Expect identifier as parameter.")?;
+                params.push(self.previous.get_lexeme().to_string());
+                if !self.is_match(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        if params.len() > u8::MAX as usize {
+            return self.error(&format!("Number of parameters must not exceed {}", u8::MAX));
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+
+        // The function itself, its parameters and the body share a scope
+        let mut names = vec![name.clone()];
+        names.extend(params.iter().cloned());
+        let body = self.nested_statement(|parser| {
+            parser.consume(TokenType::LeftBrace, "Expect '{' before body.")?;
+            parser.functions.push(FunctionScopes { scopes: vec![names], loops: 0 });
+            let body = parser.block_declarations();
+            parser.functions.pop();
+            body
+        });
+
+        self.declare(&name);
+        Ok(Stmt::Fun(Rc::new(Function { name, params, body: body? })))
+    }
+
+    fn var_declaration(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::Identifier, "Expect variable name.")?;
+        let name = self.previous.get_lexeme().to_string();
+        self.check_redeclaration(&name)?;
+
+        let initializer = if self.is_match(TokenType::Equal)? {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.")?;
+
+        self.declare(&name);
+        Ok(Stmt::Var { name, initializer })
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        self.nested_statement(Parser::parse_statement)
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<Stmt> {
+        if self.is_match(TokenType::Print)? {
+            let value = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
+            Ok(Stmt::Print(value))
+        } else if self.is_match(TokenType::Assert)? {
+            self.assert_statement()
+        } else if self.is_match(TokenType::For)? {
+            self.for_statement()
+        } else if self.is_match(TokenType::If)? {
+            self.if_statement()
+        } else if self.is_match(TokenType::While)? {
+            self.while_statement()
+        } else if self.is_match(TokenType::Switch)? {
+            self.switch_statement()
+        } else if self.is_match(TokenType::LeftBrace)? {
+            self.begin_scope();
+            let statements = self.block_declarations();
+            self.end_scope();
+            Ok(Stmt::Block(statements?))
+        } else if self.is_match(TokenType::Continue)? {
+            if self.functions.last().unwrap().loops == 0 {
+                return self.error_at_current("'continue' can only be used in a loop context.");
+            }
+            self.consume(TokenType::Semicolon, "Expect ';' after continue.")?;
+            Ok(Stmt::Continue)
+        } else if self.is_match(TokenType::Return)? {
+            if self.functions.len() == 1 {
+                return self.error("Can't return from top level code.");
+            }
+            if self.is_match(TokenType::Semicolon)? {
+                return Ok(Stmt::Return(None));
+            }
+            let value = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+            Ok(Stmt::Return(Some(value)))
+        } else {
+            let expr = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+            Ok(Stmt::Expression(expr))
+        }
+    }
+
+    fn block_declarations(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            statements.push(self.declaration()?);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn assert_statement(&mut self) -> ParseResult<Stmt> {
+        let line = self.previous.get_line();
+        let first = self.current.clone();
+        let condition = self.expression()?;
+        let start = source_offset(self.source, first.get_line(), first.get_column());
+        let end = source_offset(self.source, self.previous.get_line(), self.previous.get_column()) + self.previous.get_lexeme().len();
+        let text = self.source
+            .get(start..end.max(start))
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        let message = if self.is_match(TokenType::Comma)? {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after assertion.")?;
+        Ok(Stmt::Assert { condition, message, text, line })
+    }
+
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        self.begin_scope();
+        let result = self.for_clauses_and_body();
+        self.end_scope();
+        result
+    }
+
+    fn for_clauses_and_body(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+
+        let initializer = if self.is_match(TokenType::Semicolon)? {
+            None
+        } else if self.is_match(TokenType::Var)? {
+            Some(Box::new(self.var_declaration()?))
+        } else {
+            let expr = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+            Some(Box::new(Stmt::Expression(expr)))
+        };
+
+        let mut condition = None;
+        if !self.is_match(TokenType::Semicolon)? {
+            condition = Some(self.expression()?);
+            self.consume(TokenType::Semicolon, "Expect ';'.")?;
+        }
+
+        let mut increment = None;
+        if !self.is_match(TokenType::RightParen)? {
+            increment = Some(self.expression()?);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+        }
+
+        let body = self.loop_body()?;
+        Ok(Stmt::For { initializer, condition, increment, body })
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.is_match(TokenType::Else)? {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(Stmt::If { condition, then_branch, else_branch })
+    }
+
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let body = self.loop_body()?;
+        Ok(Stmt::While { condition, body })
+    }
+
+    fn loop_body(&mut self) -> ParseResult<Box<Stmt>> {
+        self.functions.last_mut().unwrap().loops += 1;
+        let body = self.statement();
+        self.functions.last_mut().unwrap().loops -= 1;
+        Ok(Box::new(body?))
+    }
+
+    fn switch_statement(&mut self) -> ParseResult<Stmt> {
+        self.begin_scope();
+        let result = self.switch_cases();
+        self.end_scope();
+        result
+    }
+
+    fn switch_cases(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.")?;
+        let subject = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{'.")?;
+
+        let mut cases = vec![];
+        let mut default = None;
+        loop {
+            if self.is_match(TokenType::Case)? {
+                let value = self.expression()?;
+                self.consume(TokenType::Colon, "Expect ':' after case expression.")?;
+                cases.push((value, self.case_statements()?));
+            } else if self.is_match(TokenType::Default)? {
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.")?;
+                default = Some(self.case_statements()?);
+                break;
+            } else {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' at end of switch statement.")?;
+        Ok(Stmt::Switch { subject, cases, default })
+    }
+
+    fn case_statements(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = vec![];
+        while !self.check(TokenType::Case) && !self.check(TokenType::Default) && !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.nested_expression(Parser::assignment)
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let target = self.or()?;
+        if !self.is_match(TokenType::Equal)? {
+            return Ok(target);
+        }
+        match target {
+            Expr::Variable { name, .. } => {
+                let value = self.expression()?;
+                let line = self.previous.get_line();
+                Ok(Expr::Assign { name, value: Box::new(value), line })
+            },
+            _ => self.error("Invalid assignment target."),
+        }
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
+        while self.is_match(TokenType::Or)? {
+            let right = self.nested_expression(Parser::and)?;
+            expr = Expr::Logical { operator: TokenType::Or, left: Box::new(expr), right: Box::new(right) };
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.binary(0)?;
+        while self.is_match(TokenType::And)? {
+            let right = self.nested_expression(|parser| parser.binary(0))?;
+            expr = Expr::Logical { operator: TokenType::And, left: Box::new(expr), right: Box::new(right) };
+        }
+        Ok(expr)
+    }
+
+    // Left associative binary operators, from the lowest precedence level
+    fn binary(&mut self, level: usize) -> ParseResult<Expr> {
+        const LEVELS: [&[TokenType]; 4] = [
+            &[TokenType::EqualEqual, TokenType::BangEqual],
+            &[TokenType::Greater, TokenType::GreaterEqual, TokenType::Less, TokenType::LessEqual],
+            &[TokenType::Plus, TokenType::Minus],
+            &[TokenType::Star, TokenType::Slash],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut expr = self.binary(level + 1)?;
+        while LEVELS[level].contains(&self.current.get_token_type()) {
+            let operator = self.current.get_token_type();
+            self.advance()?;
+            let right = self.nested_expression(|parser| parser.binary(level + 1))?;
+            let line = self.previous.get_line();
+            expr = Expr::Binary { operator, left: Box::new(expr), right: Box::new(right), line };
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        let operator = self.current.get_token_type();
+        if operator == TokenType::Bang || operator == TokenType::Minus {
+            self.advance()?;
+            let operand = self.nested_expression(Parser::unary)?;
+            let line = self.previous.get_line();
+            return Ok(Expr::Unary { operator, operand: Box::new(operand), line });
+        }
+        self.call()
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
+        while self.is_match(TokenType::LeftParen)? {
+            let mut args = vec![];
+            if !self.check(TokenType::RightParen) {
+                loop {
+                    if args.len() == u8::MAX as usize {
+                        return self.error(&format!("Can't have more than {} arguments.", u8::MAX));
+                    }
+                    args.push(self.expression()?);
+                    if !self.is_match(TokenType::Comma)? {
+                        break;
+                    }
+                }
+            }
+            self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+            let line = self.previous.get_line();
+            expr = Expr::Call { callee: Box::new(expr), args, line };
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        self.advance()?;
+        let lexeme = self.previous.get_lexeme();
+        let expr = match self.previous.get_token_type() {
+            TokenType::Number => Expr::Number(lexeme.parse::<f64>().unwrap_or_default()),
+            TokenType::String => Expr::Str(lexeme[1..(lexeme.len() - 1)].to_string()),
+            TokenType::True => Expr::Bool(true),
+            TokenType::False => Expr::Bool(false),
+            TokenType::Nil => Expr::Nil,
+            TokenType::Identifier => Expr::Variable { name: lexeme.to_string(), line: self.previous.get_line() },
+            TokenType::LeftParen => {
+                let expr = self.expression()?;
+                self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+                Expr::Grouping(Box::new(expr))
+            },
+            _ => return self.error("Expect expression."),
+        };
+        Ok(expr)
+    }
+
+    fn begin_scope(&mut self) {
+        self.functions.last_mut().unwrap().scopes.push(vec![]);
+    }
+
+    fn end_scope(&mut self) {
+        self.functions.last_mut().unwrap().scopes.pop();
+    }
+
+    fn declare(&mut self, name: &str) {
+        if let Some(scope) = self.functions.last_mut().unwrap().scopes.last_mut() {
+            scope.push(name.to_string());
+        }
+    }
+
+    fn check_redeclaration(&self, name: &str) -> ParseResult<()> {
+        match self.functions.last().unwrap().scopes.last() {
+            Some(scope) if scope.iter().any(|declared| declared == name) =>
+                self.error("Already a variable with this name in scope"),
+            _ => Ok(()),
+        }
+    }
+
+    fn advance(&mut self) -> ParseResult<()> {
+        // The scanner returns nothing after the end of the source
+        let next = self.scanner.next().unwrap_or_else(|| self.current.clone());
+        self.previous = std::mem::replace(&mut self.current, next);
+        if self.current.get_token_type() == TokenType::Error {
+            return self.error_at_current("a scan error occurred");
+        }
+        Ok(())
+    }
+
+    fn check(&self, expected_type: TokenType) -> bool {
+        self.current.get_token_type() == expected_type
+    }
+
+    fn is_match(&mut self, expected_type: TokenType) -> ParseResult<bool> {
+        if !self.check(expected_type) {
+            return Ok(false);
+        }
+        self.advance()?;
+        Ok(true)
+    }
+
+    fn consume(&mut self, expected_type: TokenType, message: &str) -> ParseResult<()> {
+        if self.check(expected_type) {
+            return self.advance();
+        }
+        self.error_at_current(message)
+    }
+
+    // The depths are counted where the compiler counts them, so that both
+    // report a program that is nested too deeply at the same token
+    fn nested_expression<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.expr_depth == MAX_DEPTH {
+            return self.error_at_current("Too much nesting.");
+        }
+        self.expr_depth += 1;
+        let result = parse(self);
+        self.expr_depth -= 1;
+        result
+    }
+
+    fn nested_statement<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.stmt_depth == MAX_DEPTH {
+            return self.error_at_current("Too much nesting.");
+        }
+        self.stmt_depth += 1;
+        let result = parse(self);
+        self.stmt_depth -= 1;
+        result
+    }
+
+    fn error<T>(&self, message: &str) -> ParseResult<T> {
+        Err(report(&self.previous, message))
+    }
+
+    fn error_at_current<T>(&self, message: &str) -> ParseResult<T> {
+        Err(report(&self.current, message))
+    }
+
+}
+
+fn report(token: &Token, message: &str) -> String {
+    let location = match token.get_token_type() {
+        TokenType::Eof => " at end".to_string(),
+        TokenType::Error => String::new(),
+        _ => format!(" at '{}'", token.get_lexeme()),
+    };
+    format!("[line {}] Error{}: {}", token.get_line(), location, message)
+}
+
+// How the execution of a statement ends
+enum Flow {
+    Next,
+    Continue,
+    Return(Value),
+}
+
+// Variables of a function call. Variables declared at depth 0
+// of the top level are globals.
+struct Frame {
+    locals: Vec<(String, Value)>,
+    depth: usize,
+}
+
+type ExecResult<T> = Result<T, RuntimeError>;
+
+// Calls nest on the native stack, so deep recursion needs a
+// lower 'max_frames' than the VM. The other limits are not supported.
+pub struct ReferenceInterpreter {
+    heap: HeapManager,
+    globals: HashMap<String, Value>,
+    functions: HashMap<HeapRef<FunData>, Rc<Function>>,
+    frames: Vec<Frame>,
+    config: VmConfig,
+    script_args: Vec<String>,
+    output: Box<dyn Write>,
+    compile_error: Option<String>,
+    last_error: Option<RuntimeError>,
+}
+
+impl Default for ReferenceInterpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReferenceInterpreter {
+
+    pub fn new() -> ReferenceInterpreter {
+        let mut heap = HeapManager::new();
+        let mut globals = HashMap::new();
+        for (name, arity, native_fn) in NATIVE_FUNCTIONS {
+            let native = heap
+                .malloc(NativeFunData::new(name, arity, native_fn))
+                .expect("the heap has no limits yet");
+            globals.insert(name.to_string(), Value::NativeFun(native));
+        }
+
+        ReferenceInterpreter {
+            heap,
+            globals,
+            functions: HashMap::new(),
+            frames: vec![],
+            config: VmConfig::default(),
+            script_args: vec![],
+            output: Box::new(io::stdout()),
+            compile_error: None,
+            last_error: None,
+        }
+    }
+
+    pub fn set_vm_config(&mut self, config: VmConfig) {
+        self.config = config;
+    }
+
+    pub fn set_script_args(&mut self, script_args: Vec<String>) {
+        self.script_args = script_args;
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // The first error of the last source, as the compiler reports it
+    pub fn compile_error(&self) -> Option<&str> {
+        self.compile_error.as_deref()
+    }
+
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.last_error.as_ref()
+    }
+
+    // Globals are kept from one source to the next, like in a session
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+
+        self.compile_error = None;
+        self.last_error = None;
+
+        let statements = match Parser::new(source).parse() {
+            Ok(statements) => statements,
+            Err(report) => {
+                self.compile_error = Some(report);
+                return InterpretResult::CompileError;
+            }
+        };
+
+        self.frames = vec![Frame { locals: vec![], depth: 0 }];
+        let result = self.execute_all(&statements);
+        self.frames.clear();
+
+        match result {
+            Ok(_) => InterpretResult::Ok,
+            Err(error) => {
+                self.last_error = Some(error);
+                InterpretResult::RuntimeError
+            }
+        }
+    }
+
+    fn execute_all(&mut self, statements: &[Stmt]) -> ExecResult<Flow> {
+        for statement in statements {
+            match self.execute(statement)? {
+                Flow::Next => (),
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn execute(&mut self, statement: &Stmt) -> ExecResult<Flow> {
+        match statement {
+            Stmt::Print(expr) => {
+                let value = self.evaluate(expr)?;
+                // Like the VM, a broken output does not stop the program
+                let _ = writeln!(self.output, "{}", value.display(&self.heap));
+            },
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+            },
+            Stmt::Var { name, initializer } => {
+                let value = match initializer {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                self.define(name, value);
+            },
+            Stmt::Fun(function) => {
+                let fun_data = FunData::new(&function.name, function.params.len() as u8, Chunk::new());
+                let fun_ref = self.heap
+                    .malloc(fun_data)
+                    .map_err(|error| heap_error(&error.to_string()))?;
+                self.functions.insert(fun_ref, function.clone());
+                self.define(&function.name, Value::Fun(fun_ref));
+            },
+            Stmt::Block(statements) => return self.in_scope(|interpreter| interpreter.execute_all(statements)),
+            Stmt::If { condition, then_branch, else_branch } => {
+                if !self.evaluate(condition)?.is_falsey() {
+                    return self.execute(then_branch);
+                } else if let Some(else_branch) = else_branch {
+                    return self.execute(else_branch);
+                }
+            },
+            Stmt::While { condition, body } => {
+                while !self.evaluate(condition)?.is_falsey() {
+                    if let Flow::Return(value) = self.execute(body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            },
+            Stmt::For { initializer, condition, increment, body } => return self.in_scope(|interpreter| {
+                if let Some(initializer) = initializer {
+                    interpreter.execute(initializer)?;
+                }
+                loop {
+                    if let Some(condition) = condition {
+                        if interpreter.evaluate(condition)?.is_falsey() {
+                            return Ok(Flow::Next);
+                        }
+                    }
+                    if let Flow::Return(value) = interpreter.execute(body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                    if let Some(increment) = increment {
+                        interpreter.evaluate(increment)?;
+                    }
+                }
+            }),
+            Stmt::Switch { subject, cases, default } => return self.in_scope(|interpreter| {
+                let subject = interpreter.evaluate(subject)?;
+                for (value, statements) in cases {
+                    if interpreter.evaluate(value)? == subject {
+                        return interpreter.execute_all(statements);
+                    }
+                }
+                match default {
+                    Some(statements) => interpreter.execute_all(statements),
+                    None => Ok(Flow::Next),
+                }
+            }),
+            Stmt::Continue => return Ok(Flow::Continue),
+            Stmt::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            },
+            Stmt::Assert { condition, message, text, line } => {
+                if self.evaluate(condition)?.is_falsey() {
+                    let mut report = format!("Assertion failed on line {}: {}", line, text);
+                    let message = match message {
+                        Some(expr) => self.evaluate(expr)?,
+                        None => Value::Nil,
+                    };
+                    if message != Value::Nil {
+                        report = format!("{} ({})", report, message.display(&self.heap));
+                    }
+                    return Err(error(ErrorKind::Assertion, &report, *line));
+                }
+            },
+        }
+        Ok(Flow::Next)
+    }
+
+    fn in_scope(&mut self, execute: impl FnOnce(&mut Self) -> ExecResult<Flow>) -> ExecResult<Flow> {
+        let frame = self.frames.last_mut().unwrap();
+        let num_locals = frame.locals.len();
+        frame.depth += 1;
+        let flow = execute(self);
+        let frame = self.frames.last_mut().unwrap();
+        frame.locals.truncate(num_locals);
+        frame.depth -= 1;
+        flow
+    }
+
+    fn define(&mut self, name: &str, value: Value) {
+        let frame = self.frames.last_mut().unwrap();
+        if frame.depth == 0 {
+            self.globals.insert(name.to_string(), value);
+        } else {
+            frame.locals.push((name.to_string(), value));
+        }
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> ExecResult<Value> {
+        let value = match expr {
+            Expr::Number(x) => Value::Number(*x),
+            Expr::Str(s) => Value::Str(self.heap.intern(s).map_err(|error| heap_error(&error.to_string()))?),
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Nil => Value::Nil,
+            Expr::Variable { name, line } => {
+                let local = self.frames.last().unwrap().locals.iter().rev().find(|(local, _)| local == name);
+                match local.map(|(_, value)| value).or_else(|| self.globals.get(name)) {
+                    Some(value) => *value,
+                    None => return Err(undefined_variable(name, *line)),
+                }
+            },
+            Expr::Assign { name, value, line } => {
+                let value = self.evaluate(value)?;
+                let frame = self.frames.last_mut().unwrap();
+                let local = frame.locals.iter_mut().rev().find(|(local, _)| local == name);
+                match local.map(|(_, value)| value).or_else(|| self.globals.get_mut(name)) {
+                    Some(variable) => *variable = value,
+                    None => return Err(undefined_variable(name, *line)),
+                }
+                value
+            },
+            Expr::Unary { operator, operand, line } => {
+                let operand = self.evaluate(operand)?;
+                match (operator, operand) {
+                    (TokenType::Bang, _) => Value::Bool(operand.is_falsey()),
+                    (_, Value::Number(x)) => Value::Number(-x),
+                    _ => return Err(error(ErrorKind::Runtime, "Operand must be a number.", *line)),
+                }
+            },
+            Expr::Binary { operator, left, right, line } => {
+                let a = self.evaluate(left)?;
+                let b = self.evaluate(right)?;
+                self.binary(*operator, a, b, *line)?
+            },
+            Expr::Logical { operator, left, right } => {
+                let left = self.evaluate(left)?;
+                if left.is_falsey() == (*operator == TokenType::And) {
+                    left
+                } else {
+                    self.evaluate(right)?
+                }
+            },
+            Expr::Call { callee, args, line } => {
+                let callee = self.evaluate(callee)?;
+                let mut values = vec![];
+                for arg in args {
+                    values.push(self.evaluate(arg)?);
+                }
+                self.call(callee, values, *line)?
+            },
+            Expr::Grouping(expr) => self.evaluate(expr)?,
+        };
+        Ok(value)
+    }
+
+    // '>=' and '<=' are negations of '<' and '>' like in the VM, which
+    // makes a difference for NaN
+    fn binary(&mut self, operator: TokenType, a: Value, b: Value, line: i32) -> ExecResult<Value> {
+        let value = match operator {
+            TokenType::EqualEqual => Value::Bool(a == b),
+            TokenType::BangEqual => Value::Bool(a != b),
+            TokenType::GreaterEqual => Value::Bool(self.arithmetic(TokenType::Less, a, b, line)?.is_falsey()),
+            TokenType::LessEqual => Value::Bool(self.arithmetic(TokenType::Greater, a, b, line)?.is_falsey()),
+            _ => self.arithmetic(operator, a, b, line)?,
+        };
+        Ok(value)
+    }
+
+    fn arithmetic(&mut self, operator: TokenType, a: Value, b: Value, line: i32) -> ExecResult<Value> {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) => Ok(match operator {
+                TokenType::Plus => Value::Number(x + y),
+                TokenType::Minus => Value::Number(x - y),
+                TokenType::Star => Value::Number(x * y),
+                TokenType::Slash => Value::Number(x / y),
+                TokenType::Greater => Value::Bool(x > y),
+                _ => Value::Bool(x < y),
+            }),
+            (Value::Str(s1), Value::Str(s2)) if operator == TokenType::Plus => s1
+                .concat(&s2, &mut self.heap)
+                .map(Value::Str)
+                .map_err(|err| error(ErrorKind::HeapLimit, &err.to_string(), line)),
+            (Value::Str(_), Value::Str(_)) => Err(error(ErrorKind::Runtime, "Operator not supported for strings.", line)),
+            _ => Err(error(ErrorKind::Runtime, "Operands must be numbers.", line)),
+        }
+    }
+
+    fn call(&mut self, callee: Value, args: Vec<Value>, line: i32) -> ExecResult<Value> {
+        match callee {
+            Value::Fun(fun_ref) => {
+                let function = self.functions[&fun_ref].clone();
+                if function.params.len() != args.len() {
+                    let message = format!("Expected {} arguments but got {}", function.params.len(), args.len());
+                    return Err(error(ErrorKind::Runtime, &message, line));
+                }
+                if self.frames.len() >= self.config.max_frames {
+                    return Err(error(ErrorKind::StackOverflow, "Stack overflow.", line));
+                }
+
+                // The function can refer to itself by its name
+                let mut locals = vec![(function.name.clone(), callee)];
+                locals.extend(function.params.iter().cloned().zip(args));
+                self.frames.push(Frame { locals, depth: 1 });
+                let flow = self.execute_all(&function.body);
+                self.frames.pop();
+
+                match flow? {
+                    Flow::Return(value) => Ok(value),
+                    _ => Ok(Value::Nil),
+                }
+            },
+            Value::NativeFun(native_ref) => {
+                let native = self.heap.get_content(&native_ref);
+                let (arity, native_fn) = (native.arity, native.fun);
+                if arity as usize != args.len() {
+                    let message = format!("Expected {} arguments but got {}", arity, args.len());
+                    return Err(error(ErrorKind::Runtime, &message, line));
+                }
+
+                let mut ctx = NativeContext {
+                    heap: &mut self.heap,
+                    script_args: &self.script_args,
+                };
+                match native_fn(&mut ctx, &args) {
+                    Ok(value) => Ok(value),
+                    Err(NativeError::Message(message)) => Err(error(ErrorKind::Runtime, &message, line)),
+                    Err(NativeError::Assertion(message)) => Err(error(ErrorKind::Assertion, &message, line)),
+                    Err(NativeError::Heap(err)) => Err(error(ErrorKind::HeapLimit, &err.to_string(), line)),
+                }
+            },
+            _ => {
+                let message = format!("{} is not a function.", callee.display(&self.heap));
+                Err(error(ErrorKind::Runtime, &message, line))
+            },
+        }
+    }
+
+}
+
+fn error(kind: ErrorKind, message: &str, line: i32) -> RuntimeError {
+    RuntimeError { kind, message: message.to_string(), line }
+}
+
+fn undefined_variable(name: &str, line: i32) -> RuntimeError {
+    error(ErrorKind::Runtime, &format!("Undefined variable '{}'.", name), line)
+}
+
+// The reference has no heap limits, so this only happens when it
+// runs out of handles
+fn heap_error(message: &str) -> RuntimeError {
+    error(ErrorKind::HeapLimit, message, 0)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::ReferenceInterpreter;
+    use crate::frontend::interpreter::CapturedOutput;
+    use crate::backend::vm::{InterpretResult, ErrorKind};
+
+    #[test]
+    fn evaluate_program() {
+
+        let captured = CapturedOutput::default();
+        let mut reference = ReferenceInterpreter::new();
+        reference.set_output(Box::new(captured.clone()));
+
+        let source = "fun add(a, b) { return a + b; }\nvar s = \"\";\nfor (var i = 0; i < 3; i = i + 1) { if (i == 1) continue; s = s + \"x\"; }\nprint add(1, 2); print s; print add;";
+        assert_eq!(reference.interpret(source), InterpretResult::Ok);
+        assert_eq!(captured.text(), "3\nxx\n<fn add/2>\n");
+
+        assert_eq!(reference.interpret("print add(1,\n  nil);"), InterpretResult::RuntimeError);
+        let error = reference.last_error().unwrap();
+        assert_eq!((error.kind, error.message.as_str(), error.line), (ErrorKind::Runtime, "Operands must be numbers.", 1));
+    }
+
+    #[test]
+    fn report_compile_errors() {
+
+        let mut reference = ReferenceInterpreter::new();
+        assert_eq!(reference.interpret("var a = 1;\n{ var a = 2; var a = 3; }"), InterpretResult::CompileError);
+        assert_eq!(reference.compile_error(), Some("[line 2] Error at 'a': Already a variable with this name in scope"));
+        assert_eq!(reference.interpret("print 1"), InterpretResult::CompileError);
+        assert_eq!(reference.compile_error(), Some("[line 1] Error at end: Expect ';' after value."));
+
+        assert_eq!(reference.interpret(&format!("{}{}", "{".repeat(100_000), "}".repeat(100_000))), InterpretResult::CompileError);
+        assert_eq!(reference.compile_error(), Some("[line 1] Error at '{': Too much nesting."));
+        assert_eq!(reference.interpret(&format!("print {}1;", "-".repeat(100_000))), InterpretResult::CompileError);
+        assert_eq!(reference.compile_error(), Some("[line 1] Error at '-': Too much nesting."));
+    }
+
+}
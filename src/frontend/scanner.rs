@@ -1,59 +1,92 @@
 use std::{str::Chars, collections::{VecDeque, HashMap}};
 use super::token::{Token, TokenType};
 
+pub const KEYWORDS: [(&str, TokenType); 21] = [
+    ("and", TokenType::And),
+    ("assert", TokenType::Assert),
+    ("case", TokenType::Case),
+    ("class", TokenType::Class),
+    ("continue", TokenType::Continue),
+    ("default", TokenType::Default),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("switch", TokenType::Switch),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
+
 pub struct Scanner<'a> {
     source_iter: Chars<'a>,
     lookahead: VecDeque<char>,
     current_lexeme: String,
     current_line: i32,
+    current_column: i32, // of the next character
+    token_column: i32, // where the current token starts
     keywords: HashMap<String, TokenType>,
     at_end: bool,
+    keep_comments: bool,
 }
 
 impl <'a> Scanner<'a> {
 
-    pub fn new(source: &str) -> Scanner {
-
-        let mut keywords = HashMap::new();
-
-        keywords.insert("and".to_string(), TokenType::And);
-        keywords.insert("case".to_string(), TokenType::Case);
-        keywords.insert("class".to_string(), TokenType::Class);
-        keywords.insert("continue".to_string(), TokenType::Continue);
-        keywords.insert("default".to_string(), TokenType::Default);
-        keywords.insert("else".to_string(), TokenType::Else);
-        keywords.insert("false".to_string(), TokenType::False);
-        keywords.insert("for".to_string(), TokenType::For);
-        keywords.insert("fun".to_string(), TokenType::Fun);
-        keywords.insert("if".to_string(), TokenType::If);
-        keywords.insert("nil".to_string(), TokenType::Nil);
-        keywords.insert("or".to_string(), TokenType::Or);
-        keywords.insert("print".to_string(), TokenType::Print);
-        keywords.insert("return".to_string(), TokenType::Return);
-        keywords.insert("super".to_string(), TokenType::Super);
-        keywords.insert("switch".to_string(), TokenType::Switch);
-        keywords.insert("this".to_string(), TokenType::This);
-        keywords.insert("true".to_string(), TokenType::True);
-        keywords.insert("var".to_string(), TokenType::Var);
-        keywords.insert("while".to_string(), TokenType::While);
+    pub fn new(source: &str) -> Scanner<'_> {
+        Self::create(source, false)
+    }
+
+    // Line comments and the shebang line become Comment tokens
+    pub fn new_with_comments(source: &str) -> Scanner<'_> {
+        Self::create(source, true)
+    }
+
+    fn create(source: &str, keep_comments: bool) -> Scanner<'_> {
+
+        let keywords = KEYWORDS
+            .iter()
+            .map(|(keyword, token_type)| (keyword.to_string(), *token_type))
+            .collect();
         
-        Scanner {
+        let mut scanner = Scanner {
             source_iter: source.chars(),
             lookahead: VecDeque::new(),
             current_lexeme: String::new(),
             current_line: 1,
+            current_column: 1,
+            token_column: 1,
             keywords,
             at_end: false,
+            keep_comments,
+        };
+
+        // Allow scripts to be executable on unix-like systems
+        if source.starts_with("#!") && !keep_comments {
+            scanner.skip_line_comment();
         }
+
+        scanner
     }
 
     fn advance(&mut self) -> Option<char> {
-        if self.lookahead.is_empty() {
+        let ch = if self.lookahead.is_empty() {
             self.source_iter.next()
         } else {
-            let ch = self.lookahead.pop_front().unwrap();
-            Some(ch)
+            self.lookahead.pop_front()
+        };
+        match ch {
+            Some('\n') => self.current_column = 1,
+            Some(_) => self.current_column += 1,
+            None => (),
         }
+        ch
     }
 
     fn peek(&mut self, idx: usize) -> Option<char> {
@@ -82,6 +115,7 @@ impl <'a> Scanner<'a> {
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
             '+' => self.make_token(TokenType::Plus),
+            '/' if self.keep_comments && self.peek(0) == Some('/') => self.scan_comment(),
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
             '!' => self.make_one_or_two_char_token('=', 
@@ -93,6 +127,8 @@ impl <'a> Scanner<'a> {
             '>' => self.make_one_or_two_char_token('=', 
                 TokenType::Greater, TokenType::GreaterEqual),
             '"' => self.scan_string(),
+            '#' if self.keep_comments && self.current_line == 1 && self.token_column == 1
+                && self.peek(0) == Some('!') => self.scan_comment(),
             _ => {
                 if ch.is_numeric() {
                     self.scan_number()
@@ -107,14 +143,10 @@ impl <'a> Scanner<'a> {
     }
 
     fn scan_identifier(&mut self) -> Token {
-        loop {
-            if let Some(ch) = self.peek(0) {
-                if ch.is_alphanumeric() || ch == '_' {
-                    self.current_lexeme.push(ch);
-                    self.advance();
-                } else {
-                    break;
-                }
+        while let Some(ch) = self.peek(0) {
+            if ch.is_alphanumeric() || ch == '_' {
+                self.current_lexeme.push(ch);
+                self.advance();
             } else {
                 break;
             }
@@ -126,25 +158,22 @@ impl <'a> Scanner<'a> {
             .unwrap_or(&TokenType::Identifier);
 
         Token::new(
-            token_type.clone(),
+            *token_type,
             lexeme,
-            self.current_line)
+            self.current_line,
+            self.token_column)
     }
 
     fn scan_number(&mut self) -> Token {
         let mut dot_found = false;
-        loop {
-            if let Some(ch) = self.peek(0) {
-                if ch.is_numeric() {
-                    self.current_lexeme.push(ch);
-                    self.advance();
-                } else {
-                    if ch == '.' {
-                        dot_found = true;
-                    }
-                    break;
-                }
+        while let Some(ch) = self.peek(0) {
+            if ch.is_numeric() {
+                self.current_lexeme.push(ch);
+                self.advance();
             } else {
+                if ch == '.' {
+                    dot_found = true;
+                }
                 break;
             }
         }
@@ -153,7 +182,8 @@ impl <'a> Scanner<'a> {
             return Token::new(
                 TokenType::Number,
                 self.current_lexeme.clone(),
-                self.current_line);
+                self.current_line,
+                self.token_column);
         }
 
         let mut has_fraction = false;
@@ -168,29 +198,27 @@ impl <'a> Scanner<'a> {
             return Token::new(
                 TokenType::Number,
                 self.current_lexeme.clone(),
-                self.current_line);
+                self.current_line,
+                self.token_column);
         }
 
         self.current_lexeme.push('.');
         self.advance();
 
-        loop {
-            if let Some(ch) = self.peek(0) {
-                if ch.is_numeric() {
-                    self.current_lexeme.push(ch);
-                    self.advance();
-                } else {
-                    break;
-                }
+        while let Some(ch) = self.peek(0) {
+            if ch.is_numeric() {
+                self.current_lexeme.push(ch);
+                self.advance();
             } else {
                 break;
             }
         }
 
-        return Token::new(
+        Token::new(
             TokenType::Number,
             self.current_lexeme.clone(),
-            self.current_line)
+            self.current_line,
+            self.token_column)
     
     }
     
@@ -209,13 +237,15 @@ impl <'a> Scanner<'a> {
                 return Token::new(
                     TokenType::Error,
                     "Unterminated string.".to_string(),
-                    start_line);
+                    start_line,
+                    self.token_column);
             }
         }
         Token::new(
             TokenType::String,
             self.current_lexeme.clone(),
-            start_line)
+            start_line,
+            self.token_column)
     }
 
     fn make_one_or_two_char_token(&mut self, 
@@ -240,7 +270,8 @@ impl <'a> Scanner<'a> {
         Token::new(
             token_type, 
             self.current_lexeme.clone(), 
-            self.current_line)
+            self.current_line,
+            self.token_column)
     } 
 
     fn skip_whitespace(&mut self) {
@@ -260,7 +291,7 @@ impl <'a> Scanner<'a> {
                     self.advance();
                 },
                 '/' => if let Some(ch2) = self.peek(1) {
-                    if ch2 == '/' {
+                    if ch2 == '/' && !self.keep_comments {
                         self.skip_line_comment();
                     } else {
                         break;
@@ -273,16 +304,23 @@ impl <'a> Scanner<'a> {
         }
     }
 
+    fn scan_comment(&mut self) -> Token {
+        while let Some(ch) = self.peek(0) {
+            if ch == '\n' {
+                break;
+            }
+            self.current_lexeme.push(ch);
+            self.advance();
+        }
+        self.make_token(TokenType::Comment)
+    }
+
     fn skip_line_comment(&mut self) {
         self.advance();
         self.advance();
-        loop {
-            if let Some(ch) = self.peek(0) {
-                if ch != '\n' {
-                    self.advance();
-                } else {
-                    break;
-                }
+        while let Some(ch) = self.peek(0) {
+            if ch != '\n' {
+                self.advance();
             } else {
                 break;
             }
@@ -299,6 +337,7 @@ impl <'a> Iterator for Scanner<'a> {
     fn next(&mut self) -> Option<Self::Item> {
 
         self.skip_whitespace();
+        self.token_column = self.current_column;
         
         if let Some(ch) = self.advance() {
             let token = self.scan_token(ch);
@@ -337,23 +376,23 @@ mod tests {
 
         assert_eq!(
             tokens[0],
-            Token::new(Var, "var".to_string(), 1)
+            Token::new(Var, "var".to_string(), 1, 1)
         );
         assert_eq!(
             tokens[1],
-            Token::new(Identifier, "answer".to_string(), 1)
+            Token::new(Identifier, "answer".to_string(), 1, 5)
         );
         assert_eq!(
             tokens[2],
-            Token::new(Equal, "=".to_string(), 1)
+            Token::new(Equal, "=".to_string(), 1, 12)
         );
         assert_eq!(
             tokens[3],
-            Token::new(Number, "42.0".to_string(), 1)
+            Token::new(Number, "42.0".to_string(), 1, 14)
         );
         assert_eq!(
             tokens[4],
-            Token::new(Semicolon, ";".to_string(), 1)
+            Token::new(Semicolon, ";".to_string(), 1, 18)
         );
         
     }
@@ -372,55 +411,55 @@ mod tests {
 
         assert_eq!(
             tokens[0],
-            Token::new(Fun, "fun".to_string(), 2)
+            Token::new(Fun, "fun".to_string(), 2, 9)
         );
         assert_eq!(
             tokens[1],
-            Token::new(Identifier, "say_hello".to_string(), 2)
+            Token::new(Identifier, "say_hello".to_string(), 2, 13)
         );
         assert_eq!(
             tokens[2],
-            Token::new(LeftParen, "(".to_string(), 2)
+            Token::new(LeftParen, "(".to_string(), 2, 22)
         );
         assert_eq!(
             tokens[3],
-            Token::new(Identifier, "name".to_string(), 2)
+            Token::new(Identifier, "name".to_string(), 2, 23)
         );
         assert_eq!(
             tokens[4],
-            Token::new(RightParen, ")".to_string(), 2)
+            Token::new(RightParen, ")".to_string(), 2, 27)
         );
         assert_eq!(
             tokens[5],
-            Token::new(LeftBrace, "{".to_string(), 2)
+            Token::new(LeftBrace, "{".to_string(), 2, 29)
         );
         assert_eq!(
             tokens[6],
-            Token::new(Print, "print".to_string(), 3)
+            Token::new(Print, "print".to_string(), 3, 13)
         );
         assert_eq!(
             tokens[7],
-            Token::new(String, "\"Hello \"".to_string(), 3)
+            Token::new(String, "\"Hello \"".to_string(), 3, 19)
         );
         assert_eq!(
             tokens[8],
-            Token::new(Plus, "+".to_string(), 3)
+            Token::new(Plus, "+".to_string(), 3, 28)
         );
         assert_eq!(
             tokens[9],
-            Token::new(Identifier, "name".to_string(), 3)
+            Token::new(Identifier, "name".to_string(), 3, 30)
         );
         assert_eq!(
             tokens[10],
-            Token::new(Semicolon, ";".to_string(), 3)
+            Token::new(Semicolon, ";".to_string(), 3, 34)
         );
         assert_eq!(
             tokens[11],
-            Token::new(RightBrace, "}".to_string(), 4)
+            Token::new(RightBrace, "}".to_string(), 4, 9)
         );
         assert_eq!(
             tokens[12],
-            Token::new(Eof, "".to_string(), 5)
+            Token::new(Eof, "".to_string(), 5, 9)
         );
 
     }
@@ -442,6 +481,33 @@ mod tests {
 
     }
 
+    #[test]
+    fn skip_shebang_line() {
+
+        let source = "#!/usr/bin/env rlox\nprint 42;";
+
+        let tokens: Vec<Token> = scan(source);
+
+        assert_eq!(
+            tokens[0],
+            Token::new(Print, "print".to_string(), 2, 1)
+        );
+        assert_eq!(tokens.len(), 4);
+    }
+
+    #[test]
+    fn keep_comments() {
+
+        let source = "#!/usr/bin/env rlox\nprint 1; // one\n// two\n";
+
+        let tokens: Vec<Token> = Scanner::new_with_comments(source).collect();
+
+        assert_eq!(tokens[0], Token::new(Comment, "#!/usr/bin/env rlox".to_string(), 1, 1));
+        assert_eq!(tokens[4], Token::new(Comment, "// one".to_string(), 2, 10));
+        assert_eq!(tokens[5], Token::new(Comment, "// two".to_string(), 3, 1));
+        assert_eq!(tokens[6].get_token_type(), Eof);
+    }
+
     fn scan(source: &str) -> Vec<Token> {
         Scanner::new(source).collect()
     }
@@ -0,0 +1,88 @@
+// What the compiler learns about the names in a source, used by the editor support
+
+use super::token::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    Function { params: Vec<String> },
+    Variable,
+    Parameter,
+}
+
+// Definition of a name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Span,
+    pub global: bool,
+}
+
+// Use of a name, resolved like the compiler resolves variables
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+    pub symbol: Option<usize>, // index into the symbols, None for undefined globals
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+    pub report: String, // as printed, e.g. "[line 1] Error at ';': Expect expression."
+}
+
+impl Diagnostic {
+
+    // The location is empty or e.g. " at ';'"
+    pub(crate) fn new(span: Span, location: &str, message: &str) -> Diagnostic {
+        let report = format!("[line {}] Error{}: {}", span.line, location, message);
+        Diagnostic { span, message: message.to_string(), report }
+    }
+
+}
+
+#[derive(Debug, Default)]
+pub struct SourceIndex {
+    pub symbols: Vec<Symbol>,
+    pub references: Vec<Reference>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl SourceIndex {
+
+    pub(crate) fn add_symbol(&mut self, name: &str, kind: SymbolKind, span: Span, global: bool) -> usize {
+        self.symbols.push(Symbol { name: name.to_string(), kind, span, global });
+        self.symbols.len() - 1
+    }
+
+    pub(crate) fn add_reference(&mut self, name: &str, span: Span, symbol: Option<usize>) {
+        self.references.push(Reference { name: name.to_string(), span, symbol });
+    }
+
+    // Globals can be used before they are defined, e.g. in functions,
+    // so references to them are resolved when the whole source is known
+    pub(crate) fn resolve_globals(&mut self) {
+        for reference in self.references.iter_mut().filter(|reference| reference.symbol.is_none()) {
+            reference.symbol = self.symbols
+                .iter()
+                .position(|symbol| symbol.global && symbol.name == reference.name);
+        }
+    }
+
+    // Index of the symbol that is defined or referenced at the position
+    pub fn symbol_at(&self, line: i32, column: i32) -> Option<usize> {
+        let defined = self.symbols
+            .iter()
+            .position(|symbol| symbol.span.contains(line, column));
+        defined.or_else(|| self.reference_at(line, column)?.symbol)
+    }
+
+    pub fn reference_at(&self, line: i32, column: i32) -> Option<&Reference> {
+        self.references
+            .iter()
+            .find(|reference| reference.span.contains(line, column))
+    }
+
+}
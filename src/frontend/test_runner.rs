@@ -0,0 +1,197 @@
+// Runs Lox scripts that state their expected behavior in comments, like the
+// test suite of Crafting Interpreters:
+//
+//   print 1 + 2;        // expect: 3
+//   print nil.x;        // expect runtime error: Only instances have properties.
+//   var a = ;           // Error at ';': Expect expression.
+//   // [line 5] Error at end: Expect '}' after block.
+
+use std::{io, path::{Path, PathBuf}, fs};
+use crate::backend::vm::VmConfig;
+use super::interpreter::{Session, CapturedOutput, read_source};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    pub output: Vec<(i32, String)>, // line of the comment and expected text
+    pub compile_errors: Vec<String>, // as reported, e.g. "[line 1] Error at ';': Expect expression."
+    pub runtime_error: Option<(i32, String)>,
+}
+
+impl Expectations {
+
+    pub fn parse(source: &str) -> Expectations {
+
+        let mut expectations = Expectations::default();
+
+        for (idx, text) in source.lines().enumerate() {
+            let line = idx as i32 + 1;
+            // Looks for the whole marker since a "//" may also be part of a string
+            let after = |marker: &str| text
+                .find(marker)
+                .map(|start| text[start + marker.len()..].trim_end().to_string());
+
+            if let Some(output) = after("// expect: ") {
+                expectations.output.push((line, output));
+            } else if let Some(message) = after("// expect runtime error: ") {
+                expectations.runtime_error = Some((line, message));
+            } else if let Some(error) = after("// [line ").filter(|error| error.contains("] Error")) {
+                expectations.compile_errors.push(format!("[line {}", error));
+            } else if let Some(error) = after("// Error at ") {
+                expectations.compile_errors.push(format!("[line {}] Error at {}", line, error));
+            } else if let Some(error) = after("// Error: ") {
+                expectations.compile_errors.push(format!("[line {}] Error: {}", line, error));
+            }
+        }
+
+        expectations
+    }
+
+}
+
+// Runs the source in a new session and returns why it does not behave
+// as its comments expect, an empty list if it does
+pub fn check_script(source: &str, config: VmConfig) -> Vec<String> {
+
+    let expectations = Expectations::parse(source);
+    let captured = CapturedOutput::default();
+
+    let mut session = Session::new();
+    session.set_vm_config(config);
+    session.set_print_errors(false);
+    session.set_output(Box::new(captured.clone()));
+    session.interpret(source);
+
+    let mut failures = vec![];
+
+    let output = captured.text();
+    let mut actual_lines = output.lines();
+    for (line, expected) in expectations.output.iter() {
+        match actual_lines.next() {
+            Some(actual) if actual == expected => (),
+            Some(actual) => failures.push(format!("Expected output '{}' on line {} and got '{}'.", expected, line, actual)),
+            None => failures.push(format!("Missing expected output '{}' on line {}.", expected, line)),
+        }
+    }
+    for actual in actual_lines {
+        failures.push(format!("Got unexpected output '{}'.", actual));
+    }
+
+    let reports: Vec<&str> = session.compile_errors()
+        .iter()
+        .map(|diagnostic| diagnostic.report.as_str())
+        .collect();
+    for expected in expectations.compile_errors.iter() {
+        if !reports.contains(&expected.as_str()) {
+            failures.push(format!("Missing expected error: {}", expected));
+        }
+    }
+    for report in reports {
+        if !expectations.compile_errors.iter().any(|expected| expected == report) {
+            failures.push(format!("Got unexpected error: {}", report));
+        }
+    }
+
+    match (&expectations.runtime_error, session.last_error()) {
+        (None, None) => (),
+        (Some((line, message)), None) =>
+            failures.push(format!("Expected runtime error '{}' on line {} and got none.", message, line)),
+        (None, Some(error)) =>
+            failures.push(format!("Got unexpected runtime error '{}' on line {}.", error.message, error.line)),
+        (Some((line, message)), Some(error)) => {
+            if *message != error.message {
+                failures.push(format!("Expected runtime error '{}' and got '{}'.", message, error.message));
+            }
+            if *line != error.line {
+                failures.push(format!("Expected runtime error on line {} but was on line {}.", line, error.line));
+            }
+        },
+    }
+
+    failures
+}
+
+// Checks every .lox file in the directory and its subdirectories, or the
+// given file. Fails if a script does not behave as expected.
+pub fn run_tests(path: &str, config: VmConfig) -> Result<(), i32> {
+
+    let mut file_paths = vec![];
+    if let Err(error) = collect_scripts(Path::new(path), &mut file_paths) {
+        eprintln!("Could not read {}: {}", path, error);
+        return Err(74);
+    }
+    file_paths.sort();
+
+    let mut num_failed = 0;
+    for file_path in file_paths.iter() {
+        let file_path = file_path.to_string_lossy();
+        let failures = check_script(&read_source(&file_path)?, config);
+        if !failures.is_empty() {
+            num_failed += 1;
+            println!("FAIL {}", file_path);
+            for failure in failures {
+                println!("     {}", failure);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", file_paths.len() - num_failed, num_failed);
+
+    if num_failed == 0 {
+        Ok(())
+    } else {
+        Err(1)
+    }
+}
+
+fn collect_scripts(path: &Path, file_paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        fs::metadata(path)?;
+        file_paths.push(path.to_path_buf());
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_dir() {
+            collect_scripts(&entry_path, file_paths)?;
+        } else if entry_path.extension().is_some_and(|extension| extension == "lox") {
+            file_paths.push(entry_path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Expectations, check_script};
+    use crate::backend::vm::VmConfig;
+
+    #[test]
+    fn parse_expectations() {
+
+        let source = "print 1; // expect: 1\nvar a = ; // Error at ';': Expect expression.\n// [line 3] Error at end: Expect '}' after block.\nprint -nil; // expect runtime error: Operand must be a number.\nprint \"a//b\"; // expect: a//b";
+        let expectations = Expectations::parse(source);
+
+        assert_eq!(expectations.output, vec![(1, "1".to_string()), (5, "a//b".to_string())]);
+        assert_eq!(expectations.compile_errors, vec![
+            "[line 2] Error at ';': Expect expression.".to_string(),
+            "[line 3] Error at end: Expect '}' after block.".to_string(),
+        ]);
+        assert_eq!(expectations.runtime_error, Some((4, "Operand must be a number.".to_string())));
+    }
+
+    #[test]
+    fn check_scripts() {
+
+        assert!(check_script("print 1 + 2; // expect: 3\nprint \"a\"; // expect: a", VmConfig::default()).is_empty());
+        assert!(check_script("print;  // Error at ';': Expect expression.", VmConfig::default()).is_empty());
+        assert!(check_script("print 1;\nprint -nil; // expect runtime error: Operand must be a number.", VmConfig::default()).len() == 1);
+
+        let failures = check_script("print 2; // expect: 3\n-nil;", VmConfig::default());
+        assert_eq!(failures, vec![
+            "Expected output '3' on line 1 and got '2'.".to_string(),
+            "Got unexpected runtime error 'Operand must be a number.' on line 2.".to_string(),
+        ]);
+    }
+
+}
@@ -1,17 +1,39 @@
+// Position of a piece of source code, columns and lengths count characters
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub line: i32,
+    pub column: i32, // starts with 1
+    pub length: usize,
+}
+
+impl Span {
+
+    pub fn new(line: i32, column: i32, length: usize) -> Span {
+        Span { line, column, length }
+    }
+
+    pub fn contains(&self, line: i32, column: i32) -> bool {
+        self.line == line && self.column <= column && column < self.column + self.length.max(1) as i32
+    }
+
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct Token {
     token_type: TokenType,
     lexeme: String,
     line: i32,
+    column: i32,
 }
 
 impl Token {
 
-    pub fn new(token_type: TokenType, lexeme: String, line: i32) -> Token {
+    pub fn new(token_type: TokenType, lexeme: String, line: i32, column: i32) -> Token {
         Token { 
             token_type, 
             lexeme, 
-            line 
+            line,
+            column,
         }
     }
 
@@ -27,6 +49,19 @@ impl Token {
         self.line
     }
 
+    pub fn get_column(&self) -> i32 {
+        self.column
+    }
+
+    // Error tokens carry a message instead of the source text
+    pub fn span(&self) -> Span {
+        let length = match self.token_type {
+            TokenType::Error | TokenType::Eof => 1,
+            _ => self.lexeme.chars().count(),
+        };
+        Span::new(self.line, self.column, length)
+    }
+
 }
 
 #[derive(Copy, Clone, PartialEq, Debug, Hash, Eq)]
@@ -59,6 +94,7 @@ pub enum TokenType {
     Number,
     // Keywords:
     And,
+    Assert,
     Case,
     Class,
     Continue,
@@ -79,6 +115,7 @@ pub enum TokenType {
     Var,
     While,
     // miscellaneous:
+    Comment, // only scanned on request, e.g. for the formatter
     Error,
     Eof,
 }
\ No newline at end of file
@@ -1,21 +1,368 @@
-use std::env;
-use rlox::frontend::interpreter::{repl, run_file};
+use std::{env, process, fs::File, io, str::FromStr, time::Duration, rc::Rc, cell::RefCell};
+use rlox::{frontend::{interpreter::{repl, run_script, read_source, disassemble_script, dump_tokens}, debugger::Debugger, dap, lsp, test_runner::run_tests, formatter::format_files}, backend::{trace::TraceConfig, observer::VmObserver, vm::VmConfig, profiler::Profiler, coverage::Coverage}};
 
-fn main() -> Result<(), i32> {
+const DEFAULT_COVERAGE_PATH: &str = "lcov.info";
 
-    let args: Vec<String> = env::args().collect();
+// Commands that may be followed by options
+const COMMANDS_WITH_OPTIONS: [&str; 5] = ["run", "-e", "debug", "profile", "test"];
 
-    match args.len() {
-        1 => repl(),
-        2 => match run_file(&args[1]) {
-            Ok(_) => (),
-            Err(error_code) => return Err(error_code)
+const USAGE: &str = "\
+Usage: rlox [options] [command]
+
+Commands:
+  (none)                  start the REPL
+  <file> [args...]        run a script
+  run <file> [args...]    run a script, '-' reads it from standard input
+  -e <code> [args...]     run the given code
+  debug <file> [args...]  run a script in the debugger
+  profile <file> [args...]
+                          run a script and report where it spends 
+                          instructions and time
+  test <path>             run the .lox files in the directory and check 
+                          their '// expect: ...' comments
+  dap                     serve the Debug Adapter Protocol on standard 
+                          input and output
+  lsp                     serve the Language Server Protocol on standard 
+                          input and output
+  fmt [--check] <file>... format scripts in place, '-' formats standard
+                          input to standard output. With '--check' only 
+                          list the scripts that are not formatted
+  disasm <file>           show the bytecode of a script
+  tokens <file>           show the tokens of a script
+
+Options:
+  --trace                 show each instruction before it is executed
+  --trace-fn <name>       only trace the function (repeatable, 'script' 
+                          is the top level)
+  --trace-lines <a>-<b>   only trace the source lines a to b
+  --trace-out <file>      write the trace to the file instead of stderr
+  --folded <file>         write the call stacks of 'profile' to the file
+                          in the folded format of flame graph tools
+  --coverage[=<file>]     write the line coverage of 'run' or '-e' to the
+                          file (default lcov.info) in the LCOV format and
+                          show a summary
+  --max-frames <n>        limit the depth of nested calls (default 1024)
+  --max-stack <n>         limit the number of values on the stack 
+                          (default 65536)
+  --max-instructions <n>  stop a run after n instructions
+  --time-limit <ms>       stop a run after the given milliseconds
+  --max-objects <n>       limit the number of objects on the heap
+  --max-heap-bytes <n>    limit the approximate size of the heap
+  --version               show the version
+  --help                  show this help
+
+Options go in front of the command or right after 'run', '-e', 'debug',
+'profile' and 'test'. Options with a value also accept '--option=value'.
+
+Scripts read their arguments with args(), which returns all of them
+separated by spaces, or one at a time with argc() and arg(index).";
+
+enum Command {
+    Repl,
+    Run { file_path: String, script_args: Vec<String> },
+    Eval { code: String, script_args: Vec<String> },
+    Debug { file_path: String, script_args: Vec<String> },
+    Profile { file_path: String, script_args: Vec<String> },
+    Test { path: String },
+    Fmt { file_paths: Vec<String>, check: bool },
+    Dap,
+    Lsp,
+    Disasm { file_path: String },
+    Tokens { file_path: String },
+    Version,
+    Help,
+}
+
+#[derive(Default)]
+struct TraceOptions {
+    enabled: bool,
+    functions: Vec<String>,
+    lines: Option<(i32, i32)>,
+    file_path: Option<String>,
+}
+
+struct Options {
+    trace: TraceOptions,
+    vm_config: VmConfig,
+    folded_path: Option<String>,
+    coverage_path: Option<String>,
+    command: Command,
+}
+
+// Options are recognized in front of the command and right after the
+// commands that run a script, all arguments after the script belong to the script
+fn parse_args(args: &[String]) -> Result<Options, String> {
+
+    let mut options = Options {
+        trace: TraceOptions::default(),
+        vm_config: VmConfig::default(),
+        folded_path: None,
+        coverage_path: None,
+        command: Command::Repl,
+    };
+
+    let mut rest = match parse_options(args, &mut options)? {
+        Some(rest) => rest.to_vec(),
+        None => return Ok(options),
+    };
+    if rest.first().is_some_and(|cmd| COMMANDS_WITH_OPTIONS.contains(&cmd.as_str())) {
+        rest = match parse_options(&rest[1..], &mut options)? {
+            Some(after) => [&rest[..1], after].concat(),
+            None => return Ok(options),
+        };
+    }
+
+    options.command = match rest.as_slice() {
+        [] => Command::Repl,
+        [cmd, file_path] if cmd == "disasm" =>
+            Command::Disasm { file_path: file_path.clone() },
+        [cmd, file_path] if cmd == "tokens" =>
+            Command::Tokens { file_path: file_path.clone() },
+        [cmd, ..] if cmd == "disasm" || cmd == "tokens" =>
+            return Err(format!("'{}' expects exactly one file", cmd)),
+        [cmd, file_path, script_args @ ..] if cmd == "run" =>
+            Command::Run { file_path: file_path.clone(), script_args: script_args.to_vec() },
+        [cmd] if cmd == "run" =>
+            return Err("'run' expects a file".to_string()),
+        [cmd, flag, file_paths @ ..] if cmd == "fmt" && flag == "--check" && !file_paths.is_empty() =>
+            Command::Fmt { file_paths: file_paths.to_vec(), check: true },
+        [cmd, file_paths @ ..] if cmd == "fmt" && !file_paths.is_empty() && file_paths[0] != "--check" =>
+            Command::Fmt { file_paths: file_paths.to_vec(), check: false },
+        [cmd, ..] if cmd == "fmt" =>
+            return Err("'fmt' expects files".to_string()),
+        [cmd] if cmd == "dap" =>
+            Command::Dap,
+        [cmd] if cmd == "lsp" =>
+            Command::Lsp,
+        [cmd, file_path, ..] if cmd == "debug" && file_path == "-" =>
+            return Err("'debug' reads its commands from standard input and needs a file".to_string()),
+        [cmd, file_path, script_args @ ..] if cmd == "debug" =>
+            Command::Debug { file_path: file_path.clone(), script_args: script_args.to_vec() },
+        [cmd] if cmd == "debug" =>
+            return Err("'debug' expects a file".to_string()),
+        [cmd, file_path, script_args @ ..] if cmd == "profile" =>
+            Command::Profile { file_path: file_path.clone(), script_args: script_args.to_vec() },
+        [cmd] if cmd == "profile" =>
+            return Err("'profile' expects a file".to_string()),
+        [cmd, path] if cmd == "test" =>
+            Command::Test { path: path.clone() },
+        [cmd, ..] if cmd == "test" =>
+            return Err("'test' expects exactly one directory or file".to_string()),
+        [cmd, code, script_args @ ..] if cmd == "-e" =>
+            Command::Eval { code: code.clone(), script_args: script_args.to_vec() },
+        [cmd] if cmd == "-e" =>
+            return Err("'-e' expects code".to_string()),
+        [file_path, script_args @ ..] =>
+            Command::Run { file_path: file_path.clone(), script_args: script_args.to_vec() },
+    };
+
+    Ok(options)
+}
+
+// Parses the options at the start of the arguments and returns the arguments
+// after them. None if '--version' or '--help' already decided the command.
+fn parse_options<'a>(args: &'a [String], options: &mut Options) -> Result<Option<&'a [String]>, String> {
+
+    let mut rest = args;
+
+    while let Some(arg) = rest.first() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+            _ => (arg.as_str(), None),
+        };
+        let takes_value = matches!(name, 
+            "--trace-fn" | "--trace-lines" | "--trace-out" | "--folded" | "--max-frames" | "--max-stack" |
+            "--max-instructions" | "--time-limit" | "--max-objects" | "--max-heap-bytes");
+        // The value of '--coverage' is optional, so it can only be given after '='
+        let value = match (inline_value, rest.get(1)) {
+            (Some(_), _) if !takes_value && name != "--coverage" => return Err(format!("'{}' takes no value", name)),
+            (Some(value), _) => value.to_string(),
+            (None, Some(value)) if takes_value => value.clone(),
+            (None, None) if takes_value => return Err(format!("'{}' expects a value", name)),
+            (None, _) => String::new(),
+        };
+
+        let trace = &mut options.trace;
+        let vm_config = &mut options.vm_config;
+        match name {
+            "--trace" => trace.enabled = true,
+            "--trace-fn" => trace.functions.push(value),
+            "--trace-lines" => trace.lines = Some(parse_line_range(&value)?),
+            "--trace-out" => trace.file_path = Some(value),
+            "--folded" => options.folded_path = Some(value),
+            "--coverage" => options.coverage_path = Some(inline_value.unwrap_or(DEFAULT_COVERAGE_PATH).to_string()),
+            "--max-frames" => vm_config.max_frames = parse_limit(name, &value)?,
+            "--max-stack" => vm_config.max_stack = parse_limit(name, &value)?,
+            "--max-instructions" => vm_config.max_instructions = Some(parse_limit(name, &value)?),
+            "--time-limit" => vm_config.time_limit = Some(Duration::from_millis(parse_limit(name, &value)?)),
+            "--max-objects" => vm_config.max_objects = Some(parse_limit(name, &value)?),
+            "--max-heap-bytes" => vm_config.max_heap_bytes = Some(parse_limit(name, &value)?),
+            "--version" => options.command = Command::Version,
+            "--help" => options.command = Command::Help,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ => break,
+        }
+        if matches!(options.command, Command::Version | Command::Help) {
+            return Ok(None);
+        }
+        rest = &rest[if takes_value && inline_value.is_none() { 2 } else { 1 }..];
+    }
+
+    Ok(Some(rest))
+}
+
+fn parse_limit<T: FromStr + PartialOrd + Default>(option: &str, value: &str) -> Result<T, String> {
+    match value.parse() {
+        Ok(limit) if limit > T::default() => Ok(limit),
+        _ => Err(format!("'{}' expects a positive number", option)),
+    }
+}
+
+// Either a single line or a range like 10-20
+fn parse_line_range(range: &str) -> Result<(i32, i32), String> {
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    match (first.trim().parse(), last.trim().parse()) {
+        (Ok(first), Ok(last)) => Ok((first, last)),
+        _ => Err(format!("Invalid line range '{}'", range)),
+    }
+}
+
+// Any trace option enables tracing
+fn observers(options: TraceOptions) -> Result<Vec<Box<dyn VmObserver>>, i32> {
+
+    if !options.enabled && options.functions.is_empty() 
+        && options.lines.is_none() && options.file_path.is_none() {
+        return Ok(vec![]);
+    }
+
+    let mut config = match options.file_path {
+        Some(file_path) => match File::create(&file_path) {
+            Ok(file) => TraceConfig::new_with_sink(Box::new(file)),
+            Err(_) => {
+                eprintln!("Could not create file {}", file_path);
+                return Err(74);
+            }
+        },
+        None => TraceConfig::new(),
+    };
+    options.functions.iter().for_each(|name| config.add_function(name));
+    if let Some((first, last)) = options.lines {
+        config.set_lines(first, last);
+    }
+
+    Ok(vec![Box::new(config)])
+}
+
+// The report goes to stderr, so that it does not mix with the output of the script
+fn report_profile(profiler: &Profiler, folded_path: Option<String>) -> Result<(), i32> {
+
+    let _ = profiler.write_report(&mut io::stderr());
+
+    if let Some(folded_path) = folded_path {
+        let written = File::create(&folded_path).and_then(|mut file| profiler.write_folded(&mut file));
+        if written.is_err() {
+            eprintln!("Could not write file {}", folded_path);
+            return Err(74);
+        }
+    }
+    Ok(())
+}
+
+// Coverage is written for failed runs as well, the summary goes to stderr
+fn run_with_coverage(
+    source: &str,
+    source_path: &str,
+    script_args: Vec<String>,
+    vm_config: VmConfig,
+    mut observers: Vec<Box<dyn VmObserver>>,
+    coverage_path: Option<String>) -> Result<(), i32> {
+
+    let coverage_path = match coverage_path {
+        Some(coverage_path) => coverage_path,
+        None => return run_script(source, script_args, vm_config, observers),
+    };
+
+    let coverage = Rc::new(RefCell::new(Coverage::new(source_path)));
+    observers.push(Box::new(coverage.clone()));
+    let result = run_script(source, script_args, vm_config, observers);
+
+    let coverage = coverage.borrow();
+    let _ = coverage.write_summary(&mut io::stderr());
+    let written = File::create(&coverage_path).and_then(|mut file| coverage.write_lcov(&mut file));
+    if written.is_err() {
+        eprintln!("Could not write file {}", coverage_path);
+        return Err(74);
+    }
+    result
+}
+
+fn execute(options: Options) -> Result<(), i32> {
+
+    match options.command {
+        Command::Repl => repl(options.vm_config),
+        Command::Run { file_path, script_args } => {
+            let source = read_source(&file_path)?;
+            let observers = observers(options.trace)?;
+            run_with_coverage(&source, &file_path, script_args, options.vm_config, observers, options.coverage_path)?;
+        },
+        Command::Eval { code, script_args } => {
+            let observers = observers(options.trace)?;
+            run_with_coverage(&code, "-e", script_args, options.vm_config, observers, options.coverage_path)?;
+        },
+        Command::Debug { file_path, script_args } => {
+            let source = read_source(&file_path)?;
+            let debugger = Debugger::new(&source, Box::new(io::stdin().lock()), Box::new(io::stdout()));
+            let mut observers = observers(options.trace)?;
+            observers.push(Box::new(debugger));
+            run_script(&source, script_args, options.vm_config, observers)?;
         },
-        _ => {
-            eprintln!("Usage: rlox [path]");
-            return Err(64);
-        },    
+        Command::Profile { file_path, script_args } => {
+            let source = read_source(&file_path)?;
+            let profiler = Rc::new(RefCell::new(Profiler::new()));
+            let mut observers = observers(options.trace)?;
+            observers.push(Box::new(profiler.clone()));
+            // The profile of a failed run is reported as well
+            let result = run_script(&source, script_args, options.vm_config, observers);
+            let mut profiler = profiler.borrow_mut();
+            profiler.finish();
+            report_profile(&profiler, options.folded_path)?;
+            result?;
+        },
+        Command::Test { path } =>
+            run_tests(&path, options.vm_config)?,
+        Command::Fmt { file_paths, check } =>
+            format_files(&file_paths, check)?,
+        Command::Dap =>
+            dap::serve(Box::new(io::stdin().lock()), Box::new(io::stdout())),
+        Command::Lsp =>
+            lsp::serve(Box::new(io::stdin().lock()), Box::new(io::stdout()))?,
+        Command::Disasm { file_path } =>
+            disassemble_script(&read_source(&file_path)?)?,
+        Command::Tokens { file_path } =>
+            dump_tokens(&read_source(&file_path)?),
+        Command::Version =>
+            println!("rlox {}", env!("CARGO_PKG_VERSION")),
+        Command::Help =>
+            println!("{}", USAGE),
     }
 
     Ok(())
 }
+
+fn main() {
+
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("{}", USAGE);
+            process::exit(64);
+        }
+    };
+
+    if let Err(exit_code) = execute(options) {
+        process::exit(exit_code);
+    }
+}
@@ -0,0 +1,512 @@
+use std::{process::{Command, Output, Stdio}, io::Write, path::Path};
+use rlox::frontend::json::Json;
+
+#[test]
+fn show_version() {
+    let output = rlox(&["--version"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), format!("rlox {}\n", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn eval_code_with_args() {
+    let output = rlox(&["-e", "print args(); print argc(); print arg(1); print arg(2);", "first", "second"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "first second\n2\nsecond\nnil\n");
+}
+
+#[test]
+fn run_script_from_stdin() {
+    let output = rlox(&["run", "-", "Welt"], "#!/usr/bin/env rlox\nprint \"Hallo \" + args();\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "Hallo Welt\n");
+}
+
+#[test]
+fn exit_codes() {
+    assert_eq!(rlox(&["-e", "print;"], "").status.code(), Some(65));
+    assert_eq!(rlox(&["-e", "print -nil;"], "").status.code(), Some(70));
+    assert_eq!(rlox(&["no/such/file.lox"], "").status.code(), Some(74));
+    assert_eq!(rlox(&["--no-such-option"], "").status.code(), Some(64));
+    assert_eq!(rlox(&["disasm"], "").status.code(), Some(64));
+}
+
+#[test]
+fn dump_tokens() {
+    let output = rlox(&["tokens", "-"], "var x;");
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let token_types: Vec<&str> = stdout
+        .lines()
+        .map(|line| line.split_whitespace().nth(1).unwrap())
+        .collect();
+    assert_eq!(token_types, vec!["Var", "Identifier", "Semicolon", "Eof"]);
+}
+
+#[test]
+fn disassemble_functions() {
+    let output = rlox(&["disasm", "-"], "fun outer() { fun inner() { return 42; } }");
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("== <script> =="));
+    assert!(stdout.contains("== <fn outer/0> =="));
+    assert!(stdout.contains("== <fn inner/0> =="));
+}
+
+fn rlox(args: &[&str], stdin: &str) -> Output {
+    rlox_in(Path::new("."), args, stdin)
+}
+
+fn rlox_in(dir_path: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .current_dir(dir_path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn trace_line_range_to_stderr() {
+    let output = rlox(&["--trace-lines", "2", "-e", "var a = 1;\nprint a;\n"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n");
+    let trace = String::from_utf8_lossy(&output.stderr).to_string();
+    assert!(trace.contains("OP_GET_GLOBAL"));
+    assert!(!trace.contains("OP_DEFINE_GLOBAL"));
+}
+
+#[test]
+fn debug_script_until_quit() {
+    let file_path = std::env::temp_dir().join(format!("rlox_debug_{}.lox", std::process::id()));
+    std::fs::write(&file_path, "print 1;\nprint 2;\n").unwrap();
+
+    let output = rlox(&["debug", file_path.to_str().unwrap()], "next\nquit\n");
+    std::fs::remove_file(&file_path).unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("[line 1] in script"));
+    assert!(stdout.contains("1\n[line 2] in script"));
+    assert!(!stdout.contains("\n2\n"));
+}
+
+#[test]
+fn debug_adapter_session() {
+    let file_path = std::env::temp_dir().join(format!("rlox_dap_{}.lox", std::process::id()));
+    std::fs::write(&file_path, "fun add(a, b) {\n    return a + b;\n}\nprint add(1, 2);\nprint \"done\";\n").unwrap();
+    let program = Json::from(file_path.to_str().unwrap());
+
+    let requests = [
+        ("initialize", Json::object(vec![("adapterID", "rlox".into())])),
+        ("launch", Json::object(vec![("program", program)])),
+        ("setBreakpoints", Json::object(vec![("breakpoints", vec![Json::object(vec![("line", 2.into())])].into())])),
+        ("configurationDone", Json::Null),
+        ("stackTrace", Json::object(vec![("threadId", 1.into())])),
+        ("scopes", Json::object(vec![("frameId", 0.into())])),
+        ("variables", Json::object(vec![("variablesReference", 2.into())])),
+        ("stepOut", Json::Null),
+        ("continue", Json::Null),
+        ("disconnect", Json::Null),
+    ];
+    let input: String = requests
+        .into_iter()
+        .enumerate()
+        .map(|(seq, (command, arguments))| frame(Json::object(vec![
+            ("seq", (seq + 1).into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])))
+        .collect();
+
+    let output = rlox(&["dap"], &input);
+    std::fs::remove_file(&file_path).unwrap();
+    assert!(output.status.success());
+
+    let messages = unframe(&stdout(&output));
+    assert!(messages.iter().all(|message| message.get("success") != Some(&Json::Bool(false))));
+
+    let summary: Vec<String> = messages
+        .iter()
+        .map(|message| match message.get("type").and_then(Json::as_str) {
+            Some("response") => message.get("command").and_then(Json::as_str).unwrap().to_string(),
+            _ => format!("{} event", message.get("event").and_then(Json::as_str).unwrap()),
+        })
+        .collect();
+    assert_eq!(summary, [
+        "initialize", "initialized event", "launch", "setBreakpoints", "configurationDone",
+        "stopped event", "stackTrace", "scopes", "variables", "stepOut", "stopped event", "continue",
+        "output event", "output event", "exited event", "terminated event", "disconnect",
+    ]);
+
+    let body = |idx: usize| messages[idx].get("body").unwrap();
+    assert_eq!(body(5).get("reason").and_then(Json::as_str), Some("breakpoint"));
+    let frames = body(6).get("stackFrames").and_then(Json::as_array).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].get("name").and_then(Json::as_str), Some("add"));
+    assert_eq!(frames[0].get("line").and_then(Json::as_i64), Some(2));
+    assert_eq!(frames[1].get("line").and_then(Json::as_i64), Some(4));
+    assert_eq!(body(8).to_string(), concat!(
+        r#"{"variables":[{"name":"add","value":"<fn add/2>","variablesReference":0},"#,
+        r#"{"name":"a","value":"1","variablesReference":0},{"name":"b","value":"2","variablesReference":0}]}"#));
+    assert_eq!(body(10).get("reason").and_then(Json::as_str), Some("step"));
+    assert_eq!(body(12).get("output").and_then(Json::as_str), Some("3\n"));
+    assert_eq!(body(13).get("output").and_then(Json::as_str), Some("done\n"));
+    assert_eq!(body(14).get("exitCode").and_then(Json::as_i64), Some(0));
+}
+
+#[test]
+fn debug_adapter_verifies_breakpoints() {
+    let file_path = std::env::temp_dir().join(format!("rlox_dap_lines_{}.lox", std::process::id()));
+    std::fs::write(&file_path, "var a = 1;\n\n// comment\nprint a;\n").unwrap();
+    let program = Json::from(file_path.to_str().unwrap());
+    let lines = |lines: &[i64]| Json::object(vec![("breakpoints", lines
+        .iter()
+        .map(|line| Json::object(vec![("line", (*line).into())]))
+        .collect::<Vec<Json>>()
+        .into())]);
+
+    let requests = [
+        ("initialize", Json::object(vec![("adapterID", "rlox".into())])),
+        ("setBreakpoints", lines(&[2, 9])),
+        ("launch", Json::object(vec![("program", program)])),
+        ("setBreakpoints", lines(&[3, 9])),
+        ("configurationDone", Json::Null),
+        ("stackTrace", Json::object(vec![("threadId", 1.into())])),
+        ("continue", Json::Null),
+        ("disconnect", Json::Null),
+    ];
+    let input: String = requests
+        .into_iter()
+        .enumerate()
+        .map(|(seq, (command, arguments))| frame(Json::object(vec![
+            ("seq", (seq + 1).into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])))
+        .collect();
+
+    let output = rlox(&["dap"], &input);
+    std::fs::remove_file(&file_path).unwrap();
+    assert!(output.status.success());
+
+    let messages = unframe(&stdout(&output));
+    let body = |idx: usize| messages[idx].get("body").unwrap();
+    let breakpoint = |breakpoint: &Json| (
+        breakpoint.get("id").and_then(Json::as_i64).unwrap(),
+        breakpoint.get("verified").and_then(Json::as_bool).unwrap(),
+        breakpoint.get("line").and_then(Json::as_i64).unwrap(),
+    );
+    let breakpoints = |idx: usize| body(idx)
+        .get("breakpoints")
+        .and_then(Json::as_array)
+        .unwrap()
+        .iter()
+        .map(breakpoint)
+        .collect::<Vec<_>>();
+
+    // Unknown before the launch, then moved to the next line with code
+    assert_eq!(breakpoints(2), [(1, false, 2), (2, false, 9)]);
+    assert_eq!(messages[3].get("event").and_then(Json::as_str), Some("breakpoint"));
+    assert_eq!(breakpoint(body(3).get("breakpoint").unwrap()), (1, true, 4));
+    assert_eq!(breakpoint(body(4).get("breakpoint").unwrap()), (2, false, 9));
+    assert_eq!(breakpoints(6), [(3, true, 4), (4, false, 9)]);
+
+    let frames = body(9).get("stackFrames").and_then(Json::as_array).unwrap();
+    assert_eq!(frames[0].get("line").and_then(Json::as_i64), Some(4));
+}
+
+#[test]
+fn language_server_session() {
+    let uri = "file:///test.lox";
+    let document = |text: &str| Json::object(vec![("uri", uri.into()), ("text", text.into())]);
+    let at = |line: i32, character: i32| Json::object(vec![
+        ("textDocument", Json::object(vec![("uri", uri.into())])),
+        ("position", Json::object(vec![("line", line.into()), ("character", character.into())])),
+    ]);
+    let source = "fun add(a, b) {\n    return a + b;\n}\nvar total = add(1, 2);\nprint sqrt(total);\n";
+
+    let messages = [
+        ("initialize", Some(1), Json::object(vec![])),
+        ("initialized", None, Json::object(vec![])),
+        ("textDocument/didOpen", None, Json::object(vec![("textDocument", document("var x = ;"))])),
+        ("textDocument/didChange", None, Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", uri.into())])),
+            ("contentChanges", vec![Json::object(vec![("text", source.into())])].into()),
+        ])),
+        ("textDocument/documentSymbol", Some(2), Json::object(vec![("textDocument", Json::object(vec![("uri", uri.into())]))])),
+        ("textDocument/definition", Some(3), at(1, 11)),
+        ("textDocument/hover", Some(4), at(3, 13)),
+        ("textDocument/hover", Some(5), at(4, 7)),
+        ("textDocument/completion", Some(6), at(4, 0)),
+        ("shutdown", Some(7), Json::Null),
+        ("exit", None, Json::Null),
+    ];
+    let input: String = messages
+        .into_iter()
+        .map(|(method, id, params)| {
+            let mut message = vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)];
+            if let Some(id) = id {
+                message.push(("id", id.into()));
+            }
+            frame(Json::object(message))
+        })
+        .collect();
+
+    let output = rlox(&["lsp"], &input);
+    assert!(output.status.success());
+    let messages = unframe(&stdout(&output));
+    assert_eq!(messages.len(), 9);
+
+    let result = |idx: usize| messages[idx].get("result").unwrap().to_string();
+    assert!(result(0).contains(r#""hoverProvider":true"#));
+    assert_eq!(messages[1].get("params").unwrap().to_string(), concat!(
+        r#"{"uri":"file:///test.lox","diagnostics":[{"range":{"start":{"line":0,"character":8},"end":{"line":0,"character":9}},"#,
+        r#""severity":1,"source":"rlox","message":"Expect expression."}]}"#));
+    assert_eq!(messages[2].get("params").unwrap().get("diagnostics"), Some(&Json::Array(vec![])));
+    assert_eq!(result(3), concat!(
+        r#"[{"name":"add","kind":12,"location":{"uri":"file:///test.lox","range":{"start":{"line":0,"character":4},"end":{"line":0,"character":7}}}},"#,
+        r#"{"name":"total","kind":13,"location":{"uri":"file:///test.lox","range":{"start":{"line":3,"character":4},"end":{"line":3,"character":9}}}}]"#));
+    assert_eq!(result(4),
+        r#"{"uri":"file:///test.lox","range":{"start":{"line":0,"character":8},"end":{"line":0,"character":9}}}"#);
+    assert_eq!(result(5), r#"{"contents":{"kind":"markdown","value":"```lox\nfun add(a, b)\n```\nTakes 2 arguments."}}"#);
+    assert_eq!(result(6), r#"{"contents":{"kind":"markdown","value":"```lox\nfun sqrt\n```\nNative function. Takes 1 argument."}}"#);
+    let labels: Vec<&str> = messages[7].get("result").and_then(Json::as_array).unwrap()
+        .iter()
+        .filter_map(|item| item.get("label")?.as_str())
+        .collect();
+    assert!(["while", "sqrt", "add", "a", "total"].iter().all(|label| labels.contains(label)));
+    assert_eq!(messages[8].get("id"), Some(&Json::from(7)));
+}
+
+#[test]
+fn language_server_rejects_too_large_messages() {
+    let shutdown = |method: &str, id: Option<i32>| {
+        let mut message = vec![("jsonrpc", "2.0".into()), ("method", method.into())];
+        message.extend(id.map(|id| ("id", id.into())));
+        frame(Json::object(message))
+    };
+    let input = format!("Content-Length: 100000000\r\n\r\n{}{}", shutdown("shutdown", Some(1)), shutdown("exit", None));
+
+    // The body is shorter than announced, so the following messages are skipped as part of it
+    let output = rlox(&["lsp"], &input);
+    assert_eq!(output.status.code(), Some(1));
+    let messages = unframe(&stdout(&output));
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].get("id"), Some(&Json::Null));
+    assert_eq!(messages[0].get("error").and_then(|error| error.get("code")).and_then(Json::as_i64), Some(-32700));
+}
+
+// Adds the Content-Length header of DAP and LSP messages
+fn frame(message: Json) -> String {
+    let body = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+fn unframe(output: &str) -> Vec<Json> {
+    output
+        .split("Content-Length: ")
+        .skip(1)
+        .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+        .collect()
+}
+
+#[test]
+fn report_stack_overflow() {
+    let output = rlox(&["--max-frames", "100", "-e", "fun f() {\n  f();\n}\nf();"], "");
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    assert!(stderr.starts_with("Stack overflow.\n[line 2] in f()\n"));
+    assert!(stderr.contains("\n... 79 more frames ...\n"));
+    assert!(stderr.ends_with("[line 2] in f()\n[line 4] in script\n"));
+    assert_eq!(stderr.lines().count(), 23);
+
+    assert_eq!(rlox(&["--max-frames", "0", "-e", ""], "").status.code(), Some(64));
+}
+
+#[test]
+fn report_exceeded_limits() {
+    let output = rlox(&["--max-instructions", "50", "-e", "print 1;\nwhile (true) {}"], "");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Instruction limit exceeded.\n[line 2] in script\n");
+
+    let output = rlox(&["--time-limit", "10", "-e", "while (true) {}"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Time limit exceeded.\n[line 1] in script\n");
+
+    let output = rlox(&["--max-objects", "20", "-e", "var s = \"\";\nwhile (true) s = s + \"x\";"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Heap object limit exceeded.\n[line 2] in script\n");
+
+    assert_eq!(rlox(&["--time-limit", "soon", "-e", ""], "").status.code(), Some(64));
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_repl_with_ctrl_c() {
+    let history_path = std::env::temp_dir().join(format!("rlox_history_{}", std::process::id()));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .env("RLOX_HISTORY", &history_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"var n = 0;\nwhile (true) { n = n + 1; }\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    stdin.write_all(b"print n > 0;\n").unwrap();
+    drop(stdin);
+
+    let output = child.wait_with_output().unwrap();
+    let _ = std::fs::remove_file(&history_path);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("true\n"));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Interrupted.\n[line 1] in script\n");
+}
+
+#[test]
+fn load_file_in_repl() {
+    let file_path = std::env::temp_dir().join(format!("rlox_load_{}.lox", std::process::id()));
+    let history_path = std::env::temp_dir().join(format!("rlox_load_history_{}", std::process::id()));
+    std::fs::write(&file_path, "var x = 1;\nfun greet() { print \"hi\"; }\ngreet();\nx = x + 1;\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .env("RLOX_HISTORY", &history_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = format!(":load {}\nx;\n", file_path.display());
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&file_path).unwrap();
+    let _ = std::fs::remove_file(&history_path);
+
+    // Only the expression typed at the prompt is echoed
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).replace("> ", ""), "hi\n2\n\n");
+}
+
+#[test]
+fn profile_script() {
+    let folded_path = std::env::temp_dir().join(format!("rlox_folded_{}.txt", std::process::id()));
+    let source = "fun loop(n) {\n  for (var i = 0; i < n; i = i + 1) {}\n}\nloop(20000);\nprint \"done\";";
+    let output = rlox(&["--folded", folded_path.to_str().unwrap(), "profile", "-"], source);
+    let folded = std::fs::read_to_string(&folded_path).unwrap();
+    std::fs::remove_file(&folded_path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "done\n");
+    let report = String::from_utf8_lossy(&output.stderr).to_string();
+    assert!(report.lines().any(|line| line.trim_start().starts_with("1 ") && line.ends_with(" loop")));
+    assert!(report.lines().any(|line| line.trim_start().starts_with("2 ") && line.ends_with(" loop")));
+    assert!(folded.lines().any(|line| line.starts_with("script;loop ")));
+
+    assert_eq!(rlox(&["profile"], "").status.code(), Some(64));
+}
+
+#[test]
+fn write_line_coverage() {
+    let lcov_path = std::env::temp_dir().join(format!("rlox_lcov_{}.info", std::process::id()));
+    let source = "var x = 1;\nif (x > 1) {\n  print \"big\";\n}\nprint x;\n";
+    let coverage = format!("--coverage={}", lcov_path.display());
+    let output = rlox(&[&coverage, "run", "-"], source);
+    let lcov = std::fs::read_to_string(&lcov_path).unwrap();
+    std::fs::remove_file(&lcov_path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n");
+    let summary = String::from_utf8_lossy(&output.stderr).to_string();
+    assert_eq!(summary, "-: 4 of 5 lines covered (80.0%)\nNot covered: 3\n");
+    assert!(lcov.starts_with("TN:\nSF:-\n"));
+    assert!(lcov.contains("\nDA:1,") && lcov.contains("\nDA:3,0\n") && lcov.contains("\nDA:5,"));
+    assert!(!lcov.contains("\nDA:6,") && lcov.contains("\nLF:5\n"));
+}
+
+#[test]
+fn options_after_the_command() {
+    let dir_path = std::env::temp_dir().join(format!("rlox_options_{}", std::process::id()));
+    std::fs::create_dir_all(&dir_path).unwrap();
+    std::fs::write(dir_path.join("f.lox"), "print 1;\nprint 2;\n").unwrap();
+
+    // Without a path the coverage goes to lcov.info
+    let output = rlox_in(&dir_path, &["run", "--coverage", "f.lox", "--coverage"], "");
+    let lcov = std::fs::read_to_string(dir_path.join("lcov.info"));
+    let traced = rlox_in(&dir_path, &["-e", "--trace-lines=1", "print 3;"], "");
+    std::fs::remove_dir_all(&dir_path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n2\n");
+    let lcov = lcov.unwrap();
+    assert!(lcov.contains("SF:f.lox\n") && lcov.contains("\nDA:2,3\nLF:2\n"));
+    assert!(traced.status.success());
+    assert!(String::from_utf8_lossy(&traced.stderr).contains("OP_PRINT"));
+
+    assert_eq!(rlox(&["run", "--max-frames"], "").status.code(), Some(64));
+    assert_eq!(rlox(&["--trace=1", "-e", "print 1;"], "").status.code(), Some(64));
+}
+
+#[test]
+fn report_failing_lox_tests() {
+    let dir_path = std::env::temp_dir().join(format!("rlox_tests_{}", std::process::id()));
+    std::fs::create_dir_all(dir_path.join("nested")).unwrap();
+    std::fs::write(dir_path.join("pass.lox"), "print 1; // expect: 1\n").unwrap();
+    std::fs::write(dir_path.join("nested").join("fail.lox"), "print 1; // expect: 2\n").unwrap();
+    std::fs::write(dir_path.join("notes.txt"), "print 1;\n").unwrap();
+
+    let output = rlox(&["test", dir_path.to_str().unwrap()], "");
+    std::fs::remove_dir_all(&dir_path).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with(&format!("FAIL {}", dir_path.join("nested").join("fail.lox").display())));
+    assert!(stdout.contains("Expected output '2' on line 1 and got '1'."));
+    assert!(stdout.ends_with("1 passed, 1 failed\n"));
+
+    assert_eq!(rlox(&["test", "no/such/dir"], "").status.code(), Some(74));
+}
+
+#[test]
+fn format_stdin() {
+    let output = rlox(&["fmt", "-"], "var a=1;// one\nif(a>0){print a;}\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "var a = 1; // one\nif (a > 0) {\n    print a;\n}\n");
+
+    assert_eq!(rlox(&["fmt", "-"], "print;").status.code(), Some(65));
+    assert_eq!(rlox(&["fmt"], "").status.code(), Some(64));
+    assert_eq!(rlox(&["fmt", "--check"], "").status.code(), Some(64));
+}
+
+#[test]
+fn format_files_in_place() {
+    let file_path = std::env::temp_dir().join(format!("rlox_fmt_{}.lox", std::process::id()));
+    let file_path_str = file_path.to_str().unwrap();
+    std::fs::write(&file_path, "print  1 ;\n").unwrap();
+
+    let check = rlox(&["fmt", "--check", file_path_str], "");
+    assert_eq!(check.status.code(), Some(1));
+    assert_eq!(stdout(&check), format!("{}\n", file_path_str));
+    assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "print  1 ;\n");
+
+    assert!(rlox(&["fmt", file_path_str], "").status.success());
+    let formatted = std::fs::read_to_string(&file_path).unwrap();
+    let check = rlox(&["fmt", "--check", file_path_str], "");
+    std::fs::remove_file(&file_path).unwrap();
+
+    assert_eq!(formatted, "print 1;\n");
+    assert!(check.status.success());
+    assert_eq!(stdout(&check), "");
+}
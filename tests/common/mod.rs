@@ -0,0 +1,427 @@
+// Random Lox programs for the tests that run many programs. The programs
+// terminate: loops count up to a small bound with counters that no
+// statement assigns, and functions only call the functions declared before them.
+
+// Xorshift, good enough to pick productions and reproducible from the seed
+pub struct Rng(u64);
+
+impl Rng {
+
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Str,
+}
+
+#[derive(Clone)]
+struct Variable {
+    name: String,
+    var_type: Type,
+    assignable: bool, // loop counters are not
+}
+
+#[derive(Clone)]
+struct Function {
+    name: String,
+    arity: usize,
+}
+
+#[derive(Default)]
+struct Scope {
+    variables: Vec<Variable>,
+    functions: Vec<Function>,
+}
+
+const MAX_DEPTH: usize = 4;
+const MAX_LOOP_DEPTH: usize = 2;
+const MAX_EXPR_DEPTH: usize = 3;
+const STRINGS: [&str; 5] = ["a", "b", "ab", "", "x y"];
+
+pub struct ProgramGenerator {
+    rng: Rng,
+    source: String,
+    indent: usize,
+    globals: Scope,
+    scopes: Vec<Scope>, // of the current function, or blocks of the top level
+    loop_depth: usize,
+    in_function: bool,
+    next_id: usize,
+}
+
+impl ProgramGenerator {
+
+    pub fn new(seed: u64) -> ProgramGenerator {
+        ProgramGenerator {
+            rng: Rng::new(seed),
+            source: String::new(),
+            indent: 0,
+            globals: Scope::default(),
+            scopes: vec![],
+            loop_depth: 0,
+            in_function: false,
+            next_id: 0,
+        }
+    }
+
+    // A program of top level declarations that uses the script arguments "one" and "two"
+    pub fn program(mut self) -> String {
+        let num_declarations = 4 + self.rng.below(8);
+        for _ in 0..num_declarations {
+            self.declaration(0);
+        }
+        self.source
+    }
+
+    fn declaration(&mut self, depth: usize) {
+        match self.rng.below(10) {
+            0 | 1 => self.var_declaration(),
+            2 if depth == 0 || self.rng.chance(30) => self.fun_declaration(),
+            _ => self.statement(depth),
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let var_type = if self.rng.chance(75) { Type::Number } else { Type::Str };
+        let value = self.expr(var_type, 0);
+        let name = self.fresh_name("v");
+        self.line(&format!("var {} = {};", name, value));
+        self.declare_variable(Variable { name, var_type, assignable: true });
+    }
+
+    fn fun_declaration(&mut self) {
+        let name = self.fresh_name("f");
+        let arity = self.rng.below(4);
+        let params: Vec<String> = (0..arity).map(|_| self.fresh_name("p")).collect();
+        self.line(&format!("fun {}({}) {{", name, params.join(", ")));
+
+        // Functions see the globals but not the locals around them
+        let outer_scopes = std::mem::take(&mut self.scopes);
+        let outer_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let outer_in_function = std::mem::replace(&mut self.in_function, true);
+        let variables = params
+            .iter()
+            .map(|param| Variable { name: param.clone(), var_type: Type::Number, assignable: true })
+            .collect();
+        self.scopes.push(Scope { variables, functions: vec![] });
+
+        self.indent += 1;
+        for _ in 0..1 + self.rng.below(4) {
+            self.declaration(1);
+        }
+        if self.rng.chance(90) {
+            let value = self.expr(Type::Number, 0);
+            self.line(&format!("return {};", value));
+        }
+        self.indent -= 1;
+        self.line("}");
+
+        self.scopes = outer_scopes;
+        self.loop_depth = outer_loop_depth;
+        self.in_function = outer_in_function;
+        self.declare_function(Function { name, arity });
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let nested = depth < MAX_DEPTH;
+        match self.rng.below(20) {
+            0..=4 => {
+                let var_type = if self.rng.chance(70) { Type::Number } else { Type::Str };
+                let value = self.expr(var_type, 0);
+                self.line(&format!("print {};", value));
+            },
+            5 | 6 => self.assignment(),
+            7 | 8 if nested => self.if_statement(depth),
+            9 | 10 if nested && self.loop_depth < MAX_LOOP_DEPTH => self.loop_statement(depth),
+            11 if nested => self.switch_statement(depth),
+            12 if nested => self.block(depth, |_| ()),
+            13 if self.loop_depth > 0 => {
+                let condition = self.condition(0);
+                self.line(&format!("if ({}) continue;", condition));
+            },
+            14 if self.in_function => {
+                let (condition, value) = (self.condition(0), self.expr(Type::Number, 0));
+                self.line(&format!("if ({}) return {};", condition, value));
+            },
+            15 if self.rng.chance(20) => {
+                let condition = self.condition(0);
+                self.line(&format!("assert {}, \"checked\";", condition));
+            },
+            _ => {
+                let call = self.call(0);
+                self.line(&format!("{};", call));
+            },
+        }
+    }
+
+    fn assignment(&mut self) {
+        let variables: Vec<Variable> = self.visible_variables()
+            .into_iter()
+            .filter(|variable| variable.assignable)
+            .collect();
+        if variables.is_empty() {
+            return self.line("print \"nothing to assign\";");
+        }
+        let variable = self.rng.pick(&variables).clone();
+        let value = self.expr(variable.var_type, 0);
+        self.line(&format!("{} = {};", variable.name, value));
+    }
+
+    fn if_statement(&mut self, depth: usize) {
+        let condition = self.condition(0);
+        self.line(&format!("if ({})", condition));
+        self.nested_statement(depth);
+        if self.rng.chance(50) {
+            self.line("else");
+            self.nested_statement(depth);
+        }
+    }
+
+    fn loop_statement(&mut self, depth: usize) {
+        let bound = 1 + self.rng.below(3);
+        match self.rng.below(3) {
+            0 => {
+                let counter = self.fresh_name("i");
+                self.line(&format!("for (var {c} = 0; {c} < {b}; {c} = {c} + 1)", c = counter, b = bound));
+                self.scopes.push(Scope::default());
+                self.declare_variable(Variable { name: counter, var_type: Type::Number, assignable: false });
+                self.loop_depth += 1;
+                self.nested_statement(depth);
+                self.loop_depth -= 1;
+                self.scopes.pop();
+            },
+            // The counter is incremented first, so that 'continue' does not skip it
+            kind => self.block(depth, |generator| {
+                let counter = generator.fresh_name("w");
+                generator.line(&format!("var {} = 0;", counter));
+                let header = if kind == 1 { "while (" } else { "for (; " };
+                let footer = if kind == 1 { ")" } else { ";)" };
+                generator.line(&format!("{}{} < {}{}", header, counter, bound, footer));
+                generator.declare_variable(Variable { name: counter.clone(), var_type: Type::Number, assignable: false });
+                generator.loop_depth += 1;
+                generator.block(depth + 1, |generator| generator.line(&format!("{c} = {c} + 1;", c = counter)));
+                generator.loop_depth -= 1;
+            }),
+        }
+    }
+
+    fn switch_statement(&mut self, depth: usize) {
+        let subject = match self.rng.below(3) {
+            0 => self.rng.below(4).to_string(),
+            _ => self.expr(Type::Number, 1),
+        };
+        self.line(&format!("switch ({}) {{", subject));
+        for case in 0..1 + self.rng.below(3) {
+            self.line(&format!("case {}:", case));
+            self.case_statements(depth);
+        }
+        if self.rng.chance(50) {
+            self.line("default:");
+            self.case_statements(depth);
+        }
+        self.line("}");
+    }
+
+    // Cases contain statements but no declarations
+    fn case_statements(&mut self, depth: usize) {
+        self.indent += 1;
+        for _ in 0..self.rng.below(3) {
+            self.statement(depth + 1);
+        }
+        self.indent -= 1;
+    }
+
+    fn nested_statement(&mut self, depth: usize) {
+        if self.rng.chance(70) {
+            self.block(depth + 1, |_| ());
+        } else {
+            self.indent += 1;
+            self.statement(depth + 1);
+            self.indent -= 1;
+        }
+    }
+
+    // Starts the block with the given statements
+    fn block(&mut self, depth: usize, start: impl FnOnce(&mut Self)) {
+        self.line("{");
+        self.indent += 1;
+        self.scopes.push(Scope::default());
+        start(self);
+        for _ in 0..1 + self.rng.below(3) {
+            self.declaration(depth + 1);
+        }
+        self.scopes.pop();
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn expr(&mut self, var_type: Type, depth: usize) -> String {
+        // Some operations fail, to compare the runtime errors as well
+        if self.rng.below(300) == 0 {
+            return self.faulty_expr();
+        }
+        let leaf = depth >= MAX_EXPR_DEPTH || self.rng.chance(30);
+        match var_type {
+            Type::Number if leaf => match self.variable(Type::Number) {
+                Some(name) if self.rng.chance(60) => name,
+                _ => self.number(),
+            },
+            Type::Number => match self.rng.below(8) {
+                0 => format!("-{}", self.expr(Type::Number, depth + 1)),
+                1 => format!("({})", self.expr(Type::Number, depth + 1)),
+                2 => self.call(depth + 1),
+                3 => {
+                    let (left, right) = (self.expr(Type::Number, depth + 1), self.expr(Type::Number, depth + 1));
+                    format!("{} {} {}", left, self.rng.pick(&["and", "or"]), right)
+                },
+                4 => match self.variable(Type::Number).filter(|name| self.is_assignable(name)) {
+                    Some(name) => format!("({} = {})", name, self.expr(Type::Number, depth + 1)),
+                    None => self.number(),
+                },
+                _ => {
+                    let (left, right) = (self.expr(Type::Number, depth + 1), self.expr(Type::Number, depth + 1));
+                    format!("{} {} {}", left, self.rng.pick(&["+", "-", "*", "/"]), right)
+                },
+            },
+            Type::Str if leaf => match self.variable(Type::Str) {
+                Some(name) if self.rng.chance(60) => name,
+                _ => format!("\"{}\"", self.rng.pick(&STRINGS)),
+            },
+            Type::Str => match self.rng.below(4) {
+                0 => format!("concat({}, {})", self.expr(Type::Str, depth + 1), self.expr(Type::Str, depth + 1)),
+                1 => self.rng.pick(&["args()", "arg(0)"]).to_string(),
+                _ => format!("{} + {}", self.expr(Type::Str, depth + 1), self.expr(Type::Str, depth + 1)),
+            },
+        }
+    }
+
+    fn condition(&mut self, depth: usize) -> String {
+        match self.rng.below(if depth < MAX_EXPR_DEPTH { 6 } else { 2 }) {
+            0 => self.rng.pick(&["true", "false", "nil"]).to_string(),
+            1 => format!("{} == {}", self.expr(Type::Str, depth + 1), self.expr(Type::Str, depth + 1)),
+            2 => format!("!({})", self.condition(depth + 1)),
+            3 => format!("{} {} {}", self.condition(depth + 1), self.rng.pick(&["and", "or"]), self.condition(depth + 1)),
+            _ => {
+                let (left, right) = (self.expr(Type::Number, depth + 1), self.expr(Type::Number, depth + 1));
+                format!("{} {} {}", left, self.rng.pick(&["<", "<=", ">", ">=", "==", "!="]), right)
+            },
+        }
+    }
+
+    fn faulty_expr(&mut self) -> String {
+        match self.rng.below(5) {
+            0 => "1 + \"a\"".to_string(),
+            1 => "-\"a\"".to_string(),
+            2 => "\"a\" < \"b\"".to_string(),
+            3 => "undefined_variable".to_string(),
+            _ => "sqrt(1, 2)".to_string(),
+        }
+    }
+
+    fn call(&mut self, depth: usize) -> String {
+        let functions = self.visible_functions();
+        if functions.is_empty() || self.rng.chance(20) {
+            return match self.rng.below(2) {
+                0 => format!("sqrt({})", self.expr(Type::Number, depth + 1)),
+                _ => "argc()".to_string(),
+            };
+        }
+        let function = self.rng.pick(&functions).clone();
+        let args: Vec<String> = (0..function.arity).map(|_| self.expr(Type::Number, depth + 1)).collect();
+        format!("{}({})", function.name, args.join(", "))
+    }
+
+    fn number(&mut self) -> String {
+        match self.rng.below(5) {
+            0 => format!("{}.5", self.rng.below(10)),
+            _ => self.rng.below(10).to_string(),
+        }
+    }
+
+    fn variable(&mut self, var_type: Type) -> Option<String> {
+        let names: Vec<String> = self.visible_variables()
+            .into_iter()
+            .filter(|variable| variable.var_type == var_type)
+            .map(|variable| variable.name)
+            .collect();
+        if names.is_empty() {
+            None
+        } else {
+            Some(self.rng.pick(&names).clone())
+        }
+    }
+
+    fn is_assignable(&self, name: &str) -> bool {
+        self.visible_variables()
+            .iter()
+            .rev()
+            .find(|variable| variable.name == name)
+            .is_some_and(|variable| variable.assignable)
+    }
+
+    fn visible_variables(&self) -> Vec<Variable> {
+        let mut variables = self.globals.variables.clone();
+        for scope in self.scopes.iter() {
+            variables.extend(scope.variables.iter().cloned());
+        }
+        variables
+    }
+
+    fn visible_functions(&self) -> Vec<Function> {
+        let mut functions = self.globals.functions.clone();
+        for scope in self.scopes.iter() {
+            functions.extend(scope.functions.iter().cloned());
+        }
+        functions
+    }
+
+    fn declare_variable(&mut self, variable: Variable) {
+        match self.scopes.last_mut() {
+            Some(scope) => scope.variables.push(variable),
+            None => self.globals.variables.push(variable),
+        }
+    }
+
+    fn declare_function(&mut self, function: Function) {
+        match self.scopes.last_mut() {
+            Some(scope) => scope.functions.push(function),
+            None => self.globals.functions.push(function),
+        }
+    }
+
+    fn fresh_name(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}", prefix, self.next_id)
+    }
+
+    fn line(&mut self, text: &str) {
+        self.source.push_str(&"  ".repeat(self.indent));
+        self.source.push_str(text);
+        self.source.push('\n');
+    }
+
+}
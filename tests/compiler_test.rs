@@ -1,6 +1,4 @@
-use std::{ops::Deref};
-
-use rlox::{frontend::compiler::Compiler, backend::{util::disassemble, objects::FunData, value::Value}};
+use rlox::{frontend::{compiler::Compiler, symbols::SymbolKind, token::Span}, backend::{util::{disassemble, disassemble_instruction}, objects::FunData, value::Value, instruction::Instruction, heap::HeapManager, globals::GlobalTable}};
 
 #[test]
 fn compile_arithmetic_expr() {
@@ -65,7 +63,8 @@ fn compile_invalid_assignment() {
         print answer;
     ";
 
-    let mut compiler = Compiler::new(source);
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
     let res = compiler.compile();
     
     assert!(res.is_none()); 
@@ -95,7 +94,8 @@ fn compile_invalid_local_def() {
         }
     ";
 
-    let mut compiler = Compiler::new(source);
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
  
     let res = compiler.compile();
     
@@ -200,7 +200,8 @@ fn continue_not_allowed_outside_loop() {
         }
     ";
 
-    let mut compiler = Compiler::new(source);
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
   
     let res = compiler.compile();
     
@@ -221,27 +222,122 @@ fn fun_declaration() {
         }
     ";
 
-    let top = compile_code(source, "fun_declaration");
+    let (top, heap) = compile_code(source, "fun_declaration");
 
-    let top_chunk = top.borrow_chunk();
-    let say_hello = top_chunk.read_value(4).unwrap();
+    let top_chunk = top.chunk();
+    let say_hello = top_chunk.read_value(2).unwrap();
 
-    println!("");
+    println!();
 
     match say_hello {
         Value::Closure(closure) => {
-            let closure = closure.get_content();
-            let fun_data = closure.fun_data;
-            let fun_data = fun_data.borrow();
-            let fun_chunk = fun_data.borrow_chunk();
+            let closure = heap.get_content(closure);
+            let fun_data = heap.get_content(&closure.fun);
 
-            disassemble(&fun_chunk, "fun say_hello");
+            disassemble(fun_data.chunk(), "fun say_hello", &heap);
         }
-        _ => assert!(false)
+        _ => panic!("say_hello should be a closure")
     }
 
 }
 
+#[test]
+fn fold_arithmetic() {
+    assert_folded("60 * 60 * 24", "86400");
+    assert_folded("(1 + 2) * 3 - 4", "5");
+    assert_folded("-(1 + 2)", "-3");
+}
+
+#[test]
+fn fold_string_concat() {
+    assert_folded("\"Hallo\" + \" \" + \"Welt!\"", "Hallo Welt!");
+}
+
+#[test]
+fn fold_logical_and_comparison() {
+    assert_folded("!nil", "OP_TRUE");
+    assert_folded("!(5 - 4 > 3 * 2 == !nil)", "OP_TRUE");
+    assert_folded("1 >= 2", "OP_FALSE");
+    assert_folded("\"a\" == \"a\"", "OP_TRUE");
+}
+
+#[test]
+fn no_folding_of_ill_typed_constants() {
+    let (top, _) = compile_code("-\"x\";", "ill-typed negation");
+    let chunk = top.chunk();
+    let instrs: Vec<String> = chunk
+        .instruction_iter()
+        .map(|(instr, _)| instr.to_string())
+        .collect();
+    
+    assert_eq!(instrs, vec!["Constant(0)", "Negate", "Pop", "Return"]);
+}
+
+#[test]
+fn no_folding_of_variables() {
+    let (top, _) = compile_code("var x = 1; x + 2 * 3;", "partial folding");
+    let chunk = top.chunk();
+    let instrs: Vec<String> = chunk
+        .instruction_iter()
+        .map(|(instr, _)| instr.to_string())
+        .collect();
+    
+    assert_eq!(instrs[2..5], ["GetGlobal(0)", "Constant(1)", "Add"]);
+    assert_eq!(chunk.num_values(), 2);
+}
+
+#[test]
+fn folding_drops_operand_constants() {
+    let (top, heap) = compile_code("print \"a\" + \"b\" + 1 * 2 + -3;", "pool");
+    let chunk = top.chunk();
+    let values: Vec<_> = (0..chunk.num_values())
+        .map(|idx| chunk.read_value(idx).unwrap().display(&heap).to_string())
+        .collect();
+
+    assert_eq!(values, ["ab", "2", "-3"]);
+}
+
+#[test]
+fn echo_top_level_expressions() {
+
+    let source = "
+        1 + x;
+        {
+            x;
+        }
+    ";
+
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
+    compiler.set_echo_expressions(true);
+    let top = compiler.compile().unwrap();
+    let instrs: Vec<String> = top
+        .chunk()
+        .instruction_iter()
+        .map(|(instr, _)| instr.to_string())
+        .collect();
+    
+    assert_eq!(instrs, vec![
+        "Constant(0)", "GetGlobal(0)", "Add", "Print", 
+        "GetGlobal(0)", "Pop", 
+        "Return"]);
+}
+
+fn assert_folded(expr: &str, expected: &str) {
+    let (top, heap) = compile_code(&format!("print {expr};"), "folding");
+    let chunk = top.chunk();
+    let instrs: Vec<_> = chunk.instruction_iter().collect();
+
+    assert_eq!(instrs.len(), 3); // load, OP_PRINT, OP_RETURN
+
+    let (load, _) = &instrs[0];
+    let folded = match load {
+        Instruction::Constant { value_idx } => 
+            chunk.read_value(*value_idx as usize).unwrap().display(&heap).to_string(),
+        _ => disassemble_instruction(chunk, load, &heap),
+    };
+    assert_eq!(folded, expected);
+}
 
 fn compile_expression(source: &str) {
     // Add semicolon to compile as expression statement
@@ -249,19 +345,101 @@ fn compile_expression(source: &str) {
     compile_code(&expr_statement, "expression");
 }
 
-fn compile_code(source: &str, name: &str) -> FunData {
-    let mut compiler = Compiler::new(source);
+fn compile_code(source: &str, name: &str) -> (FunData, HeapManager) {
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
  
     let func_opt = compiler.compile();
     
     assert!(func_opt.is_some()); 
 
-    let mut func = func_opt.unwrap();
-    {
-        let chunk = func.borrow_chunk_mut();
-        let chunk = chunk.deref();
-        disassemble(&chunk,name);
-    }
+    let func = func_opt.unwrap();
+    disassemble(func.chunk(), name, &heap);
+
+    (func, heap)
+}
+
+#[test]
+fn debug_table_of_locals() {
+
+    let source = "
+        fun f(a) {
+            var b = a;
+            {
+                var c = b;
+            }
+        }
+    ";
 
-    func
-}
\ No newline at end of file
+    let (top, heap) = compile_code(source, "debug table");
+    let fun = match top.chunk().read_value(0) {
+        Some(Value::Closure(closure)) => heap.get_content(&heap.get_content(closure).fun),
+        _ => panic!("f should be a closure"),
+    };
+    let chunk = fun.chunk();
+
+    let names_at = |offset: usize| -> Vec<(String, usize)> {
+        chunk.locals_at(offset).map(|local| (local.name.clone(), local.slot)).collect()
+    };
+
+    assert_eq!(names_at(0), [("f".to_string(), 0), ("a".to_string(), 1)]);
+    let in_block = (0..chunk.size())
+        .find(|offset| names_at(*offset).len() == 4)
+        .expect("c should be live inside the block");
+    assert_eq!(names_at(in_block)[2..], [("b".to_string(), 2), ("c".to_string(), 3)]);
+    assert!(names_at(chunk.size() - 1).is_empty());
+}
+
+#[test]
+fn source_index_resolves_names() {
+
+    let source = "fun twice(x) {
+    var y = x * 2;
+    return square(y) + count;
+}
+var count = 1;
+print twice(count);
+";
+
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new(source, &mut heap, &GlobalTable::new_rc_refcell());
+    assert!(compiler.compile().is_some());
+    let index = compiler.take_source_index();
+
+    let symbols: Vec<(&str, bool)> = index.symbols
+        .iter()
+        .map(|symbol| (symbol.name.as_str(), symbol.global))
+        .collect();
+    assert_eq!(symbols, [("twice", true), ("x", false), ("y", false), ("count", true)]);
+    assert_eq!(index.symbols[0].kind, SymbolKind::Function { params: vec!["x".to_string()] });
+    assert_eq!(index.symbols[0].span, Span::new(1, 5, 5));
+
+    // Locals resolve to their definition, globals even if defined later,
+    // undefined globals to nothing
+    let resolved = |line, column| index.symbol_at(line, column).map(|idx| index.symbols[idx].name.as_str());
+    assert_eq!(resolved(2, 13), Some("x"));
+    assert_eq!(resolved(3, 19), Some("y"));
+    assert_eq!(resolved(3, 24), Some("count"));
+    assert_eq!(resolved(6, 7), Some("twice"));
+    assert_eq!(resolved(3, 12), None);
+    assert_eq!(index.reference_at(3, 12).map(|reference| reference.name.as_str()), Some("square"));
+    assert!(index.diagnostics.is_empty());
+}
+
+#[test]
+fn source_index_collects_diagnostics() {
+
+    let mut heap = HeapManager::new();
+    let mut compiler = Compiler::new("var a = 1\nprint a;\nprint (;\n", &mut heap, &GlobalTable::new_rc_refcell());
+    compiler.set_print_errors(false);
+    assert!(compiler.compile().is_none());
+
+    let diagnostics: Vec<(Span, &str)> = compiler.source_index().diagnostics
+        .iter()
+        .map(|diagnostic| (diagnostic.span, diagnostic.message.as_str()))
+        .collect();
+    assert_eq!(diagnostics, [
+        (Span::new(2, 1, 5), "Expect ';' after variable declaration."),
+        (Span::new(3, 8, 1), "Expect expression."),
+    ]);
+}
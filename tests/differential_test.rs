@@ -0,0 +1,159 @@
+mod common;
+
+use rlox::{frontend::{interpreter::{Session, CapturedOutput}, reference::ReferenceInterpreter}, backend::{InterpretResult, vm::{VmConfig, RuntimeError, ErrorKind}}};
+use common::ProgramGenerator;
+
+// Calls nest on the native stack in the reference
+const CONFIG: VmConfig = VmConfig {
+    max_frames: 64,
+    max_stack: 64 * 1024,
+    max_instructions: None,
+    time_limit: None,
+    max_objects: None,
+    max_heap_bytes: None,
+};
+
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    result: InterpretResult,
+    output: String,
+    compile_error: Option<String>, // the first one
+    runtime_error: Option<RuntimeError>,
+}
+
+fn script_args() -> Vec<String> {
+    vec!["one".to_string(), "two".to_string()]
+}
+
+fn run_vm(source: &str) -> Outcome {
+    let captured = CapturedOutput::default();
+    let mut session = Session::new();
+    session.set_vm_config(CONFIG);
+    session.set_script_args(script_args());
+    session.set_print_errors(false);
+    session.set_output(Box::new(captured.clone()));
+    let result = session.interpret(source);
+    Outcome {
+        result,
+        output: captured.text(),
+        compile_error: session.compile_errors().first().map(|diagnostic| diagnostic.report.clone()),
+        runtime_error: session.last_error().cloned(),
+    }
+}
+
+fn run_reference(source: &str) -> Outcome {
+    let captured = CapturedOutput::default();
+    let mut reference = ReferenceInterpreter::new();
+    reference.set_vm_config(CONFIG);
+    reference.set_script_args(script_args());
+    reference.set_output(Box::new(captured.clone()));
+    let result = reference.interpret(source);
+    Outcome {
+        result,
+        output: captured.text(),
+        compile_error: reference.compile_error().map(str::to_string),
+        runtime_error: reference.last_error().cloned(),
+    }
+}
+
+fn assert_same_behavior(source: &str) -> Outcome {
+    let vm = run_vm(source);
+    assert_eq!(vm, run_reference(source), "VM and reference differ on\n{}", source);
+    vm
+}
+
+#[test]
+fn control_flow() {
+
+    let sources = [
+        "if (1 < 2) print \"then\"; else print \"else\"; if (nil) print 1; else if (false) print 2; else print 3;",
+        "var i = 0; while (i < 3) { i = i + 1; if (i == 2) continue; print i; }",
+        "for (var i = 0; i < 4; i = i + 1) { var sq = i * i; if (sq == 4) continue; print sq; }",
+        "var n = 0; for (; n < 2;) { n = n + 1; print n; } for (var k = 3; k > 0;) { k = k - 1; print k; }",
+        "for (var i = 0; i < 2; i = i + 1) for (var j = 0; j < 2; j = j + 1) { if (i == j) continue; print i * 10 + j; }",
+        "for (var i = 0; i < 4; i = i + 1) switch (i) { case 0: print \"zero\"; case 1: print \"one\"; continue; default: print \"many\"; }",
+        "fun f(a, b) { var x = 10; switch (a) { case 1: print \"one\"; } print x; return b; } print f(1, 2); print f(3, 4);",
+        "var s = \"b\"; switch (s) { case \"a\": print 1; case \"b\": print 2; case \"c\": print 3; } switch (5) { default: print \"d\"; }",
+        "print nil or \"default\"; print 1 and 2; print false and undefined; print (1 > 2 or 3 < 4) and !nil;",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(12);",
+        "fun outer() { var x = 1; fun inner(y) { return y * 2; } return inner(x + 1); } print outer(); print outer;",
+        "fun count(n) { for (var i = 0; i < 10; i = i + 1) { if (i == n) return i; } return -1; } print count(3); print count(20);",
+        "{ var a = 1; { var a = a + 1; print a; } print a; } var g = \"global\"; { var g = g + \"!\"; print g; } print g;",
+        "print args(); print argc(); print arg(1); print arg(5); print concat(\"con\", \"cat\"); print sqrt(-1); print 0 / 0 >= 1;",
+        "assert 1 < 2; assert_eq(1, 1); assert_ne(\"1\", 1); fun f() {} print f();",
+    ];
+
+    for source in sources {
+        assert_eq!(assert_same_behavior(source).result, InterpretResult::Ok, "{}", source);
+    }
+}
+
+#[test]
+fn runtime_errors() {
+
+    let sources = [
+        "print 1 +\n  nil;",
+        "print -\"a\";",
+        "print \"a\" < \"b\";",
+        "print undefined;",
+        "undefined = 1;",
+        "fun f(a) {} f(1, 2);",
+        "var x = 3; x();",
+        "print 1;\nsqrt(\"4\");",
+        "fun f(n) { return f(n + 1); } f(0);",
+        "var i = 0;\nwhile (true) {\n  i = i + 1;\n  assert i < 3, \"i is \" + \"big\";\n}",
+        "assert_eq(\"abc\",\n \"abd\");",
+        "fun f() { switch (1) { case 1: return 1 + true; } } print f();",
+    ];
+
+    for source in sources {
+        assert_eq!(assert_same_behavior(source).result, InterpretResult::RuntimeError, "{}", source);
+    }
+
+    let error = assert_same_behavior("fun f(n) { return f(n + 1); } f(0);").runtime_error.unwrap();
+    assert_eq!(error.kind, ErrorKind::StackOverflow);
+}
+
+#[test]
+fn compile_errors() {
+
+    let sources = [
+        "print ;",
+        "var 1 = 2;",
+        "{ var a = 1; var a = 2; }",
+        "fun f(a) { var a; }",
+        "return 1;",
+        "continue;",
+        "fun f() { while (true) { fun g() { continue; } } }",
+        "if (true) var a = 1;",
+        "switch (1) { case 1: var a = 1; }",
+        "a + b = 3;",
+        "(a) = 3;",
+        "print 1 +;",
+        "print \"unterminated;",
+        "switch (1) { case 1: print 1;",
+        "class A {}",
+        "{ print 1;",
+    ];
+
+    for source in sources {
+        assert_eq!(assert_same_behavior(source).result, InterpretResult::CompileError, "{}", source);
+    }
+}
+
+#[test]
+fn random_programs() {
+
+    let mut num_ok = 0;
+    for seed in 0..400 {
+        let source = ProgramGenerator::new(seed).program();
+        let outcome = assert_same_behavior(&source);
+        assert_ne!(outcome.result, InterpretResult::CompileError, "{}\n{}", source, outcome.compile_error.unwrap_or_default());
+        if outcome.result == InterpretResult::Ok {
+            num_ok += 1;
+        }
+    }
+
+    // Most programs run to the end, so that all their statements are compared
+    assert!(num_ok > 200, "only {} programs ran without errors", num_ok);
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::fs;
+use rlox::{frontend::{formatter::format_source, scanner::Scanner, token::TokenType, test_runner::check_script}, backend::vm::VmConfig};
+use common::{ProgramGenerator, Rng};
+
+const NUM_PROGRAMS: u64 = 300;
+
+// The tokens of the source separated by whitespace without blank lines
+fn scramble(source: &str, rng: &mut Rng) -> String {
+    Scanner::new(source)
+        .filter(|token| token.get_token_type() != TokenType::Eof)
+        .map(|token| token.get_lexeme().to_string())
+        .collect::<Vec<String>>()
+        .join(*rng.pick(&[" ", "\n", " \t\n  "]))
+}
+
+// Inserts comments at the ends of random lines and on lines of their own.
+// Assertions show the source of their condition when they fail, so comments
+// in there would change the message and are left out.
+fn add_comments(source: &str, rng: &mut Rng) -> String {
+    let mut commented = String::new();
+    let mut in_assert = false;
+    for (idx, line) in source.lines().enumerate() {
+        if rng.chance(10) && !in_assert {
+            commented.push_str(&format!("// before {}\n", idx));
+        }
+        in_assert = (in_assert || line.trim_start().starts_with("assert")) && !line.contains(';');
+        commented.push_str(line);
+        if rng.chance(20) && !in_assert {
+            commented.push_str(&format!(" // after {}", idx));
+        }
+        commented.push('\n');
+    }
+    commented
+}
+
+fn assert_formatted(source: &str) -> String {
+    let formatted = format_source(source).unwrap_or_else(|errors| panic!("{:?} for\n{}", errors, source));
+    assert_eq!(format_source(&formatted).unwrap(), formatted, "not idempotent for\n{}", source);
+    formatted
+}
+
+#[test]
+fn layout_does_not_depend_on_whitespace() {
+
+    let mut rng = Rng::new(42);
+
+    for seed in 0..NUM_PROGRAMS {
+        let source = ProgramGenerator::new(seed).program();
+        let formatted = assert_formatted(&source);
+        assert_eq!(assert_formatted(&scramble(&source, &mut rng)), formatted);
+    }
+}
+
+#[test]
+fn keep_comments() {
+
+    let mut rng = Rng::new(7);
+
+    for seed in 0..NUM_PROGRAMS {
+        let mut source = ProgramGenerator::new(seed).program();
+        // Comments in the middle of statements too
+        if seed % 2 == 1 {
+            source = scramble(&source, &mut rng);
+        }
+        let source = add_comments(&source, &mut rng);
+        let formatted = assert_formatted(&source);
+
+        let comments = |source: &str| Scanner::new_with_comments(source)
+            .filter(|token| token.get_token_type() == TokenType::Comment)
+            .count();
+        assert_eq!(comments(&formatted), comments(&source));
+    }
+}
+
+// Expectations are trailing comments, so formatted tests still pass
+#[test]
+fn format_test_scripts() {
+
+    let mut file_paths: Vec<_> = fs::read_dir("tests/lox").unwrap().map(|entry| entry.unwrap().path()).collect();
+    file_paths.sort();
+
+    for file_path in file_paths {
+        let source = fs::read_to_string(&file_path).unwrap();
+        if let Ok(formatted) = format_source(&source) {
+            assert_eq!(format_source(&formatted).unwrap(), formatted);
+            assert!(check_script(&formatted, VmConfig::default()).is_empty(), "{}", file_path.display());
+        }
+    }
+}
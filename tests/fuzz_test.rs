@@ -0,0 +1,200 @@
+mod common;
+
+use std::{panic::{self, AssertUnwindSafe}, io};
+use rlox::{frontend::{interpreter::Session, scanner::Scanner, token::TokenType}, backend::{InterpretResult, vm::VmConfig}};
+use common::{ProgramGenerator, Rng};
+
+const NUM_PROGRAMS: u64 = 1000;
+
+// Tokens inserted into programs, some of them malformed
+const FRAGMENTS: [&str; 30] = [
+    "(", ")", "{", "}", ",", ".", ";", ":", "=", "==", "!", "-", "+", "/", "*", "<=",
+    "var", "fun", "return", "continue", "switch", "case", "default", "class", "assert",
+    "x", "1", "\"s\"", "\"unterminated", "@",
+];
+
+// Small limits, so that their errors are reached as well
+fn limited_config() -> VmConfig {
+    VmConfig {
+        max_frames: 8,
+        max_stack: 64,
+        max_instructions: Some(20_000),
+        max_objects: Some(40),
+        max_heap_bytes: Some(4096),
+        ..VmConfig::default()
+    }
+}
+
+// Changes a few tokens of the program or cuts it off
+fn mutate(source: &str, rng: &mut Rng) -> String {
+
+    let mut tokens: Vec<String> = Scanner::new(source)
+        .filter(|token| token.get_token_type() != TokenType::Eof)
+        .map(|token| token.get_lexeme().to_string())
+        .collect();
+
+    for _ in 0..1 + rng.below(3) {
+        if tokens.is_empty() {
+            break;
+        }
+        let idx = rng.below(tokens.len());
+        match rng.below(5) {
+            0 => {
+                tokens.remove(idx);
+            },
+            1 => tokens.insert(idx, rng.pick(&FRAGMENTS).to_string()),
+            2 => tokens[idx] = rng.pick(&FRAGMENTS).to_string(),
+            3 => {
+                let other = rng.below(tokens.len());
+                tokens.swap(idx, other);
+            },
+            _ => tokens.truncate(idx),
+        }
+    }
+
+    tokens.join(" ")
+}
+
+// Characters of Lox programs in random order
+fn noise(rng: &mut Rng) -> String {
+    const ALPHABET: &[u8] = b"abfnorsuvx019(){};,.=!<>+-*/\" \n\t:";
+    (0..rng.below(200))
+        .map(|_| *rng.pick(ALPHABET) as char)
+        .collect()
+}
+
+// Expression nested by parentheses and prefix operators in random order,
+// each element is the opening and the closing part of one level
+fn nested_expression(depth: usize, rng: &mut Rng) -> String {
+    const LEVELS: [(&str, &str); 6] = [("(", ")"), ("-", ""), ("!", ""), ("1 + (", ")"), ("-(", ")"), ("f(", ")")];
+    let levels: Vec<&(&str, &str)> = (0..depth).map(|_| rng.pick(&LEVELS)).collect();
+    let opening: String = levels.iter().map(|(opening, _)| *opening).collect();
+    let closing: String = levels.iter().rev().map(|(_, closing)| *closing).collect();
+    format!("fun f(x) {{ return x; }}\nprint {}1{};", opening, closing)
+}
+
+fn run(source: &str, config: VmConfig) -> Result<InterpretResult, String> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut session = Session::new();
+        session.set_vm_config(config);
+        session.set_print_errors(false);
+        session.set_output(Box::new(io::sink()));
+        session.interpret(source)
+    }));
+    result.map_err(|payload| {
+        payload.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default()
+    })
+}
+
+// Runs the sources with default and small limits and lists those that make the
+// interpreter panic or return an unexpected result. The small heap can make
+// the compilation of any program fail.
+fn failures(sources: impl Iterator<Item = String>, allowed: &[InterpretResult]) -> Vec<String> {
+
+    let mut failures = vec![];
+    for source in sources {
+        let runs = [
+            (VmConfig { max_instructions: Some(200_000), ..VmConfig::default() }, allowed),
+            (limited_config(), &[InterpretResult::Ok, InterpretResult::CompileError, InterpretResult::RuntimeError][..]),
+        ];
+        for (config, allowed) in runs {
+            match run(&source, config) {
+                Ok(result) if allowed.contains(&result) => (),
+                Ok(result) => failures.push(format!("{:?} for\n{}", result, source)),
+                Err(message) => failures.push(format!("panic '{}' for\n{}", message, source)),
+            }
+        }
+    }
+    failures
+}
+
+#[test]
+fn valid_programs() {
+
+    let sources = (0..NUM_PROGRAMS).map(|seed| ProgramGenerator::new(seed).program());
+    let failures = failures(sources, &[InterpretResult::Ok, InterpretResult::RuntimeError]);
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn malformed_programs() {
+
+    let mut rng = Rng::new(48);
+    let sources = (0..NUM_PROGRAMS).map(|seed| mutate(&ProgramGenerator::new(seed).program(), &mut rng));
+    let allowed = [InterpretResult::Ok, InterpretResult::CompileError, InterpretResult::RuntimeError];
+    let failures = failures(sources, &allowed);
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn random_characters() {
+
+    let mut rng = Rng::new(4711);
+    let sources: Vec<String> = (0..NUM_PROGRAMS).map(|_| noise(&mut rng)).collect();
+    let allowed = [InterpretResult::Ok, InterpretResult::CompileError, InterpretResult::RuntimeError];
+    let failures = failures(sources.into_iter(), &allowed);
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn deeply_nested_expressions() {
+
+    let mut rng = Rng::new(49);
+    let depths = [10, 100, 255, 256, 1000, 50_000, 200_000];
+    let mut sources = vec![];
+    for depth in depths {
+        sources.push(format!("print {}1{};", "(".repeat(depth), ")".repeat(depth)));
+        sources.push(format!("print {}1;", "-".repeat(depth)));
+        sources.push(format!("print {}true;", "!".repeat(depth)));
+        sources.push(nested_expression(depth, &mut rng));
+    }
+
+    let allowed = [InterpretResult::Ok, InterpretResult::CompileError, InterpretResult::RuntimeError];
+    let failures = failures(sources.into_iter(), &allowed);
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+// Statements nested to the given depth around a print statement
+fn nested_statements(depth: usize) -> Vec<String> {
+    let functions: String = (0..depth).map(|idx| format!("fun f{}() {{ ", idx)).collect();
+    vec![
+        format!("{}print 1;{}", "{ ".repeat(depth), " }".repeat(depth)),
+        format!("{}print 1;", "if (true) ".repeat(depth)),
+        format!("{}print 1;", "if (false) print 0; else ".repeat(depth)),
+        format!("var i = 0; {}i = i + 1; print i;", "while (i < 1) ".repeat(depth)),
+        format!("{}print 1;", "for (var i = 0; i < 1; i = i + 1) ".repeat(depth)),
+        format!("{}print 1;{}", functions, " }".repeat(depth)),
+    ]
+}
+
+#[test]
+fn deeply_nested_statements() {
+
+    for source in nested_statements(255) {
+        assert_eq!(run(&source, VmConfig::default()), Ok(InterpretResult::Ok), "{}", &source[..40]);
+    }
+    for depth in [256, 1000, 50_000] {
+        for source in nested_statements(depth) {
+            assert_eq!(run(&source, VmConfig::default()), Ok(InterpretResult::CompileError), "{}", &source[..40]);
+        }
+    }
+}
+
+#[test]
+fn oversized_programs() {
+
+    let body = "x = x + 1;\n".repeat(10_000);
+    let functions: String = (0..=u16::MAX as usize + 1).map(|idx| format!("fun f{}() {{}}\n", idx)).collect();
+    let sources = [
+        format!("var x = 0; while (x < 1) {{ {} }}", body),
+        format!("var x = 0; if (false) {{ {} }} print x;", body),
+        format!("var x = 0; print false and {} 1;", "x + ".repeat(20_000)),
+        format!("{}f0();", functions),
+    ];
+
+    for source in sources {
+        assert_eq!(run(&source, VmConfig::default()), Ok(InterpretResult::CompileError));
+    }
+}
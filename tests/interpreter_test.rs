@@ -1,4 +1,5 @@
-use rlox::{frontend::interpreter, backend::InterpretResult};
+use std::{rc::Rc, cell::RefCell, time::Duration};
+use rlox::{frontend::{interpreter, debugger::Debugger, test_runner}, backend::{InterpretResult, trace::TraceConfig, observer::{VmObserver, VmState}, objects::NativeFunData, vm::{VmConfig, ErrorKind}, profiler::Profiler, coverage::Coverage}};
 
 #[test]
 fn run_file() {
@@ -319,3 +320,472 @@ fn interpret_native_fun() {
     let result = interpreter::interpret(source);
     assert_eq!(result, InterpretResult::Ok);
 }
+
+#[test]
+fn interpret_folded_constants() {
+
+    let source = "
+        var seconds_per_day = 60 * 60 * 24;
+        print seconds_per_day;
+        print \"Hallo\" + \" \" + \"Welt!\";
+        print !(1 >= 2);
+    ";
+
+    let result = interpreter::interpret(source);
+    assert_eq!(result, InterpretResult::Ok);
+}
+
+#[test]
+fn interpret_ill_typed_constant() {
+
+    let source = "
+        print -\"x\";
+    ";
+
+    let result = interpreter::interpret(source);
+    assert_eq!(result, InterpretResult::RuntimeError);
+}
+
+#[test]
+fn interpret_late_global_definition() {
+
+    let source = "
+        fun show_answer() {
+            print answer;
+        }
+
+        var answer = 42;
+        show_answer();
+    ";
+
+    let result = interpreter::interpret(source);
+    assert_eq!(result, InterpretResult::Ok);
+}
+
+#[test]
+fn interpret_undefined_global() {
+
+    let source = "
+        fun show_answer() {
+            print answer;
+        }
+
+        show_answer();
+        var answer = 42;
+    ";
+
+    let result = interpreter::interpret(source);
+    assert_eq!(result, InterpretResult::RuntimeError);
+}
+
+#[test]
+fn interpret_assign_undefined_global() {
+
+    let source = "
+        answer = 42;
+    ";
+
+    let result = interpreter::interpret(source);
+    assert_eq!(result, InterpretResult::RuntimeError);
+}
+
+#[test]
+fn interpret_interned_string_equality() {
+
+    // Reading the undefined global fails if the comparison goes wrong
+    let source = "
+        fun greeting(name) {
+            return \"Hallo, \" + name;
+        }
+
+        var greet_welt = greeting(\"Welt\");
+        if (greet_welt != \"Hallo, Welt\") print undefined;
+        if (greet_welt != concat(\"Hallo, \", \"Welt\")) print undefined;
+        if (greet_welt == greeting(\"Lox\")) print undefined;
+    ";
+
+    let result = interpreter::interpret(source);
+    assert_eq!(result, InterpretResult::Ok);
+}
+
+#[test]
+fn session_keeps_state_between_inputs() {
+
+    let mut session = interpreter::Session::new();
+
+    assert_eq!(session.interpret("var x = 1;"), InterpretResult::Ok);
+    assert_eq!(session.interpret("fun inc(n) { return n + 1; }"), InterpretResult::Ok);
+    assert_eq!(session.interpret("x = inc(x); print x;"), InterpretResult::Ok);
+    assert_eq!(session.interpret("if (x != 2) print undefined;"), InterpretResult::Ok);
+}
+
+#[test]
+fn session_recovers_from_errors() {
+
+    let mut session = interpreter::Session::new();
+
+    assert_eq!(session.interpret("var greeting = \"Hallo\";"), InterpretResult::Ok);
+    assert_eq!(session.interpret("print greeting +;"), InterpretResult::CompileError);
+    assert_eq!(session.interpret("fun f() { return -greeting; } f();"), InterpretResult::RuntimeError);
+    assert_eq!(session.interpret("print greeting + \" Welt\";"), InterpretResult::Ok);
+}
+
+#[test]
+fn detect_incomplete_input() {
+
+    assert!(interpreter::is_complete_input("var x = 1;\n"));
+    assert!(!interpreter::is_complete_input("fun f() {\n"));
+    assert!(!interpreter::is_complete_input("print (1 +\n"));
+    assert!(!interpreter::is_complete_input("print \"Hallo\n"));
+    assert!(interpreter::is_complete_input("fun f() {\n  print \"}\";\n}\n"));
+    assert!(interpreter::is_complete_input("print 1);\n"));
+}
+
+#[test]
+fn session_introspection() {
+
+    let mut session = interpreter::Session::new();
+    session.interpret("var answer = 42; fun greet(name) { print \"Hallo \" + name; }");
+
+    let globals = session.globals();
+    assert!(globals.contains(&("answer".to_string(), "42".to_string())));
+    assert!(globals.contains(&("greet".to_string(), "<fn greet/1>".to_string())));
+
+    assert!(session.disassemble("greet").is_ok());
+    assert!(session.disassemble("answer").is_err());
+    assert!(session.disassemble("undefined").is_err());
+
+    session.reset();
+    assert!(session.globals().iter().all(|(name, _)| name != "answer"));
+    assert_eq!(session.interpret("print answer;"), InterpretResult::RuntimeError);
+}
+
+#[test]
+fn trace_selected_function() {
+
+    let source = "
+        fun add(a, b) {
+            return a + b;
+        }
+        print add(1, 2);
+    ";
+
+    let buffer = interpreter::CapturedOutput::default();
+    let mut trace = TraceConfig::new_with_sink(Box::new(buffer.clone()));
+    trace.add_function("add");
+
+    let mut session = interpreter::Session::new();
+    session.add_observer(Box::new(trace));
+    assert_eq!(session.interpret(source), InterpretResult::Ok);
+
+    let output = buffer.text();
+    let traced: Vec<&str> = output
+        .lines()
+        .filter(|line| !line.starts_with(' '))
+        .collect();
+
+    assert_eq!(traced.len(), 4);
+    assert!(traced.iter().all(|line| line.starts_with("add") && line.contains(" 3  OP_")));
+    assert!(output.contains("[ <fn add/2> ][ 1 ][ 2 ][ 3 ]"));
+}
+
+#[derive(Default)]
+struct EventRecorder {
+    events: Vec<String>,
+    instructions: usize,
+}
+
+impl VmObserver for EventRecorder {
+
+    fn on_instruction(&mut self, _state: &VmState) {
+        self.instructions += 1;
+    }
+
+    fn on_call(&mut self, state: &VmState) {
+        self.events.push(format!("call {} depth {}", state.function_name(), state.depth()));
+    }
+
+    fn on_return(&mut self, state: &VmState) {
+        let result = state.stack().last().map(|value| value.display(state.heap()).to_string());
+        self.events.push(format!("return {} {}", state.function_name(), result.unwrap_or_default()));
+    }
+
+    fn on_native_call(&mut self, state: &VmState, native: &NativeFunData) {
+        self.events.push(format!("native {} line {}", native.name, state.line()));
+    }
+
+    fn on_error(&mut self, state: &VmState, message: &str) {
+        self.events.push(format!("error in {} line {}: {}", state.function_name(), state.line(), message));
+    }
+}
+
+#[test]
+fn observe_execution() {
+
+    let source = "
+        fun root(x) {
+            return sqrt(x);
+        }
+        print root(16);
+        print -root;
+    ";
+
+    let recorder = Rc::new(RefCell::new(EventRecorder::default()));
+    let mut session = interpreter::Session::new();
+    session.add_observer(Box::new(recorder.clone()));
+
+    assert_eq!(session.interpret(source), InterpretResult::RuntimeError);
+
+    let recorder = recorder.borrow();
+    assert_eq!(recorder.events, [
+        "call script depth 1",
+        "call root depth 2",
+        "native sqrt line 3",
+        "return root 4",
+        "error in script line 6: Operand must be a number.",
+    ]);
+    assert!(recorder.instructions > 10);
+}
+
+#[test]
+fn debug_with_breakpoints_and_stepping() {
+
+    let source = "var total = 0;
+fun add(a, b) {
+    var sum = a + b;
+    return sum;
+}
+{
+    var x = 2;
+    total = add(x, 3);
+}
+print total;
+";
+
+    let commands = "break add\ncontinue\nnext\nlocals\nbacktrace\nfinish\nprint x\nquit\n";
+    let output = interpreter::CapturedOutput::default();
+    let debugger = Debugger::new(source, Box::new(commands.as_bytes()), Box::new(output.clone()));
+
+    let mut session = interpreter::Session::new();
+    session.add_observer(Box::new(debugger));
+    assert_eq!(session.interpret(source), InterpretResult::Interrupted);
+
+    let output = output.text();
+    assert!(output.contains("[line 3] in add"));
+    assert!(output.contains("[line 4] in add"));
+    assert!(output.contains("a = 2\nb = 3\nsum = 5\n"));
+    assert!(output.contains("#0 add at line 4\n#1 script at line 8\n"));
+    assert!(output.contains("[line 8] in script"));
+    assert!(output.contains("x = 2\n"));
+}
+
+#[test]
+fn debug_runtime_error() {
+
+    let source = "var x = 1;\nprint -nil;\n";
+    let output = interpreter::CapturedOutput::default();
+    let debugger = Debugger::new(source, Box::new("continue\nprint x\ncontinue\n".as_bytes()), Box::new(output.clone()));
+
+    let mut session = interpreter::Session::new();
+    session.add_observer(Box::new(debugger));
+    assert_eq!(session.interpret(source), InterpretResult::RuntimeError);
+
+    let output = output.text();
+    assert!(output.contains("Runtime error: Operand must be a number.\n[line 2] in script"));
+    assert!(output.contains("x = 1\n"));
+}
+
+#[test]
+fn stack_overflow() {
+
+    let mut session = interpreter::Session::new();
+    session.set_vm_config(VmConfig { max_frames: 64, ..VmConfig::default() });
+    session.interpret("var depth = 0; fun down() { depth = depth + 1; down(); }");
+
+    assert_eq!(session.interpret("down();"), InterpretResult::RuntimeError);
+    assert_eq!(session.interpret("if (depth != 63) -nil;"), InterpretResult::Ok);
+
+    // The value stack is limited independently
+    session.set_vm_config(VmConfig { max_frames: 100_000, max_stack: 1000, ..VmConfig::default() });
+    session.interpret("fun wide(a, b, c, d, e, f, g, h) { return wide(a, b, c, d, e, f, g, h); }");
+    assert_eq!(session.interpret("wide(1, 2, 3, 4, 5, 6, 7, 8);"), InterpretResult::RuntimeError);
+
+    assert_eq!(session.interpret("print \"still working\";"), InterpretResult::Ok);
+}
+
+#[test]
+fn resource_limits() {
+
+    let mut session = interpreter::Session::new();
+    let last_kind = |session: &interpreter::Session| session.last_error().map(|error| error.kind);
+
+    session.set_vm_config(VmConfig { max_instructions: Some(1000), ..VmConfig::default() });
+    assert_eq!(session.interpret("var i = 0; while (i < 10) i = i + 1;"), InterpretResult::Ok);
+    assert_eq!(last_kind(&session), None);
+    assert_eq!(session.interpret("while (true) {}"), InterpretResult::RuntimeError);
+    assert_eq!(last_kind(&session), Some(ErrorKind::InstructionLimit));
+
+    // Fuel is given anew for each run
+    assert_eq!(session.interpret("var j = 0; while (j < 10) j = j + 1;"), InterpretResult::Ok);
+
+    session.set_vm_config(VmConfig { time_limit: Some(Duration::from_millis(20)), ..VmConfig::default() });
+    assert_eq!(session.interpret("while (true) {}"), InterpretResult::RuntimeError);
+    assert_eq!(last_kind(&session), Some(ErrorKind::TimeLimit));
+
+    session.set_vm_config(VmConfig { max_objects: Some(100), ..VmConfig::default() });
+    assert_eq!(session.interpret("var s = \"\"; while (true) s = s + \"x\";"), InterpretResult::RuntimeError);
+    assert_eq!(last_kind(&session), Some(ErrorKind::HeapLimit));
+    assert_eq!(session.last_error().unwrap().line, 1);
+
+    session.reset();
+    session.set_vm_config(VmConfig { max_heap_bytes: Some(4096), ..VmConfig::default() });
+    assert_eq!(session.interpret("var t = \"x\"; while (true) t = concat(t, t);"), InterpretResult::RuntimeError);
+    assert_eq!(session.last_error().unwrap().message, "Heap size limit exceeded.");
+}
+
+#[test]
+fn object_limit_only_counts_live_inputs() {
+
+    let mut session = interpreter::Session::new();
+    session.set_vm_config(VmConfig { max_objects: Some(20), ..VmConfig::default() });
+
+    for _ in 0..100 {
+        assert_eq!(session.interpret("print 1;"), InterpretResult::Ok);
+    }
+    assert_eq!(session.interpret("var s = \"x\";"), InterpretResult::Ok);
+}
+
+#[test]
+fn heap_limits_count_garbage() {
+
+    let mut session = interpreter::Session::new();
+    session.set_vm_config(VmConfig { max_objects: Some(20), ..VmConfig::default() });
+
+    // Only the last string is reachable, but the unreachable ones are never freed
+    let source = "var s = \"\"; for (var i = 0; i < 30; i = i + 1) s = s + \"x\";";
+    assert_eq!(session.interpret(source), InterpretResult::RuntimeError);
+    assert_eq!(session.last_error().map(|error| error.kind), Some(ErrorKind::HeapLimit));
+
+    session.reset();
+    assert_eq!(session.interpret("var s = \"\"; for (var i = 0; i < 10; i = i + 1) s = s + \"x\";"), InterpretResult::Ok);
+}
+
+#[test]
+fn interrupt_from_another_thread() {
+
+    let mut session = interpreter::Session::new();
+    let handle = session.interrupt_handle();
+
+    // Requests before the run are discarded
+    handle.interrupt();
+    session.interpret("var n = 0;");
+
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        handle.interrupt();
+    });
+    assert_eq!(session.interpret("while (true) n = n + 1;"), InterpretResult::Interrupted);
+    interrupter.join().unwrap();
+    assert_eq!(session.last_error().map(|error| error.kind), Some(ErrorKind::Interrupted));
+
+    // The session survives with its globals
+    assert_eq!(session.interpret("if (n == 0) -nil;"), InterpretResult::Ok);
+    session.reset();
+    assert_eq!(session.interpret("var m = 1; while (m < 10000) m = m + 1;"), InterpretResult::Ok);
+}
+
+#[test]
+fn profile_functions_lines_and_natives() {
+
+    let profiler = Rc::new(RefCell::new(Profiler::new()));
+    let mut session = interpreter::Session::new();
+    session.add_observer(Box::new(profiler.clone()));
+
+    let source = "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 1) + fib(n - 2);\n}\nvar x = fib(5);\nvar y = sqrt(x);\n";
+    assert_eq!(session.interpret(source), InterpretResult::Ok);
+
+    let mut profiler = profiler.borrow_mut();
+    profiler.finish();
+
+    let fib = profiler.functions().iter().find(|function| function.name == "fib").unwrap();
+    assert_eq!(fib.calls, 15);
+    assert_eq!(fib.lines.keys().copied().collect::<Vec<i32>>(), vec![2, 3]);
+    assert_eq!(fib.instructions, fib.lines.values().map(|line| line.instructions).sum::<u64>());
+    assert!(fib.total_time >= fib.self_time);
+
+    let script = profiler.functions().iter().find(|function| function.name == "script").unwrap();
+    assert_eq!(script.calls, 1);
+    assert!(script.total_time >= fib.total_time);
+    // The implicit return is on the last line, not on the one after the final newline
+    assert_eq!(script.lines.keys().copied().collect::<Vec<i32>>(), vec![4, 5, 6]);
+
+    assert_eq!(profiler.natives().len(), 1);
+    assert_eq!(profiler.natives()[0].calls, 1);
+
+    let mut folded = vec![];
+    profiler.write_folded(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(folded.lines().all(|line| line.starts_with("script")));
+    assert!(folded.lines().all(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().is_ok()));
+
+    let mut report = vec![];
+    profiler.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.lines().any(|line| line.trim_start().starts_with("15 ") && line.ends_with(" fib")));
+    assert!(report.lines().any(|line| line.trim_start().starts_with("1 ") && line.ends_with(" sqrt")));
+}
+
+#[test]
+fn line_coverage() {
+
+    let coverage = Rc::new(RefCell::new(Coverage::new("test.lox")));
+    let mut session = interpreter::Session::new();
+    session.add_observer(Box::new(coverage.clone()));
+
+    let source = "fun sign(n) {\n  if (n < 0) {\n    return -1;\n  }\n  return 1;\n}\nprint sign(5);\n";
+    assert_eq!(session.interpret(source), InterpretResult::Ok);
+
+    let coverage = coverage.borrow();
+    assert_eq!(coverage.lines().get(&3), Some(&0));
+    assert!(coverage.lines()[&2] > 0 && coverage.lines()[&5] > 0 && coverage.lines()[&7] > 0);
+    assert_eq!(coverage.missed_ranges(), vec![(3, 3)]);
+
+    let mut lcov = vec![];
+    coverage.write_lcov(&mut lcov).unwrap();
+    let lcov = String::from_utf8(lcov).unwrap();
+    assert!(lcov.starts_with("TN:\nSF:test.lox\nFN:2,sign\nFNDA:1,sign\nFNF:1\nFNH:1\n"));
+    assert!(lcov.contains("\nDA:3,0\n"));
+    assert!(lcov.ends_with(&format!("LF:{}\nLH:{}\nend_of_record\n", coverage.lines().len(), coverage.lines_hit())));
+}
+
+#[test]
+fn lox_test_suite() {
+    assert_eq!(test_runner::run_tests("tests/lox", VmConfig::default()), Ok(()));
+}
+
+#[test]
+fn assertions() {
+
+    let mut session = interpreter::Session::new();
+    session.set_print_errors(false);
+
+    assert_eq!(session.interpret("var x = 2;\nassert x == 2;\nassert x > 1, \"big\" + nil;"), InterpretResult::Ok);
+
+    assert_eq!(session.interpret("fun check(n) {\n  assert n  <\n    0, \"negative\";\n}\ncheck(x);"), InterpretResult::RuntimeError);
+    let error = session.last_error().unwrap();
+    assert_eq!(error.kind, ErrorKind::Assertion);
+    assert_eq!(error.message, "Assertion failed on line 2: n < 0 (negative)");
+    assert_eq!(error.line, 2);
+
+    assert_eq!(session.interpret("assert_eq(x, 2); assert_ne(x, \"2\");"), InterpretResult::Ok);
+    assert_eq!(session.interpret("assert_eq(\"abc\", \"abd\");"), InterpretResult::RuntimeError);
+    let error = session.last_error().unwrap();
+    assert_eq!(error.kind, ErrorKind::Assertion);
+    assert_eq!(error.message, "Values are not equal.\n  left:  \"abc\"\n  right: \"abd\"\n            ^");
+
+    assert_eq!(session.interpret("assert_eq(x, true);"), InterpretResult::RuntimeError);
+    assert_eq!(session.last_error().unwrap().message, "Values are not equal.\n  left:  2 (number)\n  right: true (bool)");
+
+    assert_eq!(session.interpret("assert x"), InterpretResult::CompileError);
+    assert_eq!(session.compile_errors()[0].report, "[line 1] Error at end: Expect ';' after assertion.");
+}